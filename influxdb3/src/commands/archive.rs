@@ -0,0 +1,103 @@
+use std::path::PathBuf;
+use std::sync::Arc;
+
+use clap::Parser;
+use clap_blocks::object_store::{make_object_store, ObjectStoreConfig};
+use influxdb3_write::archive::{archive_database, restore_database, ArchiveSummary};
+use object_store::DynObjectStore;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("error configuring object store: {0}")]
+    ObjectStore(#[from] clap_blocks::object_store::ParseError),
+
+    #[error(transparent)]
+    Archive(#[from] influxdb3_write::archive::Error),
+}
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Parser)]
+pub struct Config {
+    #[clap(subcommand)]
+    cmd: SubCommand,
+}
+
+#[derive(Debug, Parser)]
+pub enum SubCommand {
+    /// Export a database, its catalog and all of its persisted parquet files, to an archive
+    Export(ArchiveConfig),
+
+    /// Restore a database from an archive into the object store of a stopped server
+    Restore(ArchiveConfig),
+}
+
+// the archive must be stored apart from the server's own data, so either a bucket or a
+// directory has to be given for it
+#[derive(Debug, Parser)]
+#[clap(group(
+    clap::ArgGroup::new("archive_location")
+        .required(true)
+        .args(["archive_bucket", "archive_data_dir"])
+))]
+pub struct ArchiveConfig {
+    /// The object store of the server
+    #[clap(flatten)]
+    object_store_config: ObjectStoreConfig,
+
+    /// The name of the database to archive or restore
+    #[clap(short = 'd', long = "dbname", env = "INFLUXDB3_DATABASE_NAME")]
+    database_name: String,
+
+    /// The bucket that the archive is stored in. It must be in the same type of object store as
+    /// the server's, and is accessed with the same credentials.
+    #[clap(long = "archive-bucket", env = "INFLUXDB3_ARCHIVE_BUCKET")]
+    archive_bucket: Option<String>,
+
+    /// The directory that the archive is stored in, when using the file object store
+    #[clap(long = "archive-data-dir", env = "INFLUXDB3_ARCHIVE_DATA_DIR")]
+    archive_data_dir: Option<PathBuf>,
+}
+
+impl ArchiveConfig {
+    fn object_stores(&self) -> Result<(Arc<DynObjectStore>, Arc<DynObjectStore>)> {
+        let server_store = make_object_store(&self.object_store_config)?;
+
+        let mut archive_store_config = self.object_store_config.clone();
+        if let Some(bucket) = &self.archive_bucket {
+            archive_store_config.bucket = Some(bucket.clone());
+        }
+        if let Some(dir) = &self.archive_data_dir {
+            archive_store_config.database_directory = Some(dir.clone());
+        }
+        let archive_store = make_object_store(&archive_store_config)?;
+
+        Ok((server_store, archive_store))
+    }
+}
+
+pub(crate) async fn command(config: Config) -> Result<()> {
+    let (verb, summary) = match config.cmd {
+        SubCommand::Export(config) => {
+            let (server_store, archive_store) = config.object_stores()?;
+            let summary =
+                archive_database(server_store, archive_store, &config.database_name).await?;
+            ("Archived", summary)
+        }
+        SubCommand::Restore(config) => {
+            let (server_store, archive_store) = config.object_stores()?;
+            let summary =
+                restore_database(archive_store, server_store, &config.database_name).await?;
+            ("Restored", summary)
+        }
+    };
+
+    let ArchiveSummary {
+        segments,
+        parquet_files,
+        parquet_bytes,
+    } = summary;
+    println!("{verb} {parquet_files} parquet files ({parquet_bytes} bytes) in {segments} segments");
+
+    Ok(())
+}
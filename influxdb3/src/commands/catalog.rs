@@ -0,0 +1,96 @@
+use std::path::PathBuf;
+
+use clap::Parser;
+use clap_blocks::object_store::{make_object_store, ObjectStoreConfig};
+use influxdb3_write::recovery::{infer_catalog, recover_catalog, RecoverySummary};
+use influxdb3_write::wal::WalImpl;
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("error configuring object store: {0}")]
+    ObjectStore(#[from] clap_blocks::object_store::ParseError),
+
+    #[error("error opening the wal: {0}")]
+    Wal(#[from] influxdb3_write::wal::Error),
+
+    #[error(transparent)]
+    Recovery(#[from] influxdb3_write::recovery::Error),
+
+    #[error("error serializing the catalog: {0}")]
+    Serialize(#[from] serde_json::Error),
+
+    #[error("the wal directory {0} doesn't exist")]
+    WalDirectoryNotFound(PathBuf),
+}
+
+pub(crate) type Result<T> = std::result::Result<T, Error>;
+
+#[derive(Debug, Parser)]
+pub struct Config {
+    #[clap(subcommand)]
+    cmd: SubCommand,
+}
+
+#[derive(Debug, Parser)]
+pub enum SubCommand {
+    /// Recover a lost catalog of a stopped server from the writes in its WAL
+    Recover(RecoverConfig),
+}
+
+#[derive(Debug, Parser)]
+pub struct RecoverConfig {
+    /// The object store of the server, that the recovered catalog is persisted to
+    #[clap(flatten)]
+    object_store_config: ObjectStoreConfig,
+
+    /// The WAL directory of the server
+    #[clap(long = "wal-directory", env = "INFLUXDB3_WAL_DIRECTORY", action)]
+    wal_directory: PathBuf,
+
+    /// Only read this many of the most recent WAL segments. All of them are read by default.
+    #[clap(long = "segments", action)]
+    segments: Option<usize>,
+
+    /// Print the recovered catalog as JSON instead of persisting it. The object store is still
+    /// read for the tables of the persisted segments.
+    #[clap(long = "dry-run", default_value = "false", action)]
+    dry_run: bool,
+}
+
+pub(crate) async fn command(config: Config) -> Result<()> {
+    match config.cmd {
+        SubCommand::Recover(config) => {
+            // opening the WAL creates its directory, which would recover an empty catalog from a
+            // mistyped one
+            if !config.wal_directory.is_dir() {
+                return Err(Error::WalDirectoryNotFound(config.wal_directory));
+            }
+            let wal = WalImpl::new(config.wal_directory)?;
+            let object_store = make_object_store(&config.object_store_config)?;
+            let summary = if config.dry_run {
+                let (catalog, summary) = infer_catalog(&wal, object_store, config.segments).await?;
+                println!("{}", serde_json::to_string_pretty(&catalog.into_inner())?);
+                summary
+            } else {
+                recover_catalog(&wal, object_store, config.segments).await?
+            };
+
+            let RecoverySummary {
+                segments,
+                writes,
+                skipped_writes,
+                persisted_segments,
+                skipped_tables,
+                databases,
+                tables,
+            } = summary;
+            eprintln!(
+                "Recovered {databases} databases and {tables} tables from {writes} writes in \
+                 {segments} WAL segments, skipping {skipped_writes} writes, and from \
+                 {persisted_segments} persisted segments, skipping {skipped_tables} tables"
+            );
+        }
+    }
+
+    Ok(())
+}
@@ -0,0 +1,78 @@
+//! Runs the server types that are registered with [`crate::main_with`], which include the
+//! built-in server as `serve`, with the same listen address, metrics, tracing and shutdown on
+//! signals for every server type.
+
+use clap_blocks::socket_addr::SocketAddr;
+use influxdb3_server::server_type::ServerTypes;
+use influxdb3_server::wait_for_signal;
+use observability_deps::tracing::info;
+use tokio_util::sync::CancellationToken;
+use trace_exporters::TracingConfig;
+use trogging::cli::LoggingConfig;
+
+use super::serve::{common_server_state, DEFAULT_HTTP_BIND_ADDR};
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error(transparent)]
+    CommonState(#[from] super::serve::Error),
+
+    #[error(transparent)]
+    ServerType(#[from] influxdb3_server::server_type::Error),
+}
+
+pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, clap::Parser)]
+pub struct Config {
+    /// The name of the server type to run. The registered server types are listed if none is
+    /// given.
+    #[clap(action)]
+    server_type: Option<String>,
+
+    /// logging options
+    #[clap(flatten)]
+    pub(crate) logging_config: LoggingConfig,
+
+    /// tracing options
+    #[clap(flatten)]
+    pub(crate) tracing_config: TracingConfig,
+
+    /// The address on which the server type listens
+    #[clap(
+    long = "http-bind",
+    env = "INFLUXDB3_HTTP_BIND_ADDR",
+    default_value = DEFAULT_HTTP_BIND_ADDR,
+    action,
+    )]
+    http_bind_address: SocketAddr,
+
+    /// The arguments for the server type, given after `--`
+    #[clap(last = true, action)]
+    args: Vec<String>,
+}
+
+pub(crate) async fn command(config: Config, server_types: ServerTypes) -> Result<()> {
+    let Some(name) = config.server_type else {
+        for (name, server_type) in server_types.iter() {
+            println!("{name}\t{}", server_type.description());
+        }
+        return Ok(());
+    };
+
+    let common_state = common_server_state(&config.tracing_config, *config.http_bind_address)?;
+
+    let shutdown = CancellationToken::new();
+    let signal_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        wait_for_signal().await;
+        signal_shutdown.cancel();
+    });
+
+    info!(server_type = %name, "starting server type");
+    server_types
+        .run(&name, config.args, common_state, shutdown)
+        .await?;
+
+    Ok(())
+}
@@ -1,5 +1,6 @@
 //! Entrypoint for InfluxDB 3.0 Edge Server
 
+use async_trait::async_trait;
 use clap_blocks::{
     memory_size::MemorySize,
     object_store::{make_object_store, ObjectStoreConfig},
@@ -11,12 +12,21 @@ use influxdb3_process::{
     build_malloc_conf, setup_metric_registry, INFLUXDB3_GIT_HASH, INFLUXDB3_VERSION, PROCESS_UUID,
 };
 use influxdb3_server::{
-    auth::AllOrNothingAuthorizer, builder::ServerBuilder, query_executor::QueryExecutorImpl, serve,
-    CommonServerState,
+    auth::{AllOrNothingAuthorizer, HttpCalloutAuthorizer},
+    builder::ServerBuilder,
+    query_executor::QueryExecutorImpl,
+    replication::{
+        ReplicationConfig, SpoolConfig, SpoolFullPolicy, DEFAULT_REPLICATION_QUEUE_SIZE,
+    },
+    rollup::RollupRule,
+    schema_export::{SchemaFormat, SchemaRegistryConfig, SchemaRegistryExporter},
+    serve,
+    server_type::{BoxError, ServerType},
+    wait_for_signal, CommonServerState,
 };
 use influxdb3_write::persister::PersisterImpl;
-use influxdb3_write::wal::WalImpl;
-use influxdb3_write::write_buffer::WriteBufferImpl;
+use influxdb3_write::wal::{WalCodec, WalImpl};
+use influxdb3_write::write_buffer::{BlockAction, Blocklist, WriteBufferImpl};
 use influxdb3_write::SegmentDuration;
 use iox_query::exec::{DedicatedExecutor, Executor, ExecutorConfig};
 use iox_time::SystemProvider;
@@ -30,6 +40,7 @@ use std::{
     num::NonZeroUsize,
     path::{Path, PathBuf},
     sync::Arc,
+    time::Duration,
 };
 use thiserror::Error;
 use tokio_util::sync::CancellationToken;
@@ -65,6 +76,27 @@ pub enum Error {
 
     #[error("invalid token: {0}")]
     InvalidToken(#[from] hex::FromHexError),
+
+    #[error("invalid replication target: {0}")]
+    InvalidReplicationTarget(#[source] influxdb3_client::Error),
+
+    #[error("error creating authorization callout client: {0}")]
+    AuthorizationCallout(#[source] reqwest::Error),
+
+    #[error("error creating schema registry client: {0}")]
+    SchemaRegistry(#[source] reqwest::Error),
+
+    #[error("error reading rollup config {path}: {source}")]
+    ReadRollupConfig {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("invalid rollup config: {0}")]
+    InvalidRollupConfig(String),
+
+    #[error("error opening replication spool: {0}")]
+    ReplicationSpool(#[from] influxdb3_server::replication::SpoolError),
 }
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
@@ -102,6 +134,68 @@ pub struct Config {
     #[clap(long = "wal-directory", env = "INFLUXDB3_WAL_DIRECTORY", action)]
     pub wal_directory: Option<PathBuf>,
 
+    /// The codec used to serialize the batches written to new WAL segments. Existing segments
+    /// are always read with the codec they were written with.
+    #[clap(
+        long = "wal-codec",
+        env = "INFLUXDB3_WAL_CODEC",
+        default_value = "json",
+        action
+    )]
+    pub wal_codec: WalCodecArg,
+
+    /// Stop serving requests on SIGINT or SIGTERM and write a snapshot of the write buffer to the
+    /// WAL directory before exiting. The next start loads the snapshot instead of replaying the
+    /// WAL segments that were open, which makes planned restarts faster.
+    #[clap(
+        long = "snapshot-on-shutdown",
+        env = "INFLUXDB3_SNAPSHOT_ON_SHUTDOWN",
+        default_value = "false",
+        action
+    )]
+    pub snapshot_on_shutdown: bool,
+
+    /// Reject writes to a database with a 503 once this many consecutive attempts to persist
+    /// segments with its data to object storage have failed, until persisting succeeds again.
+    /// Writes are never rejected for failed persists by default.
+    #[clap(
+        long = "persist-failure-limit",
+        env = "INFLUXDB3_PERSIST_FAILURE_LIMIT",
+        default_value = "0",
+        action
+    )]
+    pub persist_failure_limit: usize,
+
+    /// Comma separated glob patterns of measurement names to block writes to in every database,
+    /// e.g. `debug_*`. `*` matches any run of characters and `?` any single character.
+    #[clap(
+        long = "blocked-measurements",
+        env = "INFLUXDB3_BLOCKED_MEASUREMENTS",
+        value_delimiter = ',',
+        action
+    )]
+    pub blocked_measurements: Vec<String>,
+
+    /// Comma separated glob patterns of tag and field names to block writes of in every
+    /// database.
+    #[clap(
+        long = "blocked-columns",
+        env = "INFLUXDB3_BLOCKED_COLUMNS",
+        value_delimiter = ',',
+        action
+    )]
+    pub blocked_columns: Vec<String>,
+
+    /// What to do with lines that match the blocked measurements or columns.
+    #[clap(
+        value_enum,
+        long = "blocked-action",
+        env = "INFLUXDB3_BLOCKED_ACTION",
+        default_value = "drop",
+        action
+    )]
+    pub blocked_action: BlockedAction,
+
     /// The address on which InfluxDB will serve HTTP API requests
     #[clap(
     long = "http-bind",
@@ -167,6 +261,238 @@ pub struct Config {
         action
     )]
     pub query_log_size: usize,
+
+    /// Start the server in standby mode. The server fully initializes, but its health endpoint
+    /// reports it as unavailable until it is promoted with `POST /api/v3/admin/promote`.
+    #[clap(
+        long = "standby",
+        env = "INFLUXDB3_STANDBY",
+        default_value = "false",
+        action
+    )]
+    pub standby: bool,
+
+    /// The number of hours of per minute write statistics, by database and table, to keep in
+    /// memory. The statistics are served from `/api/v3/write_stats`.
+    #[clap(
+        long = "write-stats-retention-hours",
+        env = "INFLUXDB3_WRITE_STATS_RETENTION_HOURS",
+        default_value = "24",
+        action
+    )]
+    pub write_stats_retention_hours: usize,
+
+    /// The number of recent writes to keep the metadata of in memory, to help debug what is
+    /// being written to the server. The writes are served from `/debug/recent-writes`. Nothing is
+    /// kept by default.
+    #[clap(
+        long = "recent-writes-capacity",
+        env = "INFLUXDB3_RECENT_WRITES_CAPACITY",
+        default_value = "0",
+        action
+    )]
+    pub recent_writes_capacity: usize,
+
+    /// The number of recently accepted lines to keep in memory for each database, to check
+    /// that data is flowing for a database without running a query. The lines are served from
+    /// `/api/v3/recent_lines`. Nothing is kept by default.
+    #[clap(
+        long = "recent-lines-capacity",
+        env = "INFLUXDB3_RECENT_LINES_CAPACITY",
+        default_value = "0",
+        action
+    )]
+    pub recent_lines_capacity: usize,
+
+    /// The base URL of a remote server, e.g. `http://replica:8181`, that all accepted writes
+    /// will be asynchronously replicated to.
+    #[clap(
+        long = "replication-target",
+        env = "INFLUXDB3_REPLICATION_TARGET",
+        action
+    )]
+    pub replication_target: Option<String>,
+
+    /// The bearer token used to authenticate with the replication target, if it requires one.
+    #[clap(
+        long = "replication-token",
+        env = "INFLUXDB3_REPLICATION_TOKEN",
+        requires = "replication_target",
+        action
+    )]
+    pub replication_token: Option<String>,
+
+    /// The maximum number of accepted writes that can be waiting to be replicated. Writes that
+    /// are accepted while the queue is full are not replicated.
+    #[clap(
+        long = "replication-queue-size",
+        env = "INFLUXDB3_REPLICATION_QUEUE_SIZE",
+        default_value_t = DEFAULT_REPLICATION_QUEUE_SIZE,
+        action
+    )]
+    pub replication_queue_size: usize,
+
+    /// A directory to spool writes to while the replication target is unreachable. Spooled
+    /// writes are replayed, in order, once the target can be reached again, including after a
+    /// restart. Without a spool, writes are held in memory and retried.
+    #[clap(
+        long = "replication-spool-dir",
+        env = "INFLUXDB3_REPLICATION_SPOOL_DIR",
+        requires = "replication_target",
+        action
+    )]
+    pub replication_spool_dir: Option<PathBuf>,
+
+    /// The maximum size, in bytes, of the replication spool.
+    #[clap(
+    long = "replication-spool-max-size",
+    env = "INFLUXDB3_REPLICATION_SPOOL_MAX_SIZE",
+    default_value = "1073741824", // 1 GiB
+    action,
+    )]
+    pub replication_spool_max_size: u64,
+
+    /// What to do with writes that don't fit in a full replication spool.
+    #[clap(
+        value_enum,
+        long = "replication-spool-full-policy",
+        env = "INFLUXDB3_REPLICATION_SPOOL_FULL_POLICY",
+        default_value_t = SpoolFull::DropOldest,
+        action
+    )]
+    pub replication_spool_full_policy: SpoolFull,
+
+    /// The URL of an external authorization service. Every request to the HTTP API is
+    /// authorized by POSTing its `namespace`, `token` and `operation`, as JSON, to this URL. A
+    /// `2xx` response allows the request, any other response, or failing to get one, denies it.
+    #[clap(
+        long = "authz-callout-url",
+        env = "INFLUXDB3_AUTHZ_CALLOUT_URL",
+        action
+    )]
+    pub authz_callout_url: Option<reqwest::Url>,
+
+    /// The timeout, in milliseconds, for requests to the external authorization service.
+    #[clap(
+        long = "authz-callout-timeout-ms",
+        env = "INFLUXDB3_AUTHZ_CALLOUT_TIMEOUT_MS",
+        default_value = "5000",
+        action
+    )]
+    pub authz_callout_timeout_ms: u64,
+
+    /// The URL of a schema registry, implementing the Confluent Schema Registry API, to publish
+    /// the schema of every table to. A new version is registered for a table under the
+    /// `<db>.<table>-value` subject whenever columns are added to it.
+    #[clap(
+        long = "schema-registry-url",
+        env = "INFLUXDB3_SCHEMA_REGISTRY_URL",
+        action
+    )]
+    pub schema_registry_url: Option<reqwest::Url>,
+
+    /// The format that schemas are published to the schema registry in.
+    #[clap(
+        value_enum,
+        long = "schema-registry-format",
+        env = "INFLUXDB3_SCHEMA_REGISTRY_FORMAT",
+        default_value = "json",
+        action
+    )]
+    pub schema_registry_format: RegistryFormat,
+
+    /// How often, in milliseconds, the catalog is checked for schema changes to publish.
+    #[clap(
+        long = "schema-registry-poll-interval-ms",
+        env = "INFLUXDB3_SCHEMA_REGISTRY_POLL_INTERVAL_MS",
+        default_value = "1000",
+        action
+    )]
+    pub schema_registry_poll_interval_ms: u64,
+
+    /// A JSON file with the rollups to compute over accepted writes, e.g.,
+    /// `[{"db": "foo", "table": "cpu", "interval_secs": 60, "aggregates": ["mean", "max"]}]`.
+    /// Each rollup is written to a `<table>_<interval>` table, e.g. `cpu_1m`, in the
+    /// `target_db` of the rollup, or the database it was computed from.
+    #[clap(long = "rollup-config", env = "INFLUXDB3_ROLLUP_CONFIG", action)]
+    pub rollup_config: Option<PathBuf>,
+}
+
+/// The format that table schemas are published to a schema registry in
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+#[clap(rename_all = "snake_case")]
+pub enum RegistryFormat {
+    /// JSON Schema
+    Json,
+    /// Avro record schemas
+    Avro,
+}
+
+impl From<RegistryFormat> for SchemaFormat {
+    fn from(this: RegistryFormat) -> Self {
+        match this {
+            RegistryFormat::Json => Self::Json,
+            RegistryFormat::Avro => Self::Avro,
+        }
+    }
+}
+
+/// The codec used to serialize WAL batches
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+#[clap(rename_all = "snake_case")]
+pub enum WalCodecArg {
+    /// JSON, readable by all versions of the server
+    Json,
+    /// A compact binary framing of the writes, with the database names of each batch stored
+    /// once rather than with every write
+    Binary,
+}
+
+impl From<WalCodecArg> for WalCodec {
+    fn from(this: WalCodecArg) -> Self {
+        match this {
+            WalCodecArg::Json => Self::Json,
+            WalCodecArg::Binary => Self::Binary,
+        }
+    }
+}
+
+/// What to do with the lines of writes that match the blocklist
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+#[clap(rename_all = "snake_case")]
+pub enum BlockedAction {
+    /// Silently remove blocked measurements, tags and fields from writes
+    Drop,
+    /// Reject the lines with blocked measurements, tags or fields as invalid
+    Reject,
+}
+
+impl From<BlockedAction> for BlockAction {
+    fn from(this: BlockedAction) -> Self {
+        match this {
+            BlockedAction::Drop => Self::Drop,
+            BlockedAction::Reject => Self::Reject,
+        }
+    }
+}
+
+/// What to do with writes that don't fit in a full replication spool
+#[derive(Debug, Clone, Copy, clap::ValueEnum)]
+#[clap(rename_all = "snake_case")]
+pub enum SpoolFull {
+    /// Remove the oldest spooled writes to make room
+    DropOldest,
+    /// Drop the new write
+    DropNewest,
+}
+
+impl From<SpoolFull> for SpoolFullPolicy {
+    fn from(this: SpoolFull) -> Self {
+        match this {
+            SpoolFull::DropOldest => Self::DropOldest,
+            SpoolFull::DropNewest => Self::DropNewest,
+        }
+    }
 }
 
 /// If `p` does not exist, try to create it as a directory.
@@ -183,7 +509,83 @@ fn ensure_directory_exists(p: &Path) {
     }
 }
 
+fn load_rollup_rules(path: PathBuf) -> Result<Vec<RollupRule>> {
+    let contents = std::fs::read(&path).map_err(|source| Error::ReadRollupConfig {
+        path: path.clone(),
+        source,
+    })?;
+    serde_json::from_slice(&contents).map_err(|e| Error::InvalidRollupConfig(e.to_string()))
+}
+
+/// The built-in server, which is registered as the `serve` server type so that it can be run
+/// with `influxdb3 run` like the server types of other crates. When it's run that way, it
+/// listens on the address and traces with the settings given to `run`, rather than its own.
+#[derive(Debug)]
+pub struct Serve;
+
+#[async_trait]
+impl ServerType for Serve {
+    fn description(&self) -> &str {
+        "the InfluxDB 3.0 server, as run by `influxdb3 serve`"
+    }
+
+    async fn run(
+        &self,
+        args: Vec<String>,
+        common_state: CommonServerState,
+        shutdown: CancellationToken,
+    ) -> Result<(), BoxError> {
+        let config: Config =
+            clap::Parser::try_parse_from(std::iter::once("serve".to_string()).chain(args))?;
+        run_server(config, common_state, shutdown).await?;
+        Ok(())
+    }
+}
+
+/// Builds the state shared by the server and the server types from the tracing config and the
+/// address to listen on
+pub(crate) fn common_server_state(
+    tracing_config: &TracingConfig,
+    http_bind_address: std::net::SocketAddr,
+) -> Result<CommonServerState> {
+    let metrics = setup_metric_registry();
+    let trace_exporter = tracing_config.build()?;
+    let trace_header_parser = TraceHeaderParser::new()
+        .with_jaeger_trace_context_header_name(
+            &tracing_config.traces_jaeger_trace_context_header_name,
+        )
+        .with_jaeger_debug_name(&tracing_config.traces_jaeger_debug_name);
+
+    Ok(CommonServerState::new(
+        metrics,
+        trace_exporter,
+        trace_header_parser,
+        http_bind_address,
+    )?)
+}
+
 pub async fn command(config: Config) -> Result<()> {
+    let common_state = common_server_state(&config.tracing_config, *config.http_bind_address)?;
+
+    // Construct a token to trigger clean shutdown
+    let frontend_shutdown = CancellationToken::new();
+    if config.snapshot_on_shutdown {
+        let shutdown = frontend_shutdown.clone();
+        tokio::spawn(async move {
+            wait_for_signal().await;
+            shutdown.cancel();
+        });
+    }
+
+    run_server(config, common_state, frontend_shutdown).await
+}
+
+/// Runs the server with the common state until `frontend_shutdown` is cancelled
+async fn run_server(
+    config: Config,
+    common_state: CommonServerState,
+    frontend_shutdown: CancellationToken,
+) -> Result<()> {
     let num_cpus = num_cpus::get();
     let build_malloc_conf = build_malloc_conf();
     info!(
@@ -195,7 +597,7 @@ pub async fn command(config: Config) -> Result<()> {
         "InfluxDB3 Edge server starting",
     );
 
-    let metrics = setup_metric_registry();
+    let metrics = common_state.metric_registry();
 
     // Install custom panic handler and forget about it.
     //
@@ -206,14 +608,9 @@ pub async fn command(config: Config) -> Result<()> {
     let f = SendPanicsToTracing::new_with_metrics(&metrics);
     std::mem::forget(f);
 
-    // Construct a token to trigger clean shutdown
-    let frontend_shutdown = CancellationToken::new();
-
     let object_store: Arc<DynObjectStore> =
         make_object_store(&config.object_store_config).map_err(Error::ObjectStoreParsing)?;
 
-    let trace_exporter = config.tracing_config.build()?;
-
     let parquet_store =
         ParquetStorage::new(Arc::clone(&object_store), StorageId::from("influxdb3"));
 
@@ -248,24 +645,10 @@ pub async fn command(config: Config) -> Result<()> {
     let runtime_env = exec.new_context().inner().runtime_env();
     register_iox_object_store(runtime_env, parquet_store.id(), Arc::clone(&object_store));
 
-    let trace_header_parser = TraceHeaderParser::new()
-        .with_jaeger_trace_context_header_name(
-            config
-                .tracing_config
-                .traces_jaeger_trace_context_header_name,
-        )
-        .with_jaeger_debug_name(config.tracing_config.traces_jaeger_debug_name);
-
-    let common_state = CommonServerState::new(
-        Arc::clone(&metrics),
-        trace_exporter,
-        trace_header_parser,
-        *config.http_bind_address,
-    )?;
     let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
     let wal: Option<Arc<WalImpl>> = config
         .wal_directory
-        .map(|dir| WalImpl::new(dir).map(Arc::new))
+        .map(|dir| WalImpl::new(dir).map(|wal| Arc::new(wal.with_codec(config.wal_codec.into()))))
         .transpose()?;
 
     let time_provider = Arc::new(SystemProvider::new());
@@ -276,9 +659,32 @@ pub async fn command(config: Config) -> Result<()> {
             Arc::clone(&time_provider),
             config.segment_duration,
             Arc::clone(&exec),
+            Arc::clone(&metrics),
         )
-        .await?,
+        .await?
+        .with_persist_failure_limit(config.persist_failure_limit)
+        .with_blocklist(Blocklist::new(
+            config.blocked_measurements,
+            config.blocked_columns,
+            config.blocked_action.into(),
+        )),
     );
+    let schema_exporter = config
+        .schema_registry_url
+        .map(|url| {
+            SchemaRegistryExporter::new(
+                SchemaRegistryConfig {
+                    url,
+                    format: config.schema_registry_format.into(),
+                    poll_interval: Duration::from_millis(config.schema_registry_poll_interval_ms),
+                },
+                write_buffer.catalog(),
+            )
+            .map(SchemaRegistryExporter::spawn)
+        })
+        .transpose()
+        .map_err(Error::SchemaRegistry)?;
+
     let query_executor = Arc::new(QueryExecutorImpl::new(
         write_buffer.catalog(),
         Arc::clone(&write_buffer),
@@ -289,9 +695,38 @@ pub async fn command(config: Config) -> Result<()> {
         config.query_log_size,
     ));
 
-    let builder = ServerBuilder::new(common_state)
+    let mut builder = ServerBuilder::new(common_state)
         .max_request_size(config.max_http_request_size)
-        .write_buffer(write_buffer)
+        .standby(config.standby)
+        .write_stats_retention_hours(config.write_stats_retention_hours)
+        .recent_writes_capacity(config.recent_writes_capacity)
+        .recent_lines_capacity(config.recent_lines_capacity);
+    if let Some(target) = config.replication_target {
+        let mut replication = ReplicationConfig::new(target, config.replication_token)
+            .map_err(Error::InvalidReplicationTarget)?
+            .with_queue_size(config.replication_queue_size);
+        if let Some(dir) = config.replication_spool_dir {
+            replication = replication.with_spool(SpoolConfig {
+                dir,
+                max_bytes: config.replication_spool_max_size,
+                full_policy: config.replication_spool_full_policy.into(),
+            })?;
+        }
+        builder = builder.replication(replication);
+    }
+    if let Some(url) = config.authz_callout_url {
+        let authorizer =
+            HttpCalloutAuthorizer::new(url, Duration::from_millis(config.authz_callout_timeout_ms))
+                .map_err(Error::AuthorizationCallout)?;
+        builder = builder.request_authorizer(Arc::new(authorizer));
+    }
+    if let Some(path) = config.rollup_config {
+        builder = builder
+            .rollups(load_rollup_rules(path)?)
+            .map_err(|e| Error::InvalidRollupConfig(e.to_string()))?;
+    }
+    let builder = builder
+        .write_buffer(Arc::clone(&write_buffer))
         .query_executor(query_executor)
         .time_provider(time_provider)
         .persister(persister);
@@ -305,6 +740,16 @@ pub async fn command(config: Config) -> Result<()> {
     };
     serve(server, frontend_shutdown).await?;
 
+    // requests have finished, and the rollups have stopped being written, by the time the server
+    // returns, so with the schema exporter stopped nothing touches the buffer after the snapshot
+    if let Some(schema_exporter) = schema_exporter {
+        schema_exporter.abort();
+    }
+    if config.snapshot_on_shutdown {
+        info!("writing write buffer snapshot for shutdown");
+        write_buffer.snapshot()?;
+    }
+
     Ok(())
 }
 
@@ -0,0 +1,207 @@
+//! Named profiles of defaults for the server's flags, so that every server of a type, e.g. at
+//! the edge or in a region, can be started with the same settings from a single flag.
+//!
+//! The settings of a profile are the environment variables of the flags they set, e.g.
+//! `INFLUXDB3_QUERY_LOG_SIZE`. They are applied before the command line is parsed, and only when
+//! the variable isn't already set, so flags and the environment (including `.env`) take
+//! precedence over the profile, and the profile over the default of each flag.
+//!
+//! Besides the built-in profiles, profiles can be defined in a JSON file passed with
+//! `--profile-file`, and can extend another profile to only override some of its settings:
+//!
+//! ```json
+//! {
+//!     "profile": "small-edge",
+//!     "profiles": {
+//!         "small-edge": {
+//!             "extends": "edge",
+//!             "settings": { "INFLUXDB3_EXEC_MEM_POOL_BYTES": 1073741824 }
+//!         }
+//!     }
+//! }
+//! ```
+//!
+//! The `profile` in the file is used when no profile is given with `--profile`.
+
+use std::collections::{BTreeMap, HashMap};
+use std::ffi::OsString;
+use std::path::PathBuf;
+
+use serde::Deserialize;
+
+const PROFILE_ENV: &str = "INFLUXDB3_PROFILE";
+const PROFILE_FILE_ENV: &str = "INFLUXDB3_PROFILE_FILE";
+
+#[derive(Debug, thiserror::Error)]
+pub(crate) enum Error {
+    #[error("error reading profile file {path:?}: {source}")]
+    ReadFile {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("invalid profile file {path:?}: {source}")]
+    InvalidFile {
+        path: PathBuf,
+        source: serde_json::Error,
+    },
+
+    #[error("unknown profile {0:?}")]
+    UnknownProfile(String),
+
+    #[error("profile {0:?} extends a profile that extends it")]
+    Cycle(String),
+
+    #[error("invalid value for setting {0:?} of profile {1:?}, expected a string, number or bool")]
+    InvalidSetting(String, String),
+}
+
+pub(crate) type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct ProfileFile {
+    #[serde(default)]
+    profile: Option<String>,
+    #[serde(default)]
+    profiles: HashMap<String, Profile>,
+}
+
+#[derive(Debug, Default, Deserialize)]
+#[serde(deny_unknown_fields)]
+struct Profile {
+    #[serde(default)]
+    extends: Option<String>,
+    #[serde(default)]
+    settings: BTreeMap<String, serde_json::Value>,
+}
+
+/// Sizes and timeouts for a server on constrained hardware at the edge, that persists often and
+/// keeps little in memory
+const EDGE: &[(&str, &str)] = &[
+    ("INFLUXDB3_EXEC_MEM_POOL_BYTES", "2147483648"),
+    ("INFLUXDB3_RAM_POOL_DATA_BYTES", "268435456"),
+    ("INFLUXDB3_SEGMENT_DURATION", "10m"),
+    ("INFLUXDB3_QUERY_LOG_SIZE", "100"),
+    ("INFLUXDB3_WRITE_STATS_RETENTION_HOURS", "6"),
+    ("INFLUXDB3_REPLICATION_QUEUE_SIZE", "1000"),
+    ("INFLUXDB3_AUTHZ_CALLOUT_TIMEOUT_MS", "1000"),
+];
+
+/// Sizes and timeouts for a regional server that aggregates the data of many others
+const REGIONAL: &[(&str, &str)] = &[
+    ("INFLUXDB3_MAX_HTTP_REQUEST_SIZE", "104857600"),
+    ("INFLUXDB3_EXEC_MEM_POOL_BYTES", "17179869184"),
+    ("INFLUXDB3_RAM_POOL_DATA_BYTES", "4294967296"),
+    ("INFLUXDB3_SEGMENT_DURATION", "1h"),
+    ("INFLUXDB3_QUERY_LOG_SIZE", "10000"),
+    ("INFLUXDB3_WRITE_STATS_RETENTION_HOURS", "72"),
+    ("INFLUXDB3_REPLICATION_QUEUE_SIZE", "100000"),
+    ("INFLUXDB3_AUTHZ_CALLOUT_TIMEOUT_MS", "10000"),
+];
+
+fn built_in(name: &str) -> Option<&'static [(&'static str, &'static str)]> {
+    match name {
+        "edge" => Some(EDGE),
+        "regional" => Some(REGIONAL),
+        _ => None,
+    }
+}
+
+/// Sets the environment variables of the profile given with `--profile`, or in the environment,
+/// that aren't already set. This must be called before the command line is parsed.
+pub(crate) fn apply_profile() -> Result<()> {
+    let args: Vec<OsString> = std::env::args_os().collect();
+    let profile = arg_value(&args, "--profile").or_else(|| std::env::var(PROFILE_ENV).ok());
+    let profile_file = arg_value(&args, "--profile-file")
+        .or_else(|| std::env::var(PROFILE_FILE_ENV).ok())
+        .map(PathBuf::from);
+
+    let file = match profile_file {
+        Some(path) => {
+            let data = std::fs::read(&path).map_err(|source| Error::ReadFile {
+                path: path.clone(),
+                source,
+            })?;
+            serde_json::from_slice(&data).map_err(|source| Error::InvalidFile { path, source })?
+        }
+        None => ProfileFile::default(),
+    };
+
+    let Some(profile) = profile.or_else(|| file.profile.clone()) else {
+        return Ok(());
+    };
+
+    for (name, value) in resolve(&profile, &file)? {
+        if std::env::var_os(&name).is_none() {
+            std::env::set_var(name, value);
+        }
+    }
+
+    Ok(())
+}
+
+/// Returns the value of the flag from the arguments, given as either `--flag value` or
+/// `--flag=value`
+fn arg_value(args: &[OsString], flag: &str) -> Option<String> {
+    let mut args = args.iter().filter_map(|arg| arg.to_str());
+    while let Some(arg) = args.next() {
+        if arg == "--" {
+            return None;
+        }
+        if arg == flag {
+            return args.next().map(ToString::to_string);
+        }
+        if let Some(value) = arg
+            .strip_prefix(flag)
+            .and_then(|rest| rest.strip_prefix('='))
+        {
+            return Some(value.to_string());
+        }
+    }
+    None
+}
+
+/// Returns the settings of the profile, including those it inherits. Profiles in the file take
+/// precedence over the built-in profiles of the same name.
+fn resolve(name: &str, file: &ProfileFile) -> Result<BTreeMap<String, String>> {
+    let mut chain = vec![];
+    let mut next = Some(name.to_string());
+    while let Some(name) = next.take() {
+        if chain.contains(&name) {
+            return Err(Error::Cycle(name));
+        }
+        next = match file.profiles.get(&name) {
+            Some(profile) => profile.extends.clone(),
+            None if built_in(&name).is_some() => None,
+            None => return Err(Error::UnknownProfile(name)),
+        };
+        chain.push(name);
+    }
+
+    // apply the settings from the base of the chain, so each profile overrides those it extends
+    let mut settings = BTreeMap::new();
+    for name in chain.iter().rev() {
+        match file.profiles.get(name) {
+            Some(profile) => {
+                for (key, value) in &profile.settings {
+                    let value = match value {
+                        serde_json::Value::String(s) => s.clone(),
+                        serde_json::Value::Number(n) => n.to_string(),
+                        serde_json::Value::Bool(b) => b.to_string(),
+                        _ => return Err(Error::InvalidSetting(key.clone(), name.clone())),
+                    };
+                    settings.insert(key.clone(), value);
+                }
+            }
+            None => {
+                let built_in = built_in(name).expect("profile should be built in");
+                for (key, value) in built_in {
+                    settings.insert(key.to_string(), value.to_string());
+                }
+            }
+        }
+    }
+
+    Ok(settings)
+}
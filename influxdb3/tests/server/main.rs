@@ -16,7 +16,11 @@ mod auth;
 mod flight;
 mod limits;
 mod ping;
+mod profile;
 mod query;
+mod replication;
+mod run;
+mod standby;
 mod system_tables;
 mod write;
 
@@ -24,6 +28,11 @@ mod write;
 #[derive(Debug, Default)]
 pub struct TestConfig {
     auth_token: Option<(String, String)>,
+    standby: bool,
+    replication_target: Option<String>,
+    profile: Option<String>,
+    profile_file: Option<String>,
+    server_type: bool,
 }
 
 impl TestConfig {
@@ -37,6 +46,36 @@ impl TestConfig {
         self
     }
 
+    /// Start the [`TestServer`] in standby mode
+    pub fn standby(mut self) -> Self {
+        self.standby = true;
+        self
+    }
+
+    /// Replicate writes accepted by the [`TestServer`] to the server at `target`
+    pub fn replication_target<S: Into<String>>(mut self, target: S) -> Self {
+        self.replication_target = Some(target.into());
+        self
+    }
+
+    /// Start the [`TestServer`] with the defaults of the named profile
+    pub fn profile<S: Into<String>>(mut self, profile: S) -> Self {
+        self.profile = Some(profile.into());
+        self
+    }
+
+    /// Start the [`TestServer`] with the profiles defined in the file at `path`
+    pub fn profile_file<S: Into<String>>(mut self, path: S) -> Self {
+        self.profile_file = Some(path.into());
+        self
+    }
+
+    /// Start the [`TestServer`] as the `serve` server type, with `influxdb3 run serve`
+    pub fn server_type(mut self) -> Self {
+        self.server_type = true;
+        self
+    }
+
     /// Spawn a new [`TestServer`] with this configuration
     ///
     /// This will run the `influxdb3 serve` command, and bind its HTTP
@@ -50,6 +89,18 @@ impl TestConfig {
         if let Some((token, _)) = &self.auth_token {
             args.append(&mut vec!["--bearer-token", token]);
         }
+        if self.standby {
+            args.push("--standby");
+        }
+        if let Some(target) = &self.replication_target {
+            args.append(&mut vec!["--replication-target", target]);
+        }
+        if let Some(profile) = &self.profile {
+            args.append(&mut vec!["--profile", profile]);
+        }
+        if let Some(path) = &self.profile_file {
+            args.append(&mut vec!["--profile-file", path]);
+        }
         args
     }
 }
@@ -85,9 +136,18 @@ impl TestServer {
     async fn spawn_inner(config: TestConfig) -> Self {
         let bind_addr = get_local_bind_addr();
         let mut command = Command::cargo_bin("influxdb3").expect("create the influxdb3 command");
-        let mut command = command
-            .arg("serve")
-            .args(["--http-bind", &bind_addr.to_string()])
+        let mut command = if config.server_type {
+            // the flags of `run` come before those of the server type, which follow `--`
+            command
+                .args(["run", "serve"])
+                .args(["--http-bind", &bind_addr.to_string()])
+                .arg("--")
+        } else {
+            command
+                .arg("serve")
+                .args(["--http-bind", &bind_addr.to_string()])
+        };
+        command = command
             .args(["--object-store", "memory"])
             .args(config.as_args());
 
@@ -0,0 +1,46 @@
+use reqwest::StatusCode;
+
+use crate::TestServer;
+
+#[tokio::test]
+async fn profile_from_file_extends_built_in_profile() {
+    let dir = test_helpers::tmp_dir().unwrap();
+    let path = dir.path().join("profiles.json");
+    std::fs::write(
+        &path,
+        r#"{
+            "profiles": {
+                "standby-edge": {
+                    "extends": "edge",
+                    "settings": { "INFLUXDB3_STANDBY": true }
+                }
+            }
+        }"#,
+    )
+    .unwrap();
+
+    let server = TestServer::configure()
+        .profile("standby-edge")
+        .profile_file(path.to_str().unwrap())
+        .spawn()
+        .await;
+    let client = reqwest::Client::new();
+
+    // the server started in standby, as set by the profile
+    let resp = client
+        .get(format!("{base}/health", base = server.client_addr()))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // and takes writes as usual with the settings of the edge profile
+    server
+        .write_lp_to_db(
+            "foo",
+            "cpu,host=s1 usage=0.9 1",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+}
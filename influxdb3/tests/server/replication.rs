@@ -0,0 +1,50 @@
+use std::time::Duration;
+
+use crate::TestServer;
+
+#[tokio::test]
+async fn writes_are_replicated_to_target() {
+    let replica = TestServer::spawn().await;
+    let primary = TestServer::configure()
+        .replication_target(replica.client_addr())
+        .spawn()
+        .await;
+
+    primary
+        .write_lp_to_db(
+            "foo",
+            "cpu,host=s1 usage=0.9 1\n\
+             cpu,host=s2 usage=0.5 2",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+
+    let client = reqwest::Client::new();
+    let url = format!("{base}/api/v3/query_sql", base = replica.client_addr());
+    let mut body = String::new();
+    for _ in 0..100 {
+        let resp = client
+            .get(&url)
+            .query(&[
+                ("db", "foo"),
+                ("q", "SELECT host, usage FROM cpu ORDER BY host"),
+                ("format", "json"),
+            ])
+            .send()
+            .await
+            .unwrap();
+        if resp.status().is_success() {
+            body = resp.text().await.unwrap();
+            if body != "[]" {
+                break;
+            }
+        }
+        tokio::time::sleep(Duration::from_millis(50)).await;
+    }
+
+    assert_eq!(
+        body,
+        "[{\"host\":\"s1\",\"usage\":0.9},{\"host\":\"s2\",\"usage\":0.5}]"
+    );
+}
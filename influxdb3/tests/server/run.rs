@@ -0,0 +1,70 @@
+use assert_cmd::cargo::CommandCargoExt;
+use influxdb3_client::Precision;
+use std::process::Command;
+
+use crate::TestServer;
+
+#[test]
+fn lists_the_built_in_server_type() {
+    let output = Command::cargo_bin("influxdb3")
+        .expect("create the influxdb3 command")
+        .arg("run")
+        .output()
+        .expect("run the influxdb3 command");
+
+    assert!(output.status.success());
+    let stdout = String::from_utf8(output.stdout).unwrap();
+    assert!(
+        stdout.lines().any(|line| line.starts_with("serve\t")),
+        "serve is not listed in: {stdout}"
+    );
+}
+
+#[tokio::test]
+async fn runs_the_built_in_server_as_a_server_type() {
+    let server = TestServer::configure().server_type().spawn().await;
+
+    server
+        .write_lp_to_db(
+            "foo",
+            "cpu,host=s1,region=us-east usage=0.9 1",
+            Precision::Nanosecond,
+        )
+        .await
+        .unwrap();
+
+    let resp = server
+        .api_v3_query_influxql(&[
+            ("db", "foo"),
+            ("q", "SELECT time, host, region, usage FROM cpu"),
+            ("format", "pretty"),
+        ])
+        .await
+        .text()
+        .await
+        .unwrap();
+    assert_eq!(
+        "+------------------+-------------------------------+------+---------+-------+\n\
+        | iox::measurement | time                          | host | region  | usage |\n\
+        +------------------+-------------------------------+------+---------+-------+\n\
+        | cpu              | 1970-01-01T00:00:00.000000001 | s1   | us-east | 0.9   |\n\
+        +------------------+-------------------------------+------+---------+-------+",
+        resp
+    );
+}
+
+#[test]
+fn rejects_unknown_server_types() {
+    let output = Command::cargo_bin("influxdb3")
+        .expect("create the influxdb3 command")
+        .args(["run", "proxy"])
+        .output()
+        .expect("run the influxdb3 command");
+
+    assert!(!output.status.success());
+    let stderr = String::from_utf8(output.stderr).unwrap();
+    assert!(
+        stderr.contains("unknown server type \"proxy\""),
+        "unexpected error: {stderr}"
+    );
+}
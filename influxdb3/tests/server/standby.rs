@@ -0,0 +1,36 @@
+use reqwest::StatusCode;
+
+use crate::TestServer;
+
+#[tokio::test]
+async fn standby_fails_health_until_promoted() {
+    let server = TestServer::configure().standby().spawn().await;
+    let client = reqwest::Client::new();
+    let health_url = format!("{base}/health", base = server.client_addr());
+    let promote_url = format!("{base}/api/v3/admin/promote", base = server.client_addr());
+
+    let resp = client.get(&health_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::SERVICE_UNAVAILABLE);
+
+    // writes are still accepted while in standby
+    server
+        .write_lp_to_db(
+            "foo",
+            "cpu,host=s1 usage=0.9 1",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+
+    let resp = client.post(&promote_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = client.get(&health_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    // promoting again is a no-op
+    let resp = client.post(&promote_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let resp = client.get(&health_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+}
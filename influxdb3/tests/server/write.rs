@@ -272,3 +272,471 @@ async fn api_v2_write_round_trip() {
         +------------------+-------------------------------+------+-------+"
     );
 }
+
+#[tokio::test]
+async fn api_v3_write_stats() {
+    let server = TestServer::spawn().await;
+    let client = reqwest::Client::new();
+
+    server
+        .write_lp_to_db(
+            "foo",
+            "cpu,host=a usage=0.5 1\n\
+            cpu,host=b usage=0.6 2\n\
+            mem,host=a used=10i 3",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+    server
+        .write_lp_to_db(
+            "bar",
+            "cpu,host=a usage=0.5 1",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+
+    let resp = client
+        .get(format!(
+            "{base}/api/v3/write_stats",
+            base = server.client_addr()
+        ))
+        .query(&[("db", "foo")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let rows = resp.json::<serde_json::Value>().await.unwrap();
+    let rows = rows.as_array().unwrap();
+    let lines: u64 = rows
+        .iter()
+        .filter(|row| row["table"] == "cpu")
+        .map(|row| row["lines"].as_u64().unwrap())
+        .sum();
+    assert_eq!(lines, 2);
+    let new_columns: u64 = rows
+        .iter()
+        .filter(|row| row["table"] == "mem")
+        .map(|row| row["new_columns"].as_u64().unwrap())
+        .sum();
+    assert_eq!(new_columns, 2);
+    assert!(rows.iter().all(|row| row["db"] == "foo"));
+}
+
+#[tokio::test]
+async fn api_v3_ingest_transforms() {
+    let server = TestServer::spawn().await;
+    let client = reqwest::Client::new();
+    let transforms_url = format!(
+        "{base}/api/v3/configure/transforms",
+        base = server.client_addr()
+    );
+
+    let transforms = serde_json::json!([
+        {"type": "map_measurement", "from": "cpu_old", "to": "cpu"},
+        {"type": "rename_tag", "table": "cpu", "from": "hostname", "to": "host"},
+        {"type": "drop_field", "field": "debug"},
+    ]);
+    let resp = client
+        .post(&transforms_url)
+        .query(&[("db", "foo")])
+        .json(&transforms)
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = client
+        .get(&transforms_url)
+        .query(&[("db", "foo")])
+        .send()
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+    assert_eq!(resp.as_array().unwrap().len(), 3);
+
+    server
+        .write_lp_to_db(
+            "foo",
+            "cpu_old,hostname=a usage=0.5,debug=true 1",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+
+    let resp = server
+        .api_v3_query_influxql(&[
+            ("q", "SELECT * FROM cpu"),
+            ("db", "foo"),
+            ("format", "pretty"),
+        ])
+        .await
+        .text()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        resp,
+        "+------------------+---------------------+------+-------+\n\
+        | iox::measurement | time                | host | usage |\n\
+        +------------------+---------------------+------+-------+\n\
+        | cpu              | 1970-01-01T00:00:01 | a    | 0.5   |\n\
+        +------------------+---------------------+------+-------+"
+    );
+}
+
+#[tokio::test]
+async fn api_v3_write_flags() {
+    let server = TestServer::spawn().await;
+    let client = reqwest::Client::new();
+    let write_flags_url = format!(
+        "{base}/api/v3/configure/write_flags",
+        base = server.client_addr()
+    );
+
+    server
+        .write_lp_to_db(
+            "foo",
+            "cpu,host=a usage=0.5 1",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+
+    let resp = client
+        .post(&write_flags_url)
+        .query(&[("db", "foo")])
+        .json(&serde_json::json!({"strict_schema": true}))
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+
+    let resp = client
+        .get(&write_flags_url)
+        .query(&[("db", "foo")])
+        .send()
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+    assert_eq!(
+        resp,
+        serde_json::json!({
+            "accept_partial": null,
+            "coerce_field_types": false,
+            "strict_schema": true,
+            "replicate": true,
+            "validate_histograms": false,
+        })
+    );
+
+    // writes to existing columns are accepted, new columns are not
+    server
+        .write_lp_to_db(
+            "foo",
+            "cpu,host=b usage=0.7 2",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+    let resp = client
+        .post(format!(
+            "{base}/api/v3/write_lp",
+            base = server.client_addr()
+        ))
+        .query(&[("db", "foo")])
+        .body("cpu,host=a,region=us usage=0.5 3")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::UNPROCESSABLE_ENTITY);
+}
+
+#[tokio::test]
+async fn api_v3_schema() {
+    let server = TestServer::spawn().await;
+    let client = reqwest::Client::new();
+    let schema_url = format!("{base}/api/v3/schema", base = server.client_addr());
+
+    server
+        .write_lp_to_db(
+            "foo",
+            "cpu,host=a usage=0.5,count=2i,ok=true 1",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+
+    let resp = client
+        .get(&schema_url)
+        .query(&[("db", "foo")])
+        .send()
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+
+    assert_eq!(
+        resp["tables"],
+        serde_json::json!([{
+            "db": "foo",
+            "table": "cpu",
+            "schema": {
+                "$schema": "http://json-schema.org/draft-07/schema#",
+                "title": "foo.cpu",
+                "type": "object",
+                "properties": {
+                    "count": {"type": "integer", "description": "field"},
+                    "host": {"type": "string", "description": "tag"},
+                    "ok": {"type": "boolean", "description": "field"},
+                    "time": {
+                        "type": "integer",
+                        "description": "timestamp in nanoseconds since the epoch"
+                    },
+                    "usage": {"type": "number", "description": "field"},
+                },
+                "required": ["time"],
+            },
+        }])
+    );
+    let sequence = resp["sequence"].as_u64().unwrap();
+
+    // adding a column moves the catalog sequence on
+    server
+        .write_lp_to_db(
+            "foo",
+            "cpu,host=a,region=us usage=0.5 2",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+
+    let resp = client
+        .get(&schema_url)
+        .query(&[("db", "foo"), ("format", "avro")])
+        .send()
+        .await
+        .unwrap()
+        .json::<serde_json::Value>()
+        .await
+        .unwrap();
+
+    assert!(resp["sequence"].as_u64().unwrap() > sequence);
+    let schema = &resp["tables"][0]["schema"];
+    assert_eq!(schema["type"], "record");
+    assert_eq!(schema["name"], "cpu");
+    assert_eq!(schema["namespace"], "foo");
+    let region = schema["fields"]
+        .as_array()
+        .unwrap()
+        .iter()
+        .find(|field| field["name"] == "region")
+        .unwrap();
+    assert_eq!(
+        region,
+        &serde_json::json!({"name": "region", "type": ["null", "string"], "default": null})
+    );
+}
+
+#[tokio::test]
+async fn api_v3_write_returns_wal_positions() {
+    let server = TestServer::spawn().await;
+    let client = reqwest::Client::new();
+    let write_url = format!("{base}/api/v3/write_lp", base = server.client_addr());
+
+    // positions are only returned when asked for
+    let resp = client
+        .post(&write_url)
+        .query(&[("db", "foo")])
+        .body("cpu,host=a usage=0.5")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    assert!(resp.text().await.unwrap().is_empty());
+
+    let mut positions = vec![];
+    for lp in ["cpu,host=a usage=0.6", "cpu,host=a usage=0.7"] {
+        let resp = client
+            .post(&write_url)
+            .query(&[("db", "foo"), ("return_wal_positions", "true")])
+            .body(lp)
+            .send()
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let body = resp.json::<serde_json::Value>().await.unwrap();
+        let wal_positions = body["wal_positions"].as_array().unwrap();
+        assert_eq!(wal_positions.len(), 1);
+        positions.push(wal_positions[0].clone());
+    }
+
+    // writes are sequenced in the order they were accepted
+    let position = |p: &serde_json::Value| {
+        (
+            p["segment_id"].as_u64().unwrap(),
+            p["sequence_number"].as_u64().unwrap(),
+        )
+    };
+    assert!(position(&positions[0]) < position(&positions[1]));
+}
+
+#[tokio::test]
+async fn api_v3_partial_write_returns_wal_positions() {
+    let server = TestServer::spawn().await;
+    let client = reqwest::Client::new();
+    let write_url = format!("{base}/api/v3/write_lp", base = server.client_addr());
+
+    let resp = client
+        .post(&write_url)
+        .query(&[("db", "foo"), ("return_wal_positions", "true")])
+        .body("cpu,host=a usage=0.5\ncpu,host=a usage=")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body = resp.json::<serde_json::Value>().await.unwrap();
+    assert_eq!(body["data"].as_array().unwrap().len(), 1);
+    assert_eq!(body["wal_positions"].as_array().unwrap().len(), 1);
+
+    // positions are only returned when asked for
+    let resp = client
+        .post(&write_url)
+        .query(&[("db", "foo")])
+        .body("cpu,host=a usage=0.6\ncpu,host=a usage=")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+    let body = resp.json::<serde_json::Value>().await.unwrap();
+    assert!(body.get("wal_positions").is_none());
+}
+
+#[tokio::test]
+async fn api_v3_watermarks() {
+    let server = TestServer::spawn().await;
+    let client = reqwest::Client::new();
+    let write_url = format!("{base}/api/v3/write_lp", base = server.client_addr());
+    let watermarks_url = format!("{base}/api/v3/watermarks", base = server.client_addr());
+
+    let resp = client
+        .post(&write_url)
+        .query(&[("db", "foo"), ("return_wal_positions", "true")])
+        .body("cpu,host=a usage=0.5")
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = resp.json::<serde_json::Value>().await.unwrap();
+    let position = &body["wal_positions"][0];
+    let segment_id = position["segment_id"].to_string();
+    let sequence_number = position["sequence_number"].to_string();
+
+    // the write is readable as soon as it has been accepted
+    let resp = client
+        .get(&watermarks_url)
+        .query(&[
+            ("segment_id", segment_id.as_str()),
+            ("sequence_number", sequence_number.as_str()),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = resp.json::<serde_json::Value>().await.unwrap();
+    assert_eq!(body["reached"], true);
+    assert_eq!(body["open"][0], *position);
+
+    // but its segment is still open, so isn't persisted
+    let resp = client
+        .get(&watermarks_url)
+        .query(&[
+            ("segment_id", segment_id.as_str()),
+            ("sequence_number", sequence_number.as_str()),
+            ("wait", "persisted"),
+            ("timeout_ms", "50"),
+        ])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = resp.json::<serde_json::Value>().await.unwrap();
+    assert_eq!(body["reached"], false);
+
+    // without a position only the watermarks are returned
+    let resp = client.get(&watermarks_url).send().await.unwrap();
+    assert_eq!(resp.status(), StatusCode::OK);
+    let body = resp.json::<serde_json::Value>().await.unwrap();
+    assert!(body.get("reached").is_none());
+
+    let resp = client
+        .get(&watermarks_url)
+        .query(&[("segment_id", segment_id.as_str())])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(resp.status(), StatusCode::BAD_REQUEST);
+}
+
+#[tokio::test]
+async fn api_v3_subscribe() {
+    let server = TestServer::spawn().await;
+    let client = reqwest::Client::new();
+    let subscribe_url = format!("{base}/api/v3/subscribe", base = server.client_addr());
+
+    let mut lp_resp = client
+        .get(&subscribe_url)
+        .query(&[("db", "foo")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(lp_resp.status(), StatusCode::OK);
+    let mut json_resp = client
+        .get(&subscribe_url)
+        .query(&[("db", "foo"), ("format", "json")])
+        .send()
+        .await
+        .unwrap();
+    assert_eq!(json_resp.status(), StatusCode::OK);
+
+    // writes to other databases aren't sent to the subscriber
+    server
+        .write_lp_to_db(
+            "bar",
+            "cpu,host=b usage=0.7 1",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+    server
+        .write_lp_to_db(
+            "foo",
+            "cpu,host=a usage=0.5 1",
+            influxdb3_client::Precision::Second,
+        )
+        .await
+        .unwrap();
+
+    let chunk = lp_resp.chunk().await.unwrap().unwrap();
+    assert_eq!(chunk, "cpu,host=a usage=0.5 1000000000\n");
+
+    let chunk = json_resp.chunk().await.unwrap().unwrap();
+    let line: serde_json::Value = serde_json::from_slice(&chunk).unwrap();
+    assert_eq!(
+        line,
+        serde_json::json!({
+            "db": "foo",
+            "measurement": "cpu",
+            "tags": {"host": "a"},
+            "fields": {"usage": 0.5},
+            "time": 1_000_000_000,
+        })
+    );
+}
@@ -1,6 +1,11 @@
+use std::sync::Arc;
+use std::time::Duration;
+
 use async_trait::async_trait;
 use authz::{Authorizer, Error, Permission};
-use observability_deps::tracing::{debug, warn};
+use observability_deps::tracing::{debug, error, warn};
+use reqwest::{StatusCode, Url};
+use serde::Serialize;
 use sha2::{Digest, Sha512};
 
 /// An [`Authorizer`] implementation that will grant access to all
@@ -56,3 +61,273 @@ impl Authorizer for DefaultAuthorizer {
         Ok(())
     }
 }
+
+/// The operation performed by a request to the HTTP API
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum Operation {
+    /// Writing line protocol
+    Write,
+    /// Running a SQL or InfluxQL query
+    Query,
+    /// Reading or changing the configuration of a database
+    Configure,
+    /// Administering the server, e.g., promoting it from standby
+    Admin,
+    /// Reading the health, metrics, or statistics of the server
+    Monitor,
+}
+
+/// The information given to a [`RequestAuthorizer`] to authorize a request
+#[derive(Debug, Clone, Copy)]
+pub struct AuthorizationRequest<'a> {
+    /// The namespace, i.e., database, that the request is for, if it is for one
+    pub namespace: Option<&'a str>,
+    /// The token provided with the request, if any
+    pub token: Option<&'a [u8]>,
+    pub operation: Operation,
+}
+
+/// An extension point for authorizing every request to the HTTP API with the namespace and
+/// operation it is for, e.g., by calling out to an external authorization service.
+///
+/// This is invoked after the server's [`Authorizer`] has accepted the request's token. Requests
+/// to the gRPC API are authorized through a [`RequestAuthorizingAuthorizer`].
+#[async_trait]
+pub trait RequestAuthorizer: std::fmt::Debug + Send + Sync + 'static {
+    async fn authorize(&self, request: AuthorizationRequest<'_>) -> Result<(), Error>;
+}
+
+/// A [`RequestAuthorizer`] that authorizes requests by making a `POST` request with a JSON body
+/// containing the `namespace`, `token` and `operation` to an external authorization service.
+///
+/// A `2xx` response allows the request, `401` means the token is invalid and anything else,
+/// including failing to reach the service, forbids the request.
+#[derive(Debug)]
+pub struct HttpCalloutAuthorizer {
+    url: Url,
+    client: reqwest::Client,
+}
+
+#[derive(Debug, Serialize)]
+struct CalloutRequest<'a> {
+    namespace: Option<&'a str>,
+    token: Option<String>,
+    operation: Operation,
+}
+
+impl HttpCalloutAuthorizer {
+    pub fn new(url: Url, timeout: Duration) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Client::builder().timeout(timeout).build()?;
+        Ok(Self { url, client })
+    }
+}
+
+#[async_trait]
+impl RequestAuthorizer for HttpCalloutAuthorizer {
+    async fn authorize(&self, request: AuthorizationRequest<'_>) -> Result<(), Error> {
+        let body = serde_json::to_vec(&CalloutRequest {
+            namespace: request.namespace,
+            token: request
+                .token
+                .map(|token| String::from_utf8_lossy(token).into_owned()),
+            operation: request.operation,
+        })
+        .expect("authorization callout request serializes to JSON");
+
+        let resp = self
+            .client
+            .post(self.url.clone())
+            .header(reqwest::header::CONTENT_TYPE, "application/json")
+            .body(body)
+            .send()
+            .await;
+
+        match resp.map(|resp| resp.status()) {
+            Ok(status) if status.is_success() => Ok(()),
+            Ok(StatusCode::UNAUTHORIZED) => Err(Error::InvalidToken),
+            Ok(status) => {
+                debug!(%status, ?request.operation, "authorization callout forbid request");
+                Err(Error::Forbidden)
+            }
+            Err(e) => {
+                error!(error = %e, url = %self.url, "authorization callout failed");
+                Err(Error::Forbidden)
+            }
+        }
+    }
+}
+
+/// An [`Authorizer`] that authorizes requests with the server's [`Authorizer`], and then with a
+/// [`RequestAuthorizer`] as queries, for the gRPC API to be authorized the same way as the HTTP
+/// API. The namespace of a gRPC request isn't known to the [`Authorizer`], so none is given.
+#[derive(Debug)]
+pub struct RequestAuthorizingAuthorizer {
+    authorizer: Arc<dyn Authorizer>,
+    request_authorizer: Arc<dyn RequestAuthorizer>,
+}
+
+impl RequestAuthorizingAuthorizer {
+    pub fn new(
+        authorizer: Arc<dyn Authorizer>,
+        request_authorizer: Arc<dyn RequestAuthorizer>,
+    ) -> Self {
+        Self {
+            authorizer,
+            request_authorizer,
+        }
+    }
+}
+
+#[async_trait]
+impl Authorizer for RequestAuthorizingAuthorizer {
+    async fn permissions(
+        &self,
+        token: Option<Vec<u8>>,
+        perms: &[Permission],
+    ) -> Result<Vec<Permission>, Error> {
+        let permissions = self.authorizer.permissions(token.clone(), perms).await?;
+        self.request_authorizer
+            .authorize(AuthorizationRequest {
+                namespace: None,
+                token: token.as_deref(),
+                operation: Operation::Query,
+            })
+            .await?;
+        Ok(permissions)
+    }
+
+    async fn probe(&self) -> Result<(), Error> {
+        self.authorizer.probe().await
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use hyper::service::{make_service_fn, service_fn};
+    use hyper::{Body, Request, Response};
+    use parking_lot::Mutex;
+    use std::convert::Infallible;
+    use std::net::SocketAddr;
+
+    /// Starts an authorization service that responds to `/allow`, `/deny` and `/unauthorized`
+    /// with the corresponding status, and to `/slow` after a second, recording the body of every
+    /// request it is sent
+    fn start_callout_service() -> (SocketAddr, Arc<Mutex<Vec<serde_json::Value>>>) {
+        let requests = Arc::new(Mutex::new(vec![]));
+        let service_requests = Arc::clone(&requests);
+        let make_service = make_service_fn(move |_| {
+            let requests = Arc::clone(&service_requests);
+            async move {
+                Ok::<_, Infallible>(service_fn(move |req: Request<Body>| {
+                    let requests = Arc::clone(&requests);
+                    async move {
+                        let path = req.uri().path().to_string();
+                        let body = hyper::body::to_bytes(req.into_body()).await.unwrap();
+                        requests.lock().push(serde_json::from_slice(&body).unwrap());
+                        let status = match path.as_str() {
+                            "/allow" => StatusCode::OK,
+                            "/unauthorized" => StatusCode::UNAUTHORIZED,
+                            "/slow" => {
+                                tokio::time::sleep(Duration::from_secs(1)).await;
+                                StatusCode::OK
+                            }
+                            _ => StatusCode::FORBIDDEN,
+                        };
+                        let mut resp = Response::new(Body::empty());
+                        *resp.status_mut() = status;
+                        Ok::<_, Infallible>(resp)
+                    }
+                }))
+            }
+        });
+        let server = hyper::Server::bind(&"127.0.0.1:0".parse().unwrap()).serve(make_service);
+        let addr = server.local_addr();
+        tokio::spawn(server);
+        (addr, requests)
+    }
+
+    fn callout(addr: SocketAddr, path: &str) -> HttpCalloutAuthorizer {
+        let url = Url::parse(&format!("http://{addr}{path}")).unwrap();
+        HttpCalloutAuthorizer::new(url, Duration::from_millis(100)).unwrap()
+    }
+
+    const REQUEST: AuthorizationRequest<'static> = AuthorizationRequest {
+        namespace: Some("foo"),
+        token: Some(&b"secret"[..]),
+        operation: Operation::Write,
+    };
+
+    #[tokio::test]
+    async fn callout_allows_requests() {
+        let (addr, requests) = start_callout_service();
+
+        callout(addr, "/allow").authorize(REQUEST).await.unwrap();
+
+        assert_eq!(
+            *requests.lock(),
+            vec![serde_json::json!({
+                "namespace": "foo",
+                "token": "secret",
+                "operation": "write",
+            })]
+        );
+    }
+
+    #[tokio::test]
+    async fn callout_denies_requests() {
+        let (addr, _) = start_callout_service();
+
+        assert!(matches!(
+            callout(addr, "/deny").authorize(REQUEST).await,
+            Err(Error::Forbidden)
+        ));
+        assert!(matches!(
+            callout(addr, "/unauthorized").authorize(REQUEST).await,
+            Err(Error::InvalidToken)
+        ));
+    }
+
+    #[tokio::test]
+    async fn callout_denies_requests_when_it_times_out() {
+        let (addr, requests) = start_callout_service();
+
+        assert!(matches!(
+            callout(addr, "/slow").authorize(REQUEST).await,
+            Err(Error::Forbidden)
+        ));
+        assert_eq!(requests.lock().len(), 1);
+    }
+
+    #[tokio::test]
+    async fn grpc_requests_are_authorized_with_the_request_authorizer() {
+        let (addr, requests) = start_callout_service();
+        let allowed = RequestAuthorizingAuthorizer::new(
+            Arc::new(DefaultAuthorizer),
+            Arc::new(callout(addr, "/allow")),
+        );
+        let denied = RequestAuthorizingAuthorizer::new(
+            Arc::new(DefaultAuthorizer),
+            Arc::new(callout(addr, "/deny")),
+        );
+
+        allowed
+            .permissions(Some(b"secret".to_vec()), &[])
+            .await
+            .unwrap();
+        assert!(matches!(
+            denied.permissions(Some(b"secret".to_vec()), &[]).await,
+            Err(Error::Forbidden)
+        ));
+
+        assert_eq!(
+            requests.lock()[0],
+            serde_json::json!({
+                "namespace": null,
+                "token": "secret",
+                "operation": "query",
+            })
+        );
+    }
+}
@@ -1,8 +1,17 @@
 use std::sync::Arc;
 
 use authz::Authorizer;
+use influxdb3_write::WriteBuffer;
+use iox_time::TimeProvider;
 
-use crate::{auth::DefaultAuthorizer, http::HttpApi, CommonServerState, Server};
+use crate::{
+    auth::{DefaultAuthorizer, RequestAuthorizer, RequestAuthorizingAuthorizer},
+    http::{HttpApi, HttpApiOptions},
+    replication::{ReplicationConfig, Replicator},
+    rollup::{validate_rules, Error as RollupError, RollupFlushTask, RollupHandler, RollupRule},
+    write_stats::DEFAULT_WRITE_STATS_RETENTION_HOURS,
+    CommonServerState, Server,
+};
 
 #[derive(Debug)]
 pub struct ServerBuilder<W, Q, P, T> {
@@ -13,6 +22,13 @@ pub struct ServerBuilder<W, Q, P, T> {
     query_executor: Q,
     persister: P,
     authorizer: Arc<dyn Authorizer>,
+    request_authorizer: Option<Arc<dyn RequestAuthorizer>>,
+    standby: bool,
+    write_stats_retention_hours: usize,
+    replication: Option<ReplicationConfig>,
+    rollups: Vec<RollupRule>,
+    recent_writes_capacity: usize,
+    recent_lines_capacity: usize,
 }
 
 impl ServerBuilder<NoWriteBuf, NoQueryExec, NoPersister, NoTimeProvider> {
@@ -25,6 +41,13 @@ impl ServerBuilder<NoWriteBuf, NoQueryExec, NoPersister, NoTimeProvider> {
             query_executor: NoQueryExec,
             persister: NoPersister,
             authorizer: Arc::new(DefaultAuthorizer),
+            request_authorizer: None,
+            standby: false,
+            write_stats_retention_hours: DEFAULT_WRITE_STATS_RETENTION_HOURS,
+            replication: None,
+            rollups: vec![],
+            recent_writes_capacity: 0,
+            recent_lines_capacity: 0,
         }
     }
 }
@@ -39,6 +62,54 @@ impl<W, Q, P, T> ServerBuilder<W, Q, P, T> {
         self.authorizer = a;
         self
     }
+
+    /// Additionally authorize every request with the operation it is for, and the namespace of
+    /// HTTP requests
+    pub fn request_authorizer(mut self, a: Arc<dyn RequestAuthorizer>) -> Self {
+        self.request_authorizer = Some(a);
+        self
+    }
+
+    /// Start the server in standby mode. It is fully initialized, but reports itself as not
+    /// ready on the health endpoint until it is promoted through the admin API.
+    pub fn standby(mut self, standby: bool) -> Self {
+        self.standby = standby;
+        self
+    }
+
+    /// The number of hours of per minute write statistics to keep in memory
+    pub fn write_stats_retention_hours(mut self, hours: usize) -> Self {
+        self.write_stats_retention_hours = hours;
+        self
+    }
+
+    /// Replicate all accepted writes to a remote server
+    pub fn replication(mut self, config: ReplicationConfig) -> Self {
+        self.replication = Some(config);
+        self
+    }
+
+    /// Compute rollups of accepted writes and write them to rollup tables. Fails if any of the
+    /// rules can't be used, e.g., because it has no interval.
+    pub fn rollups(mut self, rules: Vec<RollupRule>) -> Result<Self, RollupError> {
+        validate_rules(&rules)?;
+        self.rollups = rules;
+        Ok(self)
+    }
+
+    /// Keep the metadata of the last `capacity` writes in memory, to serve from
+    /// `/debug/recent-writes`. Nothing is kept if the capacity is zero.
+    pub fn recent_writes_capacity(mut self, capacity: usize) -> Self {
+        self.recent_writes_capacity = capacity;
+        self
+    }
+
+    /// Keep up to the last `capacity` lines accepted for each database in memory, to serve from
+    /// `/api/v3/recent_lines`. Nothing is kept if the capacity is zero.
+    pub fn recent_lines_capacity(mut self, capacity: usize) -> Self {
+        self.recent_lines_capacity = capacity;
+        self
+    }
 }
 
 #[derive(Debug)]
@@ -68,6 +139,13 @@ impl<Q, P, T> ServerBuilder<NoWriteBuf, Q, P, T> {
             query_executor: self.query_executor,
             persister: self.persister,
             authorizer: self.authorizer,
+            request_authorizer: self.request_authorizer,
+            standby: self.standby,
+            write_stats_retention_hours: self.write_stats_retention_hours,
+            replication: self.replication,
+            rollups: self.rollups,
+            recent_writes_capacity: self.recent_writes_capacity,
+            recent_lines_capacity: self.recent_lines_capacity,
         }
     }
 }
@@ -82,6 +160,13 @@ impl<W, P, T> ServerBuilder<W, NoQueryExec, P, T> {
             query_executor: WithQueryExec(qe),
             persister: self.persister,
             authorizer: self.authorizer,
+            request_authorizer: self.request_authorizer,
+            standby: self.standby,
+            write_stats_retention_hours: self.write_stats_retention_hours,
+            replication: self.replication,
+            rollups: self.rollups,
+            recent_writes_capacity: self.recent_writes_capacity,
+            recent_lines_capacity: self.recent_lines_capacity,
         }
     }
 }
@@ -96,6 +181,13 @@ impl<W, Q, T> ServerBuilder<W, Q, NoPersister, T> {
             query_executor: self.query_executor,
             persister: WithPersister(p),
             authorizer: self.authorizer,
+            request_authorizer: self.request_authorizer,
+            standby: self.standby,
+            write_stats_retention_hours: self.write_stats_retention_hours,
+            replication: self.replication,
+            rollups: self.rollups,
+            recent_writes_capacity: self.recent_writes_capacity,
+            recent_lines_capacity: self.recent_lines_capacity,
         }
     }
 }
@@ -110,29 +202,73 @@ impl<W, Q, P> ServerBuilder<W, Q, P, NoTimeProvider> {
             query_executor: self.query_executor,
             persister: self.persister,
             authorizer: self.authorizer,
+            request_authorizer: self.request_authorizer,
+            standby: self.standby,
+            write_stats_retention_hours: self.write_stats_retention_hours,
+            replication: self.replication,
+            rollups: self.rollups,
+            recent_writes_capacity: self.recent_writes_capacity,
+            recent_lines_capacity: self.recent_lines_capacity,
         }
     }
 }
 
 impl<W, Q, P, T>
     ServerBuilder<WithWriteBuf<W>, WithQueryExec<Q>, WithPersister<P>, WithTimeProvider<T>>
+where
+    W: WriteBuffer,
+    T: TimeProvider,
 {
     pub fn build(self) -> Server<W, Q, P, T> {
         let persister = Arc::clone(&self.persister.0);
         let authorizer = Arc::clone(&self.authorizer);
+        let replicator = self
+            .replication
+            .map(|config| Replicator::new(config, &self.common_state.metric_registry()));
+        let rollups = (!self.rollups.is_empty()).then(|| {
+            Arc::new(
+                RollupHandler::new(self.rollups, &self.common_state.metric_registry())
+                    .expect("rollup rules are validated when they are set"),
+            )
+        });
+        let rollup_flush = rollups.as_ref().map(|rollups| {
+            RollupFlushTask::spawn(
+                Arc::clone(rollups),
+                Arc::clone(&self.write_buffer.0),
+                Arc::clone(&self.time_provider.0),
+            )
+        });
+        // the HTTP API calls the request authorizer itself, with the namespace of the request
+        let grpc_authorizer: Arc<dyn Authorizer> = match &self.request_authorizer {
+            Some(request_authorizer) => Arc::new(RequestAuthorizingAuthorizer::new(
+                Arc::clone(&authorizer),
+                Arc::clone(request_authorizer),
+            )),
+            None => Arc::clone(&authorizer),
+        };
         let http = Arc::new(HttpApi::new(
             self.common_state.clone(),
             Arc::clone(&self.time_provider.0),
             Arc::clone(&self.write_buffer.0),
             Arc::clone(&self.query_executor.0),
             self.max_request_size,
-            Arc::clone(&authorizer),
+            authorizer,
+            HttpApiOptions {
+                request_authorizer: self.request_authorizer,
+                standby: self.standby,
+                write_stats_retention_hours: self.write_stats_retention_hours,
+                replicator,
+                rollups,
+                recent_writes_capacity: self.recent_writes_capacity,
+                recent_lines_capacity: self.recent_lines_capacity,
+            },
         ));
         Server {
             common_state: self.common_state,
             http,
             persister,
-            authorizer,
+            authorizer: grpc_authorizer,
+            rollup_flush,
         }
     }
 }
@@ -1,5 +1,13 @@
 //! HTTP API service implementations for `server`
 
+use crate::auth::{AuthorizationRequest, Operation, RequestAuthorizer};
+use crate::recent_lines::RecentLines;
+use crate::recent_writes::RecentWrites;
+use crate::replication::{ReplicatedWrite, Replicator};
+use crate::rollup::RollupHandler;
+use crate::schema_export::{export_schemas, SchemaFormat};
+use crate::subscribe::{subscription_body, SubscribeFormat};
+use crate::write_stats::WriteStats;
 use crate::{query_executor, QueryKind};
 use crate::{CommonServerState, QueryExecutor};
 use arrow::record_batch::RecordBatch;
@@ -17,16 +25,23 @@ use hyper::header::ACCEPT;
 use hyper::header::AUTHORIZATION;
 use hyper::header::CONTENT_ENCODING;
 use hyper::header::CONTENT_TYPE;
+use hyper::header::RETRY_AFTER;
 use hyper::http::HeaderValue;
 use hyper::HeaderMap;
 use hyper::{Body, Method, Request, Response, StatusCode};
 use influxdb3_process::{INFLUXDB3_GIT_HASH_SHORT, INFLUXDB3_VERSION};
 use influxdb3_write::catalog::Error as CatalogError;
+use influxdb3_write::catalog::{IngestTransform, WriteFlags};
 use influxdb3_write::persister::TrackedMemoryArrowWriter;
 use influxdb3_write::write_buffer::Error as WriteBufferError;
 use influxdb3_write::BufferedWriteRequest;
 use influxdb3_write::Precision;
+use influxdb3_write::SegmentId;
+use influxdb3_write::SequenceNumber;
+use influxdb3_write::WalPosition;
+use influxdb3_write::Watermarks;
 use influxdb3_write::WriteBuffer;
+use influxdb3_write::WriteLineError;
 use iox_http::write::single_tenant::SingleTenantRequestUnifier;
 use iox_http::write::v1::V1_NAMESPACE_RP_SEPARATOR;
 use iox_http::write::{WriteParseError, WriteRequestUnifier};
@@ -42,12 +57,25 @@ use std::fmt::Debug;
 use std::pin::Pin;
 use std::str::Utf8Error;
 use std::string::FromUtf8Error;
+use std::sync::atomic::{AtomicBool, Ordering};
 use std::sync::Arc;
+use std::time::Duration;
 use thiserror::Error;
+use tokio_util::sync::CancellationToken;
 use unicode_segmentation::UnicodeSegmentation;
 
 mod v1;
 
+/// The number of seconds clients are asked to wait before retrying writes that were rejected
+/// while the write buffer is failing to persist to object storage
+const PERSIST_BACKPRESSURE_RETRY_AFTER_SECONDS: &str = "10";
+
+/// How long requests to the watermarks API wait for a position by default, and at most
+const WATERMARK_DEFAULT_TIMEOUT: Duration = Duration::from_secs(10);
+const WATERMARK_MAX_TIMEOUT: Duration = Duration::from_secs(60);
+/// How often the watermarks are checked while waiting for a position
+const WATERMARK_POLL_INTERVAL: Duration = Duration::from_millis(10);
+
 #[derive(Debug, Error)]
 pub enum Error {
     /// The requested path has no registered handler.
@@ -163,7 +191,11 @@ pub enum Error {
     DbName(#[from] ValidateDbNameError),
 
     #[error("partial write of line protocol occurred")]
-    PartialLpWrite(BufferedWriteRequest),
+    PartialLpWrite {
+        write: BufferedWriteRequest,
+        /// Include the WAL positions of the accepted lines in the response
+        return_wal_positions: bool,
+    },
 
     #[error("error in InfluxQL statement: {0}")]
     InfluxqlRewrite(#[from] rewrite::Error),
@@ -204,6 +236,16 @@ struct ErrorMessage<T: Serialize> {
     data: Option<T>,
 }
 
+/// The body of the response to a partial write, which has the positions in the WAL that the
+/// valid lines were written to when they were requested
+#[derive(Debug, Serialize)]
+struct PartialWriteErrorMessage {
+    error: String,
+    data: Option<Vec<WriteLineError>>,
+    #[serde(skip_serializing_if = "Option::is_none")]
+    wal_positions: Option<Vec<WalPosition>>,
+}
+
 impl Error {
     /// Convert this error into an HTTP [`Response`]
     fn into_response(self) -> Response<Body> {
@@ -224,6 +266,31 @@ impl Error {
                     .body(body)
                     .unwrap()
             }
+            Self::WriteBuffer(err @ WriteBufferError::SchemaChangeNotAllowed { .. }) => {
+                let err: ErrorMessage<()> = ErrorMessage {
+                    error: err.to_string(),
+                    data: None,
+                };
+                let serialized = serde_json::to_string(&err).unwrap();
+                let body = Body::from(serialized);
+                Response::builder()
+                    .status(StatusCode::UNPROCESSABLE_ENTITY)
+                    .body(body)
+                    .unwrap()
+            }
+            Self::WriteBuffer(err @ WriteBufferError::PersistBackpressure { .. }) => {
+                let err: ErrorMessage<()> = ErrorMessage {
+                    error: err.to_string(),
+                    data: None,
+                };
+                let serialized = serde_json::to_string(&err).unwrap();
+                let body = Body::from(serialized);
+                Response::builder()
+                    .status(StatusCode::SERVICE_UNAVAILABLE)
+                    .header(RETRY_AFTER, PERSIST_BACKPRESSURE_RETRY_AFTER_SECONDS)
+                    .body(body)
+                    .unwrap()
+            }
             Self::WriteBuffer(WriteBufferError::ParseError(err)) => {
                 let err = ErrorMessage {
                     error: "parsing failed for write_lp endpoint".into(),
@@ -248,10 +315,14 @@ impl Error {
                     .body(body)
                     .unwrap()
             }
-            Self::PartialLpWrite(data) => {
-                let err = ErrorMessage {
+            Self::PartialLpWrite {
+                write,
+                return_wal_positions,
+            } => {
+                let err = PartialWriteErrorMessage {
                     error: "partial write of line protocol occurred".into(),
-                    data: Some(data.invalid_lines),
+                    data: Some(write.invalid_lines),
+                    wal_positions: return_wal_positions.then_some(write.wal_positions),
                 };
                 let serialized = serde_json::to_string(&err).unwrap();
                 let body = Body::from(serialized);
@@ -260,6 +331,14 @@ impl Error {
                     .body(body)
                     .unwrap()
             }
+            Self::Unauthenticated => Response::builder()
+                .status(StatusCode::UNAUTHORIZED)
+                .body(Body::empty())
+                .unwrap(),
+            Self::Forbidden => Response::builder()
+                .status(StatusCode::FORBIDDEN)
+                .body(Body::empty())
+                .unwrap(),
             Self::UnsupportedMethod => {
                 let err: ErrorMessage<()> = ErrorMessage {
                     error: self.to_string(),
@@ -293,7 +372,29 @@ pub(crate) struct HttpApi<W, Q, T> {
     pub(crate) query_executor: Arc<Q>,
     max_request_bytes: usize,
     authorizer: Arc<dyn Authorizer>,
+    request_authorizer: Option<Arc<dyn RequestAuthorizer>>,
     legacy_write_param_unifier: SingleTenantRequestUnifier,
+    /// While in standby the server is fully initialized but reports as not ready
+    standby: AtomicBool,
+    write_stats: WriteStats,
+    replicator: Option<Replicator>,
+    rollups: Option<Arc<RollupHandler>>,
+    recent_writes: Option<RecentWrites>,
+    recent_lines: Option<RecentLines>,
+    /// Cancelled when the server shuts down, to end the streams of subscribers
+    subscriptions_closed: CancellationToken,
+}
+
+/// The optional parts of the [`HttpApi`], set through the [`ServerBuilder`](crate::builder::ServerBuilder)
+#[derive(Debug)]
+pub(crate) struct HttpApiOptions {
+    pub(crate) request_authorizer: Option<Arc<dyn RequestAuthorizer>>,
+    pub(crate) standby: bool,
+    pub(crate) write_stats_retention_hours: usize,
+    pub(crate) replicator: Option<Replicator>,
+    pub(crate) rollups: Option<Arc<RollupHandler>>,
+    pub(crate) recent_writes_capacity: usize,
+    pub(crate) recent_lines_capacity: usize,
 }
 
 impl<W, Q, T> HttpApi<W, Q, T> {
@@ -304,7 +405,17 @@ impl<W, Q, T> HttpApi<W, Q, T> {
         query_executor: Arc<Q>,
         max_request_bytes: usize,
         authorizer: Arc<dyn Authorizer>,
+        options: HttpApiOptions,
     ) -> Self {
+        let HttpApiOptions {
+            request_authorizer,
+            standby,
+            write_stats_retention_hours,
+            replicator,
+            rollups,
+            recent_writes_capacity,
+            recent_lines_capacity,
+        } = options;
         let legacy_write_param_unifier = SingleTenantRequestUnifier::new(Arc::clone(&authorizer));
         Self {
             common_state,
@@ -313,9 +424,24 @@ impl<W, Q, T> HttpApi<W, Q, T> {
             query_executor,
             max_request_bytes,
             authorizer,
+            request_authorizer,
             legacy_write_param_unifier,
+            standby: AtomicBool::new(standby),
+            write_stats: WriteStats::new(write_stats_retention_hours),
+            replicator,
+            rollups,
+            recent_writes: (recent_writes_capacity > 0)
+                .then(|| RecentWrites::new(recent_writes_capacity)),
+            recent_lines: (recent_lines_capacity > 0)
+                .then(|| RecentLines::new(recent_lines_capacity)),
+            subscriptions_closed: CancellationToken::new(),
         }
     }
+
+    /// Ends the streams of all subscribers, which would otherwise hold up a graceful shutdown
+    pub(crate) fn close_subscriptions(&self) {
+        self.subscriptions_closed.cancel();
+    }
 }
 
 impl<W, Q, T> HttpApi<W, Q, T>
@@ -337,6 +463,43 @@ where
         req: Request<Body>,
         accept_rp: bool,
     ) -> Result<Response<Body>> {
+        let received_at = self.time_provider.now();
+        let db_name = params.db.clone();
+        let return_wal_positions = params.return_wal_positions;
+
+        let result = self.buffer_write_lp(params, req, accept_rp).await;
+        if let Some(recent_writes) = &self.recent_writes {
+            recent_writes.record(received_at, &db_name, &result);
+        }
+        let result = result?;
+
+        if !result.invalid_lines.is_empty() {
+            Err(Error::PartialLpWrite {
+                write: result,
+                return_wal_positions,
+            })
+        } else if return_wal_positions {
+            let body = serde_json::to_vec(&WriteResponse {
+                wal_positions: result.wal_positions,
+            })?;
+            Ok(Response::builder()
+                .status(StatusCode::OK)
+                .header(CONTENT_TYPE, "application/json")
+                .body(Body::from(body))
+                .unwrap())
+        } else {
+            Ok(Response::new(Body::empty()))
+        }
+    }
+
+    /// Write the line protocol in the body of the request to the buffer, returning the invalid
+    /// lines, if any, along with the summary of the write
+    async fn buffer_write_lp(
+        &self,
+        params: WriteParams,
+        req: Request<Body>,
+        accept_rp: bool,
+    ) -> Result<BufferedWriteRequest> {
         validate_db_name(&params.db, accept_rp)?;
         info!("write_lp to {}", params.db);
 
@@ -347,31 +510,75 @@ where
 
         let default_time = self.time_provider.now();
 
-        let result = self
-            .write_buffer
-            .write_lp(
-                database,
-                body,
-                default_time,
-                params.accept_partial,
-                params.precision,
-            )
-            .await?;
-
-        if result.invalid_lines.is_empty() {
-            Ok(Response::new(Body::empty()))
+        let result = if params.backfill {
+            self.write_buffer
+                .write_lp_backfill(
+                    database,
+                    body,
+                    default_time,
+                    params.accept_partial,
+                    params.precision,
+                )
+                .await?
         } else {
-            Err(Error::PartialLpWrite(result))
+            self.write_buffer
+                .write_lp(
+                    database,
+                    body,
+                    default_time,
+                    params.accept_partial,
+                    params.precision,
+                )
+                .await?
+        };
+
+        self.write_stats.record(
+            default_time,
+            result.db_name.as_str(),
+            &result.table_summaries,
+        );
+
+        if let Some(recent_lines) = &self.recent_lines {
+            for op in &result.accepted_ops {
+                recent_lines.record(default_time, &op.db_name, &op.lp);
+            }
         }
+
+        if let Some(rollups) = self
+            .rollups
+            .as_ref()
+            .filter(|rollups| rollups.has_rules_for(result.db_name.as_str()))
+        {
+            let now = self.time_provider.now();
+            for op in &result.accepted_ops {
+                rollups.observe(op, now);
+            }
+        }
+
+        let replicate = || {
+            self.write_buffer
+                .catalog()
+                .db_schema(result.db_name.as_str())
+                .map_or(true, |db| db.write_flags().replicate)
+        };
+        if let Some(replicator) = self.replicator.as_ref().filter(|_| replicate()) {
+            for op in &result.accepted_ops {
+                replicator.replicate(ReplicatedWrite::from_op(op));
+            }
+        }
+
+        Ok(result)
     }
 
-    async fn query_sql(&self, req: Request<Body>) -> Result<Response<Body>> {
+    async fn query_sql(&self, mut req: Request<Body>) -> Result<Response<Body>> {
+        let deferred = req.extensions_mut().remove::<DeferredAuthorization>();
         let QueryRequest {
             database,
             query_str,
             format,
             params,
         } = self.extract_query_request::<String>(req).await?;
+        self.authorize_query(deferred, Some(&database)).await?;
 
         info!(%database, %query_str, ?format, "handling query_sql");
 
@@ -387,7 +594,8 @@ where
             .map_err(Into::into)
     }
 
-    async fn query_influxql(&self, req: Request<Body>) -> Result<Response<Body>> {
+    async fn query_influxql(&self, mut req: Request<Body>) -> Result<Response<Body>> {
+        let deferred = req.extensions_mut().remove::<DeferredAuthorization>();
         let QueryRequest {
             database,
             query_str,
@@ -398,7 +606,7 @@ where
         info!(?database, %query_str, ?format, "handling query_influxql");
 
         let stream = self
-            .query_influxql_inner(database, &query_str, params)
+            .query_influxql_inner(database, &query_str, params, deferred)
             .await?;
 
         Response::builder()
@@ -409,10 +617,279 @@ where
     }
 
     fn health(&self) -> Result<Response<Body>> {
+        if self.standby.load(Ordering::Acquire) {
+            return Ok(Response::builder()
+                .status(StatusCode::SERVICE_UNAVAILABLE)
+                .body(Body::from("standby"))
+                .unwrap());
+        }
+
         let response_body = "OK";
         Ok(Response::new(Body::from(response_body.to_string())))
     }
 
+    /// Promote a server running in standby so that it starts reporting as ready. Promoting a
+    /// server that is not in standby is a no-op.
+    fn promote(&self) -> Result<Response<Body>> {
+        if self.standby.swap(false, Ordering::AcqRel) {
+            info!("promoted server out of standby");
+        }
+
+        Ok(Response::new(Body::empty()))
+    }
+
+    fn write_stats(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let params: WriteStatsParams = match req.uri().query() {
+            Some(query) => serde_urlencoded::from_str(query)?,
+            None => WriteStatsParams::default(),
+        };
+
+        let rows = self.write_stats.rows(
+            self.time_provider.now(),
+            params.hours.unwrap_or(1),
+            params.db.as_deref(),
+        );
+        let body = serde_json::to_vec(&rows)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    fn recent_writes(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let Some(recent_writes) = &self.recent_writes else {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from(
+                    "recent writes are not being recorded, set --recent-writes-capacity to enable",
+                ))
+                .unwrap());
+        };
+
+        let params: RecentWritesParams = match req.uri().query() {
+            Some(query) => serde_urlencoded::from_str(query)?,
+            None => RecentWritesParams::default(),
+        };
+
+        let writes = recent_writes.recent(params.db.as_deref(), params.limit.unwrap_or(100));
+        let body = serde_json::to_vec(&writes)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    fn recent_lines(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let Some(recent_lines) = &self.recent_lines else {
+            return Ok(Response::builder()
+                .status(StatusCode::NOT_FOUND)
+                .body(Body::from(
+                    "recent lines are not being kept, set --recent-lines-capacity to enable",
+                ))
+                .unwrap());
+        };
+
+        let query = req.uri().query().ok_or(Error::MissingWriteParams)?;
+        let params: RecentLinesParams = serde_urlencoded::from_str(query)?;
+        validate_db_name(&params.db, false)?;
+
+        let window = Duration::from_secs(params.minutes.unwrap_or(5).saturating_mul(60));
+        let summary = recent_lines.recent(
+            self.time_provider.now(),
+            &params.db,
+            window,
+            params.limit.unwrap_or(100),
+        );
+        let body = serde_json::to_vec(&summary)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    /// Returns how far the write buffer has got through the WAL. If a position is given, as
+    /// returned from the write API, waits for it to become readable, or persisted, and responds
+    /// with whether it did before the timeout.
+    async fn watermarks(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let params: WatermarksParams = match req.uri().query() {
+            Some(query) => serde_urlencoded::from_str(query)?,
+            None => WatermarksParams::default(),
+        };
+        let position = match (params.segment_id, params.sequence_number) {
+            (Some(segment_id), Some(sequence_number)) => Some(WalPosition {
+                segment_id: SegmentId::new(segment_id),
+                sequence_number: SequenceNumber::new(sequence_number),
+            }),
+            (None, None) => None,
+            _ => {
+                return Ok(Response::builder()
+                    .status(StatusCode::BAD_REQUEST)
+                    .body(Body::from(
+                        "both segment_id and sequence_number are needed to wait for a position",
+                    ))
+                    .unwrap())
+            }
+        };
+
+        let timeout = params
+            .timeout_ms
+            .map_or(WATERMARK_DEFAULT_TIMEOUT, Duration::from_millis)
+            .min(WATERMARK_MAX_TIMEOUT);
+        let deadline = tokio::time::Instant::now() + timeout;
+
+        let mut watermarks = self.write_buffer.watermarks();
+        let reached = match position {
+            Some(position) => loop {
+                let reached = match params.wait {
+                    WatermarkWait::Readable => watermarks.is_readable(position),
+                    WatermarkWait::Persisted => watermarks.is_persisted(position),
+                };
+                if reached || tokio::time::Instant::now() >= deadline {
+                    break Some(reached);
+                }
+                tokio::time::sleep(WATERMARK_POLL_INTERVAL).await;
+                watermarks = self.write_buffer.watermarks();
+            },
+            None => None,
+        };
+        let body = serde_json::to_vec(&WatermarksResponse {
+            reached,
+            watermarks,
+        })?;
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    fn subscribe(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let query = req.uri().query().ok_or(Error::MissingWriteParams)?;
+        let params: SubscribeParams = serde_urlencoded::from_str(query)?;
+        validate_db_name(&params.db, false)?;
+        let format = params.format.unwrap_or_default();
+
+        info!(db = %params.db, ?format, "subscribing to writes");
+
+        let content_type = match format {
+            SubscribeFormat::Lp => "text/plain; charset=utf-8",
+            SubscribeFormat::Json => "application/x-ndjson",
+        };
+        let body = subscription_body(
+            self.write_buffer.subscribe(),
+            params.db,
+            format,
+            self.subscriptions_closed.clone(),
+        );
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, content_type)
+            .body(body)
+            .unwrap())
+    }
+
+    fn schema(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let params: SchemaParams = match req.uri().query() {
+            Some(query) => serde_urlencoded::from_str(query)?,
+            None => SchemaParams::default(),
+        };
+
+        let export = export_schemas(
+            &self.write_buffer.catalog(),
+            params.db.as_deref(),
+            params.format.unwrap_or_default(),
+        );
+        let body = serde_json::to_vec(&export)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    fn get_ingest_transforms(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let query = req.uri().query().ok_or(Error::MissingWriteParams)?;
+        let params: IngestTransformParams = serde_urlencoded::from_str(query)?;
+
+        let transforms = self
+            .write_buffer
+            .catalog()
+            .db_schema(&params.db)
+            .map(|db| db.ingest_transforms().to_vec())
+            .unwrap_or_default();
+        let body = serde_json::to_vec(&transforms)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    async fn set_ingest_transforms(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let query = req.uri().query().ok_or(Error::MissingWriteParams)?;
+        let params: IngestTransformParams = serde_urlencoded::from_str(query)?;
+        validate_db_name(&params.db, false)?;
+        let database = NamespaceName::new(params.db)?;
+
+        let body = self.read_body(req).await?;
+        let transforms: Vec<IngestTransform> = serde_json::from_slice(&body)?;
+
+        info!(%database, ?transforms, "setting ingest transforms");
+
+        self.write_buffer
+            .set_ingest_transforms(database.as_str(), transforms)
+            .await?;
+
+        Ok(Response::new(Body::empty()))
+    }
+
+    fn get_write_flags(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let query = req.uri().query().ok_or(Error::MissingWriteParams)?;
+        let params: IngestTransformParams = serde_urlencoded::from_str(query)?;
+
+        let write_flags = self
+            .write_buffer
+            .catalog()
+            .db_schema(&params.db)
+            .map(|db| db.write_flags())
+            .unwrap_or_default();
+        let body = serde_json::to_vec(&write_flags)?;
+
+        Ok(Response::builder()
+            .status(StatusCode::OK)
+            .header(CONTENT_TYPE, "application/json")
+            .body(Body::from(body))
+            .unwrap())
+    }
+
+    async fn set_write_flags(&self, req: Request<Body>) -> Result<Response<Body>> {
+        let query = req.uri().query().ok_or(Error::MissingWriteParams)?;
+        let params: IngestTransformParams = serde_urlencoded::from_str(query)?;
+        validate_db_name(&params.db, false)?;
+        let database = NamespaceName::new(params.db)?;
+
+        let body = self.read_body(req).await?;
+        let write_flags: WriteFlags = serde_json::from_slice(&body)?;
+
+        info!(%database, ?write_flags, "setting write flags");
+
+        self.write_buffer
+            .set_write_flags(database.as_str(), write_flags)
+            .await?;
+
+        Ok(Response::new(Body::empty()))
+    }
+
     fn ping(&self) -> Result<Response<Body>> {
         #[derive(Debug, Serialize)]
         struct PingResponse<'a> {
@@ -514,7 +991,31 @@ where
 
         // Currently we pass an empty permissions list, but in future we may be able to derive
         // the permissions based on the incoming request
-        let permissions = self.authorizer.permissions(auth, &[]).await?;
+        let permissions = self.authorizer.permissions(auth.clone(), &[]).await?;
+
+        if let Some(request_authorizer) = &self.request_authorizer {
+            // routes that aren't known to have an operation are denied, rather than let through
+            // without being authorized
+            let operation = request_operation(req.method(), req.uri().path())
+                .ok_or(AuthorizationError::Forbidden)?;
+            if is_query_route(req.uri().path()) {
+                // the database of a query can be given in the body, or in an InfluxQL statement,
+                // so queries are authorized by their handlers once it is known
+                req.extensions_mut().insert(DeferredAuthorization {
+                    token: auth.clone(),
+                    operation,
+                });
+            } else {
+                let namespace = request_namespace(req);
+                request_authorizer
+                    .authorize(AuthorizationRequest {
+                        namespace: namespace.as_deref(),
+                        token: auth.as_deref(),
+                        operation,
+                    })
+                    .await?;
+            }
+        }
 
         // Extend the request with the permissions, which may be useful in future
         req.extensions_mut().insert(permissions);
@@ -522,6 +1023,31 @@ where
         Ok(())
     }
 
+    /// Authorize a query with the request authorizer, now that the database it is for is known.
+    /// Queries are only let through without a [`DeferredAuthorization`] if there is no request
+    /// authorizer.
+    async fn authorize_query(
+        &self,
+        deferred: Option<DeferredAuthorization>,
+        database: Option<&str>,
+    ) -> Result<()> {
+        let Some(request_authorizer) = &self.request_authorizer else {
+            return Ok(());
+        };
+        let deferred = deferred.ok_or(Error::Forbidden)?;
+        request_authorizer
+            .authorize(AuthorizationRequest {
+                namespace: database,
+                token: deferred.token.as_deref(),
+                operation: deferred.operation,
+            })
+            .await
+            .map_err(|e| match e {
+                authz::Error::Forbidden => Error::Forbidden,
+                _ => Error::Unauthenticated,
+            })
+    }
+
     async fn extract_query_request<D: DeserializeOwned>(
         &self,
         req: Request<Body>,
@@ -564,6 +1090,7 @@ where
         database: Option<String>,
         query_str: &str,
         params: Option<StatementParams>,
+        deferred: Option<DeferredAuthorization>,
     ) -> Result<SendableRecordBatchStream> {
         let mut statements = rewrite::parse_statements(query_str)?;
 
@@ -586,6 +1113,7 @@ where
                 }
             }
         };
+        self.authorize_query(deferred, database.as_deref()).await?;
 
         if statement.statement().is_show_databases() {
             self.query_executor.show_databases()
@@ -660,6 +1188,68 @@ fn validate_auth_header(header: HeaderValue) -> Result<Vec<u8>, AuthorizationErr
     Ok(token.as_bytes().to_vec())
 }
 
+/// The [`Operation`] performed by a request to the given route, if it is one the API serves
+fn request_operation(method: &Method, path: &str) -> Option<Operation> {
+    let operation = match (method, path) {
+        (&Method::POST, "/write" | "/api/v2/write" | "/api/v3/write_lp") => Operation::Write,
+        (&Method::GET | &Method::POST, "/api/v3/query_sql" | "/api/v3/query_influxql") => {
+            Operation::Query
+        }
+        (&Method::GET, "/query") => Operation::Query,
+        (
+            &Method::GET | &Method::POST,
+            "/api/v3/configure/transforms" | "/api/v3/configure/write_flags",
+        ) => Operation::Configure,
+        (&Method::GET, "/api/v3/schema" | "/api/v3/subscribe" | "/api/v3/recent_lines") => {
+            Operation::Query
+        }
+        (&Method::POST, "/api/v3/admin/promote") => Operation::Admin,
+        (
+            &Method::GET,
+            "/health"
+            | "/api/v1/health"
+            | "/metrics"
+            | "/api/v3/write_stats"
+            | "/api/v3/watermarks"
+            | "/debug/recent-writes",
+        )
+        | (&Method::GET | &Method::POST, "/ping") => Operation::Monitor,
+        _ => return None,
+    };
+    Some(operation)
+}
+
+/// Whether the route is one of the query APIs, whose requests are authorized by their handlers
+fn is_query_route(path: &str) -> bool {
+    matches!(
+        path,
+        "/api/v3/query_sql" | "/api/v3/query_influxql" | "/query"
+    )
+}
+
+/// The token and operation of a query request, kept in its extensions for the handler to
+/// authorize once the database of the query is known
+#[derive(Debug, Clone)]
+struct DeferredAuthorization {
+    token: Option<Vec<u8>>,
+    operation: Operation,
+}
+
+/// The namespace given in the query string of a request, as the `db` parameter, or the `bucket`
+/// parameter of the `/api/v2/write` API
+fn request_namespace(req: &Request<Body>) -> Option<String> {
+    let query = req.uri().query()?;
+    let key = if req.uri().path() == "/api/v2/write" {
+        "bucket"
+    } else {
+        "db"
+    };
+    serde_urlencoded::from_str::<Vec<(String, String)>>(query)
+        .ok()?
+        .into_iter()
+        .find_map(|(k, v)| (k == key).then_some(v))
+}
+
 impl From<authz::Error> for AuthorizationError {
     fn from(auth_error: authz::Error) -> Self {
         match auth_error {
@@ -861,6 +1451,103 @@ pub(crate) struct WriteParams {
     pub(crate) accept_partial: bool,
     #[serde(default)]
     pub(crate) precision: Precision,
+    /// Respond with the positions in the WAL that the write was written to
+    #[serde(default)]
+    pub(crate) return_wal_positions: bool,
+    /// The write is part of a bulk load of historical data, so the old segments it writes to are
+    /// persisted as soon as the load stops writing to them
+    #[serde(default)]
+    pub(crate) backfill: bool,
+}
+
+/// The body of a successful write response, when WAL positions were requested
+#[derive(Debug, Serialize)]
+pub(crate) struct WriteResponse {
+    pub(crate) wal_positions: Vec<WalPosition>,
+}
+
+/// Query parameters for the ingest transforms and write flags APIs
+#[derive(Debug, Deserialize)]
+pub(crate) struct IngestTransformParams {
+    pub(crate) db: String,
+}
+
+/// Query parameters for the write statistics API
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct WriteStatsParams {
+    /// Only return statistics for this database
+    pub(crate) db: Option<String>,
+    /// The number of hours of statistics to return, defaults to the last hour
+    pub(crate) hours: Option<usize>,
+}
+
+/// Query parameters for the recent writes API
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct RecentWritesParams {
+    /// Only return writes to this database
+    pub(crate) db: Option<String>,
+    /// The maximum number of writes to return, newest first, defaults to 100
+    pub(crate) limit: Option<usize>,
+}
+
+/// Query parameters for the recent lines API
+#[derive(Debug, Deserialize)]
+pub(crate) struct RecentLinesParams {
+    pub(crate) db: String,
+    /// The number of minutes before now to return lines for, defaults to 5
+    pub(crate) minutes: Option<u64>,
+    /// The maximum number of lines to return, newest first, defaults to 100
+    pub(crate) limit: Option<usize>,
+}
+
+/// What a request to the watermarks API waits for the position to be
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum WatermarkWait {
+    /// Buffered, and so queryable
+    #[default]
+    Readable,
+    /// Persisted to object storage
+    Persisted,
+}
+
+/// Query parameters for the watermarks API
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct WatermarksParams {
+    /// The position to wait for, as returned from the write API
+    pub(crate) segment_id: Option<u32>,
+    pub(crate) sequence_number: Option<u32>,
+    #[serde(default)]
+    pub(crate) wait: WatermarkWait,
+    /// How long to wait for the position, defaults to 10 seconds and is at most 60
+    pub(crate) timeout_ms: Option<u64>,
+}
+
+/// The body of a response from the watermarks API
+#[derive(Debug, Serialize)]
+pub(crate) struct WatermarksResponse {
+    /// Whether the position that was waited for was reached, if one was given
+    #[serde(skip_serializing_if = "Option::is_none")]
+    pub(crate) reached: Option<bool>,
+    #[serde(flatten)]
+    pub(crate) watermarks: Watermarks,
+}
+
+/// Query parameters for the subscribe API
+#[derive(Debug, Deserialize)]
+pub(crate) struct SubscribeParams {
+    pub(crate) db: String,
+    /// The format writes are streamed in, defaults to line protocol
+    pub(crate) format: Option<SubscribeFormat>,
+}
+
+/// Query parameters for the schema API
+#[derive(Debug, Default, Deserialize)]
+pub(crate) struct SchemaParams {
+    /// Only return the schemas of tables in this database
+    pub(crate) db: Option<String>,
+    /// The format to render schemas in, defaults to JSON Schema
+    pub(crate) format: Option<SchemaFormat>,
 }
 
 impl From<iox_http::write::WriteParams> for WriteParams {
@@ -870,6 +1557,8 @@ impl From<iox_http::write::WriteParams> for WriteParams {
             // legacy behaviour was to not accept partial:
             accept_partial: false,
             precision: legacy.precision.into(),
+            return_wal_positions: false,
+            backfill: false,
         }
     }
 }
@@ -943,8 +1632,21 @@ where
         }
         (Method::GET, "/query") => http_server.v1_query(req).await,
         (Method::GET, "/health" | "/api/v1/health") => http_server.health(),
+        (Method::POST, "/api/v3/admin/promote") => http_server.promote(),
         (Method::GET | Method::POST, "/ping") => http_server.ping(),
         (Method::GET, "/metrics") => http_server.handle_metrics(),
+        (Method::GET, "/api/v3/write_stats") => http_server.write_stats(req),
+        (Method::GET, "/debug/recent-writes") => http_server.recent_writes(req),
+        (Method::GET, "/api/v3/schema") => http_server.schema(req),
+        (Method::GET, "/api/v3/subscribe") => http_server.subscribe(req),
+        (Method::GET, "/api/v3/recent_lines") => http_server.recent_lines(req),
+        (Method::GET, "/api/v3/watermarks") => http_server.watermarks(req).await,
+        (Method::GET, "/api/v3/configure/transforms") => http_server.get_ingest_transforms(req),
+        (Method::POST, "/api/v3/configure/transforms") => {
+            http_server.set_ingest_transforms(req).await
+        }
+        (Method::GET, "/api/v3/configure/write_flags") => http_server.get_write_flags(req),
+        (Method::POST, "/api/v3/configure/write_flags") => http_server.set_write_flags(req).await,
         _ => {
             let body = Body::from("not found");
             Ok(Response::builder()
@@ -986,6 +1688,7 @@ fn legacy_write_error_to_response(e: WriteParseError) -> Response<Body> {
 mod tests {
     use super::validate_db_name;
     use super::ValidateDbNameError;
+    use super::{request_namespace, request_operation, Body, Method, Operation, Request};
 
     macro_rules! assert_validate_db_name {
         ($name:literal, $accept_rp:literal, $expected:pat) => {
@@ -1013,4 +1716,48 @@ mod tests {
         assert_validate_db_name!("_foo", false, Err(ValidateDbNameError::InvalidStartChar));
         assert_validate_db_name!("", false, Err(ValidateDbNameError::Empty));
     }
+
+    #[test]
+    fn test_request_operation_and_namespace() {
+        assert_eq!(
+            request_operation(&Method::POST, "/api/v3/write_lp"),
+            Some(Operation::Write)
+        );
+        assert_eq!(
+            request_operation(&Method::GET, "/query"),
+            Some(Operation::Query)
+        );
+        assert_eq!(
+            request_operation(&Method::POST, "/api/v3/admin/promote"),
+            Some(Operation::Admin)
+        );
+        assert_eq!(
+            request_operation(&Method::GET, "/api/v3/subscribe"),
+            Some(Operation::Query)
+        );
+        assert_eq!(
+            request_operation(&Method::GET, "/api/v3/recent_lines"),
+            Some(Operation::Query)
+        );
+        assert_eq!(
+            request_operation(&Method::GET, "/api/v3/watermarks"),
+            Some(Operation::Monitor)
+        );
+        assert_eq!(request_operation(&Method::GET, "/api/v3/write_lp"), None);
+
+        let req = |uri: &str| Request::get(uri).body(Body::empty()).unwrap();
+        assert_eq!(
+            request_namespace(&req("/api/v3/write_lp?db=foo&precision=second")).as_deref(),
+            Some("foo")
+        );
+        assert_eq!(
+            request_namespace(&req("/api/v2/write?org=o&bucket=bar")).as_deref(),
+            Some("bar")
+        );
+        assert_eq!(
+            request_namespace(&req("/query?db=foo%2Fautogen")).as_deref(),
+            Some("foo/autogen")
+        );
+        assert_eq!(request_namespace(&req("/health")), None);
+    }
 }
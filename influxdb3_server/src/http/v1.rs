@@ -28,7 +28,7 @@ use serde_json::Value;
 
 use crate::QueryExecutor;
 
-use super::{Error, HttpApi, Result};
+use super::{DeferredAuthorization, Error, HttpApi, Result};
 
 const DEFAULT_CHUNK_SIZE: usize = 10_000;
 
@@ -46,7 +46,8 @@ where
     /// response stream will be chunked into chunks of size `chunk_size`, if provided,
     /// or 10,000. For InfluxQL queries that select from multiple measurements, chunks
     /// will be split on the `chunk_size`, or series, whichever comes first.
-    pub(super) async fn v1_query(&self, req: Request<Body>) -> Result<Response<Body>> {
+    pub(super) async fn v1_query(&self, mut req: Request<Body>) -> Result<Response<Body>> {
+        let deferred = req.extensions_mut().remove::<DeferredAuthorization>();
         let params = QueryParams::from_request(&req)?;
         info!(?params, "handle v1 query API");
         let QueryParams {
@@ -62,7 +63,9 @@ where
 
         // TODO - Currently not supporting parameterized queries, see
         //        https://github.com/influxdata/influxdb/issues/24805
-        let stream = self.query_influxql_inner(database, &query, None).await?;
+        let stream = self
+            .query_influxql_inner(database, &query, None, deferred)
+            .await?;
         let stream =
             QueryResponseStream::new(0, stream, chunk_size, pretty, epoch).map_err(QueryError)?;
         let body = Body::wrap_stream(stream);
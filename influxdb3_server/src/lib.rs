@@ -16,11 +16,20 @@ pub mod builder;
 mod grpc;
 mod http;
 pub mod query_executor;
+mod recent_lines;
+mod recent_writes;
+pub mod replication;
+pub mod rollup;
+pub mod schema_export;
+pub mod server_type;
 mod service;
+mod subscribe;
+mod write_stats;
 
 use crate::grpc::make_flight_server;
 use crate::http::route_request;
 use crate::http::HttpApi;
+use crate::rollup::RollupFlushTask;
 use async_trait::async_trait;
 use authz::Authorizer;
 use datafusion::execution::SendableRecordBatchStream;
@@ -111,6 +120,11 @@ impl CommonServerState {
     pub fn metric_registry(&self) -> Arc<metric::Registry> {
         Arc::<metric::Registry>::clone(&self.metrics)
     }
+
+    /// The address the server listens on
+    pub fn http_addr(&self) -> SocketAddr {
+        self.http_addr
+    }
 }
 
 #[allow(dead_code)]
@@ -119,7 +133,10 @@ pub struct Server<W, Q, P, T> {
     common_state: CommonServerState,
     http: Arc<HttpApi<W, Q, T>>,
     persister: Arc<P>,
+    /// The authorizer of the gRPC API
     authorizer: Arc<dyn Authorizer>,
+    /// Writes the rollups, if any are configured, until the server has shut down
+    rollup_flush: Option<RollupFlushTask>,
 }
 
 #[async_trait]
@@ -193,11 +210,25 @@ where
 
     let hybrid_make_service = hybrid(rest_service, grpc_service);
 
+    // subscriber streams never end on their own, so they are closed for the graceful shutdown
+    // to complete
+    let http = Arc::clone(&server.http);
+    let subscriptions_shutdown = shutdown.clone();
+    tokio::spawn(async move {
+        subscriptions_shutdown.cancelled().await;
+        http.close_subscriptions();
+    });
+
     hyper::Server::bind(&server.common_state.http_addr)
         .serve(hybrid_make_service)
         .with_graceful_shutdown(shutdown.cancelled())
         .await?;
 
+    // nothing is written to the buffer once this returns, e.g., for it to be snapshotted
+    if let Some(rollup_flush) = server.rollup_flush {
+        rollup_flush.stop().await;
+    }
+
     Ok(())
 }
 
@@ -225,23 +256,31 @@ pub async fn wait_for_signal() {
 
 #[cfg(test)]
 mod tests {
-    use crate::auth::DefaultAuthorizer;
+    use crate::auth::{AuthorizationRequest, DefaultAuthorizer, RequestAuthorizer};
     use crate::builder::ServerBuilder;
     use crate::serve;
+    use async_trait::async_trait;
+    use bytes::Bytes;
     use datafusion::parquet::data_type::AsBytes;
+    use futures::stream::BoxStream;
     use hyper::{body, Body, Client, Request, Response, StatusCode};
     use influxdb3_write::persister::PersisterImpl;
     use influxdb3_write::SegmentDuration;
     use iox_query::exec::{DedicatedExecutor, Executor, ExecutorConfig};
     use iox_time::{MockProvider, Time};
-    use object_store::DynObjectStore;
+    use object_store::path::Path;
+    use object_store::{
+        DynObjectStore, GetOptions, GetResult, ListResult, MultipartId, ObjectMeta, ObjectStore,
+        PutOptions, PutResult,
+    };
     use parquet_file::storage::{ParquetStorage, StorageId};
     use pretty_assertions::assert_eq;
     use std::collections::HashMap;
     use std::net::{SocketAddr, SocketAddrV4};
     use std::num::NonZeroUsize;
-    use std::sync::atomic::{AtomicU16, Ordering};
+    use std::sync::atomic::{AtomicBool, AtomicU16, Ordering};
     use std::sync::Arc;
+    use tokio::io::AsyncWrite;
     use tokio_util::sync::CancellationToken;
 
     static NEXT_PORT: AtomicU16 = AtomicU16::new(8090);
@@ -279,6 +318,7 @@ mod tests {
                 Arc::clone(&time_provider),
                 SegmentDuration::new_5m(),
                 Arc::clone(&exec),
+                Arc::clone(&metrics),
             )
             .await
             .unwrap(),
@@ -439,6 +479,7 @@ mod tests {
                 Arc::clone(&time_provider),
                 SegmentDuration::new_5m(),
                 Arc::clone(&exec),
+                Arc::clone(&metrics),
             )
             .await
             .unwrap(),
@@ -646,6 +687,7 @@ mod tests {
                 Arc::clone(&time_provider),
                 SegmentDuration::new_5m(),
                 Arc::clone(&exec),
+                Arc::clone(&metrics),
             )
             .await
             .unwrap(),
@@ -786,6 +828,363 @@ mod tests {
         shutdown.cancel();
     }
 
+    /// An object store that fails every put while `failing` is set
+    #[derive(Debug)]
+    struct FailingObjectStore {
+        inner: Arc<DynObjectStore>,
+        failing: AtomicBool,
+    }
+
+    impl FailingObjectStore {
+        fn check(&self) -> object_store::Result<()> {
+            if self.failing.load(Ordering::SeqCst) {
+                return Err(object_store::Error::Generic {
+                    store: "failing",
+                    source: "object store is unavailable".into(),
+                });
+            }
+            Ok(())
+        }
+    }
+
+    impl std::fmt::Display for FailingObjectStore {
+        fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+            write!(f, "FailingObjectStore({})", self.inner)
+        }
+    }
+
+    #[async_trait]
+    impl ObjectStore for FailingObjectStore {
+        async fn put(&self, location: &Path, bytes: Bytes) -> object_store::Result<PutResult> {
+            self.check()?;
+            self.inner.put(location, bytes).await
+        }
+
+        async fn put_opts(
+            &self,
+            location: &Path,
+            bytes: Bytes,
+            opts: PutOptions,
+        ) -> object_store::Result<PutResult> {
+            self.check()?;
+            self.inner.put_opts(location, bytes, opts).await
+        }
+
+        async fn put_multipart(
+            &self,
+            location: &Path,
+        ) -> object_store::Result<(MultipartId, Box<dyn AsyncWrite + Unpin + Send>)> {
+            self.check()?;
+            self.inner.put_multipart(location).await
+        }
+
+        async fn abort_multipart(
+            &self,
+            location: &Path,
+            multipart_id: &MultipartId,
+        ) -> object_store::Result<()> {
+            self.inner.abort_multipart(location, multipart_id).await
+        }
+
+        async fn get_opts(
+            &self,
+            location: &Path,
+            options: GetOptions,
+        ) -> object_store::Result<GetResult> {
+            self.inner.get_opts(location, options).await
+        }
+
+        async fn delete(&self, location: &Path) -> object_store::Result<()> {
+            self.inner.delete(location).await
+        }
+
+        fn list(&self, prefix: Option<&Path>) -> BoxStream<'_, object_store::Result<ObjectMeta>> {
+            self.inner.list(prefix)
+        }
+
+        async fn list_with_delimiter(
+            &self,
+            prefix: Option<&Path>,
+        ) -> object_store::Result<ListResult> {
+            self.inner.list_with_delimiter(prefix).await
+        }
+
+        async fn copy(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+            self.inner.copy(from, to).await
+        }
+
+        async fn copy_if_not_exists(&self, from: &Path, to: &Path) -> object_store::Result<()> {
+            self.inner.copy_if_not_exists(from, to).await
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn write_lp_persist_backpressure() {
+        let addr = get_free_port();
+        let trace_header_parser = trace_http::ctx::TraceHeaderParser::new();
+        let metrics = Arc::new(metric::Registry::new());
+        let common_state =
+            crate::CommonServerState::new(Arc::clone(&metrics), None, trace_header_parser, addr)
+                .unwrap();
+        let failing_object_store = Arc::new(FailingObjectStore {
+            inner: Arc::new(object_store::memory::InMemory::new()),
+            failing: AtomicBool::new(false),
+        });
+        let object_store: Arc<DynObjectStore> = Arc::clone(&failing_object_store) as _;
+        let parquet_store =
+            ParquetStorage::new(Arc::clone(&object_store), StorageId::from("influxdb3"));
+        let exec = Arc::new(Executor::new_with_config_and_executor(
+            ExecutorConfig {
+                target_query_partitions: NonZeroUsize::new(1).unwrap(),
+                object_stores: [&parquet_store]
+                    .into_iter()
+                    .map(|store| (store.id(), Arc::clone(store.object_store())))
+                    .collect(),
+                metric_registry: Arc::clone(&metrics),
+                mem_pool_size: usize::MAX,
+            },
+            DedicatedExecutor::new_testing(),
+        ));
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+
+        let write_buffer = Arc::new(
+            influxdb3_write::write_buffer::WriteBufferImpl::new(
+                Arc::clone(&persister),
+                None::<Arc<influxdb3_write::wal::WalImpl>>,
+                Arc::clone(&time_provider),
+                SegmentDuration::new_5m(),
+                Arc::clone(&exec),
+                Arc::clone(&metrics),
+            )
+            .await
+            .unwrap()
+            .with_persist_failure_limit(1),
+        );
+        let query_executor = crate::query_executor::QueryExecutorImpl::new(
+            write_buffer.catalog(),
+            Arc::clone(&write_buffer),
+            Arc::clone(&exec),
+            Arc::clone(&metrics),
+            Arc::new(HashMap::new()),
+            10,
+            10,
+        );
+
+        let server = ServerBuilder::new(common_state)
+            .write_buffer(Arc::clone(&write_buffer))
+            .query_executor(Arc::new(query_executor))
+            .persister(persister)
+            .authorizer(Arc::new(DefaultAuthorizer))
+            .time_provider(Arc::clone(&time_provider))
+            .build();
+        let frontend_shutdown = CancellationToken::new();
+        let shutdown = frontend_shutdown.clone();
+
+        tokio::spawn(async move { serve(server, frontend_shutdown).await });
+
+        let server = format!("http://{}", addr);
+        let write = |database: &'static str| {
+            write_lp(
+                &server,
+                database,
+                "cpu,host=a val=1i",
+                None,
+                false,
+                "nanosecond",
+            )
+        };
+        let write = &write;
+        // writes to the database until it gets a response with the status, or gives up
+        let write_until = move |database: &'static str, status: StatusCode| async move {
+            for _ in 0..100 {
+                let resp = write(database).await;
+                if resp.status() == status {
+                    return resp;
+                }
+                tokio::time::sleep(std::time::Duration::from_millis(100)).await;
+            }
+            panic!("writes to {database} never got a {status} response");
+        };
+
+        assert_eq!(write("foo").await.status(), StatusCode::OK);
+
+        // the segment with the write is persisted once it's old enough, which fails while the
+        // object store is unavailable, so writes to the database are turned away
+        failing_object_store.failing.store(true, Ordering::SeqCst);
+        time_provider.set(Time::from_timestamp(600, 0).unwrap());
+        let resp = write_until("foo", StatusCode::SERVICE_UNAVAILABLE).await;
+        assert_eq!(
+            resp.headers()
+                .get(hyper::header::RETRY_AFTER)
+                .and_then(|value| value.to_str().ok()),
+            Some("10")
+        );
+        let body =
+            String::from_utf8(body::to_bytes(resp.into_body()).await.unwrap().to_vec()).unwrap();
+        assert_eq!(
+            body,
+            "{\
+                \"error\":\"writes to foo are paused after 1 consecutive failures to persist its \
+                data to object storage\",\
+                \"data\":null\
+            }"
+        );
+        // writes to databases without data in the failed segment are still accepted
+        assert_eq!(write("bar").await.status(), StatusCode::OK);
+
+        // once the object store is back, the segment is persisted and writes are accepted again
+        failing_object_store.failing.store(false, Ordering::SeqCst);
+        write_until("foo", StatusCode::OK).await;
+
+        shutdown.cancel();
+    }
+
+    /// A request authorizer that only allows requests for the `foo` namespace, recording the
+    /// namespaces it was asked about
+    #[derive(Debug, Default)]
+    struct FooOnlyAuthorizer {
+        namespaces: parking_lot::Mutex<Vec<Option<String>>>,
+    }
+
+    #[async_trait]
+    impl RequestAuthorizer for FooOnlyAuthorizer {
+        async fn authorize(&self, request: AuthorizationRequest<'_>) -> Result<(), authz::Error> {
+            self.namespaces
+                .lock()
+                .push(request.namespace.map(ToString::to_string));
+            match request.namespace {
+                Some("foo") => Ok(()),
+                _ => Err(authz::Error::Forbidden),
+            }
+        }
+    }
+
+    #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
+    async fn queries_are_authorized_with_their_database() {
+        let addr = get_free_port();
+        let trace_header_parser = trace_http::ctx::TraceHeaderParser::new();
+        let metrics = Arc::new(metric::Registry::new());
+        let common_state =
+            crate::CommonServerState::new(Arc::clone(&metrics), None, trace_header_parser, addr)
+                .unwrap();
+        let object_store: Arc<DynObjectStore> = Arc::new(object_store::memory::InMemory::new());
+        let parquet_store =
+            ParquetStorage::new(Arc::clone(&object_store), StorageId::from("influxdb3"));
+        let exec = Arc::new(Executor::new_with_config_and_executor(
+            ExecutorConfig {
+                target_query_partitions: NonZeroUsize::new(1).unwrap(),
+                object_stores: [&parquet_store]
+                    .into_iter()
+                    .map(|store| (store.id(), Arc::clone(store.object_store())))
+                    .collect(),
+                metric_registry: Arc::clone(&metrics),
+                mem_pool_size: usize::MAX,
+            },
+            DedicatedExecutor::new_testing(),
+        ));
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+
+        let write_buffer = Arc::new(
+            influxdb3_write::write_buffer::WriteBufferImpl::new(
+                Arc::clone(&persister),
+                None::<Arc<influxdb3_write::wal::WalImpl>>,
+                Arc::clone(&time_provider),
+                SegmentDuration::new_5m(),
+                Arc::clone(&exec),
+                Arc::clone(&metrics),
+            )
+            .await
+            .unwrap(),
+        );
+        let query_executor = crate::query_executor::QueryExecutorImpl::new(
+            write_buffer.catalog(),
+            Arc::clone(&write_buffer),
+            Arc::clone(&exec),
+            Arc::clone(&metrics),
+            Arc::new(HashMap::new()),
+            10,
+            10,
+        );
+        let request_authorizer = Arc::new(FooOnlyAuthorizer::default());
+
+        let server = ServerBuilder::new(common_state)
+            .write_buffer(Arc::clone(&write_buffer))
+            .query_executor(Arc::new(query_executor))
+            .persister(persister)
+            .authorizer(Arc::new(DefaultAuthorizer))
+            .request_authorizer(Arc::clone(&request_authorizer) as _)
+            .time_provider(Arc::clone(&time_provider))
+            .build();
+        let frontend_shutdown = CancellationToken::new();
+        let shutdown = frontend_shutdown.clone();
+
+        tokio::spawn(async move { serve(server, frontend_shutdown).await });
+
+        let server = format!("http://{}", addr);
+        for db in ["foo", "bar"] {
+            write_lp(
+                &server,
+                db,
+                "cpu,host=a val=1i 1",
+                None,
+                false,
+                "nanosecond",
+            )
+            .await;
+        }
+        request_authorizer.namespaces.lock().clear();
+
+        let client = Client::new();
+        let post = |path: &str, body: serde_json::Value| {
+            Request::builder()
+                .uri(format!("{server}{path}"))
+                .method("POST")
+                .body(Body::from(body.to_string()))
+                .unwrap()
+        };
+
+        // the database of POST requests is in their body
+        let resp = client
+            .request(post(
+                "/api/v3/query_sql",
+                serde_json::json!({"db": "foo", "q": "select * from cpu"}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::OK);
+        let resp = client
+            .request(post(
+                "/api/v3/query_sql",
+                serde_json::json!({"db": "bar", "q": "select * from cpu"}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        // the database of an InfluxQL query can be given in its statement
+        let resp = client
+            .request(post(
+                "/api/v3/query_influxql",
+                serde_json::json!({"q": "select * from bar.autogen.cpu"}),
+            ))
+            .await
+            .unwrap();
+        assert_eq!(resp.status(), StatusCode::FORBIDDEN);
+
+        assert_eq!(
+            *request_authorizer.namespaces.lock(),
+            vec![
+                Some("foo".to_string()),
+                Some("bar".to_string()),
+                Some("bar".to_string())
+            ]
+        );
+
+        shutdown.cancel();
+    }
+
     pub(crate) async fn write_lp(
         server: impl Into<String> + Send,
         database: impl Into<String> + Send,
@@ -0,0 +1,182 @@
+//! A bounded, in-memory buffer of the lines most recently accepted for each database, so that
+//! dashboards can check that data is flowing for a database, and see what it looks like, without
+//! running a query against the write buffer.
+//!
+//! Up to a fixed number of lines are kept for each database, and lines older than an hour are
+//! dropped, so that the buffer only ever answers questions about the last few minutes of writes.
+
+use std::collections::{BTreeMap, HashMap, VecDeque};
+use std::time::Duration;
+
+use influxdb_line_protocol::parse_lines;
+use iox_time::Time;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// Lines received longer ago than this are dropped
+const RECENT_LINES_MAX_AGE: Duration = Duration::from_secs(60 * 60);
+
+#[derive(Debug, Clone)]
+struct RecentLine {
+    received_at: Time,
+    table: String,
+    line: String,
+}
+
+/// The lines accepted for a database in the requested window, as returned from the
+/// `/api/v3/recent_lines` API
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct RecentLinesSummary {
+    pub(crate) db: String,
+    /// The number of lines that are still buffered for the window
+    pub(crate) line_count: usize,
+    /// The number of lines for each table in the window
+    pub(crate) tables: BTreeMap<String, usize>,
+    /// When the most recent line was received, if any were in the window
+    pub(crate) last_received: Option<String>,
+    /// Up to the requested number of lines in the window, newest first
+    pub(crate) lines: Vec<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RecentLines {
+    capacity_per_db: usize,
+    lines: Mutex<HashMap<String, VecDeque<RecentLine>>>,
+}
+
+impl RecentLines {
+    pub(crate) fn new(capacity_per_db: usize) -> Self {
+        Self {
+            capacity_per_db,
+            lines: Mutex::new(HashMap::new()),
+        }
+    }
+
+    /// Record the lines of the line protocol that are valid, which were accepted for `db_name`
+    /// at the given time. This is the line protocol as it was buffered, so that the lines are
+    /// what queries of the database return.
+    pub(crate) fn record(&self, time: Time, db_name: &str, lp: &str) {
+        let accepted: Vec<_> = lp
+            .lines()
+            .filter_map(|line| {
+                let parsed = parse_lines(line).next()?.ok()?;
+                Some(RecentLine {
+                    received_at: time,
+                    table: parsed.series.measurement.to_string(),
+                    line: line.to_string(),
+                })
+            })
+            .collect();
+        if accepted.is_empty() {
+            return;
+        }
+
+        let mut lines = self.lines.lock();
+        let db_lines = lines.entry(db_name.to_string()).or_default();
+        let skip = accepted.len().saturating_sub(self.capacity_per_db);
+        db_lines.extend(accepted.into_iter().skip(skip));
+        while db_lines.len() > self.capacity_per_db {
+            db_lines.pop_front();
+        }
+
+        let oldest = time.checked_sub(RECENT_LINES_MAX_AGE);
+        while db_lines
+            .front()
+            .is_some_and(|line| oldest.is_some_and(|oldest| line.received_at < oldest))
+        {
+            db_lines.pop_front();
+        }
+    }
+
+    /// Returns the lines received for `db_name` in the `window` before `now`, with at most
+    /// `limit` of the lines themselves
+    pub(crate) fn recent(
+        &self,
+        now: Time,
+        db_name: &str,
+        window: Duration,
+        limit: usize,
+    ) -> RecentLinesSummary {
+        let since = now.checked_sub(window);
+        let lines = self.lines.lock();
+        let in_window: Vec<_> = lines
+            .get(db_name)
+            .into_iter()
+            .flatten()
+            .rev()
+            .take_while(|line| since.map_or(true, |since| line.received_at >= since))
+            .collect();
+
+        let mut tables = BTreeMap::new();
+        for line in &in_window {
+            *tables.entry(line.table.clone()).or_default() += 1;
+        }
+
+        RecentLinesSummary {
+            db: db_name.to_string(),
+            line_count: in_window.len(),
+            tables,
+            last_received: in_window.first().map(|line| line.received_at.to_rfc3339()),
+            lines: in_window
+                .iter()
+                .take(limit)
+                .map(|line| line.line.clone())
+                .collect(),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn keeps_recent_lines_per_database() {
+        let recent_lines = RecentLines::new(3);
+        let start = Time::from_timestamp(1_000, 0).unwrap();
+        let minute = Duration::from_secs(60);
+
+        recent_lines.record(start, "foo", "cpu,host=a usage=1 1\nnot valid lp");
+        recent_lines.record(
+            start + minute * 5,
+            "foo",
+            "cpu,host=b usage=2 2\nmem free=3i 2",
+        );
+        recent_lines.record(start + minute * 5, "bar", "disk used=1i 1");
+
+        let now = start + minute * 6;
+        let summary = recent_lines.recent(now, "foo", minute * 10, 10);
+        assert_eq!(summary.line_count, 3);
+        assert_eq!(
+            summary.tables,
+            BTreeMap::from([("cpu".to_string(), 2), ("mem".to_string(), 1)])
+        );
+        assert_eq!(
+            summary.last_received,
+            Some((start + minute * 5).to_rfc3339())
+        );
+        assert_eq!(
+            summary.lines,
+            vec![
+                "mem free=3i 2",
+                "cpu,host=b usage=2 2",
+                "cpu,host=a usage=1 1"
+            ]
+        );
+
+        // only lines received in the window are returned
+        let summary = recent_lines.recent(now, "foo", minute * 2, 1);
+        assert_eq!(summary.line_count, 2);
+        assert_eq!(summary.lines, vec!["mem free=3i 2"]);
+
+        // the oldest lines are dropped once the database has more than the capacity
+        recent_lines.record(now, "foo", "cpu,host=c usage=3 3");
+        let summary = recent_lines.recent(now, "foo", minute * 10, 10);
+        assert_eq!(summary.line_count, 3);
+        assert_eq!(summary.tables.get("cpu"), Some(&2));
+
+        let summary = recent_lines.recent(now, "baz", minute * 10, 10);
+        assert_eq!(summary.line_count, 0);
+        assert_eq!(summary.last_received, None);
+    }
+}
@@ -0,0 +1,184 @@
+//! A bounded, in-memory record of the most recent writes that the server has received, to help
+//! operators debug what is being written to a server without a tracing backend.
+//!
+//! Only the metadata of each write is kept, not the line protocol. Once the buffer is full the
+//! oldest writes are dropped as new ones are recorded.
+
+use std::collections::VecDeque;
+
+use influxdb3_write::BufferedWriteRequest;
+use iox_time::Time;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+/// The outcome of a write
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum RecentWriteStatus {
+    /// All lines of the write were accepted
+    Accepted,
+    /// Some lines of the write were accepted, and the rest were invalid
+    Partial,
+    /// None of the write was accepted
+    Rejected,
+}
+
+/// A write that was recently received, as returned from the `/debug/recent-writes` API
+#[derive(Debug, Clone, Serialize, PartialEq, Eq)]
+pub(crate) struct RecentWrite {
+    pub(crate) time: String,
+    pub(crate) db: String,
+    /// The tables that lines were accepted for, ordered by name
+    pub(crate) tables: Vec<String>,
+    pub(crate) lines: usize,
+    pub(crate) invalid_lines: usize,
+    /// The size of the line protocol of the accepted lines in bytes
+    pub(crate) bytes: usize,
+    pub(crate) status: RecentWriteStatus,
+    /// The error for a rejected write, or for the first invalid line of a partial write
+    pub(crate) error: Option<String>,
+}
+
+#[derive(Debug)]
+pub(crate) struct RecentWrites {
+    capacity: usize,
+    writes: Mutex<VecDeque<RecentWrite>>,
+}
+
+impl RecentWrites {
+    pub(crate) fn new(capacity: usize) -> Self {
+        Self {
+            capacity,
+            writes: Mutex::new(VecDeque::with_capacity(capacity)),
+        }
+    }
+
+    /// Record the result of a write to `db_name` that was received at the given time
+    pub(crate) fn record<E: ToString>(
+        &self,
+        time: Time,
+        db_name: &str,
+        result: &Result<BufferedWriteRequest, E>,
+    ) {
+        let write = match result {
+            Ok(request) => {
+                let mut tables: Vec<_> = request.table_summaries.keys().cloned().collect();
+                tables.sort();
+                let status = if request.invalid_lines.is_empty() {
+                    RecentWriteStatus::Accepted
+                } else {
+                    RecentWriteStatus::Partial
+                };
+                RecentWrite {
+                    time: time.to_rfc3339(),
+                    db: db_name.to_string(),
+                    tables,
+                    lines: request.line_count,
+                    invalid_lines: request.invalid_lines.len(),
+                    bytes: request.table_summaries.values().map(|s| s.bytes).sum(),
+                    status,
+                    error: request
+                        .invalid_lines
+                        .first()
+                        .map(|line| line.error_message.clone()),
+                }
+            }
+            Err(e) => RecentWrite {
+                time: time.to_rfc3339(),
+                db: db_name.to_string(),
+                tables: vec![],
+                lines: 0,
+                invalid_lines: 0,
+                bytes: 0,
+                status: RecentWriteStatus::Rejected,
+                error: Some(e.to_string()),
+            },
+        };
+
+        let mut writes = self.writes.lock();
+        if writes.len() == self.capacity {
+            writes.pop_front();
+        }
+        writes.push_back(write);
+    }
+
+    /// Returns up to `limit` of the most recent writes, optionally only those to `db_name`,
+    /// newest first
+    pub(crate) fn recent(&self, db_name: Option<&str>, limit: usize) -> Vec<RecentWrite> {
+        self.writes
+            .lock()
+            .iter()
+            .rev()
+            .filter(|write| db_name.map_or(true, |name| name == write.db))
+            .take(limit)
+            .cloned()
+            .collect()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::collections::HashMap;
+
+    use data_types::NamespaceName;
+    use influxdb3_write::{TableWriteSummary, WriteLineError};
+
+    use super::*;
+
+    fn request(db_name: &'static str, tables: &[&str], invalid: usize) -> BufferedWriteRequest {
+        BufferedWriteRequest {
+            db_name: NamespaceName::new(db_name).unwrap(),
+            invalid_lines: (0..invalid)
+                .map(|line_number| WriteLineError {
+                    original_line: "bad".to_string(),
+                    line_number,
+                    error_message: "invalid line".to_string(),
+                })
+                .collect(),
+            line_count: tables.len(),
+            field_count: tables.len(),
+            tag_count: 0,
+            table_summaries: tables
+                .iter()
+                .map(|table| {
+                    (
+                        table.to_string(),
+                        TableWriteSummary {
+                            line_count: 1,
+                            bytes: 10,
+                            new_column_count: 0,
+                        },
+                    )
+                })
+                .collect::<HashMap<_, _>>(),
+            wal_positions: vec![],
+            accepted_ops: vec![],
+        }
+    }
+
+    #[test]
+    fn keeps_most_recent_writes() {
+        let recent_writes = RecentWrites::new(2);
+        let time = Time::from_timestamp_nanos(0);
+
+        recent_writes.record::<String>(time, "foo", &Ok(request("foo", &["mem", "cpu"], 0)));
+        recent_writes.record::<String>(time, "bar", &Ok(request("bar", &["cpu"], 1)));
+        recent_writes.record(time, "foo", &Err("parsing failed"));
+
+        let writes = recent_writes.recent(None, 10);
+        assert_eq!(writes.len(), 2);
+        assert_eq!(writes[0].db, "foo");
+        assert_eq!(writes[0].status, RecentWriteStatus::Rejected);
+        assert_eq!(writes[0].error.as_deref(), Some("parsing failed"));
+        assert_eq!(writes[1].db, "bar");
+        assert_eq!(writes[1].status, RecentWriteStatus::Partial);
+        assert_eq!(writes[1].tables, vec!["cpu".to_string()]);
+        assert_eq!(writes[1].invalid_lines, 1);
+        assert_eq!(writes[1].error.as_deref(), Some("invalid line"));
+
+        let writes = recent_writes.recent(Some("bar"), 10);
+        assert_eq!(writes.len(), 1);
+        assert_eq!(writes[0].bytes, 10);
+        assert_eq!(recent_writes.recent(None, 1).len(), 1);
+    }
+}
@@ -0,0 +1,453 @@
+//! Asynchronous replication of accepted writes to a remote InfluxDB 3.0 server.
+//!
+//! Writes that have been accepted by this server are put on a bounded queue and forwarded, in
+//! order, to the `/api/v3/write_lp` API of the replication target, as the lines that were
+//! buffered here, with the time of every line written out. Failed requests are retried with an
+//! exponential backoff. If the target falls far enough behind that the queue fills up, new writes
+//! are dropped rather than applying backpressure to local writes.
+//!
+//! Optionally, writes can be spooled to local disk while the target is unreachable, for example
+//! when running as a gateway on an edge network with intermittent connectivity. Spooled writes
+//! are replayed, in order, once the target can be reached again.
+
+use std::borrow::Cow;
+use std::time::{Duration, Instant};
+
+use influxdb3_client::{Client, Error as ClientError};
+use influxdb3_write::{LpWriteOp, Precision};
+use influxdb_line_protocol::parse_lines;
+use metric::{DurationHistogram, Metric, U64Counter, U64Gauge};
+use observability_deps::tracing::{debug, error, info, warn};
+use reqwest::StatusCode;
+use tokio::sync::mpsc;
+
+use self::spool::Spool;
+pub use self::spool::{Error as SpoolError, SpoolConfig, SpoolFullPolicy};
+
+mod spool;
+
+/// The default number of writes that can be waiting to be replicated
+pub const DEFAULT_REPLICATION_QUEUE_SIZE: usize = 10_000;
+
+const INITIAL_BACKOFF: Duration = Duration::from_millis(100);
+const MAX_BACKOFF: Duration = Duration::from_secs(30);
+
+/// Configuration for replicating writes to a remote server
+#[derive(Debug)]
+pub struct ReplicationConfig {
+    /// The base URL of the server to replicate to, e.g. `http://replica:8181`
+    target: String,
+    client: Client,
+    /// The maximum number of writes that can be queued for replication
+    queue_size: usize,
+    spool: Option<Spool>,
+}
+
+impl ReplicationConfig {
+    /// Create the configuration to replicate to the server at the `target` URL, optionally
+    /// authenticating with a bearer token.
+    pub fn new(target: impl Into<String>, auth_token: Option<String>) -> Result<Self, ClientError> {
+        let target = target.into();
+        let mut client = Client::new(target.as_str())?;
+        if let Some(token) = auth_token {
+            client = client.with_auth_token(token);
+        }
+
+        Ok(Self {
+            target,
+            client,
+            queue_size: DEFAULT_REPLICATION_QUEUE_SIZE,
+            spool: None,
+        })
+    }
+
+    /// Set the maximum number of writes that can be queued for replication
+    pub fn with_queue_size(mut self, queue_size: usize) -> Self {
+        self.queue_size = queue_size;
+        self
+    }
+
+    /// Spool writes to local disk while the target is unreachable, rather than holding them in
+    /// memory until they can be sent. This opens the spool, so that any writes left in it by a
+    /// previous run of the server are replayed.
+    pub fn with_spool(mut self, spool: SpoolConfig) -> Result<Self, SpoolError> {
+        self.spool = Some(Spool::open(spool)?);
+        Ok(self)
+    }
+}
+
+/// A write that was accepted locally and is waiting to be replicated
+#[derive(Debug)]
+pub(crate) struct ReplicatedWrite {
+    pub(crate) db_name: String,
+    pub(crate) lp: String,
+    /// The time, in nanoseconds, that lines without a timestamp were given when they were
+    /// accepted locally
+    pub(crate) default_time: i64,
+    pub(crate) precision: Precision,
+    pub(crate) accepted_at: Instant,
+}
+
+impl ReplicatedWrite {
+    /// The write of an op that was accepted into the local buffer
+    pub(crate) fn from_op(op: &LpWriteOp) -> Self {
+        Self {
+            db_name: op.db_name.clone(),
+            lp: op.lp.clone(),
+            default_time: op.default_time,
+            precision: op.precision,
+            accepted_at: Instant::now(),
+        }
+    }
+}
+
+#[derive(Debug)]
+pub(crate) struct Replicator {
+    tx: mpsc::Sender<ReplicatedWrite>,
+    metrics: ReplicationMetrics,
+}
+
+#[derive(Debug, Clone)]
+struct ReplicationMetrics {
+    queue_length: U64Gauge,
+    lag: DurationHistogram,
+    replicated: U64Counter,
+    dropped: U64Counter,
+    rejected: U64Counter,
+    retries: U64Counter,
+    spooled: U64Counter,
+    spool_bytes: U64Gauge,
+}
+
+impl ReplicationMetrics {
+    fn new(registry: &metric::Registry, target: &str) -> Self {
+        let attributes = [("target", Cow::Owned(target.to_string()))];
+
+        let queue_length = registry
+            .register_metric::<U64Gauge>(
+                "influxdb3_replication_queue_length",
+                "number of accepted writes waiting to be replicated",
+            )
+            .recorder(attributes.clone());
+        let lag = registry
+            .register_metric::<DurationHistogram>(
+                "influxdb3_replication_lag",
+                "time from a write being accepted until it was replicated to the target",
+            )
+            .recorder(attributes.clone());
+
+        let writes: Metric<U64Counter> = registry.register_metric(
+            "influxdb3_replication_writes",
+            "number of accepted writes by replication result",
+        );
+        let replicated = writes.recorder([
+            attributes[0].clone(),
+            ("result", Cow::Borrowed("replicated")),
+        ]);
+        let dropped =
+            writes.recorder([attributes[0].clone(), ("result", Cow::Borrowed("dropped"))]);
+        let rejected =
+            writes.recorder([attributes[0].clone(), ("result", Cow::Borrowed("rejected"))]);
+
+        let retries = registry
+            .register_metric::<U64Counter>(
+                "influxdb3_replication_retries",
+                "number of failed replication requests that were retried",
+            )
+            .recorder(attributes.clone());
+
+        let spooled = registry
+            .register_metric::<U64Counter>(
+                "influxdb3_replication_spooled",
+                "number of writes spooled to disk while the replication target was unreachable",
+            )
+            .recorder(attributes.clone());
+        let spool_bytes = registry
+            .register_metric::<U64Gauge>(
+                "influxdb3_replication_spool_bytes",
+                "size of the writes in the replication spool",
+            )
+            .recorder(attributes);
+
+        Self {
+            queue_length,
+            lag,
+            replicated,
+            dropped,
+            rejected,
+            retries,
+            spooled,
+            spool_bytes,
+        }
+    }
+}
+
+impl Replicator {
+    /// Create a new replicator and start the background task that forwards writes to the target.
+    pub(crate) fn new(config: ReplicationConfig, registry: &metric::Registry) -> Self {
+        let metrics = ReplicationMetrics::new(registry, &config.target);
+        let (tx, rx) = mpsc::channel(config.queue_size);
+
+        let spool = config.spool;
+        if let Some(spool) = &spool {
+            metrics.spool_bytes.set(spool.size());
+            if !spool.is_empty() {
+                info!("replaying writes left in the replication spool");
+            }
+        }
+
+        tokio::spawn(run_replication(config.client, rx, metrics.clone(), spool));
+
+        Self { tx, metrics }
+    }
+
+    /// Queue a write for replication. If the queue is full the write is dropped.
+    pub(crate) fn replicate(&self, write: ReplicatedWrite) {
+        match self.tx.try_send(write) {
+            Ok(()) => self.metrics.queue_length.inc(1),
+            Err(mpsc::error::TrySendError::Full(write)) => {
+                warn!(db_name = %write.db_name, "replication queue full, dropping write");
+                self.metrics.dropped.inc(1);
+            }
+            Err(mpsc::error::TrySendError::Closed(write)) => {
+                error!(db_name = %write.db_name, "replication task is not running, dropping write");
+                self.metrics.dropped.inc(1);
+            }
+        }
+    }
+}
+
+async fn run_replication(
+    client: Client,
+    mut rx: mpsc::Receiver<ReplicatedWrite>,
+    metrics: ReplicationMetrics,
+    mut spool: Option<Spool>,
+) {
+    loop {
+        if let Some(spool) = spool.as_mut().filter(|spool| !spool.is_empty()) {
+            // keep spooling newly accepted writes until the spool has been replayed, so that
+            // writes reach the target in the order they were accepted
+            if !replay_spool(&client, &mut rx, &metrics, spool).await {
+                return;
+            }
+            continue;
+        }
+
+        let Some(write) = rx.recv().await else {
+            return;
+        };
+        metrics.queue_length.dec(1);
+
+        let mut backoff = INITIAL_BACKOFF;
+        loop {
+            match send_write(&client, &write).await {
+                Ok(()) => {
+                    debug!(db_name = %write.db_name, "replicated write");
+                    metrics.replicated.inc(1);
+                    metrics.lag.record(write.accepted_at.elapsed());
+                    break;
+                }
+                Err(ClientError::ApiError { code, message }) if is_rejection(code) => {
+                    // the target won't accept this write no matter how many times it is sent
+                    error!(
+                        db_name = %write.db_name,
+                        %code,
+                        %message,
+                        "replication target rejected write"
+                    );
+                    metrics.rejected.inc(1);
+                    break;
+                }
+                Err(e) => {
+                    if let Some(spool) = spool.as_mut() {
+                        warn!(
+                            db_name = %write.db_name,
+                            error = %e,
+                            "failed to replicate write, spooling to disk"
+                        );
+                        spool_write(spool, &write, &metrics);
+                        break;
+                    }
+                    warn!(
+                        db_name = %write.db_name,
+                        error = %e,
+                        ?backoff,
+                        "failed to replicate write, retrying"
+                    );
+                    metrics.retries.inc(1);
+                    tokio::time::sleep(backoff).await;
+                    backoff = (backoff * 2).min(MAX_BACKOFF);
+                }
+            }
+        }
+    }
+}
+
+/// Replays the spool until it is empty, spooling any writes that are accepted in the meantime.
+/// Returns false if the replicator has been shut down.
+async fn replay_spool(
+    client: &Client,
+    rx: &mut mpsc::Receiver<ReplicatedWrite>,
+    metrics: &ReplicationMetrics,
+    spool: &mut Spool,
+) -> bool {
+    let mut backoff = INITIAL_BACKOFF;
+    loop {
+        while let Ok(write) = rx.try_recv() {
+            metrics.queue_length.dec(1);
+            spool_write(spool, &write, metrics);
+        }
+
+        let write = match spool.oldest() {
+            Ok(Some(write)) => write,
+            Ok(None) => return true,
+            Err(e) => {
+                error!(error = %e, "failed to read write from replication spool, dropping it");
+                metrics.dropped.inc(1);
+                remove_oldest(spool, metrics);
+                continue;
+            }
+        };
+
+        match send_write(client, &write).await {
+            Ok(()) => {
+                debug!(db_name = %write.db_name, "replicated spooled write");
+                metrics.replicated.inc(1);
+                metrics.lag.record(write.accepted_at.elapsed());
+                remove_oldest(spool, metrics);
+                backoff = INITIAL_BACKOFF;
+            }
+            Err(ClientError::ApiError { code, message }) if is_rejection(code) => {
+                error!(
+                    db_name = %write.db_name,
+                    %code,
+                    %message,
+                    "replication target rejected spooled write"
+                );
+                metrics.rejected.inc(1);
+                remove_oldest(spool, metrics);
+            }
+            Err(e) => {
+                warn!(
+                    db_name = %write.db_name,
+                    error = %e,
+                    ?backoff,
+                    "failed to replay spooled write, retrying"
+                );
+                metrics.retries.inc(1);
+
+                // spool writes as they are accepted while waiting, so the queue doesn't fill up
+                let sleep = tokio::time::sleep(backoff);
+                tokio::pin!(sleep);
+                loop {
+                    tokio::select! {
+                        _ = &mut sleep => break,
+                        write = rx.recv() => match write {
+                            Some(write) => {
+                                metrics.queue_length.dec(1);
+                                spool_write(spool, &write, metrics);
+                            }
+                            None => return false,
+                        },
+                    }
+                }
+                backoff = (backoff * 2).min(MAX_BACKOFF);
+            }
+        }
+    }
+}
+
+fn spool_write(spool: &mut Spool, write: &ReplicatedWrite, metrics: &ReplicationMetrics) {
+    match spool.push(write) {
+        Ok(dropped) => {
+            metrics.spooled.inc(1);
+            if dropped > 0 {
+                warn!(dropped, "replication spool full, dropped oldest writes");
+                metrics.dropped.inc(dropped as u64);
+            }
+        }
+        Err(e) => {
+            warn!(db_name = %write.db_name, error = %e, "failed to spool write, dropping it");
+            metrics.dropped.inc(1);
+        }
+    }
+    metrics.spool_bytes.set(spool.size());
+}
+
+fn remove_oldest(spool: &mut Spool, metrics: &ReplicationMetrics) {
+    if let Err(e) = spool.remove_oldest() {
+        error!(error = %e, "failed to remove write from replication spool");
+    }
+    metrics.spool_bytes.set(spool.size());
+}
+
+/// Returns true if the target responded that it won't accept the write however many times it is
+/// sent. Rate limiting and authorization failures are retried, as they clear up once the target
+/// has capacity again, or its tokens are fixed, and the write is still wanted then. The other
+/// client errors are rejections, which are counted by the `rejected` writes metric.
+fn is_rejection(code: StatusCode) -> bool {
+    code.is_client_error()
+        && !matches!(
+            code,
+            StatusCode::TOO_MANY_REQUESTS | StatusCode::UNAUTHORIZED | StatusCode::FORBIDDEN
+        )
+}
+
+async fn send_write(client: &Client, write: &ReplicatedWrite) -> Result<(), ClientError> {
+    client
+        .api_v3_write_lp(write.db_name.as_str())
+        .precision(influxdb3_client::Precision::Nanosecond)
+        .body(with_explicit_timestamps(
+            &write.lp,
+            write.default_time,
+            write.precision,
+        ))
+        .send()
+        .await
+}
+
+/// Returns the lines with their time written out in nanoseconds, as they were buffered here, so
+/// that lines without a timestamp get the time they were accepted at here rather than the time
+/// they reach the target.
+fn with_explicit_timestamps(lp: &str, default_time: i64, precision: Precision) -> String {
+    parse_lines(lp)
+        // the lines were all accepted into the local buffer, so they parse
+        .filter_map(|line| line.ok())
+        .map(|mut line| {
+            let time = line
+                .timestamp
+                .map(|ts| precision.timestamp_to_nanos(ts))
+                .unwrap_or(default_time);
+            line.timestamp = Some(time);
+            line.to_string()
+        })
+        .collect::<Vec<_>>()
+        .join("\n")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn writes_out_the_time_of_every_line() {
+        let lp = "cpu,host=a usage=1 2\n\
+                  cpu,host=b usage=2";
+
+        assert_eq!(
+            with_explicit_timestamps(lp, 5_000_000_000, Precision::Second),
+            "cpu,host=a usage=1 2000000000\n\
+             cpu,host=b usage=2 5000000000"
+        );
+    }
+
+    #[test]
+    fn retries_throttled_and_unauthorized_writes() {
+        assert!(is_rejection(StatusCode::BAD_REQUEST));
+        assert!(is_rejection(StatusCode::UNPROCESSABLE_ENTITY));
+        assert!(!is_rejection(StatusCode::TOO_MANY_REQUESTS));
+        assert!(!is_rejection(StatusCode::UNAUTHORIZED));
+        assert!(!is_rejection(StatusCode::FORBIDDEN));
+        assert!(!is_rejection(StatusCode::SERVICE_UNAVAILABLE));
+    }
+}
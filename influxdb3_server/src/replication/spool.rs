@@ -0,0 +1,346 @@
+//! A bounded, on disk spool of writes that could not be replicated because the target was
+//! unreachable.
+//!
+//! Each spooled write is stored in its own file, named by a monotonically increasing id, so that
+//! writes are replayed in the order they were accepted, including across restarts of the server.
+
+use std::collections::VecDeque;
+use std::fs::{self, File};
+use std::io::Write;
+use std::path::{Path, PathBuf};
+use std::time::Instant;
+
+use influxdb3_write::Precision;
+use observability_deps::tracing::warn;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+
+use super::ReplicatedWrite;
+
+const SPOOL_FILE_EXTENSION: &str = "json";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("io error for spool file {path}: {source}")]
+    Io {
+        path: PathBuf,
+        source: std::io::Error,
+    },
+
+    #[error("error serializing spooled write: {0}")]
+    Serialize(#[from] serde_json::Error),
+
+    #[error("spooled write of {size} bytes is larger than the spool")]
+    WriteTooLarge { size: u64 },
+
+    #[error("spool is full")]
+    Full,
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What to do with a write that does not fit in a full spool
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq)]
+pub enum SpoolFullPolicy {
+    /// Remove the oldest spooled writes until the new write fits
+    #[default]
+    DropOldest,
+    /// Drop the new write, keeping what is already spooled
+    DropNewest,
+}
+
+/// Configuration of the spool used to store writes while the replication target is unreachable
+#[derive(Debug, Clone)]
+pub struct SpoolConfig {
+    /// The directory that spooled writes are stored in
+    pub dir: PathBuf,
+    /// The maximum total size, in bytes, of the spooled writes
+    pub max_bytes: u64,
+    pub full_policy: SpoolFullPolicy,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SpooledWrite {
+    db_name: String,
+    lp: String,
+    /// The time, in nanoseconds, that lines without a timestamp were given when they were
+    /// accepted
+    default_time: i64,
+    precision: Precision,
+}
+
+#[derive(Debug)]
+struct SpoolEntry {
+    id: u64,
+    size: u64,
+    accepted_at: Instant,
+}
+
+#[derive(Debug)]
+pub(crate) struct Spool {
+    config: SpoolConfig,
+    entries: VecDeque<SpoolEntry>,
+    next_id: u64,
+    size: u64,
+}
+
+impl Spool {
+    /// Open the spool in the configured directory, creating it if it doesn't exist. Writes that
+    /// were spooled by a previous run of the server are picked up to be replayed.
+    pub(crate) fn open(config: SpoolConfig) -> Result<Self> {
+        fs::create_dir_all(&config.dir).map_err(|source| Error::Io {
+            path: config.dir.clone(),
+            source,
+        })?;
+
+        let read_dir = fs::read_dir(&config.dir).map_err(|source| Error::Io {
+            path: config.dir.clone(),
+            source,
+        })?;
+
+        let mut entries = vec![];
+        for dir_entry in read_dir {
+            let dir_entry = dir_entry.map_err(|source| Error::Io {
+                path: config.dir.clone(),
+                source,
+            })?;
+            let path = dir_entry.path();
+            let Some(id) = spool_file_id(&path) else {
+                warn!(path = %path.display(), "ignoring unexpected file in replication spool");
+                continue;
+            };
+            let size = dir_entry
+                .metadata()
+                .map_err(|source| Error::Io {
+                    path: path.clone(),
+                    source,
+                })?
+                .len();
+            entries.push(SpoolEntry {
+                id,
+                size,
+                // the time the write was accepted isn't kept across restarts
+                accepted_at: Instant::now(),
+            });
+        }
+        entries.sort_by_key(|entry| entry.id);
+
+        let next_id = entries.last().map(|entry| entry.id + 1).unwrap_or(0);
+        let size = entries.iter().map(|entry| entry.size).sum();
+
+        Ok(Self {
+            config,
+            entries: entries.into(),
+            next_id,
+            size,
+        })
+    }
+
+    pub(crate) fn is_empty(&self) -> bool {
+        self.entries.is_empty()
+    }
+
+    /// The total size, in bytes, of the spooled writes
+    pub(crate) fn size(&self) -> u64 {
+        self.size
+    }
+
+    /// Add a write to the end of the spool. Returns the number of spooled writes that were
+    /// dropped to make room for it.
+    pub(crate) fn push(&mut self, write: &ReplicatedWrite) -> Result<usize> {
+        let data = serde_json::to_vec(&SpooledWrite {
+            db_name: write.db_name.clone(),
+            lp: write.lp.clone(),
+            default_time: write.default_time,
+            precision: write.precision,
+        })?;
+        let size = data.len() as u64;
+        if size > self.config.max_bytes {
+            return Err(Error::WriteTooLarge { size });
+        }
+
+        let mut dropped = 0;
+        while self.size + size > self.config.max_bytes {
+            match self.config.full_policy {
+                SpoolFullPolicy::DropNewest => return Err(Error::Full),
+                SpoolFullPolicy::DropOldest => {
+                    self.remove_oldest()?;
+                    dropped += 1;
+                }
+            }
+        }
+
+        let id = self.next_id;
+        let path = self.spool_file_path(id);
+        let mut file = File::create(&path).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+        file.write_all(&data)
+            .and_then(|_| file.sync_all())
+            .map_err(|source| Error::Io { path, source })?;
+
+        self.next_id += 1;
+        self.size += size;
+        self.entries.push_back(SpoolEntry {
+            id,
+            size,
+            accepted_at: write.accepted_at,
+        });
+
+        Ok(dropped)
+    }
+
+    /// Read the oldest write in the spool, without removing it
+    pub(crate) fn oldest(&self) -> Result<Option<ReplicatedWrite>> {
+        let Some(entry) = self.entries.front() else {
+            return Ok(None);
+        };
+
+        let path = self.spool_file_path(entry.id);
+        let data = fs::read(&path).map_err(|source| Error::Io {
+            path: path.clone(),
+            source,
+        })?;
+        let SpooledWrite {
+            db_name,
+            lp,
+            default_time,
+            precision,
+        } = serde_json::from_slice(&data)?;
+
+        Ok(Some(ReplicatedWrite {
+            db_name,
+            lp,
+            default_time,
+            precision,
+            accepted_at: entry.accepted_at,
+        }))
+    }
+
+    /// Remove the oldest write from the spool
+    pub(crate) fn remove_oldest(&mut self) -> Result<()> {
+        let Some(entry) = self.entries.front() else {
+            return Ok(());
+        };
+
+        // the entry is only removed once its file is, so that a failed removal leaves the spool
+        // as it was
+        let path = self.spool_file_path(entry.id);
+        fs::remove_file(&path).map_err(|source| Error::Io { path, source })?;
+
+        let entry = self.entries.pop_front().expect("front entry exists");
+        self.size -= entry.size;
+
+        Ok(())
+    }
+
+    fn spool_file_path(&self, id: u64) -> PathBuf {
+        self.config
+            .dir
+            .join(format!("{id:020}.{SPOOL_FILE_EXTENSION}"))
+    }
+}
+
+fn spool_file_id(path: &Path) -> Option<u64> {
+    if path.extension()? != SPOOL_FILE_EXTENSION {
+        return None;
+    }
+    path.file_stem()?.to_str()?.parse().ok()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn write(lp: &str) -> ReplicatedWrite {
+        ReplicatedWrite {
+            db_name: "foo".to_string(),
+            lp: lp.to_string(),
+            default_time: 123,
+            precision: Precision::Nanosecond,
+            accepted_at: Instant::now(),
+        }
+    }
+
+    fn spooled_lp(spool: &mut Spool) -> Vec<String> {
+        let mut lp = vec![];
+        while let Some(write) = spool.oldest().unwrap() {
+            lp.push(write.lp);
+            spool.remove_oldest().unwrap();
+        }
+        lp
+    }
+
+    #[test]
+    fn replays_in_order_across_reopen() {
+        let dir = test_helpers::tmp_dir().unwrap();
+        let config = SpoolConfig {
+            dir: dir.path().to_path_buf(),
+            max_bytes: 1024,
+            full_policy: SpoolFullPolicy::DropOldest,
+        };
+
+        let mut spool = Spool::open(config.clone()).unwrap();
+        assert!(spool.is_empty());
+        spool.push(&write("cpu usage=1 1")).unwrap();
+        spool.push(&write("cpu usage=2 2")).unwrap();
+        let size = spool.size();
+        drop(spool);
+
+        let mut spool = Spool::open(config).unwrap();
+        assert_eq!(spool.size(), size);
+        assert_eq!(spool.oldest().unwrap().unwrap().default_time, 123);
+        assert_eq!(
+            spooled_lp(&mut spool),
+            vec!["cpu usage=1 1".to_string(), "cpu usage=2 2".to_string()]
+        );
+        assert!(spool.is_empty());
+        assert_eq!(spool.size(), 0);
+    }
+
+    #[test]
+    fn full_policies() {
+        let entry_size = serde_json::to_vec(&SpooledWrite {
+            db_name: "foo".to_string(),
+            lp: "cpu usage=1 1".to_string(),
+            default_time: 123,
+            precision: Precision::Nanosecond,
+        })
+        .unwrap()
+        .len() as u64;
+
+        let dir = test_helpers::tmp_dir().unwrap();
+        let mut spool = Spool::open(SpoolConfig {
+            dir: dir.path().to_path_buf(),
+            max_bytes: entry_size * 2,
+            full_policy: SpoolFullPolicy::DropOldest,
+        })
+        .unwrap();
+        assert_eq!(spool.push(&write("cpu usage=1 1")).unwrap(), 0);
+        assert_eq!(spool.push(&write("cpu usage=2 2")).unwrap(), 0);
+        assert_eq!(spool.push(&write("cpu usage=3 3")).unwrap(), 1);
+        assert_eq!(
+            spooled_lp(&mut spool),
+            vec!["cpu usage=2 2".to_string(), "cpu usage=3 3".to_string()]
+        );
+
+        let dir = test_helpers::tmp_dir().unwrap();
+        let mut spool = Spool::open(SpoolConfig {
+            dir: dir.path().to_path_buf(),
+            max_bytes: entry_size * 2,
+            full_policy: SpoolFullPolicy::DropNewest,
+        })
+        .unwrap();
+        spool.push(&write("cpu usage=1 1")).unwrap();
+        spool.push(&write("cpu usage=2 2")).unwrap();
+        assert!(matches!(
+            spool.push(&write("cpu usage=3 3")),
+            Err(Error::Full)
+        ));
+        assert_eq!(
+            spooled_lp(&mut spool),
+            vec!["cpu usage=1 1".to_string(), "cpu usage=2 2".to_string()]
+        );
+    }
+}
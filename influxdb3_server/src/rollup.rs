@@ -0,0 +1,572 @@
+//! Rollups of incoming writes, computed at ingest time.
+//!
+//! A [`RollupRule`] aggregates the numeric fields of every series in a table into fixed windows of
+//! time, e.g., the mean and max per minute. As lines are accepted they are added to the window that
+//! their timestamp falls into. Once a window has ended, plus a grace period for late arriving
+//! data, it is emitted as a line of line protocol to a rollup table, named
+//! `<table>_<interval>`, e.g. `cpu_1m`, in the rule's target database.
+//!
+//! Rollup state is held in memory, so windows that are open when the server stops are lost.
+//! Lines for a window of a series that has already been emitted are not included in any rollup,
+//! as long as the series has had a window emitted within the last hour.
+//!
+//! A `<field>_<aggregate>` field that would have the same name as a tag of its series is left out
+//! of the rollup, as the line could not be written otherwise.
+
+use std::collections::{BTreeMap, HashMap, HashSet};
+use std::fmt::Write;
+use std::sync::Arc;
+use std::time::Duration;
+
+use data_types::NamespaceName;
+use influxdb3_write::{Bufferer, LpWriteOp, Precision};
+use influxdb_line_protocol::{parse_lines, FieldValue};
+use iox_time::{Time, TimeProvider};
+use metric::U64Counter;
+use observability_deps::tracing::{debug, error, warn};
+use parking_lot::Mutex;
+use serde::Deserialize;
+use thiserror::Error;
+use tokio::task::JoinHandle;
+use tokio_util::sync::CancellationToken;
+
+const FLUSH_INTERVAL: Duration = Duration::from_secs(1);
+const NANOS_PER_SECOND: i64 = 1_000_000_000;
+/// How long the state of a series is kept after it last had a window emitted
+const IDLE_SERIES_RETENTION_NANOS: i64 = 60 * 60 * NANOS_PER_SECOND;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("rollup for database {db} must have a non-zero interval")]
+    ZeroInterval { db: String },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// Checks that the rules can be used to compute rollups
+pub(crate) fn validate_rules(rules: &[RollupRule]) -> Result<()> {
+    if let Some(rule) = rules.iter().find(|rule| rule.interval_secs == 0) {
+        return Err(Error::ZeroInterval {
+            db: rule.db.clone(),
+        });
+    }
+    Ok(())
+}
+
+/// An aggregate computed over the values of a field in each window
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum Aggregate {
+    Mean,
+    Min,
+    Max,
+    Sum,
+    Count,
+}
+
+impl Aggregate {
+    fn name(&self) -> &'static str {
+        match self {
+            Self::Mean => "mean",
+            Self::Min => "min",
+            Self::Max => "max",
+            Self::Sum => "sum",
+            Self::Count => "count",
+        }
+    }
+}
+
+/// Configuration of a rollup computed for the writes to a database
+#[derive(Debug, Clone, PartialEq, Deserialize)]
+pub struct RollupRule {
+    /// The database whose writes are rolled up
+    pub db: String,
+    /// Only roll up this table, if set, otherwise all tables in the database are rolled up
+    #[serde(default)]
+    pub table: Option<String>,
+    /// The length of each window, in seconds
+    pub interval_secs: u64,
+    /// The aggregates computed for each numeric field, written to `<field>_<aggregate>` fields
+    pub aggregates: Vec<Aggregate>,
+    /// The database the rollup tables are written to, defaults to `db`
+    #[serde(default)]
+    pub target_db: Option<String>,
+    /// How long, in seconds, after a window ends that lines are still added to it
+    #[serde(default)]
+    pub grace_secs: u64,
+}
+
+impl RollupRule {
+    fn applies_to(&self, db_name: &str, table_name: &str) -> bool {
+        self.db == db_name
+            && self
+                .table
+                .as_deref()
+                .map_or(true, |table| table == table_name)
+    }
+
+    fn interval_nanos(&self) -> i64 {
+        self.interval_secs as i64 * NANOS_PER_SECOND
+    }
+
+    fn target_table(&self, table_name: &str) -> String {
+        let interval = match self.interval_secs {
+            s if s % 3600 == 0 => format!("{}h", s / 3600),
+            s if s % 60 == 0 => format!("{}m", s / 60),
+            s => format!("{s}s"),
+        };
+        format!("{table_name}_{interval}")
+    }
+}
+
+#[derive(Debug, Clone, PartialEq, Eq, Hash)]
+struct SeriesKey {
+    rule: usize,
+    table: String,
+    tags: Vec<(String, String)>,
+}
+
+#[derive(Debug, Default)]
+struct SeriesState {
+    /// The end of the latest window that has been emitted; lines before this are late
+    watermark: i64,
+    /// When a window was last emitted for the series
+    emitted_at: i64,
+    /// Open windows, keyed by their start time
+    windows: BTreeMap<i64, Window>,
+}
+
+#[derive(Debug)]
+struct Window {
+    /// When the first line for the window was accepted
+    opened_at: Time,
+    fields: BTreeMap<String, FieldAggregates>,
+}
+
+#[derive(Debug, Clone, Copy)]
+struct FieldAggregates {
+    count: u64,
+    sum: f64,
+    min: f64,
+    max: f64,
+}
+
+impl FieldAggregates {
+    fn new(value: f64) -> Self {
+        Self {
+            count: 1,
+            sum: value,
+            min: value,
+            max: value,
+        }
+    }
+
+    fn add(&mut self, value: f64) {
+        self.count += 1;
+        self.sum += value;
+        self.min = self.min.min(value);
+        self.max = self.max.max(value);
+    }
+}
+
+/// Computes the configured rollups over accepted writes
+#[derive(Debug)]
+pub(crate) struct RollupHandler {
+    rules: Vec<RollupRule>,
+    series: Mutex<HashMap<SeriesKey, SeriesState>>,
+    late_lines: U64Counter,
+}
+
+impl RollupHandler {
+    pub(crate) fn new(rules: Vec<RollupRule>, registry: &metric::Registry) -> Result<Self> {
+        validate_rules(&rules)?;
+        let late_lines = registry
+            .register_metric::<U64Counter>(
+                "influxdb3_rollup_late_lines",
+                "number of lines not rolled up because their window had already been emitted",
+            )
+            .recorder([]);
+
+        Ok(Self {
+            rules,
+            series: Mutex::new(HashMap::new()),
+            late_lines,
+        })
+    }
+
+    /// Returns true if any rollup rules are configured for the database
+    pub(crate) fn has_rules_for(&self, db_name: &str) -> bool {
+        self.rules.iter().any(|rule| rule.db == db_name)
+    }
+
+    /// Add the lines of an op accepted into the buffer to the rollups for its database. The
+    /// lines are as they were buffered, after the ingest transforms of the database were applied.
+    pub(crate) fn observe(&self, op: &LpWriteOp, now: Time) {
+        let mut series = self.series.lock();
+        for line in parse_lines(&op.lp).filter_map(Result::ok) {
+            let table_name = line.series.measurement.as_str();
+            let time = line
+                .timestamp
+                .map(|ts| op.precision.timestamp_to_nanos(ts))
+                .unwrap_or(op.default_time);
+
+            let mut tags: Vec<(String, String)> = line
+                .series
+                .tag_set
+                .iter()
+                .flatten()
+                .map(|(key, value)| (key.to_string(), value.to_string()))
+                .collect();
+            tags.sort();
+
+            let values: Vec<(&str, f64)> = line
+                .field_set
+                .iter()
+                .filter_map(|(key, value)| {
+                    let value = match value {
+                        FieldValue::F64(v) => *v,
+                        FieldValue::I64(v) => *v as f64,
+                        FieldValue::U64(v) => *v as f64,
+                        FieldValue::Boolean(_) | FieldValue::String(_) => return None,
+                    };
+                    Some((key.as_str(), value))
+                })
+                .collect();
+            if values.is_empty() {
+                continue;
+            }
+
+            for (rule_index, rule) in self.rules.iter().enumerate() {
+                if !rule.applies_to(&op.db_name, table_name) {
+                    continue;
+                }
+
+                let state = series
+                    .entry(SeriesKey {
+                        rule: rule_index,
+                        table: table_name.to_string(),
+                        tags: tags.clone(),
+                    })
+                    .or_default();
+                if time < state.watermark {
+                    self.late_lines.inc(1);
+                    continue;
+                }
+
+                let start = time - time.rem_euclid(rule.interval_nanos());
+                let window = state.windows.entry(start).or_insert_with(|| Window {
+                    opened_at: now,
+                    fields: BTreeMap::new(),
+                });
+                for (field, value) in &values {
+                    match window.fields.get_mut(*field) {
+                        Some(aggregates) => aggregates.add(*value),
+                        None => {
+                            window
+                                .fields
+                                .insert(field.to_string(), FieldAggregates::new(*value));
+                        }
+                    }
+                }
+            }
+        }
+    }
+
+    /// Remove the windows that are ready to be emitted as of `now`, returning the line protocol
+    /// for them, grouped by the database it is to be written to.
+    pub(crate) fn flush(&self, now: Time) -> BTreeMap<String, String> {
+        let now = now.timestamp_nanos();
+        let mut out: BTreeMap<String, String> = BTreeMap::new();
+
+        let mut series = self.series.lock();
+        for (key, state) in series.iter_mut() {
+            let rule = &self.rules[key.rule];
+            let interval = rule.interval_nanos();
+            let grace = rule.grace_secs as i64 * NANOS_PER_SECOND;
+
+            let ready: Vec<i64> = state
+                .windows
+                .iter()
+                .filter(|(start, window)| {
+                    // historical data is held for the grace period after it arrives, rather than
+                    // being emitted straight away
+                    let end = (*start + interval).max(window.opened_at.timestamp_nanos());
+                    now >= end + grace
+                })
+                .map(|(start, _)| *start)
+                .collect();
+
+            for start in ready {
+                let window = state.windows.remove(&start).expect("window exists");
+                state.watermark = state.watermark.max(start + interval);
+                state.emitted_at = now;
+
+                let target_db = rule.target_db.as_deref().unwrap_or(&rule.db);
+                let lp = out.entry(target_db.to_string()).or_default();
+                write_rollup_line(lp, rule, key, start, &window);
+            }
+        }
+        series.retain(|_, state| {
+            !state.windows.is_empty() || now - state.emitted_at < IDLE_SERIES_RETENTION_NANOS
+        });
+        // windows that had no fields left to write have no lines
+        out.retain(|_, lp| !lp.is_empty());
+
+        out
+    }
+}
+
+fn write_rollup_line(
+    lp: &mut String,
+    rule: &RollupRule,
+    key: &SeriesKey,
+    start: i64,
+    window: &Window,
+) {
+    let tag_keys: HashSet<&str> = key.tags.iter().map(|(key, _)| key.as_str()).collect();
+
+    let mut fields = String::new();
+    for (field, aggregates) in &window.fields {
+        for aggregate in &rule.aggregates {
+            let name = format!("{field}_{}", aggregate.name());
+            if tag_keys.contains(name.as_str()) {
+                warn!(
+                    table = %key.table,
+                    field = %name,
+                    "rollup field has the same name as a tag of its series, leaving it out"
+                );
+                continue;
+            }
+
+            let value = match aggregate {
+                Aggregate::Mean => float_value(aggregates.sum / aggregates.count as f64),
+                Aggregate::Min => float_value(aggregates.min),
+                Aggregate::Max => float_value(aggregates.max),
+                Aggregate::Sum => float_value(aggregates.sum),
+                Aggregate::Count => Some(format!("{}i", aggregates.count)),
+            };
+            // line protocol can't represent infinite floats, e.g., from a sum overflowing
+            let Some(value) = value else {
+                continue;
+            };
+
+            if !fields.is_empty() {
+                fields.push(',');
+            }
+            escape(&mut fields, &name, &[',', '=', ' ']);
+            write!(fields, "={value}").unwrap();
+        }
+    }
+    // a line needs at least one field
+    if fields.is_empty() {
+        return;
+    }
+
+    escape(lp, &rule.target_table(&key.table), &[',', ' ']);
+    for (tag_key, tag_value) in &key.tags {
+        lp.push(',');
+        escape(lp, tag_key, &[',', '=', ' ']);
+        lp.push('=');
+        escape(lp, tag_value, &[',', '=', ' ']);
+    }
+    writeln!(lp, " {fields} {start}").unwrap();
+}
+
+fn float_value(value: f64) -> Option<String> {
+    value.is_finite().then(|| value.to_string())
+}
+
+fn escape(lp: &mut String, s: &str, special: &[char]) {
+    for c in s.chars() {
+        if special.contains(&c) || c == '\\' {
+            lp.push('\\');
+        }
+        lp.push(c);
+    }
+}
+
+/// The background task that writes the rollups, see [`run_rollup_flush`]
+#[derive(Debug)]
+pub(crate) struct RollupFlushTask {
+    shutdown: CancellationToken,
+    handle: JoinHandle<()>,
+}
+
+impl RollupFlushTask {
+    pub(crate) fn spawn<W: Bufferer, T: TimeProvider>(
+        rollups: Arc<RollupHandler>,
+        write_buffer: Arc<W>,
+        time_provider: Arc<T>,
+    ) -> Self {
+        let shutdown = CancellationToken::new();
+        let handle = tokio::spawn(run_rollup_flush(
+            rollups,
+            write_buffer,
+            time_provider,
+            shutdown.clone(),
+        ));
+        Self { shutdown, handle }
+    }
+
+    /// Stop writing rollups, waiting for any that are being written to be buffered
+    pub(crate) async fn stop(self) {
+        self.shutdown.cancel();
+        if let Err(e) = self.handle.await {
+            error!(error = %e, "rollup flush task failed");
+        }
+    }
+}
+
+/// Periodically emits the windows that are ready to be written to their rollup tables, until
+/// `shutdown` is cancelled
+async fn run_rollup_flush<W: Bufferer, T: TimeProvider>(
+    rollups: Arc<RollupHandler>,
+    write_buffer: Arc<W>,
+    time_provider: Arc<T>,
+    shutdown: CancellationToken,
+) {
+    let mut interval = tokio::time::interval(FLUSH_INTERVAL);
+    interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+    loop {
+        tokio::select! {
+            _ = interval.tick() => {}
+            _ = shutdown.cancelled() => return,
+        }
+
+        let now = time_provider.now();
+        for (db_name, lp) in rollups.flush(now) {
+            let database = match NamespaceName::new(db_name) {
+                Ok(database) => database,
+                Err(e) => {
+                    error!(error = %e, "invalid rollup target database");
+                    continue;
+                }
+            };
+
+            debug!(%database, "writing rollups");
+            match write_buffer
+                .write_lp(database.clone(), &lp, now, true, Precision::Nanosecond)
+                .await
+            {
+                Ok(result) if !result.invalid_lines.is_empty() => {
+                    warn!(
+                        %database,
+                        invalid_lines = result.invalid_lines.len(),
+                        error = %result.invalid_lines[0].error_message,
+                        "some rollups were rejected"
+                    );
+                }
+                Ok(_) => {}
+                Err(e) => error!(%database, error = %e, "failed to write rollups"),
+            }
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn rule() -> RollupRule {
+        RollupRule {
+            db: "foo".to_string(),
+            table: Some("cpu".to_string()),
+            interval_secs: 60,
+            aggregates: vec![Aggregate::Mean, Aggregate::Max, Aggregate::Count],
+            target_db: Some("foo_rollups".to_string()),
+            grace_secs: 5,
+        }
+    }
+
+    fn op(lp: &str) -> LpWriteOp {
+        LpWriteOp {
+            db_name: "foo".to_string(),
+            lp: lp.to_string(),
+            default_time: 0,
+            precision: Precision::Second,
+        }
+    }
+
+    #[test]
+    fn rolls_up_windows_per_series() {
+        let rollups = RollupHandler::new(vec![rule()], &metric::Registry::new()).unwrap();
+        let t = |secs| Time::from_timestamp(secs, 0).unwrap();
+
+        assert!(rollups.has_rules_for("foo"));
+        assert!(!rollups.has_rules_for("bar"));
+
+        rollups.observe(
+            &op("cpu,host=a usage=1,idle=3i,ok=true 10\n\
+                 cpu,host=a usage=3 50\n\
+                 cpu,host=b usage=5 20\n\
+                 mem,host=a used=1 10\n\
+                 cpu,host=a usage=7 70"),
+            t(70),
+        );
+
+        // windows are emitted once they have ended, and been open, for the grace period
+        assert!(rollups.flush(t(74)).is_empty());
+
+        let lp = rollups.flush(t(75));
+        assert_eq!(
+            lp.get("foo_rollups").map(String::as_str).map(|lp| {
+                let mut lines: Vec<_> = lp.lines().collect();
+                lines.sort();
+                lines
+            }),
+            Some(vec![
+                "cpu_1m,host=a idle_mean=3,idle_max=3,idle_count=1i,usage_mean=2,usage_max=3,usage_count=2i 0",
+                "cpu_1m,host=b usage_mean=5,usage_max=5,usage_count=1i 0",
+            ])
+        );
+
+        // lines for a window that has already been emitted are late
+        rollups.observe(&op("cpu,host=a usage=100 30"), t(80));
+
+        assert!(rollups.flush(t(124)).is_empty());
+        let lp = rollups.flush(t(125));
+        assert_eq!(
+            lp.get("foo_rollups").map(String::as_str),
+            Some("cpu_1m,host=a usage_mean=7,usage_max=7,usage_count=1i 60000000000\n")
+        );
+        assert_eq!(rollups.late_lines.fetch(), 1);
+    }
+
+    #[test]
+    fn leaves_out_fields_named_like_tags_of_the_series() {
+        let rollups = RollupHandler::new(vec![rule()], &metric::Registry::new()).unwrap();
+        let t = |secs| Time::from_timestamp(secs, 0).unwrap();
+
+        rollups.observe(
+            &op("cpu,usage_max=a usage=1,idle=2 10\n\
+                 cpu,usage_mean=a,usage_max=b,usage_count=c usage=1 10"),
+            t(10),
+        );
+
+        let lp = rollups.flush(t(65));
+        assert_eq!(
+            lp.get("foo_rollups").map(String::as_str),
+            Some("cpu_1m,usage_max=a idle_mean=2,idle_max=2,idle_count=1i,usage_mean=1,usage_count=1i 0\n")
+        );
+    }
+
+    #[test]
+    fn rejects_rules_without_an_interval() {
+        let mut rule = rule();
+        rule.interval_secs = 0;
+        assert!(matches!(
+            RollupHandler::new(vec![rule], &metric::Registry::new()),
+            Err(Error::ZeroInterval { db }) if db == "foo"
+        ));
+    }
+
+    #[test]
+    fn target_table_names() {
+        let mut rule = rule();
+        assert_eq!(rule.target_table("cpu"), "cpu_1m");
+        rule.interval_secs = 7200;
+        assert_eq!(rule.target_table("cpu"), "cpu_2h");
+        rule.interval_secs = 90;
+        assert_eq!(rule.target_table("cpu"), "cpu_90s");
+    }
+}
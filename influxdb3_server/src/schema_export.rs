@@ -0,0 +1,447 @@
+//! Export of table schemas so that consumers downstream of the server can decode the data for a
+//! table without access to the catalog.
+//!
+//! Schemas are rendered for each table as either a [JSON Schema] or an [Avro] record schema. They
+//! are served from the `/api/v3/schema` API and, optionally, published to an external schema
+//! registry that implements the Confluent Schema Registry REST API. Each table is registered
+//! under the `<db>.<table>-value` subject, and a new version is registered every time columns are
+//! added to the table.
+//!
+//! [JSON Schema]: https://json-schema.org/
+//! [Avro]: https://avro.apache.org/docs/current/specification/
+
+use std::collections::{HashMap, HashSet};
+use std::sync::Arc;
+use std::time::Duration;
+
+use influxdb3_write::catalog::{Catalog, TableDefinition};
+use influxdb3_write::SequenceNumber;
+use observability_deps::tracing::{debug, info, warn};
+use reqwest::Url;
+use schema::{InfluxColumnType, InfluxFieldType};
+use serde::{Deserialize, Serialize};
+use serde_json::{json, Value};
+
+const SCHEMA_REGISTRY_CONTENT_TYPE: &str = "application/vnd.schemaregistry.v1+json";
+
+/// How long connecting to the schema registry may take
+const SCHEMA_REGISTRY_CONNECT_TIMEOUT: Duration = Duration::from_secs(5);
+
+/// How long a request to the schema registry may take, so that an unresponsive registry doesn't
+/// stop schemas from being published once it recovers
+const SCHEMA_REGISTRY_REQUEST_TIMEOUT: Duration = Duration::from_secs(30);
+
+/// The format that table schemas are rendered in
+#[derive(Debug, Clone, Copy, Default, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "lowercase")]
+pub enum SchemaFormat {
+    #[default]
+    Json,
+    Avro,
+}
+
+impl SchemaFormat {
+    /// The `schemaType` used for the format by the schema registry
+    fn registry_schema_type(&self) -> &'static str {
+        match self {
+            Self::Json => "JSON",
+            Self::Avro => "AVRO",
+        }
+    }
+}
+
+/// The schema of a table in a database, as returned from the `/api/v3/schema` API
+#[derive(Debug, Serialize, PartialEq)]
+pub(crate) struct TableSchemaExport {
+    pub(crate) db: String,
+    pub(crate) table: String,
+    pub(crate) schema: Value,
+}
+
+/// All of the table schemas in the catalog, as of the catalog `sequence`
+#[derive(Debug, Serialize)]
+pub(crate) struct SchemaExport {
+    pub(crate) sequence: SequenceNumber,
+    pub(crate) tables: Vec<TableSchemaExport>,
+}
+
+/// Render the schema of every table in the catalog, optionally only for the database `db_name`,
+/// ordered by database and table name.
+pub(crate) fn export_schemas(
+    catalog: &Catalog,
+    db_name: Option<&str>,
+    format: SchemaFormat,
+) -> SchemaExport {
+    // read the sequence first, so consumers that poll on it never miss an update
+    let sequence = catalog.sequence_number();
+
+    let mut db_names = catalog.list_databases();
+    db_names.sort();
+
+    let mut tables = vec![];
+    for db_name in db_names
+        .iter()
+        .filter(|name| db_name.map_or(true, |db_name| db_name == name.as_str()))
+    {
+        let Some(db) = catalog.db_schema(db_name) else {
+            continue;
+        };
+        for table_name in db.table_names() {
+            let Some(table) = db.get_table(&table_name) else {
+                continue;
+            };
+            tables.push(TableSchemaExport {
+                db: db_name.clone(),
+                table: table_name,
+                schema: table_schema(db_name, table, format),
+            });
+        }
+    }
+
+    SchemaExport { sequence, tables }
+}
+
+/// Render the schema of a single table. Each row of the table is an object with a property per
+/// column. All columns other than `time` are optional.
+pub(crate) fn table_schema(db_name: &str, table: &TableDefinition, format: SchemaFormat) -> Value {
+    match format {
+        SchemaFormat::Json => {
+            let mut properties = serde_json::Map::new();
+            let mut required = vec![];
+            for (column_type, field) in table.schema.iter() {
+                let (json_type, description) = match column_type {
+                    InfluxColumnType::Tag => ("string", "tag"),
+                    InfluxColumnType::Timestamp => {
+                        required.push(field.name().clone());
+                        ("integer", "timestamp in nanoseconds since the epoch")
+                    }
+                    InfluxColumnType::Field(InfluxFieldType::Float) => ("number", "field"),
+                    InfluxColumnType::Field(InfluxFieldType::Integer)
+                    | InfluxColumnType::Field(InfluxFieldType::UInteger) => ("integer", "field"),
+                    InfluxColumnType::Field(InfluxFieldType::String) => ("string", "field"),
+                    InfluxColumnType::Field(InfluxFieldType::Boolean) => ("boolean", "field"),
+                };
+                properties.insert(
+                    field.name().clone(),
+                    json!({ "type": json_type, "description": description }),
+                );
+            }
+
+            json!({
+                "$schema": "http://json-schema.org/draft-07/schema#",
+                "title": format!("{db_name}.{}", table.name),
+                "type": "object",
+                "properties": properties,
+                "required": required,
+            })
+        }
+        SchemaFormat::Avro => {
+            let mut field_names = HashSet::new();
+            let fields: Vec<Value> = table
+                .schema
+                .iter()
+                .map(|(column_type, field)| {
+                    let avro_type = match column_type {
+                        InfluxColumnType::Timestamp => {
+                            json!({ "type": "long", "logicalType": "timestamp-nanos" })
+                        }
+                        InfluxColumnType::Tag
+                        | InfluxColumnType::Field(InfluxFieldType::String) => {
+                            json!(["null", "string"])
+                        }
+                        InfluxColumnType::Field(InfluxFieldType::Float) => {
+                            json!(["null", "double"])
+                        }
+                        InfluxColumnType::Field(InfluxFieldType::Integer)
+                        | InfluxColumnType::Field(InfluxFieldType::UInteger) => {
+                            json!(["null", "long"])
+                        }
+                        InfluxColumnType::Field(InfluxFieldType::Boolean) => {
+                            json!(["null", "boolean"])
+                        }
+                    };
+                    let mut avro_field = json!({
+                        "name": unique_avro_name(field.name(), &mut field_names),
+                        "type": avro_type,
+                    });
+                    if !matches!(column_type, InfluxColumnType::Timestamp) {
+                        avro_field["default"] = Value::Null;
+                    }
+                    if avro_field["name"] != field.name().as_str() {
+                        // record the original column name, as it isn't a valid Avro name
+                        avro_field["doc"] = json!(format!("column {}", field.name()));
+                    }
+                    avro_field
+                })
+                .collect();
+
+            json!({
+                "type": "record",
+                "name": avro_name(&table.name),
+                "namespace": avro_name(db_name),
+                "fields": fields,
+            })
+        }
+    }
+}
+
+/// Avro names must start with a letter or underscore and only contain letters, digits and
+/// underscores; any other characters are replaced with an underscore.
+fn avro_name(name: &str) -> String {
+    let mut avro_name: String = name
+        .chars()
+        .map(|c| {
+            if c.is_ascii_alphanumeric() || c == '_' {
+                c
+            } else {
+                '_'
+            }
+        })
+        .collect();
+    if !avro_name.starts_with(|c: char| c.is_ascii_alphabetic() || c == '_') {
+        avro_name.insert(0, '_');
+    }
+    avro_name
+}
+
+/// The [`avro_name`] of a field that isn't in `names` already, which it is added to. Column names
+/// that are distinct but map to the same Avro name, e.g., `a-b` and `a_b`, are suffixed with
+/// `_2`, `_3` and so on, in the order of the columns.
+fn unique_avro_name(name: &str, names: &mut HashSet<String>) -> String {
+    let base = avro_name(name);
+    let mut unique = base.clone();
+    let mut suffix = 1;
+    while !names.insert(unique.clone()) {
+        suffix += 1;
+        unique = format!("{base}_{suffix}");
+    }
+    unique
+}
+
+/// Configuration for publishing table schemas to an external schema registry
+#[derive(Debug, Clone)]
+pub struct SchemaRegistryConfig {
+    /// The base URL of the schema registry
+    pub url: Url,
+    pub format: SchemaFormat,
+    /// How often the catalog is checked for schema changes
+    pub poll_interval: Duration,
+}
+
+/// Publishes the schemas of all tables in the catalog to an external schema registry, registering
+/// a new version of a table's schema whenever it changes.
+#[derive(Debug)]
+pub struct SchemaRegistryExporter {
+    config: SchemaRegistryConfig,
+    catalog: Arc<Catalog>,
+    client: reqwest::Client,
+    /// The catalog sequence that all schemas have been published for
+    published_sequence: Option<SequenceNumber>,
+    /// The last schema published for each database and table
+    published: HashMap<(String, String), Value>,
+}
+
+impl SchemaRegistryExporter {
+    pub fn new(
+        config: SchemaRegistryConfig,
+        catalog: Arc<Catalog>,
+    ) -> Result<Self, reqwest::Error> {
+        let client = reqwest::Client::builder()
+            .connect_timeout(SCHEMA_REGISTRY_CONNECT_TIMEOUT)
+            .timeout(SCHEMA_REGISTRY_REQUEST_TIMEOUT)
+            .build()?;
+        Ok(Self {
+            config,
+            catalog,
+            client,
+            published_sequence: None,
+            published: HashMap::new(),
+        })
+    }
+
+    /// Run the exporter in a background task
+    pub fn spawn(mut self) -> tokio::task::JoinHandle<()> {
+        info!(url = %self.config.url, format = ?self.config.format, "exporting schemas to registry");
+        tokio::spawn(async move {
+            let mut interval = tokio::time::interval(self.config.poll_interval);
+            interval.set_missed_tick_behavior(tokio::time::MissedTickBehavior::Delay);
+            loop {
+                interval.tick().await;
+                self.publish_changes().await;
+            }
+        })
+    }
+
+    /// Publish the schema of every table that has changed since the last call. Schemas that fail
+    /// to publish are retried on the next call.
+    pub async fn publish_changes(&mut self) {
+        let sequence = self.catalog.sequence_number();
+        if self.published_sequence == Some(sequence) {
+            return;
+        }
+
+        let export = export_schemas(&self.catalog, None, self.config.format);
+        let mut all_published = true;
+        for TableSchemaExport { db, table, schema } in export.tables {
+            let key = (db, table);
+            if self.published.get(&key) == Some(&schema) {
+                continue;
+            }
+
+            match self.register(&key.0, &key.1, &schema).await {
+                Ok(()) => {
+                    debug!(db = %key.0, table = %key.1, "published table schema");
+                    self.published.insert(key, schema);
+                }
+                Err(e) => {
+                    warn!(db = %key.0, table = %key.1, error = %e, "failed to publish table schema");
+                    all_published = false;
+                }
+            }
+        }
+
+        if all_published {
+            self.published_sequence = Some(export.sequence);
+        }
+    }
+
+    async fn register(&self, db: &str, table: &str, schema: &Value) -> Result<(), reqwest::Error> {
+        let mut url = self.config.url.clone();
+        url.path_segments_mut()
+            .expect("schema registry URL can be a base")
+            .pop_if_empty()
+            .extend(["subjects", &format!("{db}.{table}-value"), "versions"]);
+
+        let body = json!({
+            "schemaType": self.config.format.registry_schema_type(),
+            "schema": schema.to_string(),
+        });
+
+        self.client
+            .post(url)
+            .header(reqwest::header::CONTENT_TYPE, SCHEMA_REGISTRY_CONTENT_TYPE)
+            .body(body.to_string())
+            .send()
+            .await?
+            .error_for_status()?;
+
+        Ok(())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use data_types::ColumnType;
+    use influxdb3_write::catalog::InnerCatalog;
+
+    #[test]
+    fn avro_names() {
+        assert_eq!(avro_name("cpu"), "cpu");
+        assert_eq!(avro_name("cpu-usage.total"), "cpu_usage_total");
+        assert_eq!(avro_name("5m"), "_5m");
+    }
+
+    fn catalog(columns: &[(&str, ColumnType)]) -> Arc<Catalog> {
+        let columns: serde_json::Map<String, Value> = columns
+            .iter()
+            .map(|(name, column_type)| (name.to_string(), json!(*column_type as i16)))
+            .collect();
+        let inner: InnerCatalog = serde_json::from_value(json!({
+            "databases": {
+                "foo": {
+                    "name": "foo",
+                    "tables": { "cpu": { "name": "cpu", "columns": columns } },
+                },
+            },
+            "sequence": columns.len(),
+        }))
+        .unwrap();
+        Arc::new(Catalog::from_inner(inner))
+    }
+
+    #[test]
+    fn avro_field_names_are_unique() {
+        let catalog = catalog(&[
+            ("a-b", ColumnType::F64),
+            ("a.b", ColumnType::F64),
+            ("a_b", ColumnType::F64),
+            ("time", ColumnType::Time),
+        ]);
+        let db = catalog.db_schema("foo").unwrap();
+
+        let schema = table_schema("foo", db.get_table("cpu").unwrap(), SchemaFormat::Avro);
+
+        let names: Vec<_> = schema["fields"]
+            .as_array()
+            .unwrap()
+            .iter()
+            .map(|field| field["name"].as_str().unwrap())
+            .collect();
+        assert_eq!(names, vec!["a_b", "a_b_2", "a_b_3", "time"]);
+    }
+
+    #[tokio::test]
+    async fn publishes_changed_schemas() {
+        let mut registry = mockito::Server::new_async().await;
+        let config = SchemaRegistryConfig {
+            url: Url::parse(&registry.url()).unwrap(),
+            format: SchemaFormat::Json,
+            poll_interval: Duration::from_secs(1),
+        };
+        let mut exporter = SchemaRegistryExporter::new(
+            config,
+            catalog(&[("usage", ColumnType::F64), ("time", ColumnType::Time)]),
+        )
+        .unwrap();
+
+        // a schema that fails to publish is retried on the next call
+        let failed = registry
+            .mock("POST", "/subjects/foo.cpu-value/versions")
+            .with_status(500)
+            .expect(1)
+            .create_async()
+            .await;
+        exporter.publish_changes().await;
+        failed.assert_async().await;
+        assert_eq!(exporter.published_sequence, None);
+        failed.remove_async().await;
+
+        let published = registry
+            .mock("POST", "/subjects/foo.cpu-value/versions")
+            .match_header("content-type", SCHEMA_REGISTRY_CONTENT_TYPE)
+            .match_body(mockito::Matcher::PartialJson(
+                json!({ "schemaType": "JSON" }),
+            ))
+            .with_status(200)
+            .with_body(r#"{"id":1}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        exporter.publish_changes().await;
+        // nothing has changed, so nothing is published
+        exporter.publish_changes().await;
+        published.assert_async().await;
+        assert_eq!(exporter.published_sequence, Some(SequenceNumber::new(2)));
+        published.remove_async().await;
+
+        // adding a column publishes a new version
+        let republished = registry
+            .mock("POST", "/subjects/foo.cpu-value/versions")
+            .match_body(mockito::Matcher::Regex("host".to_string()))
+            .with_status(200)
+            .with_body(r#"{"id":2}"#)
+            .expect(1)
+            .create_async()
+            .await;
+        exporter.catalog = catalog(&[
+            ("host", ColumnType::Tag),
+            ("usage", ColumnType::F64),
+            ("time", ColumnType::Time),
+        ]);
+        exporter.publish_changes().await;
+        exporter.publish_changes().await;
+        republished.assert_async().await;
+        assert_eq!(exporter.published_sequence, Some(SequenceNumber::new(3)));
+    }
+}
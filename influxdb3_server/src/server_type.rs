@@ -0,0 +1,191 @@
+//! Types of server that are run by the `influxdb3` binary alongside its built-in server, e.g.
+//! ingest proxies or exporters, so that they can be written in other crates and share the common
+//! state, listen address and shutdown handling of the built-in server.
+//!
+//! A [`ServerType`] is registered with [`ServerTypes`] under the name it is run as, with
+//! `influxdb3 run <name> -- <args>`, and is given the arguments after `--` to parse itself.
+
+use std::collections::BTreeMap;
+use std::fmt::Debug;
+use std::sync::Arc;
+
+use async_trait::async_trait;
+use thiserror::Error;
+use tokio_util::sync::CancellationToken;
+
+use crate::CommonServerState;
+
+pub type BoxError = Box<dyn std::error::Error + Send + Sync>;
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("a server type named {0:?} is already registered")]
+    AlreadyRegistered(String),
+
+    #[error("unknown server type {name:?}, the registered server types are: {registered}")]
+    UnknownServerType { name: String, registered: String },
+
+    #[error("server type {name:?} failed: {source}")]
+    Run { name: String, source: BoxError },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+#[async_trait]
+pub trait ServerType: Debug + Send + Sync + 'static {
+    /// A one line description of the server, shown when the server types are listed
+    fn description(&self) -> &str;
+
+    /// Runs the server until `shutdown` is cancelled. The server should listen on the address of
+    /// the common state, and report its metrics to its registry.
+    async fn run(
+        &self,
+        args: Vec<String>,
+        common_state: CommonServerState,
+        shutdown: CancellationToken,
+    ) -> Result<(), BoxError>;
+}
+
+/// The server types that can be run, by name
+#[derive(Debug, Default, Clone)]
+pub struct ServerTypes {
+    server_types: BTreeMap<String, Arc<dyn ServerType>>,
+}
+
+impl ServerTypes {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    pub fn register(
+        &mut self,
+        name: impl Into<String>,
+        server_type: Arc<dyn ServerType>,
+    ) -> Result<()> {
+        let name = name.into();
+        if self.server_types.contains_key(&name) {
+            return Err(Error::AlreadyRegistered(name));
+        }
+        self.server_types.insert(name, server_type);
+        Ok(())
+    }
+
+    pub fn get(&self, name: &str) -> Option<Arc<dyn ServerType>> {
+        self.server_types.get(name).cloned()
+    }
+
+    /// The registered server types, ordered by name
+    pub fn iter(&self) -> impl Iterator<Item = (&str, &Arc<dyn ServerType>)> {
+        self.server_types
+            .iter()
+            .map(|(name, server_type)| (name.as_str(), server_type))
+    }
+
+    /// Runs the named server type with the arguments until `shutdown` is cancelled
+    pub async fn run(
+        &self,
+        name: &str,
+        args: Vec<String>,
+        common_state: CommonServerState,
+        shutdown: CancellationToken,
+    ) -> Result<()> {
+        let server_type = self.get(name).ok_or_else(|| Error::UnknownServerType {
+            name: name.to_string(),
+            registered: self
+                .server_types
+                .keys()
+                .map(String::as_str)
+                .collect::<Vec<_>>()
+                .join(", "),
+        })?;
+
+        server_type
+            .run(args, common_state, shutdown)
+            .await
+            .map_err(|source| Error::Run {
+                name: name.to_string(),
+                source,
+            })
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use parking_lot::Mutex;
+    use std::net::SocketAddr;
+
+    #[derive(Debug, Default)]
+    struct Echo {
+        runs: Mutex<Vec<(Vec<String>, SocketAddr)>>,
+    }
+
+    #[async_trait]
+    impl ServerType for Echo {
+        fn description(&self) -> &str {
+            "records the arguments it was run with"
+        }
+
+        async fn run(
+            &self,
+            args: Vec<String>,
+            common_state: CommonServerState,
+            shutdown: CancellationToken,
+        ) -> Result<(), BoxError> {
+            if args.is_empty() {
+                return Err("no arguments".into());
+            }
+            self.runs.lock().push((args, common_state.http_addr()));
+            shutdown.cancelled().await;
+            Ok(())
+        }
+    }
+
+    #[tokio::test]
+    async fn runs_registered_server_types() {
+        let echo = Arc::new(Echo::default());
+        let mut server_types = ServerTypes::new();
+        server_types
+            .register("echo", Arc::clone(&echo) as _)
+            .unwrap();
+        assert!(matches!(
+            server_types.register("echo", Arc::clone(&echo) as _),
+            Err(Error::AlreadyRegistered(_))
+        ));
+
+        let addr: SocketAddr = "127.0.0.1:8181".parse().unwrap();
+        let common_state = CommonServerState::new(
+            Arc::new(metric::Registry::new()),
+            None,
+            trace_http::ctx::TraceHeaderParser::new(),
+            addr,
+        )
+        .unwrap();
+
+        let shutdown = CancellationToken::new();
+        shutdown.cancel();
+        server_types
+            .run(
+                "echo",
+                vec!["--flag".to_string()],
+                common_state.clone(),
+                shutdown.clone(),
+            )
+            .await
+            .unwrap();
+        assert_eq!(*echo.runs.lock(), vec![(vec!["--flag".to_string()], addr)]);
+
+        assert!(matches!(
+            server_types
+                .run("echo", vec![], common_state.clone(), shutdown.clone())
+                .await,
+            Err(Error::Run { .. })
+        ));
+        assert!(matches!(
+            server_types
+                .run("proxy", vec![], common_state, shutdown)
+                .await,
+            Err(Error::UnknownServerType { registered, .. }) if registered == "echo"
+        ));
+    }
+}
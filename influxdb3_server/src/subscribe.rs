@@ -0,0 +1,187 @@
+//! Streams the writes accepted into the write buffer for a database to subscribers over HTTP, so
+//! that external systems can follow live writes without reading the WAL.
+//!
+//! Each accepted write is sent once it has been buffered, either as line protocol with every
+//! timestamp in nanoseconds, or as newline delimited JSON with one object per line. Subscribers
+//! that fall too far behind miss writes rather than holding up the write path.
+
+use std::collections::BTreeMap;
+use std::convert::Infallible;
+use std::sync::Arc;
+
+use bytes::Bytes;
+use hyper::Body;
+use influxdb3_write::LpWriteOp;
+use influxdb_line_protocol::{parse_lines, FieldValue, ParsedLine};
+use observability_deps::tracing::warn;
+use serde::{Deserialize, Serialize};
+use tokio::sync::broadcast::{self, error::RecvError};
+use tokio_util::sync::CancellationToken;
+
+/// The format writes are sent to subscribers in
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq, Deserialize)]
+#[serde(rename_all = "snake_case")]
+pub(crate) enum SubscribeFormat {
+    #[default]
+    Lp,
+    Json,
+}
+
+/// A line of a write as it is sent to subscribers in the JSON format
+#[derive(Debug, Serialize)]
+struct JsonLine<'a> {
+    db: &'a str,
+    measurement: &'a str,
+    tags: BTreeMap<&'a str, &'a str>,
+    fields: BTreeMap<&'a str, serde_json::Value>,
+    time: i64,
+}
+
+/// Returns a streaming body of the writes to `db_name` that are received on `writes`, which ends
+/// when `closed` is cancelled or the write buffer goes away
+pub(crate) fn subscription_body(
+    writes: broadcast::Receiver<Arc<LpWriteOp>>,
+    db_name: String,
+    format: SubscribeFormat,
+    closed: CancellationToken,
+) -> Body {
+    let stream = futures::stream::unfold(writes, move |mut writes| {
+        let db_name = db_name.clone();
+        let closed = closed.clone();
+        async move {
+            loop {
+                let write = tokio::select! {
+                    _ = closed.cancelled() => return None,
+                    write = writes.recv() => write,
+                };
+                match write {
+                    Ok(op) if op.db_name == db_name => {
+                        let data = encode_write(&op, format);
+                        if !data.is_empty() {
+                            return Some((Ok::<_, Infallible>(Bytes::from(data)), writes));
+                        }
+                    }
+                    Ok(_) => continue,
+                    Err(RecvError::Lagged(skipped)) => {
+                        warn!(%db_name, skipped, "subscriber fell behind and missed writes");
+                    }
+                    Err(RecvError::Closed) => return None,
+                }
+            }
+        }
+    });
+
+    Body::wrap_stream(stream)
+}
+
+/// Encodes the lines of the write, each terminated by a newline
+fn encode_write(op: &LpWriteOp, format: SubscribeFormat) -> String {
+    let mut out = String::new();
+
+    for line in parse_lines(&op.lp).filter_map(|line| line.ok()) {
+        let time = line
+            .timestamp
+            .map(|ts| op.precision.timestamp_to_nanos(ts))
+            .unwrap_or(op.default_time);
+
+        match format {
+            SubscribeFormat::Lp => {
+                let mut line = line;
+                line.timestamp = Some(time);
+                out.push_str(&line.to_string());
+            }
+            SubscribeFormat::Json => {
+                let line = json_line(&op.db_name, &line, time);
+                out.push_str(&serde_json::to_string(&line).expect("line should serialize"));
+            }
+        }
+        out.push('\n');
+    }
+
+    out
+}
+
+fn json_line<'a>(db: &'a str, line: &'a ParsedLine<'_>, time: i64) -> JsonLine<'a> {
+    let tags = line
+        .series
+        .tag_set
+        .iter()
+        .flatten()
+        .map(|(key, value)| (key.as_str(), value.as_str()))
+        .collect();
+    let fields = line
+        .field_set
+        .iter()
+        .map(|(key, value)| {
+            let value = match value {
+                FieldValue::I64(v) => serde_json::Value::from(*v),
+                FieldValue::U64(v) => serde_json::Value::from(*v),
+                FieldValue::F64(v) => serde_json::Value::from(*v),
+                FieldValue::Boolean(v) => serde_json::Value::from(*v),
+                FieldValue::String(v) => serde_json::Value::from(v.as_str()),
+            };
+            (key.as_str(), value)
+        })
+        .collect();
+
+    JsonLine {
+        db,
+        measurement: line.series.measurement.as_str(),
+        tags,
+        fields,
+        time,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use influxdb3_write::Precision;
+
+    use super::*;
+
+    fn op() -> LpWriteOp {
+        LpWriteOp {
+            db_name: "foo".to_string(),
+            lp: "cpu,host=a usage=0.5,count=2i 1\nmem free=10u".to_string(),
+            default_time: 5_000_000_000,
+            precision: Precision::Second,
+        }
+    }
+
+    #[test]
+    fn encodes_lp_with_nanosecond_timestamps() {
+        assert_eq!(
+            encode_write(&op(), SubscribeFormat::Lp),
+            "cpu,host=a usage=0.5,count=2i 1000000000\nmem free=10u 5000000000\n"
+        );
+    }
+
+    #[test]
+    fn encodes_json_lines() {
+        let encoded = encode_write(&op(), SubscribeFormat::Json);
+        let lines: Vec<serde_json::Value> = encoded
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+
+        assert_eq!(
+            lines,
+            vec![
+                serde_json::json!({
+                    "db": "foo",
+                    "measurement": "cpu",
+                    "tags": {"host": "a"},
+                    "fields": {"usage": 0.5, "count": 2},
+                    "time": 1_000_000_000,
+                }),
+                serde_json::json!({
+                    "db": "foo",
+                    "measurement": "mem",
+                    "tags": {},
+                    "fields": {"free": 10},
+                    "time": 5_000_000_000i64,
+                }),
+            ]
+        );
+    }
+}
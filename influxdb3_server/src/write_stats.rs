@@ -0,0 +1,202 @@
+//! Rolling, in-memory statistics about the writes that the server has accepted.
+//!
+//! Writes are counted per database and table into buckets that each cover one minute of wall
+//! clock time. Buckets older than the configured retention are dropped as new ones are created.
+
+use std::collections::{BTreeMap, HashMap};
+
+use influxdb3_write::TableWriteSummary;
+use iox_time::Time;
+use parking_lot::Mutex;
+use serde::Serialize;
+
+const NANOS_PER_MINUTE: i64 = 60 * 1_000_000_000;
+
+/// Default number of hours that write statistics are kept for
+pub(crate) const DEFAULT_WRITE_STATS_RETENTION_HOURS: usize = 24;
+
+#[derive(Debug)]
+pub(crate) struct WriteStats {
+    retention_minutes: i64,
+    /// Buckets keyed by the minute they cover, as the number of minutes since the epoch
+    buckets: Mutex<BTreeMap<i64, HashMap<(String, String), BucketCounts>>>,
+}
+
+#[derive(Debug, Default, Clone, Copy)]
+struct BucketCounts {
+    lines: usize,
+    bytes: usize,
+    new_columns: usize,
+}
+
+/// A row of the write statistics returned from the API
+#[derive(Debug, Serialize, PartialEq, Eq)]
+pub(crate) struct WriteStatsRow {
+    pub(crate) minute: String,
+    pub(crate) db: String,
+    pub(crate) table: String,
+    pub(crate) lines: usize,
+    pub(crate) bytes: usize,
+    pub(crate) new_columns: usize,
+}
+
+impl WriteStats {
+    pub(crate) fn new(retention_hours: usize) -> Self {
+        Self {
+            retention_minutes: hours_to_minutes(retention_hours),
+            buckets: Mutex::new(BTreeMap::new()),
+        }
+    }
+
+    /// Add the table summaries of a write that was accepted at the given time
+    pub(crate) fn record<'a>(
+        &self,
+        time: Time,
+        db_name: &str,
+        table_summaries: impl IntoIterator<Item = (&'a String, &'a TableWriteSummary)>,
+    ) {
+        let minute = time.timestamp_nanos().div_euclid(NANOS_PER_MINUTE);
+
+        let mut buckets = self.buckets.lock();
+        let bucket = buckets.entry(minute).or_default();
+        for (table_name, summary) in table_summaries {
+            let counts = bucket
+                .entry((db_name.to_string(), table_name.to_string()))
+                .or_default();
+            counts.lines += summary.line_count;
+            counts.bytes += summary.bytes;
+            counts.new_columns += summary.new_column_count;
+        }
+
+        // drop any buckets that have aged out
+        let oldest = minute.saturating_sub(self.retention_minutes);
+        *buckets = buckets.split_off(&oldest);
+    }
+
+    /// Returns the statistics for the last `hours`, up to the retention, ending at `now`. The
+    /// rows are ordered by minute, then database and table name.
+    pub(crate) fn rows(
+        &self,
+        now: Time,
+        hours: usize,
+        db_name: Option<&str>,
+    ) -> Vec<WriteStatsRow> {
+        let now_minute = now.timestamp_nanos().div_euclid(NANOS_PER_MINUTE);
+        let minutes = hours_to_minutes(hours).min(self.retention_minutes);
+        let start = now_minute.saturating_sub(minutes);
+
+        let buckets = self.buckets.lock();
+        let mut rows = vec![];
+        for (minute, bucket) in buckets.range(start..=now_minute) {
+            let minute_time = Time::from_timestamp_nanos(minute * NANOS_PER_MINUTE).to_rfc3339();
+            let mut bucket_rows: Vec<_> = bucket
+                .iter()
+                .filter(|((db, _), _)| db_name.map_or(true, |name| name == db.as_str()))
+                .map(|((db, table), counts)| WriteStatsRow {
+                    minute: minute_time.clone(),
+                    db: db.clone(),
+                    table: table.clone(),
+                    lines: counts.lines,
+                    bytes: counts.bytes,
+                    new_columns: counts.new_columns,
+                })
+                .collect();
+            bucket_rows.sort_by(|a, b| (&a.db, &a.table).cmp(&(&b.db, &b.table)));
+            rows.extend(bucket_rows);
+        }
+
+        rows
+    }
+}
+
+/// Converts a number of hours to minutes, saturating for hours given by a request or
+/// configuration that don't fit
+fn hours_to_minutes(hours: usize) -> i64 {
+    i64::try_from(hours).unwrap_or(i64::MAX).saturating_mul(60)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn summary(line_count: usize, bytes: usize, new_column_count: usize) -> TableWriteSummary {
+        TableWriteSummary {
+            line_count,
+            bytes,
+            new_column_count,
+        }
+    }
+
+    #[test]
+    fn buckets_by_minute_and_expires() {
+        let stats = WriteStats::new(1);
+        let cpu = "cpu".to_string();
+        let mem = "mem".to_string();
+
+        let t0 = Time::from_timestamp(0, 0).unwrap();
+        let t1 = Time::from_timestamp(30, 0).unwrap();
+        let t2 = Time::from_timestamp(90, 0).unwrap();
+
+        stats.record(t0, "foo", [(&cpu, &summary(2, 40, 3))]);
+        stats.record(
+            t1,
+            "foo",
+            [(&cpu, &summary(1, 20, 0)), (&mem, &summary(1, 10, 1))],
+        );
+        stats.record(t2, "bar", [(&cpu, &summary(5, 100, 0))]);
+
+        let rows = stats.rows(t2, 1, None);
+        assert_eq!(
+            rows,
+            vec![
+                WriteStatsRow {
+                    minute: "1970-01-01T00:00:00+00:00".to_string(),
+                    db: "foo".to_string(),
+                    table: "cpu".to_string(),
+                    lines: 3,
+                    bytes: 60,
+                    new_columns: 3,
+                },
+                WriteStatsRow {
+                    minute: "1970-01-01T00:00:00+00:00".to_string(),
+                    db: "foo".to_string(),
+                    table: "mem".to_string(),
+                    lines: 1,
+                    bytes: 10,
+                    new_columns: 1,
+                },
+                WriteStatsRow {
+                    minute: "1970-01-01T00:01:00+00:00".to_string(),
+                    db: "bar".to_string(),
+                    table: "cpu".to_string(),
+                    lines: 5,
+                    bytes: 100,
+                    new_columns: 0,
+                },
+            ]
+        );
+
+        assert_eq!(stats.rows(t2, 1, Some("bar")).len(), 1);
+
+        // a write more than an hour later drops the old buckets
+        let t3 = Time::from_timestamp(2 * 60 * 60, 0).unwrap();
+        stats.record(t3, "foo", [(&cpu, &summary(1, 20, 0))]);
+        let rows = stats.rows(t3, 24, None);
+        assert_eq!(rows.len(), 1);
+        assert_eq!(rows[0].minute, "1970-01-01T02:00:00+00:00");
+    }
+
+    #[test]
+    fn clamps_huge_hours_to_the_retention() {
+        let stats = WriteStats::new(usize::MAX);
+        let cpu = "cpu".to_string();
+        let t0 = Time::from_timestamp(60, 0).unwrap();
+        stats.record(t0, "foo", [(&cpu, &summary(1, 20, 0))]);
+
+        assert_eq!(stats.rows(t0, usize::MAX, None).len(), 1);
+
+        let stats = WriteStats::new(1);
+        stats.record(t0, "foo", [(&cpu, &summary(1, 20, 0))]);
+        assert_eq!(stats.rows(t0, usize::MAX, None).len(), 1);
+    }
+}
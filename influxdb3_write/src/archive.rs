@@ -0,0 +1,432 @@
+//! Export of a single database, its catalog and all of its persisted parquet files, to an archive
+//! in another object store, and restore of an archive into a server's object store.
+//!
+//! An archive of the database `foo` is laid out as:
+//!
+//! * `foo/dbs/...`: the parquet files of the database, at the same paths they had in the source
+//! * `foo/manifest.json`: the schema of the database and the segments its parquet files were
+//!   persisted in, which is written last so that an incomplete archive is never restored
+//!
+//! Only data that has been persisted is archived; writes that are still in the buffer of a
+//! running server are not. Restores should be run while the target server is stopped, as the
+//! restored segments are given new ids that follow those already persisted in the target.
+
+use std::collections::HashMap;
+use std::sync::Arc;
+
+use futures_util::stream::StreamExt;
+use object_store::path::{Path as ObjPath, PathPart};
+use object_store::ObjectStore;
+use observability_deps::tracing::info;
+use serde::{Deserialize, Serialize};
+use thiserror::Error;
+use tokio::io::AsyncWriteExt;
+
+use crate::catalog::{self, Catalog, DatabaseSchema};
+use crate::paths::{object_store_file_stem, PARQUET_FILE_EXTENSION};
+use crate::persister::{self, PersisterImpl};
+use crate::{DatabaseTables, PersistedCatalog, PersistedSegment, Persister, SegmentId};
+
+const MANIFEST_FILE_NAME: &str = "manifest.json";
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("persister error: {0}")]
+    Persister(#[from] persister::Error),
+
+    #[error("object_store error: {0}")]
+    ObjectStore(#[from] object_store::Error),
+
+    #[error("io error: {0}")]
+    Io(#[from] std::io::Error),
+
+    #[error("serde_json error: {0}")]
+    SerdeJson(#[from] serde_json::Error),
+
+    #[error("catalog error: {0}")]
+    Catalog(#[from] catalog::Error),
+
+    #[error("database {0} not found")]
+    DatabaseNotFound(String),
+
+    #[error("database {0} already exists")]
+    DatabaseExists(String),
+
+    #[error("no archive of database {0} found")]
+    ArchiveNotFound(String),
+
+    #[error("invalid parquet file path in archive: {0}")]
+    InvalidParquetFilePath(String),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// The contents of an archive, other than the parquet files themselves
+#[derive(Debug, Serialize, Deserialize)]
+pub struct ArchiveManifest {
+    pub database: DatabaseSchema,
+    /// The segments, in order of id, that had parquet files for the database persisted in them.
+    /// Each segment only lists the database's parquet files.
+    pub segments: Vec<PersistedSegment>,
+}
+
+/// The amount of data that was archived or restored
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct ArchiveSummary {
+    pub segments: usize,
+    pub parquet_files: usize,
+    pub parquet_bytes: u64,
+}
+
+/// Archive the database `db_name` from the `source` object store to the `archive` object store
+pub async fn archive_database(
+    source: Arc<dyn ObjectStore>,
+    archive: Arc<dyn ObjectStore>,
+    db_name: &str,
+) -> Result<ArchiveSummary> {
+    let persister = PersisterImpl::new(Arc::clone(&source));
+    let PersistedCatalog { catalog, .. } = persister.load_catalog().await?.unwrap_or_default();
+    let database = Catalog::from_inner(catalog)
+        .db_schema(db_name)
+        .ok_or_else(|| Error::DatabaseNotFound(db_name.to_string()))?;
+
+    let mut summary = ArchiveSummary::default();
+    let mut segments = vec![];
+    // segments are loaded most recent first
+    for mut segment in persister.load_segments(usize::MAX).await?.into_iter().rev() {
+        let Some(tables) = segment.databases.remove(db_name) else {
+            continue;
+        };
+
+        for parquet_file in tables.tables.values().flat_map(|t| &t.parquet_files) {
+            let path = parse_parquet_file_path(&parquet_file.path)?;
+            let to_path = archive_path(db_name, path.parts());
+            summary.parquet_bytes +=
+                copy_object(source.as_ref(), &path, archive.as_ref(), &to_path).await?;
+            summary.parquet_files += 1;
+        }
+
+        segments.push(segment_for_database(segment, db_name, tables));
+        summary.segments += 1;
+    }
+
+    let manifest = ArchiveManifest {
+        database: DatabaseSchema::clone(&database),
+        segments,
+    };
+    archive
+        .put(
+            &archive_path(db_name, [PathPart::from(MANIFEST_FILE_NAME)]),
+            serde_json::to_vec_pretty(&manifest)?.into(),
+        )
+        .await?;
+
+    info!(
+        db_name,
+        segments = summary.segments,
+        parquet_files = summary.parquet_files,
+        "archived database"
+    );
+    Ok(summary)
+}
+
+/// Restore the archive of the database `db_name` from the `archive` object store into the
+/// `target` object store. The database must not already exist in the target.
+pub async fn restore_database(
+    archive: Arc<dyn ObjectStore>,
+    target: Arc<dyn ObjectStore>,
+    db_name: &str,
+) -> Result<ArchiveSummary> {
+    let manifest_path = archive_path(db_name, [PathPart::from(MANIFEST_FILE_NAME)]);
+    let manifest = match archive.get(&manifest_path).await {
+        Ok(manifest) => manifest.bytes().await?,
+        Err(object_store::Error::NotFound { .. }) => {
+            return Err(Error::ArchiveNotFound(db_name.to_string()))
+        }
+        Err(e) => return Err(e.into()),
+    };
+    let ArchiveManifest { database, segments } = serde_json::from_slice(&manifest)?;
+
+    let persister = PersisterImpl::new(Arc::clone(&target));
+    let persisted_catalog = persister.load_catalog().await?;
+    let catalog_segment_id = persisted_catalog.as_ref().map(|c| c.segment_id);
+    let catalog = Catalog::from_inner(persisted_catalog.map(|c| c.catalog).unwrap_or_default());
+    if catalog.db_schema(db_name).is_some() {
+        return Err(Error::DatabaseExists(db_name.to_string()));
+    }
+    if catalog.list_databases().len() >= Catalog::NUM_DBS_LIMIT {
+        return Err(Error::Catalog(catalog::Error::TooManyDbs));
+    }
+
+    // the restored segments follow any that have already been persisted, so that they don't
+    // overwrite them, and are given parquet file paths that match their new ids
+    let mut segment_id = persister
+        .load_segments(1)
+        .await?
+        .first()
+        .map(|s| s.segment_id)
+        .max(catalog_segment_id)
+        .unwrap_or_default();
+
+    let mut summary = ArchiveSummary::default();
+    for mut segment in segments {
+        segment_id = segment_id.next();
+        let mut tables = segment.databases.remove(db_name).unwrap_or_default();
+
+        for parquet_file in tables
+            .tables
+            .values_mut()
+            .flat_map(|t| &mut t.parquet_files)
+        {
+            let path = parse_parquet_file_path(&parquet_file.path)?;
+            let target_path = restored_parquet_file_path(&path, segment_id)?;
+            summary.parquet_bytes += copy_object(
+                archive.as_ref(),
+                &archive_path(db_name, path.parts()),
+                target.as_ref(),
+                &target_path,
+            )
+            .await?;
+            summary.parquet_files += 1;
+            parquet_file.path = target_path.to_string();
+        }
+
+        segment.segment_id = segment_id;
+        persister
+            .persist_segment(&segment_for_database(segment, db_name, tables))
+            .await?;
+        summary.segments += 1;
+    }
+
+    catalog.replace_database(catalog.sequence_number(), Arc::new(database))?;
+    persister.persist_catalog(segment_id, catalog).await?;
+
+    info!(
+        db_name,
+        segments = summary.segments,
+        parquet_files = summary.parquet_files,
+        "restored database"
+    );
+    Ok(summary)
+}
+
+/// Returns the segment with only the given tables for the database, and its totals updated to
+/// match
+fn segment_for_database(
+    segment: PersistedSegment,
+    db_name: &str,
+    tables: DatabaseTables,
+) -> PersistedSegment {
+    let parquet_files = || tables.tables.values().flat_map(|t| &t.parquet_files);
+    PersistedSegment {
+        segment_id: segment.segment_id,
+        // the WAL isn't archived
+        segment_wal_size_bytes: 0,
+        segment_parquet_size_bytes: parquet_files().map(|f| f.size_bytes).sum(),
+        segment_row_count: parquet_files().map(|f| f.row_count).sum(),
+        segment_min_time: parquet_files()
+            .map(|f| f.min_time)
+            .min()
+            .unwrap_or(segment.segment_min_time),
+        segment_max_time: parquet_files()
+            .map(|f| f.max_time)
+            .max()
+            .unwrap_or(segment.segment_max_time),
+        databases: HashMap::from([(db_name.to_string(), tables)]),
+    }
+}
+
+fn archive_path<'a>(db_name: &'a str, parts: impl IntoIterator<Item = PathPart<'a>>) -> ObjPath {
+    std::iter::once(PathPart::from(db_name))
+        .chain(parts)
+        .collect()
+}
+
+fn parse_parquet_file_path(path: &str) -> Result<ObjPath> {
+    ObjPath::parse(path).map_err(|_| Error::InvalidParquetFilePath(path.to_string()))
+}
+
+/// Parquet files are named for the segment they were persisted in, so a restored file is renamed
+/// for the new id of its segment
+fn restored_parquet_file_path(path: &ObjPath, segment_id: SegmentId) -> Result<ObjPath> {
+    let mut parts: Vec<_> = path.parts().collect();
+    if parts.pop().is_none() {
+        return Err(Error::InvalidParquetFilePath(path.to_string()));
+    }
+    let file_name = format!(
+        "{:010}.{}",
+        object_store_file_stem(segment_id.0),
+        PARQUET_FILE_EXTENSION
+    );
+    parts.push(PathPart::from(file_name));
+    Ok(parts.into_iter().collect())
+}
+
+/// Stream an object from one object store to another, returning its size in bytes
+async fn copy_object(
+    from: &dyn ObjectStore,
+    from_path: &ObjPath,
+    to: &dyn ObjectStore,
+    to_path: &ObjPath,
+) -> Result<u64> {
+    let mut stream = from.get(from_path).await?.into_stream();
+    let (multipart_id, mut writer) = to.put_multipart(to_path).await?;
+
+    let mut bytes = 0;
+    let copied: Result<()> = async {
+        while let Some(chunk) = stream.next().await {
+            let chunk = chunk?;
+            bytes += chunk.len() as u64;
+            writer.write_all(&chunk).await?;
+        }
+        writer.shutdown().await?;
+        Ok(())
+    }
+    .await;
+
+    if let Err(e) = copied {
+        // the original error is more useful than any error from aborting the upload
+        let _ = to.abort_multipart(to_path, &multipart_id).await;
+        return Err(e);
+    }
+
+    Ok(bytes)
+}
+
+#[cfg(test)]
+mod tests {
+    use bytes::Bytes;
+    use object_store::memory::InMemory;
+
+    use super::*;
+    use crate::paths::ParquetFilePath;
+    use crate::{ParquetFile, TableParquetFiles};
+
+    async fn persist_segment_with_file(
+        store: &Arc<dyn ObjectStore>,
+        segment_id: u32,
+        db_name: &str,
+        contents: &'static str,
+    ) {
+        let path =
+            ParquetFilePath::new_with_partition_key(db_name, "cpu", "2024-01-01T00-00", segment_id);
+        store
+            .put(&path, Bytes::from_static(contents.as_bytes()))
+            .await
+            .unwrap();
+
+        let tables = DatabaseTables {
+            tables: HashMap::from([(
+                "cpu".to_string(),
+                TableParquetFiles {
+                    table_name: "cpu".to_string(),
+                    parquet_files: vec![ParquetFile {
+                        path: path.to_string(),
+                        size_bytes: contents.len() as u64,
+                        row_count: 1,
+                        min_time: 1,
+                        max_time: 2,
+                    }],
+                    sort_key: vec![],
+                },
+            )]),
+        };
+        PersisterImpl::new(Arc::clone(store))
+            .persist_segment(&PersistedSegment {
+                segment_id: SegmentId::new(segment_id),
+                segment_wal_size_bytes: 10,
+                segment_parquet_size_bytes: contents.len() as u64,
+                segment_row_count: 1,
+                segment_min_time: 1,
+                segment_max_time: 2,
+                databases: HashMap::from([(db_name.to_string(), tables)]),
+            })
+            .await
+            .unwrap();
+    }
+
+    async fn persist_catalog(store: &Arc<dyn ObjectStore>, segment_id: u32, db_names: &[&str]) {
+        let catalog = Catalog::new();
+        for db_name in db_names {
+            crate::test_helpers::lp_to_table_batches(&catalog, db_name, "cpu,host=a usage=1 1", 0);
+        }
+        PersisterImpl::new(Arc::clone(store))
+            .persist_catalog(SegmentId::new(segment_id), catalog)
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn archive_and_restore_database() {
+        let source: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        persist_segment_with_file(&source, 1, "foo", "foo-1").await;
+        persist_segment_with_file(&source, 2, "bar", "bar-2").await;
+        persist_segment_with_file(&source, 3, "foo", "foo-3").await;
+        persist_catalog(&source, 3, &["foo", "bar"]).await;
+
+        let archive: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let summary = archive_database(Arc::clone(&source), Arc::clone(&archive), "foo")
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            ArchiveSummary {
+                segments: 2,
+                parquet_files: 2,
+                parquet_bytes: 10,
+            }
+        );
+
+        // restore into a server that already has persisted segments of its own
+        let target: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        persist_segment_with_file(&target, 1, "baz", "baz-1").await;
+        persist_catalog(&target, 1, &["baz"]).await;
+
+        restore_database(Arc::clone(&archive), Arc::clone(&target), "foo")
+            .await
+            .unwrap();
+
+        let persister = PersisterImpl::new(Arc::clone(&target));
+        let catalog = persister.load_catalog().await.unwrap().unwrap();
+        assert_eq!(catalog.segment_id, SegmentId::new(3));
+        let catalog = Catalog::from_inner(catalog.catalog);
+        assert!(catalog.db_schema("baz").is_some());
+        assert!(catalog.db_schema("foo").unwrap().table_exists("cpu"));
+
+        let segments = persister.load_segments(10).await.unwrap();
+        let segment_ids: Vec<_> = segments.iter().map(|s| s.segment_id).collect();
+        assert_eq!(
+            segment_ids,
+            vec![SegmentId::new(3), SegmentId::new(2), SegmentId::new(1)]
+        );
+        for (segment, contents) in segments[..2].iter().zip(["foo-3", "foo-1"]) {
+            assert_eq!(segment.segment_wal_size_bytes, 0);
+            let file = &segment.databases["foo"].tables["cpu"].parquet_files[0];
+            let expected_path = ParquetFilePath::new_with_partition_key(
+                "foo",
+                "cpu",
+                "2024-01-01T00-00",
+                segment.segment_id.0,
+            );
+            assert_eq!(file.path, expected_path.to_string());
+            let bytes = target
+                .get(&expected_path)
+                .await
+                .unwrap()
+                .bytes()
+                .await
+                .unwrap();
+            assert_eq!(bytes, contents.as_bytes());
+        }
+
+        // the database can't be restored over itself
+        assert!(matches!(
+            restore_database(Arc::clone(&archive), Arc::clone(&target), "foo").await,
+            Err(Error::DatabaseExists(_))
+        ));
+        assert!(matches!(
+            restore_database(archive, target, "bar").await,
+            Err(Error::ArchiveNotFound(_))
+        ));
+    }
+}
@@ -159,6 +159,30 @@ impl Catalog {
     pub fn list_databases(&self) -> Vec<String> {
         self.inner.read().databases.keys().cloned().collect()
     }
+
+    /// Replace the ingest transforms for a database, creating the database if it doesn't exist.
+    pub fn set_ingest_transforms(
+        &self,
+        db_name: &str,
+        transforms: Vec<IngestTransform>,
+    ) -> Result<()> {
+        let (sequence, db) = self.db_or_create(db_name)?;
+
+        let mut db = DatabaseSchema::clone(&db);
+        db.transforms = transforms;
+
+        self.replace_database(sequence, Arc::new(db))
+    }
+
+    /// Replace the write flags for a database, creating the database if it doesn't exist.
+    pub fn set_write_flags(&self, db_name: &str, write_flags: WriteFlags) -> Result<()> {
+        let (sequence, db) = self.db_or_create(db_name)?;
+
+        let mut db = DatabaseSchema::clone(&db);
+        db.write_flags = write_flags;
+
+        self.replace_database(sequence, Arc::new(db))
+    }
 }
 
 #[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Default)]
@@ -180,6 +204,10 @@ impl InnerCatalog {
         self.sequence
     }
 
+    pub fn database_count(&self) -> usize {
+        self.databases.len()
+    }
+
     #[cfg(test)]
     pub fn db_exists(&self, db_name: &str) -> bool {
         self.databases.contains_key(db_name)
@@ -191,6 +219,13 @@ pub struct DatabaseSchema {
     pub name: String,
     /// The database is a map of tables
     pub(crate) tables: BTreeMap<String, TableDefinition>,
+    /// Transforms applied, in order, to every line written to the database before it is
+    /// validated
+    #[serde(default)]
+    pub(crate) transforms: Vec<IngestTransform>,
+    /// Toggles for how writes to the database are handled
+    #[serde(default)]
+    pub(crate) write_flags: WriteFlags,
 }
 
 impl DatabaseSchema {
@@ -198,6 +233,8 @@ impl DatabaseSchema {
         Self {
             name: name.into(),
             tables: BTreeMap::new(),
+            transforms: vec![],
+            write_flags: WriteFlags::default(),
         }
     }
 
@@ -216,6 +253,68 @@ impl DatabaseSchema {
     pub fn table_exists(&self, table_name: &str) -> bool {
         self.tables.contains_key(table_name)
     }
+
+    pub fn ingest_transforms(&self) -> &[IngestTransform] {
+        &self.transforms
+    }
+
+    pub fn write_flags(&self) -> WriteFlags {
+        self.write_flags
+    }
+}
+
+/// Toggles for how writes to a database are handled, so that changes to the write path can be
+/// rolled out one database at a time
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone, Copy)]
+#[serde(default)]
+pub struct WriteFlags {
+    /// Accept, or don't accept, the valid lines of writes that contain invalid lines, regardless
+    /// of what the write requests ask for
+    pub accept_partial: Option<bool>,
+    /// Convert field values to the type of their existing column, where it can be done without
+    /// losing precision, e.g. integers written to a float column
+    pub coerce_field_types: bool,
+    /// Reject writes that would add tables or columns to the database
+    pub strict_schema: bool,
+    /// Replicate writes to the database, if replication is configured
+    pub replicate: bool,
+    /// Reject lines with string fields that start with the histogram prefix, but aren't valid
+    /// histograms, see [`crate::histogram`]
+    pub validate_histograms: bool,
+}
+
+impl Default for WriteFlags {
+    fn default() -> Self {
+        Self {
+            accept_partial: None,
+            coerce_field_types: false,
+            strict_schema: false,
+            replicate: true,
+            validate_histograms: false,
+        }
+    }
+}
+
+/// A rule that rewrites incoming lines of line protocol before they are validated and buffered.
+/// Rules that specify a `table` only apply to lines for that table.
+#[derive(Debug, Serialize, Deserialize, Eq, PartialEq, Clone)]
+#[serde(tag = "type", rename_all = "snake_case")]
+pub enum IngestTransform {
+    /// Change the name of a tag
+    RenameTag {
+        #[serde(default)]
+        table: Option<String>,
+        from: String,
+        to: String,
+    },
+    /// Remove a field. Lines that are left without any fields are dropped.
+    DropField {
+        #[serde(default)]
+        table: Option<String>,
+        field: String,
+    },
+    /// Write lines for one measurement into a table with a different name
+    MapMeasurement { from: String, to: String },
 }
 
 #[derive(Debug, Serialize, Eq, PartialEq, Clone)]
@@ -361,6 +460,8 @@ mod tests {
         let mut database = DatabaseSchema {
             name: "test".to_string(),
             tables: BTreeMap::new(),
+            transforms: vec![],
+            write_flags: WriteFlags::default(),
         };
         database.tables.insert(
             "test".into(),
@@ -369,6 +470,11 @@ mod tests {
                 BTreeMap::from([("test".to_string(), ColumnType::String as i16)]),
             ),
         );
+        database.transforms.push(IngestTransform::RenameTag {
+            table: None,
+            from: "a".to_string(),
+            to: "b".to_string(),
+        });
         let database = Arc::new(database);
         catalog
             .replace_database(SequenceNumber::new(0), database)
@@ -381,11 +487,57 @@ mod tests {
         assert_eq!(*inner, deserialized);
     }
 
+    #[test]
+    fn set_ingest_transforms_bumps_sequence() {
+        let catalog = Catalog::new();
+        let transforms = vec![IngestTransform::MapMeasurement {
+            from: "cpu_old".to_string(),
+            to: "cpu".to_string(),
+        }];
+
+        catalog
+            .set_ingest_transforms("foo", transforms.clone())
+            .unwrap();
+
+        assert_eq!(catalog.sequence_number(), SequenceNumber::new(1));
+        assert_eq!(
+            catalog.db_schema("foo").unwrap().ingest_transforms(),
+            transforms.as_slice()
+        );
+
+        // databases persisted before transforms existed can still be read
+        let db: DatabaseSchema = serde_json::from_str(r#"{"name":"foo","tables":{}}"#).unwrap();
+        assert!(db.transforms.is_empty());
+        assert_eq!(db.write_flags, WriteFlags::default());
+    }
+
+    #[test]
+    fn set_write_flags() {
+        let catalog = Catalog::new();
+        let write_flags = WriteFlags {
+            strict_schema: true,
+            ..Default::default()
+        };
+
+        catalog.set_write_flags("foo", write_flags).unwrap();
+
+        assert_eq!(catalog.sequence_number(), SequenceNumber::new(1));
+        assert_eq!(catalog.db_schema("foo").unwrap().write_flags(), write_flags);
+
+        // unset flags take their default
+        let write_flags: WriteFlags = serde_json::from_str(r#"{"accept_partial":false}"#).unwrap();
+        assert_eq!(write_flags.accept_partial, Some(false));
+        assert!(write_flags.replicate);
+        assert!(!write_flags.validate_histograms);
+    }
+
     #[test]
     fn add_columns_updates_schema() {
         let mut database = DatabaseSchema {
             name: "test".to_string(),
             tables: BTreeMap::new(),
+            transforms: vec![],
+            write_flags: WriteFlags::default(),
         };
         database.tables.insert(
             "test".into(),
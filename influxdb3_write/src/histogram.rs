@@ -0,0 +1,291 @@
+//! An encoding of histograms, e.g. from Prometheus or OTLP, as the value of a single string field,
+//! so that a histogram is written as one field of one series rather than exploded into a series
+//! per bucket.
+//!
+//! The field and column types come from the line protocol parser and the schema crates, which
+//! don't have a histogram type, so histograms are stored in string columns. A string value that
+//! starts with `histogram:` is a histogram, and is validated when it is written to a database
+//! with the `validate_histograms` write flag set:
+//!
+//! ```text
+//! histogram:sum=<float>,count=<integer>;<upper bound>=<cumulative count>,...,+Inf=<count>
+//! ```
+//!
+//! e.g., `latency="histogram:sum=27.5,count=12;0.5=4,1=9,+Inf=12"`. As in Prometheus, the count of
+//! each bucket is the number of observations less than or equal to its upper bound, so counts
+//! never decrease, and the last bucket has an upper bound of `+Inf` and counts every observation.
+
+use std::fmt;
+use std::str::FromStr;
+
+use thiserror::Error;
+
+/// The prefix of string field values that are histograms
+pub const HISTOGRAM_PREFIX: &str = "histogram:";
+
+#[derive(Debug, Error, PartialEq)]
+pub enum Error {
+    #[error("a histogram must start with {HISTOGRAM_PREFIX:?}")]
+    MissingPrefix,
+
+    #[error("a histogram must have its sum and count, then its buckets, separated by ';'")]
+    MissingBuckets,
+
+    #[error("invalid histogram {0}: {1:?}")]
+    InvalidValue(&'static str, String),
+
+    #[error("histogram bucket upper bounds must increase, but {0} follows {1}")]
+    UnorderedBounds(f64, f64),
+
+    #[error("histogram bucket counts are cumulative, but {0} follows {1}")]
+    DecreasingCounts(u64, u64),
+
+    #[error("the last histogram bucket must have an upper bound of +Inf")]
+    MissingInfBucket,
+
+    #[error("the histogram count is {count}, but its +Inf bucket counts {inf_count}")]
+    CountMismatch { count: u64, inf_count: u64 },
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// A bucket of a histogram: the number of observations less than or equal to its upper bound
+#[derive(Debug, Clone, Copy, PartialEq)]
+pub struct Bucket {
+    pub upper_bound: f64,
+    pub count: u64,
+}
+
+/// A histogram of observations, with cumulative buckets that end with a `+Inf` bucket
+#[derive(Debug, Clone, PartialEq)]
+pub struct Histogram {
+    sum: f64,
+    count: u64,
+    buckets: Vec<Bucket>,
+}
+
+impl Histogram {
+    /// Creates a histogram of the observations counted by the buckets, which must be in order of
+    /// their upper bounds and end with the `+Inf` bucket
+    pub fn try_new(sum: f64, count: u64, buckets: Vec<Bucket>) -> Result<Self> {
+        if !sum.is_finite() {
+            return Err(Error::InvalidValue("sum", sum.to_string()));
+        }
+
+        let mut previous: Option<&Bucket> = None;
+        for bucket in &buckets {
+            if bucket.upper_bound.is_nan() {
+                return Err(Error::InvalidValue("upper bound", "NaN".to_string()));
+            }
+            if let Some(previous) = previous {
+                if bucket.upper_bound <= previous.upper_bound {
+                    return Err(Error::UnorderedBounds(
+                        bucket.upper_bound,
+                        previous.upper_bound,
+                    ));
+                }
+                if bucket.count < previous.count {
+                    return Err(Error::DecreasingCounts(bucket.count, previous.count));
+                }
+            }
+            previous = Some(bucket);
+        }
+
+        match buckets.last() {
+            Some(last) if last.upper_bound == f64::INFINITY => {
+                if last.count != count {
+                    return Err(Error::CountMismatch {
+                        count,
+                        inf_count: last.count,
+                    });
+                }
+            }
+            _ => return Err(Error::MissingInfBucket),
+        }
+
+        Ok(Self {
+            sum,
+            count,
+            buckets,
+        })
+    }
+
+    pub fn sum(&self) -> f64 {
+        self.sum
+    }
+
+    pub fn count(&self) -> u64 {
+        self.count
+    }
+
+    pub fn buckets(&self) -> &[Bucket] {
+        &self.buckets
+    }
+}
+
+impl FromStr for Histogram {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self> {
+        let s = s
+            .strip_prefix(HISTOGRAM_PREFIX)
+            .ok_or(Error::MissingPrefix)?;
+        let (totals, buckets) = s.split_once(';').ok_or(Error::MissingBuckets)?;
+
+        let (mut sum, mut count) = (None, None);
+        for total in totals.split(',') {
+            match total.split_once('=') {
+                Some(("sum", value)) => sum = Some(parse_value("sum", value)?),
+                Some(("count", value)) => count = Some(parse_value("count", value)?),
+                _ => return Err(Error::InvalidValue("total", total.to_string())),
+            }
+        }
+        let sum = sum.ok_or_else(|| Error::InvalidValue("sum", String::new()))?;
+        let count = count.ok_or_else(|| Error::InvalidValue("count", String::new()))?;
+
+        let buckets = buckets
+            .split(',')
+            .map(|bucket| {
+                let (upper_bound, count) = bucket
+                    .split_once('=')
+                    .ok_or_else(|| Error::InvalidValue("bucket", bucket.to_string()))?;
+                let upper_bound = match upper_bound {
+                    "+Inf" => f64::INFINITY,
+                    upper_bound => parse_value("upper bound", upper_bound)?,
+                };
+                Ok(Bucket {
+                    upper_bound,
+                    count: parse_value("bucket count", count)?,
+                })
+            })
+            .collect::<Result<Vec<_>>>()?;
+
+        Self::try_new(sum, count, buckets)
+    }
+}
+
+fn parse_value<T: FromStr>(name: &'static str, value: &str) -> Result<T> {
+    value
+        .parse()
+        .map_err(|_| Error::InvalidValue(name, value.to_string()))
+}
+
+impl fmt::Display for Histogram {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        write!(
+            f,
+            "{HISTOGRAM_PREFIX}sum={},count={};",
+            self.sum, self.count
+        )?;
+        for (i, bucket) in self.buckets.iter().enumerate() {
+            if i > 0 {
+                f.write_str(",")?;
+            }
+            if bucket.upper_bound == f64::INFINITY {
+                write!(f, "+Inf={}", bucket.count)?;
+            } else {
+                write!(f, "{}={}", bucket.upper_bound, bucket.count)?;
+            }
+        }
+        Ok(())
+    }
+}
+
+/// Returns an error if the string field value is a histogram that isn't valid. Values that
+/// aren't histograms are valid.
+pub(crate) fn validate_string_field(value: &str) -> Result<()> {
+    if value.starts_with(HISTOGRAM_PREFIX) {
+        value.parse::<Histogram>()?;
+    }
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn encodes_and_decodes_histograms() {
+        let histogram = Histogram::try_new(
+            27.5,
+            12,
+            vec![
+                Bucket {
+                    upper_bound: -0.5,
+                    count: 1,
+                },
+                Bucket {
+                    upper_bound: 1.0,
+                    count: 9,
+                },
+                Bucket {
+                    upper_bound: f64::INFINITY,
+                    count: 12,
+                },
+            ],
+        )
+        .unwrap();
+
+        let encoded = histogram.to_string();
+
+        assert_eq!(encoded, "histogram:sum=27.5,count=12;-0.5=1,1=9,+Inf=12");
+        assert_eq!(encoded.parse::<Histogram>().unwrap(), histogram);
+        assert_eq!(
+            "histogram:count=0,sum=0;+Inf=0"
+                .parse::<Histogram>()
+                .unwrap()
+                .buckets(),
+            &[Bucket {
+                upper_bound: f64::INFINITY,
+                count: 0
+            }]
+        );
+    }
+
+    #[test]
+    fn rejects_invalid_histograms() {
+        let err = |s: &str| s.parse::<Histogram>().unwrap_err();
+
+        assert_eq!(err("sum=1,count=1;+Inf=1"), Error::MissingPrefix);
+        assert_eq!(err("histogram:sum=1,count=1"), Error::MissingBuckets);
+        assert_eq!(
+            err("histogram:sum=1;+Inf=1"),
+            Error::InvalidValue("count", String::new())
+        );
+        assert_eq!(
+            err("histogram:sum=x,count=1;+Inf=1"),
+            Error::InvalidValue("sum", "x".to_string())
+        );
+        assert_eq!(
+            err("histogram:sum=inf,count=1;+Inf=1"),
+            Error::InvalidValue("sum", "inf".to_string())
+        );
+        assert_eq!(
+            err("histogram:sum=1,count=1;1=-1,+Inf=1"),
+            Error::InvalidValue("bucket count", "-1".to_string())
+        );
+        assert_eq!(
+            err("histogram:sum=1,count=2;1=1,0.5=2,+Inf=2"),
+            Error::UnorderedBounds(0.5, 1.0)
+        );
+        assert_eq!(
+            err("histogram:sum=1,count=2;0.5=2,1=1,+Inf=2"),
+            Error::DecreasingCounts(1, 2)
+        );
+        assert_eq!(
+            err("histogram:sum=1,count=2;0.5=1,1=2"),
+            Error::MissingInfBucket
+        );
+        assert_eq!(
+            err("histogram:sum=1,count=3;0.5=1,+Inf=2"),
+            Error::CountMismatch {
+                count: 3,
+                inf_count: 2
+            }
+        );
+
+        assert!(validate_string_field("not a histogram").is_ok());
+        assert!(validate_string_field("histogram:sum=1,count=1;+Inf=1").is_ok());
+        assert!(validate_string_field("histogram:").is_err());
+    }
+}
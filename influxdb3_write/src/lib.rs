@@ -6,11 +6,14 @@
 //! When the segment reaches a certain size, or a certain amount of time has passed, it will be closed and marked
 //! to be persisted. A new open segment will be created and new writes will be written to that segment.
 
+pub mod archive;
 pub mod cache;
 pub mod catalog;
 mod chunk;
+pub mod histogram;
 pub mod paths;
 pub mod persister;
+pub mod recovery;
 pub mod wal;
 pub mod write_buffer;
 
@@ -36,6 +39,7 @@ use std::str::FromStr;
 use std::sync::Arc;
 use std::time::Duration;
 use thiserror::Error;
+use tokio::sync::broadcast;
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -84,11 +88,47 @@ pub trait Bufferer: Debug + Send + Sync + 'static {
         precision: Precision,
     ) -> write_buffer::Result<BufferedWriteRequest>;
 
+    /// Writes the line protocol as in [`Bufferer::write_lp`], for a bulk load of historical data.
+    /// The segments older than the current one that the write goes into are persisted as soon as
+    /// the backfill stops writing to them, rather than staying open for their full duration next
+    /// to the segments of live writes.
+    async fn write_lp_backfill(
+        &self,
+        database: NamespaceName<'static>,
+        lp: &str,
+        ingest_time: Time,
+        accept_partial: bool,
+        precision: Precision,
+    ) -> write_buffer::Result<BufferedWriteRequest>;
+
     /// Returns the configured WAL, if there is one.
     fn wal(&self) -> Option<Arc<impl Wal>>;
 
     /// Returns the catalog
     fn catalog(&self) -> Arc<catalog::Catalog>;
+
+    /// Replaces the ingest transforms of the database, creating it if it doesn't exist, and
+    /// persists the catalog so that the transforms are kept across restarts.
+    async fn set_ingest_transforms(
+        &self,
+        db_name: &str,
+        transforms: Vec<catalog::IngestTransform>,
+    ) -> write_buffer::Result<()>;
+
+    /// Replaces the write flags of the database, creating it if it doesn't exist, and persists
+    /// the catalog so that the flags are kept across restarts.
+    async fn set_write_flags(
+        &self,
+        db_name: &str,
+        write_flags: catalog::WriteFlags,
+    ) -> write_buffer::Result<()>;
+
+    /// Subscribes to the writes accepted into the buffer from now on, as the ops they were written
+    /// to the WAL as. Subscribers that fall too far behind miss writes.
+    fn subscribe(&self) -> broadcast::Receiver<Arc<LpWriteOp>>;
+
+    /// Returns how far the buffer has got through the WAL
+    fn watermarks(&self) -> Watermarks;
 }
 
 /// A segment in the buffer that corresponds to a single WAL segment file. It contains a catalog with any updates
@@ -381,6 +421,13 @@ pub trait Wal: Debug + Send + Sync + 'static {
     /// Deletes the WAL segment file from disk.
     fn delete_wal_segment(&self, segment_id: SegmentId) -> wal::Result<()>;
 
+    /// Writes a snapshot of the write buffer, replacing any existing snapshot.
+    fn write_snapshot(&self, data: &[u8]) -> wal::Result<()>;
+
+    /// Reads and removes the snapshot of the write buffer, if there is one, so that a snapshot
+    /// is only ever loaded once.
+    fn take_snapshot(&self) -> wal::Result<Option<Vec<u8>>>;
+
     fn as_any(&self) -> &dyn Any;
 }
 
@@ -405,6 +452,13 @@ pub trait WalSegmentWriter: Debug + Send + Sync + 'static {
 pub trait WalSegmentReader: Debug + Send + Sync + 'static {
     fn next_batch(&mut self) -> wal::Result<Option<WalOpBatch>>;
 
+    /// Returns the next batch whose header is accepted by the filter. Batches that are rejected
+    /// are skipped over without decompressing or deserializing their payload.
+    fn next_batch_matching(
+        &mut self,
+        filter: &dyn Fn(&WalOpBatchHeader) -> bool,
+    ) -> wal::Result<Option<WalOpBatch>>;
+
     fn header(&self) -> &wal::SegmentHeader;
 
     fn path(&self) -> &SegmentWalFilePath;
@@ -417,6 +471,34 @@ pub struct WalOpBatch {
     pub ops: Vec<WalOp>,
 }
 
+/// Metadata written uncompressed ahead of every `WalOpBatch` in a segment file. Readers can use it
+/// to decide whether they are interested in a batch, e.g. only batches for a given database,
+/// without having to decompress and deserialize the payload.
+#[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
+pub struct WalOpBatchHeader {
+    pub sequence_number: SequenceNumber,
+    /// The distinct database names that the ops in the batch are for, sorted.
+    pub db_names: Vec<String>,
+}
+
+impl WalOpBatchHeader {
+    pub fn new(sequence_number: SequenceNumber, ops: &[WalOp]) -> Self {
+        let mut db_names: Vec<String> = ops.iter().map(|op| op.db_name().to_string()).collect();
+        db_names.sort_unstable();
+        db_names.dedup();
+
+        Self {
+            sequence_number,
+            db_names,
+        }
+    }
+
+    /// Returns true if the batch has any ops for the given database.
+    pub fn contains_db(&self, db_name: &str) -> bool {
+        self.db_names.iter().any(|name| name == db_name)
+    }
+}
+
 /// A WalOp can be write of line protocol, the creation of a database, or other kinds of state that eventually
 /// lands in object storage. Things in the WAL are buffered until they are persisted to object storage. The write
 /// is called an `LpWrite` because it is a write of line protocol and we intend to have a new write protocol for
@@ -426,6 +508,15 @@ pub enum WalOp {
     LpWrite(LpWriteOp),
 }
 
+impl WalOp {
+    /// Returns the name of the database this op is for.
+    pub fn db_name(&self) -> &str {
+        match self {
+            Self::LpWrite(op) => &op.db_name,
+        }
+    }
+}
+
 /// A write of 1 or more lines of line protocol to a single database. The default time is set by the server at the
 /// time the write comes in.
 #[derive(Debug, Clone, Serialize, Deserialize, Eq, PartialEq)]
@@ -454,6 +545,77 @@ pub struct BufferedWriteRequest {
     pub line_count: usize,
     pub field_count: usize,
     pub tag_count: usize,
+    /// Summaries of the valid lines in the write, keyed by table name
+    pub table_summaries: HashMap<String, TableWriteSummary>,
+    /// The position in the WAL of the last batch the write was in, for each segment the write
+    /// went into, ordered by segment id
+    pub wal_positions: Vec<WalPosition>,
+    /// The ops that the valid lines of the write went into the WAL as, one for each segment the
+    /// write went into. These are the lines as they were buffered, after the blocklist, ingest
+    /// transforms and field type coercion were applied.
+    pub accepted_ops: Vec<Arc<LpWriteOp>>,
+}
+
+/// The position of a batch in the WAL: the segment it was written to and its sequence number
+/// within the segment. Once the buffer has a batch at a position it is queryable, so clients can
+/// get read-your-writes consistency by waiting until the buffer has reached a position.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, PartialOrd, Ord, Hash, Serialize, Deserialize)]
+pub struct WalPosition {
+    pub segment_id: SegmentId,
+    pub sequence_number: SequenceNumber,
+}
+
+/// How far the buffer has got through the WAL, for clients to check whether the positions their
+/// writes were written to are queryable, or have been persisted to object storage.
+///
+/// Segment ids are only ever increasing, so segments up to the last id that are neither open nor
+/// persisting have been persisted.
+#[derive(Debug, Clone, PartialEq, Eq, Serialize, Deserialize)]
+pub struct Watermarks {
+    /// The id of the last segment that was opened
+    pub last_segment_id: SegmentId,
+    /// The position of the last buffered batch in each open segment, ordered by segment id
+    pub open: Vec<WalPosition>,
+    /// The ids of the closed segments that are being persisted, which are queryable
+    pub persisting: Vec<SegmentId>,
+}
+
+impl Watermarks {
+    /// Returns true if the batch at the position has been buffered, and so is queryable
+    pub fn is_readable(&self, position: WalPosition) -> bool {
+        if position.segment_id > self.last_segment_id {
+            return false;
+        }
+
+        match self
+            .open
+            .iter()
+            .find(|open| open.segment_id == position.segment_id)
+        {
+            Some(open) => open.sequence_number >= position.sequence_number,
+            None => true,
+        }
+    }
+
+    /// Returns true if the segment of the position has been persisted to object storage
+    pub fn is_persisted(&self, position: WalPosition) -> bool {
+        position.segment_id <= self.last_segment_id
+            && !self
+                .open
+                .iter()
+                .any(|open| open.segment_id == position.segment_id)
+            && !self.persisting.contains(&position.segment_id)
+    }
+}
+
+/// Counts for the lines written to a single table in a write request.
+#[derive(Debug, Default, Clone, Copy, Eq, PartialEq)]
+pub struct TableWriteSummary {
+    pub line_count: usize,
+    /// Size of the raw line protocol for the lines in bytes
+    pub bytes: usize,
+    /// Number of columns that were added to the table's schema by the write
+    pub new_column_count: usize,
 }
 
 /// A persisted Catalog that contains the database, table, and column schemas.
@@ -537,6 +699,29 @@ impl Default for Precision {
     }
 }
 
+impl Precision {
+    /// Convert a timestamp written with this precision to nanoseconds since the epoch. If the
+    /// precision is `Auto`, it is guessed from the timestamp.
+    pub fn timestamp_to_nanos(&self, timestamp: i64) -> i64 {
+        let multiplier = match self {
+            Self::Auto => match guess_precision(timestamp) {
+                Self::Second => 1_000_000_000,
+                Self::Millisecond => 1_000_000,
+                Self::Microsecond => 1_000,
+                Self::Nanosecond => 1,
+
+                Self::Auto => unreachable!(),
+            },
+            Self::Second => 1_000_000_000,
+            Self::Millisecond => 1_000_000,
+            Self::Microsecond => 1_000,
+            Self::Nanosecond => 1,
+        };
+
+        timestamp * multiplier
+    }
+}
+
 impl From<iox_http::write::Precision> for Precision {
     fn from(legacy: iox_http::write::Precision) -> Self {
         match legacy {
@@ -716,6 +901,31 @@ mod tests {
 
         assert_eq!(expected, actual);
     }
+
+    #[test]
+    fn watermarks_readable_and_persisted() {
+        let position = |segment_id, sequence_number| WalPosition {
+            segment_id: SegmentId::new(segment_id),
+            sequence_number: SequenceNumber::new(sequence_number),
+        };
+        let watermarks = Watermarks {
+            last_segment_id: SegmentId::new(4),
+            open: vec![position(3, 5), position(4, 2)],
+            persisting: vec![SegmentId::new(2)],
+        };
+
+        assert!(watermarks.is_readable(position(1, 10)));
+        assert!(watermarks.is_readable(position(2, 10)));
+        assert!(watermarks.is_readable(position(3, 5)));
+        assert!(!watermarks.is_readable(position(3, 6)));
+        assert!(watermarks.is_readable(position(4, 1)));
+        assert!(!watermarks.is_readable(position(5, 1)));
+
+        assert!(watermarks.is_persisted(position(1, 10)));
+        assert!(!watermarks.is_persisted(position(2, 10)));
+        assert!(!watermarks.is_persisted(position(3, 1)));
+        assert!(!watermarks.is_persisted(position(5, 1)));
+    }
 }
 
 #[cfg(test)]
@@ -18,7 +18,10 @@ pub const SEGMENT_INFO_FILE_EXTENSION: &str = "info.json";
 /// File extension for segment wal files
 pub const SEGMENT_WAL_FILE_EXTENSION: &str = "wal";
 
-fn object_store_file_stem(n: u32) -> u32 {
+/// File extension for the snapshot of the write buffer
+pub const BUFFER_SNAPSHOT_FILE_EXTENSION: &str = "snapshot";
+
+pub(crate) fn object_store_file_stem(n: u32) -> u32 {
     u32::MAX - n
 }
 
@@ -130,6 +133,32 @@ impl AsRef<Path> for SegmentWalFilePath {
     }
 }
 
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct BufferSnapshotFilePath(PathBuf);
+
+impl BufferSnapshotFilePath {
+    pub fn new(dir: impl Into<PathBuf>) -> Self {
+        let mut path = dir.into();
+        path.push("buffer");
+        path.set_extension(BUFFER_SNAPSHOT_FILE_EXTENSION);
+        Self(path)
+    }
+}
+
+impl Deref for BufferSnapshotFilePath {
+    type Target = Path;
+
+    fn deref(&self) -> &Self::Target {
+        &self.0
+    }
+}
+
+impl AsRef<Path> for BufferSnapshotFilePath {
+    fn as_ref(&self) -> &Path {
+        &self.0
+    }
+}
+
 #[derive(Debug, Clone, PartialEq, Eq)]
 pub struct SegmentInfoFilePath(ObjPath);
 
@@ -213,3 +242,11 @@ fn segment_wal_file_path_new() {
         PathBuf::from("dir/0000000000.wal").as_ref()
     );
 }
+
+#[test]
+fn buffer_snapshot_file_path_new() {
+    assert_eq!(
+        *BufferSnapshotFilePath::new("dir"),
+        PathBuf::from("dir/buffer.snapshot").as_ref()
+    );
+}
@@ -0,0 +1,532 @@
+//! Recovery of a catalog that was lost, e.g. deleted from the object store by mistake, from the
+//! writes in the WAL of a stopped server.
+//!
+//! The writes in the most recent WAL segments are replayed into a fresh catalog, which infers the
+//! databases, tables and columns they were written to in the same way as the write path does.
+//! Only segments that haven't been persisted yet are still in the WAL, so the tables of the
+//! persisted segments are then added to the catalog, with the columns of the schema of their
+//! most recent parquet file.
+
+use std::collections::{BTreeMap, HashSet};
+use std::sync::Arc;
+
+use data_types::{ColumnType, NamespaceName};
+use iox_time::Time;
+use object_store::path::Path as ObjPath;
+use object_store::ObjectStore;
+use observability_deps::tracing::{info, warn};
+use parquet::arrow::arrow_reader::ParquetRecordBatchReaderBuilder;
+use schema::{InfluxColumnType, InfluxFieldType, Schema};
+use thiserror::Error;
+
+use crate::catalog::{self, Catalog, DatabaseSchema, TableDefinition};
+use crate::persister::{self, PersisterImpl};
+use crate::write_buffer::{self, parse_validate_and_update_catalog};
+use crate::{wal, Persister, SegmentDuration, SegmentId, Wal, WalOp};
+
+#[derive(Debug, Error)]
+pub enum Error {
+    #[error("wal error: {0}")]
+    Wal(#[from] wal::Error),
+
+    #[error("persister error: {0}")]
+    Persister(#[from] persister::Error),
+
+    #[error("catalog error: {0}")]
+    Catalog(#[from] catalog::Error),
+
+    #[error("object_store error: {0}")]
+    ObjectStore(#[from] object_store::Error),
+
+    #[error("invalid parquet file path {0}")]
+    InvalidParquetFilePath(String),
+
+    #[error("error reading parquet file: {0}")]
+    Parquet(#[from] parquet::errors::ParquetError),
+
+    #[error("invalid parquet file schema: {0}")]
+    ParquetSchema(#[from] schema::Error),
+
+    #[error("a catalog with {0} databases already exists and won't be replaced")]
+    CatalogExists(usize),
+
+    #[error("no writes were found in the {0} segments of the wal")]
+    NoWrites(usize),
+}
+
+pub type Result<T, E = Error> = std::result::Result<T, E>;
+
+/// What was read from the WAL and the persisted segments to recover the catalog, and what it
+/// contained
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub struct RecoverySummary {
+    pub segments: usize,
+    pub writes: usize,
+    /// Writes that couldn't be applied to the catalog, e.g. because of an invalid database name
+    pub skipped_writes: usize,
+    pub persisted_segments: usize,
+    /// Tables of persisted segments whose schema couldn't be read from their parquet files
+    pub skipped_tables: usize,
+    pub databases: usize,
+    pub tables: usize,
+}
+
+/// Returns the catalog inferred from the writes in the most recent `recent_segments` segments of
+/// the WAL, or all of them if `None`, and from the tables of the segments persisted to the object
+/// store. It is an error for the WAL to have no writes in it.
+pub async fn infer_catalog(
+    wal: &impl Wal,
+    object_store: Arc<dyn ObjectStore>,
+    recent_segments: Option<usize>,
+) -> Result<(Catalog, RecoverySummary)> {
+    let catalog = Catalog::new();
+    let mut summary = RecoverySummary::default();
+
+    let segment_files = wal.segment_files()?;
+    let skip = recent_segments.map_or(0, |n| segment_files.len().saturating_sub(n));
+
+    for segment_file in segment_files.into_iter().skip(skip) {
+        let mut reader = wal.open_segment_reader(segment_file.segment_id)?;
+        let segment_duration = SegmentDuration::from_range(reader.header().range);
+        summary.segments += 1;
+
+        while let Some(batch) = reader.next_batch()? {
+            for op in batch.ops {
+                let WalOp::LpWrite(write) = op;
+                summary.writes += 1;
+
+                let result = NamespaceName::new(write.db_name.clone())
+                    .map_err(write_buffer::Error::from)
+                    .and_then(|db_name| {
+                        parse_validate_and_update_catalog(
+                            db_name,
+                            &write.lp,
+                            &catalog,
+                            Time::from_timestamp_nanos(write.default_time),
+                            segment_duration,
+                            true,
+                            write.precision,
+                        )
+                    });
+                if let Err(e) = result {
+                    warn!(
+                        segment_id = segment_file.segment_id.0,
+                        db_name = write.db_name,
+                        error = %e,
+                        "skipping write that couldn't be applied to the recovered catalog"
+                    );
+                    summary.skipped_writes += 1;
+                }
+            }
+        }
+    }
+    // an empty WAL is most likely the wrong directory, which shouldn't produce an empty catalog
+    if summary.writes == 0 {
+        return Err(Error::NoWrites(summary.segments));
+    }
+
+    add_persisted_tables(&catalog, object_store, &mut summary).await?;
+
+    let databases = catalog.list_databases();
+    summary.databases = databases.len();
+    summary.tables = databases
+        .iter()
+        .filter_map(|name| catalog.db_schema(name))
+        .map(|db| db.tables.len())
+        .sum();
+
+    Ok((catalog, summary))
+}
+
+/// Adds the tables of every persisted segment to the catalog, with the columns of the most recent
+/// parquet file of each table. Columns that are already in the catalog, from the writes in the
+/// WAL, keep their type.
+async fn add_persisted_tables(
+    catalog: &Catalog,
+    object_store: Arc<dyn ObjectStore>,
+    summary: &mut RecoverySummary,
+) -> Result<()> {
+    let persister = PersisterImpl::new(Arc::clone(&object_store));
+    // segments are loaded most recent first, so the first file seen for a table is its newest
+    let persisted_segments = persister.load_segments(usize::MAX).await?;
+    summary.persisted_segments = persisted_segments.len();
+
+    let mut seen = HashSet::new();
+    for segment in persisted_segments {
+        for (db_name, db_tables) in segment.databases {
+            for (table_name, table) in db_tables.tables {
+                let Some(parquet_file) = table.parquet_files.last() else {
+                    continue;
+                };
+                if !seen.insert((db_name.clone(), table_name.clone())) {
+                    continue;
+                }
+
+                match parquet_file_columns(object_store.as_ref(), &parquet_file.path).await {
+                    Ok(columns) => add_table_columns(catalog, &db_name, &table_name, columns)?,
+                    Err(e) => {
+                        warn!(
+                            segment_id = segment.segment_id.0,
+                            db_name,
+                            table_name,
+                            path = parquet_file.path,
+                            error = %e,
+                            "skipping persisted table whose schema couldn't be read"
+                        );
+                        summary.skipped_tables += 1;
+                    }
+                }
+            }
+        }
+    }
+
+    Ok(())
+}
+
+/// The columns of the parquet file, read from the schema in its metadata
+async fn parquet_file_columns(
+    object_store: &dyn ObjectStore,
+    path: &str,
+) -> Result<BTreeMap<String, i16>> {
+    let path = ObjPath::parse(path).map_err(|_| Error::InvalidParquetFilePath(path.to_string()))?;
+    let bytes = object_store.get(&path).await?.bytes().await?;
+    let arrow_schema = Arc::clone(ParquetRecordBatchReaderBuilder::try_new(bytes)?.schema());
+    let schema = Schema::try_from(arrow_schema)?;
+
+    Ok(schema
+        .iter()
+        .map(|(column_type, field)| (field.name().clone(), catalog_column_type(column_type)))
+        .collect())
+}
+
+fn catalog_column_type(column_type: InfluxColumnType) -> i16 {
+    let column_type = match column_type {
+        InfluxColumnType::Tag => ColumnType::Tag,
+        InfluxColumnType::Timestamp => ColumnType::Time,
+        InfluxColumnType::Field(InfluxFieldType::Float) => ColumnType::F64,
+        InfluxColumnType::Field(InfluxFieldType::Integer) => ColumnType::I64,
+        InfluxColumnType::Field(InfluxFieldType::UInteger) => ColumnType::U64,
+        InfluxColumnType::Field(InfluxFieldType::String) => ColumnType::String,
+        InfluxColumnType::Field(InfluxFieldType::Boolean) => ColumnType::Bool,
+    };
+    column_type as i16
+}
+
+fn add_table_columns(
+    catalog: &Catalog,
+    db_name: &str,
+    table_name: &str,
+    columns: BTreeMap<String, i16>,
+) -> Result<()> {
+    let (sequence, db) = catalog.db_or_create(db_name)?;
+    let mut db = DatabaseSchema::clone(&db);
+    match db.tables.get_mut(table_name) {
+        Some(table) => {
+            let new_columns: Vec<_> = columns
+                .into_iter()
+                .filter(|(name, _)| !table.column_exists(name))
+                .collect();
+            if new_columns.is_empty() {
+                return Ok(());
+            }
+            table.add_columns(new_columns);
+        }
+        None => {
+            db.tables.insert(
+                table_name.to_string(),
+                TableDefinition::new(table_name, columns),
+            );
+        }
+    }
+
+    catalog.replace_database(sequence, Arc::new(db))?;
+    Ok(())
+}
+
+/// Recovers the catalog from the WAL and the persisted segments, as in [`infer_catalog`], and
+/// persists it to the object store. The object store must not already have a catalog with any
+/// databases in it.
+pub async fn recover_catalog(
+    wal: &impl Wal,
+    object_store: Arc<dyn ObjectStore>,
+    recent_segments: Option<usize>,
+) -> Result<RecoverySummary> {
+    let persister = PersisterImpl::new(Arc::clone(&object_store));
+    let persisted_catalog = persister.load_catalog().await?;
+    if let Some(persisted_catalog) = &persisted_catalog {
+        let databases = persisted_catalog.catalog.database_count();
+        if databases > 0 {
+            return Err(Error::CatalogExists(databases));
+        }
+    }
+
+    let (catalog, summary) = infer_catalog(wal, object_store, recent_segments).await?;
+
+    // the catalog is persisted with the last persisted segment, so that it's the one loaded on
+    // start, and is replaced when the next segment is persisted
+    let segment_id = persister
+        .load_segments(1)
+        .await?
+        .first()
+        .map(|s| s.segment_id)
+        .max(persisted_catalog.map(|c| c.segment_id))
+        .unwrap_or(SegmentId::new(0));
+    persister.persist_catalog(segment_id, catalog).await?;
+
+    info!(
+        segments = summary.segments,
+        writes = summary.writes,
+        skipped_writes = summary.skipped_writes,
+        persisted_segments = summary.persisted_segments,
+        skipped_tables = summary.skipped_tables,
+        databases = summary.databases,
+        tables = summary.tables,
+        "recovered catalog from wal"
+    );
+    Ok(summary)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::paths::ParquetFilePath;
+    use crate::wal::WalImpl;
+    use crate::{
+        DatabaseTables, LpWriteOp, ParquetFile, PersistedSegment, Precision, SegmentRange,
+        TableParquetFiles,
+    };
+    use arrow::array::{ArrayRef, DictionaryArray, Float64Array, TimestampNanosecondArray};
+    use arrow::datatypes::Int32Type;
+    use arrow::record_batch::RecordBatch;
+    use datafusion_util::stream_from_batches;
+    use object_store::memory::InMemory;
+    use pretty_assertions::assert_eq;
+    use std::collections::HashMap;
+
+    fn write(db_name: &str, lp: &str) -> WalOp {
+        WalOp::LpWrite(LpWriteOp {
+            db_name: db_name.to_string(),
+            lp: lp.to_string(),
+            default_time: 0,
+            precision: Precision::Nanosecond,
+        })
+    }
+
+    /// Persists a segment with a parquet file of a single row for each of the tables, which have
+    /// tags, float fields and the time column
+    async fn persist_segment(
+        persister: &PersisterImpl,
+        segment_id: SegmentId,
+        tables: Vec<(&str, &str, Vec<(&str, ColumnType)>)>,
+    ) {
+        let mut databases: HashMap<String, DatabaseTables> = HashMap::new();
+        let table_count = tables.len() as u64;
+        for (db_name, table_name, columns) in tables {
+            let columns: BTreeMap<String, i16> = columns
+                .into_iter()
+                .map(|(name, column_type)| (name.to_string(), column_type as i16))
+                .collect();
+            let table = TableDefinition::new(table_name, columns);
+            let arrays: Vec<ArrayRef> = table
+                .schema()
+                .iter()
+                .map(|(column_type, _)| match column_type {
+                    InfluxColumnType::Tag => {
+                        Arc::new(DictionaryArray::<Int32Type>::from_iter(["a"])) as ArrayRef
+                    }
+                    InfluxColumnType::Timestamp => {
+                        Arc::new(TimestampNanosecondArray::from(vec![10]))
+                    }
+                    _ => Arc::new(Float64Array::from(vec![0.5])),
+                })
+                .collect();
+            let batch = RecordBatch::try_new(table.schema().as_arrow(), arrays).unwrap();
+
+            let path = ParquetFilePath::new(db_name, table_name, chrono::Utc::now(), segment_id.0);
+            let (size_bytes, _) = persister
+                .persist_parquet_file(
+                    path.clone(),
+                    stream_from_batches(table.schema().as_arrow(), vec![batch]),
+                )
+                .await
+                .unwrap();
+            databases
+                .entry(db_name.to_string())
+                .or_default()
+                .tables
+                .insert(
+                    table_name.to_string(),
+                    TableParquetFiles {
+                        table_name: table_name.to_string(),
+                        parquet_files: vec![ParquetFile {
+                            path: path.to_string(),
+                            size_bytes,
+                            row_count: 1,
+                            min_time: 10,
+                            max_time: 10,
+                        }],
+                        sort_key: vec![],
+                    },
+                );
+        }
+
+        persister
+            .persist_segment(&PersistedSegment {
+                segment_id,
+                segment_wal_size_bytes: 0,
+                segment_parquet_size_bytes: 0,
+                segment_row_count: table_count,
+                segment_min_time: 10,
+                segment_max_time: 10,
+                databases,
+            })
+            .await
+            .unwrap();
+    }
+
+    #[tokio::test]
+    async fn recovers_catalog_from_wal() {
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let wal = WalImpl::new(dir).unwrap();
+
+        let mut segment = wal
+            .new_segment_writer(SegmentId::new(1), SegmentRange::test_range())
+            .unwrap();
+        segment
+            .write_batch(vec![
+                write("foo", "cpu,host=a usage=0.5 10"),
+                write("bar", "mem free=10u 10"),
+            ])
+            .unwrap();
+        let mut segment = wal
+            .new_segment_writer(SegmentId::new(2), SegmentRange::test_range().next())
+            .unwrap();
+        segment
+            .write_batch(vec![
+                write("foo", "cpu,host=a,region=us usage=0.7 20\ndisk used=1i 20"),
+                // an invalid database name
+                write("", "mem free=1u 20"),
+            ])
+            .unwrap();
+
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let summary = recover_catalog(&wal, Arc::clone(&object_store), None)
+            .await
+            .unwrap();
+        assert_eq!(
+            summary,
+            RecoverySummary {
+                segments: 2,
+                writes: 4,
+                skipped_writes: 1,
+                persisted_segments: 0,
+                skipped_tables: 0,
+                databases: 2,
+                tables: 3,
+            }
+        );
+
+        let persister = PersisterImpl::new(Arc::clone(&object_store));
+        let catalog = Catalog::from_inner(persister.load_catalog().await.unwrap().unwrap().catalog);
+        let foo = catalog.db_schema("foo").unwrap();
+        assert_eq!(foo.table_names(), vec!["cpu", "disk"]);
+        assert_eq!(
+            foo.get_table("cpu").unwrap().index_columns(),
+            vec!["host", "region"]
+        );
+        assert_eq!(catalog.db_schema("bar").unwrap().table_names(), vec!["mem"]);
+
+        // only the most recent segment is read when limited to it
+        let (catalog, summary) = infer_catalog(&wal, Arc::new(InMemory::new()), Some(1))
+            .await
+            .unwrap();
+        assert_eq!(summary.segments, 1);
+        assert_eq!(catalog.list_databases(), vec!["foo"]);
+
+        // a catalog with databases in it isn't replaced
+        assert!(matches!(
+            recover_catalog(&wal, object_store, None).await,
+            Err(Error::CatalogExists(2))
+        ));
+    }
+
+    #[tokio::test]
+    async fn recovers_tables_of_persisted_segments() {
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let wal = WalImpl::new(dir).unwrap();
+        let mut segment = wal
+            .new_segment_writer(SegmentId::new(2), SegmentRange::test_range())
+            .unwrap();
+        segment
+            .write_batch(vec![write("foo", "cpu,host=a usage=0.5 10")])
+            .unwrap();
+
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = PersisterImpl::new(Arc::clone(&object_store));
+        persist_segment(
+            &persister,
+            SegmentId::new(0),
+            vec![
+                (
+                    "foo",
+                    "cpu",
+                    vec![("host", ColumnType::Tag), ("time", ColumnType::Time)],
+                ),
+                (
+                    "baz",
+                    "mem",
+                    vec![("free", ColumnType::F64), ("time", ColumnType::Time)],
+                ),
+            ],
+        )
+        .await;
+        // the newest file of a table has all of its columns
+        persist_segment(
+            &persister,
+            SegmentId::new(1),
+            vec![(
+                "foo",
+                "cpu",
+                vec![
+                    ("dc", ColumnType::Tag),
+                    ("host", ColumnType::Tag),
+                    ("idle", ColumnType::F64),
+                    ("time", ColumnType::Time),
+                ],
+            )],
+        )
+        .await;
+
+        let (catalog, summary) = infer_catalog(&wal, object_store, None).await.unwrap();
+
+        assert_eq!(summary.persisted_segments, 2);
+        assert_eq!(summary.skipped_tables, 0);
+        assert_eq!(summary.databases, 2);
+        assert_eq!(summary.tables, 2);
+        let cpu = catalog.db_schema("foo").unwrap();
+        let cpu = cpu.get_table("cpu").unwrap();
+        assert_eq!(
+            cpu.columns(),
+            &BTreeMap::from([
+                ("dc".to_string(), ColumnType::Tag as i16),
+                ("host".to_string(), ColumnType::Tag as i16),
+                ("idle".to_string(), ColumnType::F64 as i16),
+                ("time".to_string(), ColumnType::Time as i16),
+                ("usage".to_string(), ColumnType::F64 as i16),
+            ])
+        );
+        assert_eq!(cpu.index_columns(), vec!["dc", "host"]);
+        assert_eq!(catalog.db_schema("baz").unwrap().table_names(), vec!["mem"]);
+    }
+
+    #[tokio::test]
+    async fn fails_without_writes_in_the_wal() {
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let wal = WalImpl::new(dir).unwrap();
+
+        assert!(matches!(
+            infer_catalog(&wal, Arc::new(InMemory::new()), None).await,
+            Err(Error::NoWrites(0))
+        ));
+    }
+}
@@ -1,10 +1,10 @@
 //! This is the implementation of the `Wal` that the buffer uses to make buffered data durable
 //! on disk.
 
-use crate::paths::SegmentWalFilePath;
+use crate::paths::{BufferSnapshotFilePath, SegmentWalFilePath, BUFFER_SNAPSHOT_FILE_EXTENSION};
 use crate::{
-    SegmentFile, SegmentId, SegmentRange, SequenceNumber, Wal, WalOp, WalOpBatch, WalSegmentReader,
-    WalSegmentWriter,
+    SegmentFile, SegmentId, SegmentRange, SequenceNumber, Wal, WalOp, WalOpBatch, WalOpBatchHeader,
+    WalSegmentReader, WalSegmentWriter,
 };
 use async_trait::async_trait;
 use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
@@ -24,9 +24,25 @@ use std::{
 };
 use thiserror::Error;
 
+pub use self::codec::{BatchCodec, BinaryCodec, JsonCodec, WalCodec};
+
+mod codec;
+
 /// The first bytes written into a segment file to identify it and its version.
 type FileTypeIdentifier = [u8; 8];
-const FILE_TYPE_IDENTIFIER: &[u8] = b"idb3.001";
+const FILE_TYPE_IDENTIFIER: &[u8] = b"idb3.002";
+/// The identifier of segment files written before batches had headers, which are still read so
+/// that their unpersisted writes are replayed.
+const V1_FILE_TYPE_IDENTIFIER: &[u8] = b"idb3.001";
+
+/// The layout of the batches in a segment file, given by its file type identifier
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+enum FileVersion {
+    /// `idb3.001`, where each batch is written as a block with no header ahead of it
+    V1,
+    /// `idb3.002`, where each block is preceded by the [`WalOpBatchHeader`] of its batch
+    V2,
+}
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -70,6 +86,19 @@ pub enum Error {
         actual: u32,
     },
 
+    #[error("batch header checksum mismatch for segment {segment_id:?}: expected {expected}, got {actual}")]
+    HeaderChecksumMismatch {
+        segment_id: SegmentId,
+        expected: u32,
+        actual: u32,
+    },
+
+    #[error("invalid wal batch: {0}")]
+    InvalidBatch(String),
+
+    #[error("missing batch data after batch header for segment {segment_id:?}")]
+    MissingBatchData { segment_id: SegmentId },
+
     #[error("invalid segment file name {0:?}")]
     InvalidSegmentFileName(String),
 
@@ -97,6 +126,8 @@ pub type Result<T, E = Error> = std::result::Result<T, E>;
 #[derive(Debug)]
 pub struct WalImpl {
     root: PathBuf,
+    /// The codec used for the batches of new segments
+    codec: WalCodec,
 }
 
 impl WalImpl {
@@ -112,7 +143,17 @@ impl WalImpl {
             .sync_all()
             .expect("fsync failure");
 
-        Ok(Self { root })
+        Ok(Self {
+            root,
+            codec: WalCodec::default(),
+        })
+    }
+
+    /// Write the batches of new segments with the given codec. Existing segments continue to be
+    /// written and read with the codec they were created with.
+    pub fn with_codec(mut self, codec: WalCodec) -> Self {
+        self.codec = codec;
+        self
     }
 
     fn open_segment_reader(&self, segment_id: SegmentId) -> Result<Box<dyn WalSegmentReader>> {
@@ -130,6 +171,12 @@ impl WalImpl {
             let meta = child.metadata()?;
             if meta.is_file() {
                 let path = child.path();
+                if path
+                    .extension()
+                    .is_some_and(|ext| ext == BUFFER_SNAPSHOT_FILE_EXTENSION)
+                {
+                    continue;
+                }
 
                 if let Some(file_name) = path.file_stem() {
                     match file_name.to_str() {
@@ -169,6 +216,32 @@ impl WalImpl {
         std::fs::remove_file(path)?;
         Ok(())
     }
+
+    fn write_snapshot(&self, data: &[u8]) -> Result<()> {
+        let path = BufferSnapshotFilePath::new(self.root.clone());
+
+        // write to a temporary file that is renamed into place, so that a snapshot is never
+        // partially written
+        let tmp_path = path.with_extension("tmp");
+        let mut f = File::create(&tmp_path)?;
+        f.write_all(data)?;
+        f.sync_all()?;
+        std::fs::rename(&tmp_path, &path)?;
+
+        Ok(())
+    }
+
+    fn take_snapshot(&self) -> Result<Option<Vec<u8>>> {
+        let path = BufferSnapshotFilePath::new(self.root.clone());
+        let data = match std::fs::read(&path) {
+            Ok(data) => data,
+            Err(ref e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(e.into()),
+        };
+        std::fs::remove_file(&path)?;
+
+        Ok(Some(data))
+    }
 }
 
 impl Wal for WalImpl {
@@ -177,7 +250,8 @@ impl Wal for WalImpl {
         segment_id: SegmentId,
         range: SegmentRange,
     ) -> Result<Box<dyn WalSegmentWriter>> {
-        let writer = WalSegmentWriterImpl::new(self.root.clone(), segment_id, range)?;
+        let writer =
+            WalSegmentWriterImpl::new_with_codec(self.root.clone(), segment_id, range, self.codec)?;
         Ok(Box::new(writer))
     }
 
@@ -198,6 +272,14 @@ impl Wal for WalImpl {
         self.delete_wal_segment(_segment_id)
     }
 
+    fn write_snapshot(&self, data: &[u8]) -> Result<()> {
+        self.write_snapshot(data)
+    }
+
+    fn take_snapshot(&self) -> Result<Option<Vec<u8>>> {
+        self.take_snapshot()
+    }
+
     fn as_any(&self) -> &dyn Any {
         self as &dyn Any
     }
@@ -207,6 +289,9 @@ impl Wal for WalImpl {
 pub struct SegmentHeader {
     pub id: SegmentId,
     pub range: SegmentRange,
+    /// Segments written before the codec was recorded in the header use JSON
+    #[serde(default)]
+    pub codec: WalCodec,
 }
 
 #[derive(Debug)]
@@ -215,12 +300,23 @@ pub struct WalSegmentWriterImpl {
     f: File,
     bytes_written: usize,
     sequence_number: SequenceNumber,
+    codec: WalCodec,
+    version: FileVersion,
 
     buffer: Vec<u8>,
 }
 
 impl WalSegmentWriterImpl {
     pub fn new(root: PathBuf, segment_id: SegmentId, range: SegmentRange) -> Result<Self> {
+        Self::new_with_codec(root, segment_id, range, WalCodec::default())
+    }
+
+    pub fn new_with_codec(
+        root: PathBuf,
+        segment_id: SegmentId,
+        range: SegmentRange,
+        codec: WalCodec,
+    ) -> Result<Self> {
         let path = SegmentWalFilePath::new(root, segment_id);
 
         // if there's already a file there, error out
@@ -241,6 +337,7 @@ impl WalSegmentWriterImpl {
         let header = SegmentHeader {
             id: segment_id,
             range,
+            codec,
         };
 
         let header_bytes = serde_json::to_vec(&header)?;
@@ -261,6 +358,8 @@ impl WalSegmentWriterImpl {
             f,
             bytes_written,
             sequence_number: SequenceNumber::new(0),
+            codec,
+            version: FileVersion::V2,
             buffer: Vec::with_capacity(8 * 1204), // 8kiB initial size
         })
     }
@@ -280,6 +379,9 @@ impl WalSegmentWriterImpl {
                     .try_into()
                     .expect("file length must fit in usize"),
                 sequence_number: file_info.last_sequence_number,
+                codec: file_info.codec,
+                // batches are appended in the layout of the file, so that it can be read back
+                version: file_info.version,
                 buffer: Vec::with_capacity(8 * 1204), // 8kiB initial size
             })
         } else {
@@ -293,14 +395,22 @@ impl WalSegmentWriterImpl {
 
         let sequence_number = self.sequence_number.next();
 
+        let header = match self.version {
+            FileVersion::V1 => None,
+            FileVersion::V2 => Some(serde_json::to_vec(&WalOpBatchHeader::new(
+                sequence_number,
+                &ops,
+            ))?),
+        };
+
         let batch = WalOpBatch {
             sequence_number,
             ops,
         };
 
-        let data = serde_json::to_vec(&batch)?;
+        let data = self.codec.codec().encode(&batch)?;
 
-        let bytes_written = self.write_bytes(data)?;
+        let bytes_written = self.write_bytes(header, data)?;
 
         self.bytes_written += bytes_written;
         self.sequence_number = sequence_number;
@@ -308,11 +418,29 @@ impl WalSegmentWriterImpl {
         Ok(())
     }
 
-    fn write_bytes(&mut self, data: Vec<u8>) -> Result<usize> {
+    fn write_bytes(&mut self, header: Option<Vec<u8>>, data: Vec<u8>) -> Result<usize> {
         // Only designed to support chunks up to `u32::max` bytes long.
         let uncompressed_len = data.len();
         u32::try_from(uncompressed_len)?;
 
+        // The batch header is written uncompressed ahead of the chunk, prefixed with its
+        // length and crc, so that readers can inspect it without touching the payload.
+        if let Some(header) = header {
+            // A batch for many databases has a header listing all of their names, so its
+            // length isn't bounded by a u16.
+            let header_len = u32::try_from(header.len())?;
+            self.buffer
+                .write_u32::<BigEndian>(header_len)
+                .expect("cannot fail to write to buffer");
+            self.buffer
+                .write_u32::<BigEndian>(crc32fast::hash(&header))
+                .expect("cannot fail to write to buffer");
+            self.buffer
+                .write_all(&header)
+                .expect("cannot fail to write to buffer");
+        }
+        let chunk_start = self.buffer.len();
+
         // The chunk header is two u32 values, so write a dummy u64 value and
         // come back to fill them in later.
         self.buffer
@@ -328,14 +456,14 @@ impl WalSegmentWriterImpl {
             .expect("cannot fail to flush to a Vec")
             .finalize();
 
-        // Adjust the compressed length to take into account the u64 padding
-        // above.
-        let compressed_len = buf.len() - mem::size_of::<u64>();
+        // Adjust the compressed length to take into account the batch header
+        // and the u64 padding above.
+        let compressed_len = buf.len() - chunk_start - mem::size_of::<u64>();
         let compressed_len = u32::try_from(compressed_len)?;
 
         // Go back and write the chunk header values
         let mut buf = Cursor::new(buf);
-        buf.set_position(0);
+        buf.set_position(chunk_start as u64);
 
         buf.write_u32::<BigEndian>(checksum)?;
         buf.write_u32::<BigEndian>(compressed_len)?;
@@ -414,6 +542,7 @@ pub struct WalSegmentReaderImpl {
     f: BufReader<File>,
     path: SegmentWalFilePath,
     segment_header: SegmentHeader,
+    version: FileVersion,
 }
 
 impl WalSegmentReaderImpl {
@@ -421,7 +550,7 @@ impl WalSegmentReaderImpl {
         let path = SegmentWalFilePath::new(root, segment_id);
         let mut f = BufReader::new(File::open(path.clone())?);
 
-        let segment_header = read_header(&path, &mut f)?;
+        let (segment_header, version) = read_header(&path, &mut f)?;
 
         if segment_id != segment_header.id {
             return Err(Error::InvalidSegmentFile {
@@ -437,6 +566,7 @@ impl WalSegmentReaderImpl {
             f,
             path,
             segment_header,
+            version,
         };
 
         Ok(reader)
@@ -454,43 +584,121 @@ impl WalSegmentReaderImpl {
         let bytes_written = f.len().try_into()?;
 
         let mut f = BufReader::new(f);
-        let segment_header = read_header(&path, &mut f)?;
+        let (segment_header, version) = read_header(&path, &mut f)?;
 
         let mut reader = Self {
             f,
             path,
             segment_header,
+            version,
         };
 
-        let mut last_block = None;
+        // only the batch headers are needed to find the last sequence number, so the
+        // payloads are skipped over rather than decompressed, except in v1 files, which
+        // have no batch headers
+        let mut last_sequence_number = SequenceNumber::new(0);
 
-        while let Some(block) = reader.next_segment_block()? {
-            last_block = Some(block);
+        match reader.version {
+            FileVersion::V1 => {
+                while let Some(batch) = reader.next_batch()? {
+                    last_sequence_number = batch.sequence_number;
+                }
+            }
+            FileVersion::V2 => {
+                while let Some(header) = reader.next_batch_header()? {
+                    reader.skip_segment_block()?;
+                    last_sequence_number = header.sequence_number;
+                }
+            }
         }
 
-        if let Some(block) = last_block {
-            let batch: WalOpBatch = serde_json::from_slice(&block)?;
+        Ok(Some(ExistingSegmentFileInfo {
+            last_sequence_number,
+            bytes_written,
+            codec: reader.segment_header.codec,
+            version: reader.version,
+        }))
+    }
 
-            Ok(Some(ExistingSegmentFileInfo {
-                last_sequence_number: batch.sequence_number,
-                bytes_written,
-            }))
-        } else {
-            Ok(Some(ExistingSegmentFileInfo {
-                last_sequence_number: SequenceNumber::new(0),
-                bytes_written,
-            }))
+    pub fn next_batch(&mut self) -> Result<Option<WalOpBatch>> {
+        self.next_batch_matching(&|_| true)
+    }
+
+    /// Reads batches until one is found whose header matches the filter. Batches that don't match
+    /// are skipped without being decompressed.
+    pub fn next_batch_matching(
+        &mut self,
+        filter: &dyn Fn(&WalOpBatchHeader) -> bool,
+    ) -> Result<Option<WalOpBatch>> {
+        if self.version == FileVersion::V1 {
+            return self.next_v1_batch_matching(filter);
+        }
+
+        while let Some(header) = self.next_batch_header()? {
+            if !filter(&header) {
+                self.skip_segment_block()?;
+                continue;
+            }
+
+            let Some(data) = self.next_segment_block()? else {
+                return Err(Error::MissingBatchData {
+                    segment_id: self.segment_header.id,
+                });
+            };
+            let batch = self.segment_header.codec.codec().decode(&data)?;
+
+            return Ok(Some(batch));
         }
+
+        Ok(None)
     }
 
-    pub fn next_batch(&mut self) -> Result<Option<WalOpBatch>> {
-        if let Some(data) = self.next_segment_block()? {
-            let batch: WalOpBatch = serde_json::from_slice(&data)?;
+    /// Reads batches of a v1 file until one matches the filter. The batches have no headers, so
+    /// each is decoded to check it against the header it would have been written with.
+    fn next_v1_batch_matching(
+        &mut self,
+        filter: &dyn Fn(&WalOpBatchHeader) -> bool,
+    ) -> Result<Option<WalOpBatch>> {
+        while let Some(data) = self.next_segment_block()? {
+            let batch: WalOpBatch = self.segment_header.codec.codec().decode(&data)?;
+            if filter(&WalOpBatchHeader::new(batch.sequence_number, &batch.ops)) {
+                return Ok(Some(batch));
+            }
+        }
 
-            Ok(Some(batch))
-        } else {
-            Ok(None)
+        Ok(None)
+    }
+
+    fn next_batch_header(&mut self) -> Result<Option<WalOpBatchHeader>> {
+        let len = match self.f.read_u32::<BigEndian>() {
+            Err(ref e) if e.kind() == io::ErrorKind::UnexpectedEof => return Ok(None),
+            other => other?,
+        };
+        let expected_checksum = self.f.read_u32::<BigEndian>()?;
+
+        let mut data = vec![0u8; len as usize];
+        self.f.read_exact(&mut data)?;
+
+        let actual_checksum = crc32fast::hash(&data);
+        if expected_checksum != actual_checksum {
+            return Err(Error::HeaderChecksumMismatch {
+                segment_id: self.segment_header.id,
+                expected: expected_checksum,
+                actual: actual_checksum,
+            });
         }
+
+        let header: WalOpBatchHeader = serde_json::from_slice(&data)?;
+
+        Ok(Some(header))
+    }
+
+    fn skip_segment_block(&mut self) -> Result<()> {
+        let _checksum = self.f.read_u32::<BigEndian>()?;
+        let len = self.f.read_u32::<BigEndian>()?;
+        self.f.seek_relative(len.into())?;
+
+        Ok(())
     }
 
     fn next_segment_block(&mut self) -> Result<Option<Vec<u8>>> {
@@ -532,25 +740,32 @@ impl WalSegmentReaderImpl {
     }
 }
 
-fn read_header(path: &SegmentWalFilePath, f: &mut BufReader<File>) -> Result<SegmentHeader> {
+fn read_header(
+    path: &SegmentWalFilePath,
+    f: &mut BufReader<File>,
+) -> Result<(SegmentHeader, FileVersion)> {
     let file_type: FileTypeIdentifier = read_array(f)?;
 
-    if file_type != FILE_TYPE_IDENTIFIER {
-        return Err(Error::InvalidSegmentFile {
-            path: path.to_path_buf(),
-            reason: format!(
-                "expected file type identifier {:?}, got {:?}",
-                FILE_TYPE_IDENTIFIER, file_type
-            ),
-        });
-    }
+    let version = match file_type.as_slice() {
+        FILE_TYPE_IDENTIFIER => FileVersion::V2,
+        V1_FILE_TYPE_IDENTIFIER => FileVersion::V1,
+        _ => {
+            return Err(Error::InvalidSegmentFile {
+                path: path.to_path_buf(),
+                reason: format!(
+                    "expected file type identifier {:?} or {:?}, got {:?}",
+                    FILE_TYPE_IDENTIFIER, V1_FILE_TYPE_IDENTIFIER, file_type
+                ),
+            })
+        }
+    };
 
     let len = f.read_u16::<BigEndian>()?;
     let mut data = vec![0u8; len.into()];
     f.read_exact(&mut data)?;
     let header: SegmentHeader = serde_json::from_slice(&data)?;
 
-    Ok(header)
+    Ok((header, version))
 }
 
 fn read_array<const N: usize>(f: &mut BufReader<File>) -> Result<[u8; N]> {
@@ -562,6 +777,8 @@ fn read_array<const N: usize>(f: &mut BufReader<File>) -> Result<[u8; N]> {
 struct ExistingSegmentFileInfo {
     last_sequence_number: SequenceNumber,
     bytes_written: u32,
+    codec: WalCodec,
+    version: FileVersion,
 }
 
 impl WalSegmentReader for WalSegmentReaderImpl {
@@ -569,6 +786,13 @@ impl WalSegmentReader for WalSegmentReaderImpl {
         self.next_batch()
     }
 
+    fn next_batch_matching(
+        &mut self,
+        filter: &dyn Fn(&WalOpBatchHeader) -> bool,
+    ) -> Result<Option<WalOpBatch>> {
+        self.next_batch_matching(filter)
+    }
+
     fn header(&self) -> &SegmentHeader {
         &self.segment_header
     }
@@ -663,6 +887,7 @@ mod tests {
     use crate::catalog::Catalog;
     use crate::LpWriteOp;
     use crate::Precision;
+    use std::path::Path;
     use std::sync::Arc;
 
     #[test]
@@ -727,6 +952,173 @@ mod tests {
         assert_eq!(batch.sequence_number, SequenceNumber::new(2));
     }
 
+    #[test]
+    fn segment_reader_skips_batches_not_matching_header_filter() {
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let foo_op = WalOp::LpWrite(LpWriteOp {
+            db_name: "foo".to_string(),
+            lp: "cpu host=a val=10i 10".to_string(),
+            default_time: 1,
+            precision: Precision::Nanosecond,
+        });
+        let bar_op = WalOp::LpWrite(LpWriteOp {
+            db_name: "bar".to_string(),
+            lp: "mem host=b val=20i 20".to_string(),
+            default_time: 1,
+            precision: Precision::Nanosecond,
+        });
+
+        let mut writer =
+            WalSegmentWriterImpl::new(dir.clone(), SegmentId::new(0), SegmentRange::test_range())
+                .unwrap();
+        writer.write_batch(vec![foo_op.clone()]).unwrap();
+        writer.write_batch(vec![bar_op.clone()]).unwrap();
+        writer
+            .write_batch(vec![bar_op.clone(), foo_op.clone()])
+            .unwrap();
+
+        let mut reader = WalSegmentReaderImpl::new(dir.clone(), SegmentId::new(0)).unwrap();
+        let filter = |header: &WalOpBatchHeader| header.contains_db("bar");
+
+        let batch = reader.next_batch_matching(&filter).unwrap().unwrap();
+        assert_eq!(batch.sequence_number, SequenceNumber::new(2));
+        assert_eq!(batch.ops, vec![bar_op.clone()]);
+
+        let batch = reader.next_batch_matching(&filter).unwrap().unwrap();
+        assert_eq!(batch.sequence_number, SequenceNumber::new(3));
+        assert_eq!(batch.ops, vec![bar_op, foo_op]);
+
+        assert!(reader.next_batch_matching(&filter).unwrap().is_none());
+
+        // the last sequence number is recovered from the batch headers alone
+        let writer = WalSegmentWriterImpl::open(dir, SegmentId::new(0)).unwrap();
+        assert_eq!(writer.sequence_number, SequenceNumber::new(3));
+    }
+
+    #[test]
+    fn segment_writer_writes_batch_headers_longer_than_u16() {
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let ops: Vec<WalOp> = (0..5_000)
+            .map(|i| {
+                WalOp::LpWrite(LpWriteOp {
+                    db_name: format!("database_with_a_long_name_{i}"),
+                    lp: "cpu host=a val=10i 10".to_string(),
+                    default_time: 1,
+                    precision: Precision::Nanosecond,
+                })
+            })
+            .collect();
+        let header =
+            serde_json::to_vec(&WalOpBatchHeader::new(SequenceNumber::new(1), &ops)).unwrap();
+        assert!(header.len() > u16::MAX as usize);
+
+        let mut writer =
+            WalSegmentWriterImpl::new(dir.clone(), SegmentId::new(0), SegmentRange::test_range())
+                .unwrap();
+        writer.write_batch(ops.clone()).unwrap();
+
+        let mut reader = WalSegmentReaderImpl::new(dir, SegmentId::new(0)).unwrap();
+        let batch = reader
+            .next_batch_matching(&|header| header.contains_db("database_with_a_long_name_4999"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.sequence_number, SequenceNumber::new(1));
+        assert_eq!(batch.ops, ops);
+    }
+
+    /// Writes a segment file in the `idb3.001` layout, where the segment header has no codec and
+    /// the JSON batches are written without batch headers
+    fn write_v1_segment(dir: &Path, segment_id: SegmentId, batches: &[WalOpBatch]) {
+        let mut f = File::create(SegmentWalFilePath::new(dir.to_path_buf(), segment_id)).unwrap();
+        f.write_all(V1_FILE_TYPE_IDENTIFIER).unwrap();
+
+        let mut header = serde_json::to_value(SegmentHeader {
+            id: segment_id,
+            range: SegmentRange::test_range(),
+            codec: WalCodec::Json,
+        })
+        .unwrap();
+        header.as_object_mut().unwrap().remove("codec");
+        let header = serde_json::to_vec(&header).unwrap();
+        f.write_u16::<BigEndian>(header.len().try_into().unwrap())
+            .unwrap();
+        f.write_all(&header).unwrap();
+
+        for batch in batches {
+            let mut encoder = snap::write::FrameEncoder::new(Vec::new());
+            encoder
+                .write_all(&serde_json::to_vec(batch).unwrap())
+                .unwrap();
+            let compressed = encoder.into_inner().unwrap();
+            f.write_u32::<BigEndian>(crc32fast::hash(&compressed))
+                .unwrap();
+            f.write_u32::<BigEndian>(compressed.len().try_into().unwrap())
+                .unwrap();
+            f.write_all(&compressed).unwrap();
+        }
+    }
+
+    #[test]
+    fn v1_segments_are_read_and_appended_to() {
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let foo_op = WalOp::LpWrite(LpWriteOp {
+            db_name: "foo".to_string(),
+            lp: "cpu host=a val=10i 10".to_string(),
+            default_time: 1,
+            precision: Precision::Nanosecond,
+        });
+        let bar_op = WalOp::LpWrite(LpWriteOp {
+            db_name: "bar".to_string(),
+            lp: "mem host=b val=20i 20".to_string(),
+            default_time: 1,
+            precision: Precision::Nanosecond,
+        });
+        write_v1_segment(
+            &dir,
+            SegmentId::new(0),
+            &[
+                WalOpBatch {
+                    sequence_number: SequenceNumber::new(1),
+                    ops: vec![foo_op.clone()],
+                },
+                WalOpBatch {
+                    sequence_number: SequenceNumber::new(2),
+                    ops: vec![bar_op.clone()],
+                },
+            ],
+        );
+
+        let wal = WalImpl::new(dir.clone()).unwrap();
+        let mut reader = wal.open_segment_reader(SegmentId::new(0)).unwrap();
+        assert_eq!(reader.header().codec, WalCodec::Json);
+        let batch = reader
+            .next_batch_matching(&|header| header.contains_db("bar"))
+            .unwrap()
+            .unwrap();
+        assert_eq!(batch.sequence_number, SequenceNumber::new(2));
+        assert_eq!(batch.ops, vec![bar_op.clone()]);
+        assert!(reader.next_batch().unwrap().is_none());
+
+        // the writer continues the sequence of the file, in the layout of the file
+        let mut writer = WalSegmentWriterImpl::open(dir.clone(), SegmentId::new(0)).unwrap();
+        assert_eq!(writer.sequence_number, SequenceNumber::new(2));
+        writer.write_batch(vec![foo_op.clone()]).unwrap();
+
+        let mut reader = WalSegmentReaderImpl::new(dir, SegmentId::new(0)).unwrap();
+        let mut batches = vec![];
+        while let Some(batch) = reader.next_batch().unwrap() {
+            batches.push((batch.sequence_number, batch.ops));
+        }
+        assert_eq!(
+            batches,
+            vec![
+                (SequenceNumber::new(1), vec![foo_op.clone()]),
+                (SequenceNumber::new(2), vec![bar_op]),
+                (SequenceNumber::new(3), vec![foo_op]),
+            ]
+        );
+    }
+
     #[test]
     fn wal_can_open_write_and_read_segments() {
         let dir = test_helpers::tmp_dir().unwrap().into_path();
@@ -759,6 +1151,50 @@ mod tests {
         assert_eq!(batch.sequence_number, SequenceNumber::new(1));
     }
 
+    #[test]
+    fn segments_are_read_with_the_codec_they_were_written_with() {
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let wal_op = WalOp::LpWrite(LpWriteOp {
+            db_name: "foo".to_string(),
+            lp: "cpu host=a val=10i 10".to_string(),
+            default_time: 1,
+            precision: Precision::Nanosecond,
+        });
+
+        let json_wal = WalImpl::new(dir.clone()).unwrap();
+        let mut writer = json_wal
+            .new_segment_writer(SegmentId::new(0), SegmentRange::test_range())
+            .unwrap();
+        writer.write_batch(vec![wal_op.clone()]).unwrap();
+
+        let binary_wal = WalImpl::new(dir.clone())
+            .unwrap()
+            .with_codec(WalCodec::Binary);
+        let mut writer = binary_wal
+            .new_segment_writer(SegmentId::new(1), SegmentRange::test_range())
+            .unwrap();
+        writer.write_batch(vec![wal_op.clone()]).unwrap();
+
+        // a reopened segment keeps the codec it was created with
+        let mut writer = binary_wal.open_segment_writer(SegmentId::new(0)).unwrap();
+        writer.write_batch(vec![wal_op.clone()]).unwrap();
+
+        let mut reader = binary_wal.open_segment_reader(SegmentId::new(0)).unwrap();
+        assert_eq!(reader.header().codec, WalCodec::Json);
+        assert_eq!(
+            reader.next_batch().unwrap().unwrap().ops,
+            vec![wal_op.clone()]
+        );
+        let batch = reader.next_batch().unwrap().unwrap();
+        assert_eq!(batch.sequence_number, SequenceNumber::new(2));
+        assert_eq!(batch.ops, vec![wal_op.clone()]);
+
+        let mut reader = json_wal.open_segment_reader(SegmentId::new(1)).unwrap();
+        assert_eq!(reader.header().codec, WalCodec::Binary);
+        assert_eq!(reader.next_batch().unwrap().unwrap().ops, vec![wal_op]);
+        assert!(reader.next_batch().unwrap().is_none());
+    }
+
     #[test]
     fn wal_written_and_read_with_different_precisions() {
         let dir = test_helpers::tmp_dir().unwrap().into_path();
@@ -0,0 +1,248 @@
+//! A blocklist of measurement and column names that applies to writes to every database, so that
+//! operators can stop known-bad agents across the deployment without changing the configuration
+//! of each database.
+//!
+//! Names are matched against glob patterns, where `*` matches any run of characters and `?`
+//! matches a single character. The blocklist is applied before the ingest transforms of the
+//! database, to the names as they were sent.
+
+use crate::write_buffer::parse_numbered_lines;
+use crate::WriteLineError;
+use influxdb_line_protocol::ParsedLine;
+use std::borrow::Cow;
+
+/// What happens to the lines of a write that match the blocklist
+#[derive(Debug, Default, Clone, Copy, PartialEq, Eq)]
+pub enum BlockAction {
+    /// Lines of blocked measurements, and blocked tags and fields, are removed from the write
+    /// without telling the writer
+    #[default]
+    Drop,
+    /// Lines of blocked measurements, or with blocked tags or fields, are rejected as invalid
+    /// lines of the write
+    Reject,
+}
+
+#[derive(Debug, Default, Clone)]
+pub struct Blocklist {
+    measurements: Vec<String>,
+    columns: Vec<String>,
+    action: BlockAction,
+}
+
+impl Blocklist {
+    /// Creates a blocklist of the measurement patterns, and of the column patterns, which match
+    /// the keys of both tags and fields
+    pub fn new(measurements: Vec<String>, columns: Vec<String>, action: BlockAction) -> Self {
+        Self {
+            measurements,
+            columns,
+            action,
+        }
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.measurements.is_empty() && self.columns.is_empty()
+    }
+
+    fn blocked_measurement(&self, name: &str) -> bool {
+        self.measurements
+            .iter()
+            .any(|pattern| glob_match(pattern, name))
+    }
+
+    fn blocked_column(&self, name: &str) -> bool {
+        self.columns.iter().any(|pattern| glob_match(pattern, name))
+    }
+
+    /// Applies the blocklist to every line in the line protocol, returning the line protocol
+    /// that's left along with the lines that were rejected, if the blocklist rejects lines.
+    /// Lines that fail to parse are passed through as they are. Lines that are dropped, rejected
+    /// or left with no fields are blanked out rather than removed, so that every line keeps its
+    /// number.
+    pub(crate) fn apply<'a>(&self, lp: &'a str) -> (Cow<'a, str>, Vec<WriteLineError>) {
+        if self.is_empty() {
+            return (Cow::Borrowed(lp), vec![]);
+        }
+
+        let mut changed = false;
+        let mut out: Vec<Cow<'_, str>> = lp.lines().map(Cow::Borrowed).collect();
+        let mut rejected = vec![];
+        for (line_number, raw_line, maybe_line) in parse_numbered_lines(lp) {
+            let Ok(mut line) = maybe_line else {
+                continue;
+            };
+
+            let Some(blocked) = self.blocked_name(&line) else {
+                continue;
+            };
+            changed = true;
+
+            out[line_number - 1] = match self.action {
+                BlockAction::Reject => {
+                    rejected.push(WriteLineError {
+                        original_line: raw_line.to_string(),
+                        line_number,
+                        error_message: format!("{blocked} is blocked"),
+                    });
+                    Cow::Borrowed("")
+                }
+                BlockAction::Drop if self.blocked_measurement(line.series.measurement.as_str()) => {
+                    Cow::Borrowed("")
+                }
+                BlockAction::Drop => {
+                    self.drop_columns(&mut line);
+                    if line.field_set.is_empty() {
+                        Cow::Borrowed("")
+                    } else {
+                        Cow::Owned(line.to_string())
+                    }
+                }
+            };
+        }
+
+        if changed {
+            (Cow::Owned(out.join("\n")), rejected)
+        } else {
+            (Cow::Borrowed(lp), rejected)
+        }
+    }
+
+    /// Returns a description of the first blocked name in the line, if there is one
+    fn blocked_name(&self, line: &ParsedLine<'_>) -> Option<String> {
+        let measurement = line.series.measurement.as_str();
+        if self.blocked_measurement(measurement) {
+            return Some(format!("measurement {measurement:?}"));
+        }
+
+        let tag_keys = line.series.tag_set.iter().flatten().map(|(key, _)| key);
+        let field_keys = line.field_set.iter().map(|(key, _)| key);
+        tag_keys
+            .chain(field_keys)
+            .map(|key| key.as_str())
+            .find(|key| self.blocked_column(key))
+            .map(|key| format!("column {key:?}"))
+    }
+
+    fn drop_columns(&self, line: &mut ParsedLine<'_>) {
+        if let Some(tag_set) = line.series.tag_set.as_mut() {
+            tag_set.retain(|(key, _)| !self.blocked_column(key.as_str()));
+            if tag_set.is_empty() {
+                line.series.tag_set = None;
+            }
+        }
+        line.field_set
+            .retain(|(key, _)| !self.blocked_column(key.as_str()));
+    }
+}
+
+/// Returns true if the name matches the glob pattern, where `*` matches any run of characters,
+/// including none, and `?` matches any single character
+fn glob_match(pattern: &str, name: &str) -> bool {
+    let pattern: Vec<char> = pattern.chars().collect();
+    let name: Vec<char> = name.chars().collect();
+
+    let (mut p, mut n) = (0, 0);
+    // the position of the last `*` in the pattern, and of the name when it was reached, to
+    // backtrack to when the rest of the pattern doesn't match
+    let mut star = None;
+    while n < name.len() {
+        match pattern.get(p) {
+            Some('*') => {
+                star = Some((p, n));
+                p += 1;
+            }
+            Some(&c) if c == '?' || c == name[n] => {
+                p += 1;
+                n += 1;
+            }
+            _ => match star {
+                Some((star_p, star_n)) => {
+                    p = star_p + 1;
+                    n = star_n + 1;
+                    star = Some((star_p, star_n + 1));
+                }
+                None => return false,
+            },
+        }
+    }
+
+    pattern[p..].iter().all(|&c| c == '*')
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_globs() {
+        assert!(glob_match("cpu", "cpu"));
+        assert!(!glob_match("cpu", "cpu2"));
+        assert!(glob_match("cpu*", "cpu"));
+        assert!(glob_match("cpu*", "cpu_total"));
+        assert!(glob_match("*_debug", "net_debug"));
+        assert!(glob_match("a*b*c", "axxbyyc"));
+        assert!(!glob_match("a*b*c", "axxbyy"));
+        assert!(glob_match("host?", "host1"));
+        assert!(!glob_match("host?", "host"));
+        assert!(glob_match("*", ""));
+    }
+
+    const LP: &str = "cpu,host=a usage=1 10\n\
+                      debug_agent,host=a x=1i 20\n\
+                      mem,host=a,pod_uid=1 used=2i,trace_id=\"t\" 30\n\
+                      disk trace_id=\"t\" 40\n\
+                      not valid lp";
+
+    #[test]
+    fn drops_blocked_measurements_and_columns() {
+        let blocklist = Blocklist::new(
+            vec!["debug_*".to_string()],
+            vec!["trace_id".to_string(), "pod_*".to_string()],
+            BlockAction::Drop,
+        );
+
+        let (lp, rejected) = blocklist.apply(LP);
+
+        // dropped lines, and lines left with no fields, are blanked out so the lines keep their
+        // numbers
+        assert_eq!(
+            lp,
+            "cpu,host=a usage=1 10\n\
+             \n\
+             mem,host=a used=2i 30\n\
+             \n\
+             not valid lp"
+        );
+        assert!(rejected.is_empty());
+        assert!(matches!(
+            blocklist.apply("cpu,host=a usage=1 10"),
+            (Cow::Borrowed(_), _)
+        ));
+    }
+
+    #[test]
+    fn rejects_lines_with_blocked_names() {
+        let blocklist = Blocklist::new(
+            vec!["debug_*".to_string()],
+            vec!["trace_id".to_string()],
+            BlockAction::Reject,
+        );
+
+        let (lp, rejected) = blocklist.apply(LP);
+
+        assert_eq!(lp, "cpu,host=a usage=1 10\n\n\n\nnot valid lp");
+        let rejected: Vec<_> = rejected
+            .iter()
+            .map(|e| (e.line_number, e.error_message.as_str()))
+            .collect();
+        assert_eq!(
+            rejected,
+            vec![
+                (2, "measurement \"debug_agent\" is blocked"),
+                (3, "column \"trace_id\" is blocked"),
+                (4, "column \"trace_id\" is blocked"),
+            ]
+        );
+    }
+}
@@ -9,12 +9,12 @@ use crate::write_buffer::flusher::BufferedWriteResult;
 use crate::write_buffer::table_buffer::{Builder, Result as TableBufferResult, TableBuffer};
 use crate::write_buffer::DatabaseSchema;
 use crate::write_buffer::{
-    parse_validate_and_update_catalog, Error, TableBatch, ValidSegmentedData,
+    parse_validate_and_update_catalog, DeferredRows, Error, TableBatch, ValidSegmentedData,
 };
 use crate::{
     wal, write_buffer, write_buffer::Result, DatabaseTables, ParquetFile, PersistedSegment,
     Persister, SegmentDuration, SegmentId, SegmentRange, SequenceNumber, TableParquetFiles, WalOp,
-    WalSegmentReader, WalSegmentWriter,
+    WalPosition, WalSegmentReader, WalSegmentWriter,
 };
 use arrow::datatypes::SchemaRef;
 use arrow::record_batch::RecordBatch;
@@ -35,6 +35,9 @@ use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::oneshot;
 
+/// How long a segment with backfilled data can go without backfill writes before it's persisted
+const BACKFILL_IDLE_PERSIST: Duration = Duration::from_secs(30);
+
 #[derive(Debug)]
 pub struct OpenBufferSegment {
     segment_writer: Box<dyn WalSegmentWriter>,
@@ -50,6 +53,11 @@ pub struct OpenBufferSegment {
     // TODO: This is temporarily just the number of rows in the segment. When the buffer gets refactored to use
     //       different structures, we want this to be a representation of approximate memory usage.
     segment_size: usize,
+    /// When backfilled data was last written to the segment, if it ever was
+    backfill_written_at: Option<Time>,
+    /// The sequence number of the last batch in the WAL file that has been buffered, and so is
+    /// queryable
+    readable_sequence_number: SequenceNumber,
 }
 
 impl OpenBufferSegment {
@@ -65,6 +73,8 @@ impl OpenBufferSegment {
         let (buffered_data, segment_size) = buffered_data.unwrap_or_default();
         let segment_key = PartitionKey::from(segment_range.key());
         let segment_duration = SegmentDuration::from_range(segment_range);
+        // anything already in the WAL file has been replayed into the buffer
+        let readable_sequence_number = segment_writer.last_sequence_number();
 
         Self {
             catalog,
@@ -77,14 +87,36 @@ impl OpenBufferSegment {
             starting_catalog_sequence_number,
             segment_size,
             buffered_data,
+            backfill_written_at: None,
+            readable_sequence_number,
         }
     }
 
-    #[cfg(test)]
     pub fn segment_id(&self) -> SegmentId {
         self.segment_id
     }
 
+    pub(crate) fn segment_size(&self) -> usize {
+        self.segment_size
+    }
+
+    pub(crate) fn buffered_data(&self) -> &BufferedData {
+        &self.buffered_data
+    }
+
+    /// The position of the last batch written to the segment that has been buffered
+    pub(crate) fn readable_position(&self) -> WalPosition {
+        WalPosition {
+            segment_id: self.segment_id,
+            sequence_number: self.readable_sequence_number,
+        }
+    }
+
+    /// Records that the batches up to the sequence number have been buffered
+    pub(crate) fn mark_readable(&mut self, sequence_number: SequenceNumber) {
+        self.readable_sequence_number = self.readable_sequence_number.max(sequence_number);
+    }
+
     pub fn segment_range(&self) -> &SegmentRange {
         &self.segment_range
     }
@@ -93,8 +125,14 @@ impl OpenBufferSegment {
         &self.segment_key
     }
 
-    pub fn write_wal_ops(&mut self, write_batch: Vec<WalOp>) -> wal::Result<()> {
-        self.segment_writer.write_batch(write_batch)
+    /// Writes the ops into the segment's WAL file as a single batch, returning its position
+    pub fn write_wal_ops(&mut self, write_batch: Vec<WalOp>) -> wal::Result<WalPosition> {
+        self.segment_writer.write_batch(write_batch)?;
+
+        Ok(WalPosition {
+            segment_id: self.segment_id,
+            sequence_number: self.segment_writer.last_sequence_number(),
+        })
     }
 
     #[cfg(test)]
@@ -141,11 +179,29 @@ impl OpenBufferSegment {
             .table_record_batches(db_name, table_name, schema, filter)
     }
 
+    /// Marks the segment as holding backfilled data, written at the given time
+    pub(crate) fn mark_backfill(&mut self, time: Time) {
+        self.backfill_written_at = Some(time);
+    }
+
+    pub(crate) fn is_backfill(&self) -> bool {
+        self.backfill_written_at.is_some()
+    }
+
     /// Returns true if the segment should be persisted. A segment should be persisted if both of
     /// the following are true:
     /// 1. The segment has been open longer than half its duration
     /// 2. The current time is past the end time of the segment + half its duration
+    ///
+    /// A segment with backfilled data is instead persisted once it hasn't been written to by a
+    /// backfill for a short while, so that a bulk load doesn't keep many old segments open.
     pub fn should_persist(&self, current_time: Time) -> bool {
+        if let Some(written_at) = self.backfill_written_at {
+            return current_time
+                .checked_duration_since(written_at)
+                .is_some_and(|idle| idle > BACKFILL_IDLE_PERSIST);
+        }
+
         let half_duration_seconds = self.segment_duration.duration_seconds() / 2;
         let open_duration_seconds = current_time
             .checked_duration_since(self.segment_open_time)
@@ -272,6 +328,10 @@ impl DatabaseBatch {
 
 pub struct BufferedWrite {
     pub segmented_data: Vec<ValidSegmentedData>,
+    /// The rows of a large write, which are buffered once its ops are in the WAL
+    pub deferred_rows: Vec<DeferredRows>,
+    /// The time the write was received by the server
+    pub ingest_time: Time,
     pub response_tx: oneshot::Sender<BufferedWriteResult>,
 }
 
@@ -281,6 +341,11 @@ pub struct BufferedData {
 }
 
 impl BufferedData {
+    /// Returns the names of the databases that have data in the buffer
+    pub(crate) fn database_names(&self) -> impl Iterator<Item = &String> {
+        self.database_buffers.keys()
+    }
+
     /// Returns the table data as record batches
     pub(crate) fn table_record_batches(
         &self,
@@ -295,6 +360,60 @@ impl BufferedData {
             .map(|table_buffer| table_buffer.record_batch(schema, filter))
     }
 
+    /// Returns the data of every table in the buffer as a record batch of the columns that the
+    /// table's buffer has, along with the names of its database and table
+    pub(crate) fn table_snapshots(
+        &self,
+        catalog: &Catalog,
+    ) -> Result<Vec<(String, String, RecordBatch)>> {
+        let mut snapshots = vec![];
+        for (db_name, db_buffer) in &self.database_buffers {
+            let db_schema = catalog
+                .db_schema(db_name)
+                .expect("database should exist in schema");
+            for (table_name, table_buffer) in &db_buffer.table_buffers {
+                let schema = db_schema
+                    .get_table_schema(table_name)
+                    .expect("table should exist in schema")
+                    .as_arrow();
+                let projection: Vec<_> = schema
+                    .fields()
+                    .iter()
+                    .enumerate()
+                    .filter(|(_, field)| table_buffer.data.contains_key(field.name()))
+                    .map(|(i, _)| i)
+                    .collect();
+                let schema = Arc::new(
+                    schema
+                        .project(&projection)
+                        .map_err(|e| Error::InvalidSnapshot(e.to_string()))?,
+                );
+
+                let batch = table_buffer.record_batch(schema, &[])?;
+                snapshots.push((db_name.clone(), table_name.clone(), batch));
+            }
+        }
+
+        Ok(snapshots)
+    }
+
+    /// Adds the rows of the table batch to the buffer of the table
+    pub(crate) fn buffer_table_batch(
+        &mut self,
+        db_name: &str,
+        table_name: String,
+        segment_key: &PartitionKey,
+        table_batch: TableBatch,
+        schema: &Arc<DatabaseSchema>,
+    ) {
+        self.database_buffers
+            .entry(db_name.to_string())
+            .or_insert_with(|| DatabaseBuffer {
+                table_buffers: HashMap::new(),
+            })
+            .buffer_table_batch(table_name, segment_key, table_batch, schema);
+    }
+
     /// Verifies that the passed in buffer has the same data as this buffer
     #[cfg(test)]
     pub(crate) fn verify_matches(&self, other: &BufferedData, catalog: &Catalog) {
@@ -854,6 +973,33 @@ pub(crate) mod tests {
         assert!(segment.should_persist(Time::from_timestamp(500 + 31, 0).unwrap()));
     }
 
+    #[test]
+    fn should_persist_backfill_once_idle() {
+        let catalog = Arc::new(Catalog::new());
+        let mut segment = OpenBufferSegment::new(
+            Arc::clone(&catalog),
+            SegmentId::new(0),
+            SegmentRange::from_time_and_duration(
+                Time::from_timestamp_nanos(0),
+                SegmentDuration::from_str("1h").unwrap(),
+                false,
+            ),
+            Time::from_timestamp(7_200, 0).unwrap(),
+            SequenceNumber::new(0),
+            Box::new(WalSegmentWriterNoopImpl::new(SegmentId::new(0))),
+            None,
+        );
+        segment.mark_backfill(Time::from_timestamp(7_200, 0).unwrap());
+
+        // the segment hasn't been open for half its duration, but hasn't been backfilled lately
+        assert!(!segment.should_persist(Time::from_timestamp(7_200 + 29, 0).unwrap()));
+        assert!(segment.should_persist(Time::from_timestamp(7_200 + 31, 0).unwrap()));
+
+        // another backfill write keeps it open
+        segment.mark_backfill(Time::from_timestamp(7_200 + 31, 0).unwrap());
+        assert!(!segment.should_persist(Time::from_timestamp(7_200 + 40, 0).unwrap()));
+    }
+
     #[derive(Debug, Default)]
     pub(crate) struct TestPersister {
         pub(crate) state: Mutex<PersistedState>,
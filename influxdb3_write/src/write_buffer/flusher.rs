@@ -1,12 +1,14 @@
 //! Buffers writes and flushes them to the configured wal
 
 use crate::write_buffer::buffer_segment::{BufferedWrite, WriteBatch};
-use crate::write_buffer::{Error, SegmentState, ValidSegmentedData};
-use crate::{wal, SequenceNumber, Wal, WalOp};
+use crate::write_buffer::{DeferredRows, Error, SegmentState, ValidSegmentedData};
+use crate::{wal, SequenceNumber, Wal, WalOp, WalPosition};
 use crossbeam_channel::{bounded, Receiver as CrossbeamReceiver, Sender as CrossbeamSender};
 use iox_time::{Time, TimeProvider};
+use metric::{DurationHistogram, Metric};
 use observability_deps::tracing::debug;
 use parking_lot::{Mutex, RwLock};
+use std::borrow::Cow;
 use std::collections::HashMap;
 use std::sync::Arc;
 use std::time::Duration;
@@ -23,12 +25,14 @@ const BUFFER_CHANNEL_LIMIT: usize = 10_000;
 // are buffered. If there is an error, it'll be here
 #[derive(Debug, Clone)]
 pub enum BufferedWriteResult {
-    Success(()),
+    /// The positions of the WAL batches that the write was in
+    Success(Vec<WalPosition>),
     Error(String),
 }
 
 type SegmentedWalOps = HashMap<Time, (SequenceNumber, Vec<WalOp>)>;
 type SegmentedWriteBatch = HashMap<Time, (SequenceNumber, WriteBatch)>;
+type SegmentedWalPositions = HashMap<Time, WalPosition>;
 
 /// The WriteBufferFlusher buffers writes and flushes them to the configured wal. The wal IO is done in a native
 /// thread rather than a tokio task to avoid blocking the tokio runtime. As referenced in this post, continuous
@@ -43,7 +47,10 @@ pub struct WriteBufferFlusher {
 }
 
 impl WriteBufferFlusher {
-    pub fn new<T: TimeProvider, W: Wal>(segment_state: Arc<RwLock<SegmentState<T, W>>>) -> Self {
+    pub fn new<T: TimeProvider, W: Wal>(
+        segment_state: Arc<RwLock<SegmentState<T, W>>>,
+        metric_registry: &metric::Registry,
+    ) -> Self {
         let (shutdown_tx, shutdown_rx) = watch::channel(());
         let (buffer_tx, buffer_rx) = mpsc::channel(BUFFER_CHANNEL_LIMIT);
         let (io_flush_tx, io_flush_rx) = bounded(1);
@@ -57,6 +64,7 @@ impl WriteBufferFlusher {
         };
 
         let wal_op_buffer_segment_state = Arc::clone(&segment_state);
+        let ingest_latency = IngestLatencyMetrics::new(metric_registry);
 
         *flusher.wal_io_handle.lock() = Some(
             std::thread::Builder::new()
@@ -74,6 +82,7 @@ impl WriteBufferFlusher {
                 io_flush_tx,
                 io_flush_notify_rx,
                 shutdown_rx,
+                ingest_latency,
             )
             .await;
         }));
@@ -81,15 +90,32 @@ impl WriteBufferFlusher {
         flusher
     }
 
+    /// Writes the data into the WAL and buffer, returning the positions of the WAL batches it was
+    /// written in.
     pub async fn write_to_open_segment(
         &self,
         segmented_data: Vec<ValidSegmentedData>,
-    ) -> crate::write_buffer::Result<()> {
+        ingest_time: Time,
+    ) -> crate::write_buffer::Result<Vec<WalPosition>> {
+        self.write_with_deferred_rows(segmented_data, vec![], ingest_time)
+            .await
+    }
+
+    /// Writes the data into the WAL and buffer, along with the rows that are built once the ops
+    /// of the data are in the WAL. The write is readable once all of its rows are buffered.
+    pub async fn write_with_deferred_rows(
+        &self,
+        segmented_data: Vec<ValidSegmentedData>,
+        deferred_rows: Vec<DeferredRows>,
+        ingest_time: Time,
+    ) -> crate::write_buffer::Result<Vec<WalPosition>> {
         let (response_tx, response_rx) = oneshot::channel();
 
         self.buffer_tx
             .send(BufferedWrite {
                 segmented_data,
+                deferred_rows,
+                ingest_time,
                 response_tx,
             })
             .await
@@ -98,22 +124,60 @@ impl WriteBufferFlusher {
         let summary = response_rx.await.expect("wal op buffer thread is dead");
 
         match summary {
-            BufferedWriteResult::Success(_) => Ok(()),
+            BufferedWriteResult::Success(positions) => Ok(positions),
             BufferedWriteResult::Error(e) => Err(Error::BufferSegmentError(e)),
         }
     }
 }
 
+/// Records the time from when a write was received by the server until it was durable in the WAL
+/// and readable from the buffer, per database.
+#[derive(Debug)]
+struct IngestLatencyMetrics {
+    metric: Metric<DurationHistogram>,
+    recorders: HashMap<String, DurationHistogram>,
+}
+
+impl IngestLatencyMetrics {
+    fn new(metric_registry: &metric::Registry) -> Self {
+        let metric = metric_registry.register_metric(
+            "influxdb3_write_buffer_ingest_latency",
+            "time from a write being received until it is durable in the wal and queryable \
+            from the buffer",
+        );
+
+        Self {
+            metric,
+            recorders: HashMap::new(),
+        }
+    }
+
+    fn record(&mut self, db_name: &str, latency: Duration) {
+        if !self.recorders.contains_key(db_name) {
+            let recorder = self
+                .metric
+                .recorder([("db", Cow::Owned(db_name.to_string()))]);
+            self.recorders.insert(db_name.to_string(), recorder);
+        }
+
+        self.recorders[db_name].record(latency);
+    }
+}
+
 async fn run_wal_op_buffer<T: TimeProvider, W: Wal>(
     segment_state: Arc<RwLock<SegmentState<T, W>>>,
     mut buffer_rx: mpsc::Receiver<BufferedWrite>,
     io_flush_tx: CrossbeamSender<SegmentedWalOps>,
-    io_flush_notify_rx: CrossbeamReceiver<wal::Result<()>>,
+    io_flush_notify_rx: CrossbeamReceiver<wal::Result<SegmentedWalPositions>>,
     mut shutdown: watch::Receiver<()>,
+    mut ingest_latency: IngestLatencyMetrics,
 ) {
     let mut ops = SegmentedWalOps::new();
     let mut write_batch = SegmentedWriteBatch::new();
+    let mut deferred_rows = Vec::new();
     let mut notifies = Vec::new();
+    // the database and ingest time of each buffered write, for the latency metrics
+    let mut ingest_times = Vec::new();
     let mut interval = tokio::time::interval(BUFFER_FLUSH_INTERVAL);
     interval.set_missed_tick_behavior(MissedTickBehavior::Skip);
 
@@ -121,7 +185,16 @@ async fn run_wal_op_buffer<T: TimeProvider, W: Wal>(
         // select on either buffering an op, ticking the flush interval, or shutting down
         select! {
             Some(buffered_write) = buffer_rx.recv() => {
+                if let Some(segmented_data) = buffered_write.segmented_data.first() {
+                    ingest_times.push((
+                        segmented_data.database_name.to_string(),
+                        buffered_write.ingest_time,
+                    ));
+                }
+
+                let mut segment_starts = Vec::with_capacity(buffered_write.segmented_data.len());
                 for segmented_data in buffered_write.segmented_data {
+                    segment_starts.push(segmented_data.segment_start);
                     let segment_ops = ops.entry(segmented_data.segment_start).or_insert_with(|| {
                         (segmented_data.starting_catalog_sequence_number, Vec::new())
                     });
@@ -132,7 +205,8 @@ async fn run_wal_op_buffer<T: TimeProvider, W: Wal>(
                     });
                     segment_write_batch.1.add_db_write(segmented_data.database_name, segmented_data.table_batches);
                 }
-                notifies.push(buffered_write.response_tx);
+                deferred_rows.extend(buffered_write.deferred_rows);
+                notifies.push((buffered_write.response_tx, segment_starts));
             },
             _ = interval.tick() => {
                 if ops.is_empty() {
@@ -143,32 +217,52 @@ async fn run_wal_op_buffer<T: TimeProvider, W: Wal>(
                 io_flush_tx.send(ops).expect("wal io thread is dead");
 
                 let res = match io_flush_notify_rx.recv().expect("wal io thread is dead") {
-                  Ok(()) => {
-                       let mut err = BufferedWriteResult::Success(());
-
-                        let mut segment_state = segment_state.write();
-
-                        for (time, (sequence_number, write_batch)) in write_batch {
-                            if let Err(e) = segment_state.write_batch_to_segment(time, write_batch, sequence_number) {
-                                err = BufferedWriteResult::Error(e.to_string());
-                                break;
+                  Ok(positions) => {
+                        match buffer_write_batches(&segment_state, write_batch, deferred_rows) {
+                            Ok(()) => {
+                                let mut segment_state = segment_state.write();
+                                for (time, position) in &positions {
+                                    segment_state.mark_readable(*time, position.sequence_number);
+                                }
+
+                                let now = segment_state.now();
+                                for (db_name, ingest_time) in &ingest_times {
+                                    let latency = now.checked_duration_since(*ingest_time).unwrap_or_default();
+                                    ingest_latency.record(db_name, latency);
+                                }
+
+                                Ok(positions)
                             }
+                            Err(e) => Err(e.to_string()),
                         }
-
-                        err
                     },
-                    Err(e) => BufferedWriteResult::Error(e.to_string()),
+                    Err(e) => Err(e.to_string()),
                 };
 
-                // notify the watchers of the write response
-                for response_tx in notifies {
-                    let _ = response_tx.send(res.clone());
+                // notify the watchers of the write response, with the positions of the segments
+                // their write went into
+                for (response_tx, segment_starts) in notifies {
+                    let result = match &res {
+                        Ok(positions) => {
+                            let mut write_positions: Vec<_> = segment_starts
+                                .iter()
+                                .filter_map(|segment_start| positions.get(segment_start).copied())
+                                .collect();
+                            write_positions.sort();
+                            write_positions.dedup();
+                            BufferedWriteResult::Success(write_positions)
+                        }
+                        Err(e) => BufferedWriteResult::Error(e.clone()),
+                    };
+                    let _ = response_tx.send(result);
                 }
 
                 // reset the buffers
                 ops = SegmentedWalOps::new();
                 write_batch = SegmentedWriteBatch::new();
+                deferred_rows = Vec::new();
                 notifies = Vec::new();
+                ingest_times = Vec::new();
             },
             _ = shutdown.changed() => {
                 // shutdown has been requested
@@ -179,10 +273,40 @@ async fn run_wal_op_buffer<T: TimeProvider, W: Wal>(
     }
 }
 
+/// Buffers the rows of the writes whose ops were flushed to the WAL. The deferred rows of large
+/// writes are built and buffered a chunk at a time, with the segment state unlocked in between
+/// so that queries aren't held up by them.
+fn buffer_write_batches<T: TimeProvider, W: Wal>(
+    segment_state: &RwLock<SegmentState<T, W>>,
+    write_batch: SegmentedWriteBatch,
+    deferred_rows: Vec<DeferredRows>,
+) -> crate::write_buffer::Result<()> {
+    {
+        let mut segment_state = segment_state.write();
+        for (time, (sequence_number, write_batch)) in write_batch {
+            segment_state.write_batch_to_segment(time, write_batch, sequence_number)?;
+        }
+    }
+
+    for rows in deferred_rows {
+        for table_batches in rows.chunks() {
+            let mut write_batch = WriteBatch::default();
+            write_batch.add_db_write(rows.database_name.clone(), table_batches);
+            segment_state.write().write_batch_to_segment(
+                rows.segment_start,
+                write_batch,
+                rows.starting_catalog_sequence_number,
+            )?;
+        }
+    }
+
+    Ok(())
+}
+
 fn run_io_flush<T: TimeProvider, W: Wal>(
     segment_state: Arc<RwLock<SegmentState<T, W>>>,
     buffer_rx: CrossbeamReceiver<SegmentedWalOps>,
-    buffer_notify: CrossbeamSender<wal::Result<()>>,
+    buffer_notify: CrossbeamSender<wal::Result<SegmentedWalPositions>>,
 ) {
     loop {
         let segmented_wal_ops = match buffer_rx.recv() {
@@ -197,15 +321,16 @@ fn run_io_flush<T: TimeProvider, W: Wal>(
         let mut state = segment_state.write();
 
         // write the ops to the segment files, or return on first error
-        for (time, (sequence_number, wal_ops)) in segmented_wal_ops {
-            let res = state.write_ops_to_segment(time, wal_ops, sequence_number);
-            if res.is_err() {
-                buffer_notify.send(res).expect("buffer flusher is dead");
-                continue;
-            }
-        }
+        let res = segmented_wal_ops
+            .into_iter()
+            .map(|(time, (sequence_number, wal_ops))| {
+                state
+                    .write_ops_to_segment(time, wal_ops, sequence_number)
+                    .map(|position| (time, position))
+            })
+            .collect::<wal::Result<SegmentedWalPositions>>();
 
-        buffer_notify.send(Ok(())).expect("buffer flusher is dead");
+        buffer_notify.send(res).expect("buffer flusher is dead");
     }
 }
 
@@ -255,7 +380,8 @@ mod tests {
             vec![],
             None,
         )));
-        let flusher = WriteBufferFlusher::new(Arc::clone(&segment_state));
+        let metric_registry = metric::Registry::new();
+        let flusher = WriteBufferFlusher::new(Arc::clone(&segment_state), &metric_registry);
 
         let db_name = NamespaceName::new("db1").unwrap();
         let ingest_time = Time::from_timestamp_nanos(0);
@@ -270,10 +396,17 @@ mod tests {
         )
         .unwrap();
 
-        flusher
-            .write_to_open_segment(res.valid_segmented_data)
+        let positions = flusher
+            .write_to_open_segment(res.valid_segmented_data, ingest_time)
             .await
             .unwrap();
+        assert_eq!(
+            positions,
+            vec![WalPosition {
+                segment_id,
+                sequence_number: SequenceNumber::new(1),
+            }]
+        );
 
         let res = parse_validate_and_update_catalog(
             db_name.clone(),
@@ -285,10 +418,17 @@ mod tests {
             Precision::Nanosecond,
         )
         .unwrap();
-        flusher
-            .write_to_open_segment(res.valid_segmented_data)
+        let positions = flusher
+            .write_to_open_segment(res.valid_segmented_data, ingest_time)
             .await
             .unwrap();
+        assert_eq!(
+            positions,
+            vec![WalPosition {
+                segment_id,
+                sequence_number: SequenceNumber::new(2),
+            }]
+        );
 
         let state = segment_state.read();
         let segment = state.segment_for_time(ingest_time).unwrap();
@@ -310,5 +450,13 @@ mod tests {
             .unwrap()
             .unwrap();
         assert_eq!(data.num_rows(), 2);
+
+        let latency = metric_registry
+            .get_instrument::<Metric<DurationHistogram>>("influxdb3_write_buffer_ingest_latency")
+            .unwrap()
+            .get_observer(&metric::Attributes::from(&[("db", "db1")]))
+            .unwrap()
+            .fetch();
+        assert_eq!(latency.sample_count(), 2);
     }
 }
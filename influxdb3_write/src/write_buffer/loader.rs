@@ -5,11 +5,14 @@ use crate::catalog::Catalog;
 use crate::wal::WalSegmentWriterNoopImpl;
 use crate::write_buffer::{
     buffer_segment::{load_buffer_from_segment, ClosedBufferSegment, OpenBufferSegment},
+    snapshot::decode_snapshot,
     Result,
 };
 use crate::{persister, write_buffer, PersistedCatalog, PersistedSegment, Persister, SegmentId};
 use crate::{SegmentDuration, SegmentRange, Wal};
+use data_types::PartitionKey;
 use iox_time::Time;
+use observability_deps::tracing::{info, warn};
 use std::sync::Arc;
 
 const SEGMENTS_TO_LOAD: usize = 1000;
@@ -36,7 +39,25 @@ where
     W: Wal,
     write_buffer::Error: From<<P as Persister>::Error>,
 {
-    let PersistedCatalog { catalog, .. } = persister.load_catalog().await?.unwrap_or_default();
+    let PersistedCatalog { mut catalog, .. } = persister.load_catalog().await?.unwrap_or_default();
+
+    // a snapshot written before a planned shutdown has the catalog as it was at shutdown, and the
+    // buffers of the segments that were open
+    let mut snapshot = match wal.as_ref().map(|wal| wal.take_snapshot()).transpose()? {
+        Some(Some(data)) => match decode_snapshot(&data) {
+            Ok(snapshot) => Some(snapshot),
+            Err(e) => {
+                warn!(error = %e, "ignoring invalid write buffer snapshot, replaying the WAL");
+                None
+            }
+        },
+        _ => None,
+    };
+    if let Some(snapshot) = &snapshot {
+        if snapshot.catalog.sequence_number() > catalog.sequence_number() {
+            catalog = snapshot.catalog.clone();
+        }
+    }
     let catalog = Arc::new(Catalog::from_inner(catalog));
 
     let persisted_segments = persister.load_segments(SEGMENTS_TO_LOAD).await?;
@@ -72,7 +93,24 @@ where
             let starting_sequence_number = catalog.sequence_number();
             let segment_reader = wal.open_segment_reader(segment_file.segment_id)?;
             let segment_header = *segment_reader.header();
-            let buffer = load_buffer_from_segment(&catalog, segment_reader)?;
+            let segment_writer = wal.open_segment_writer(segment_file.segment_id)?;
+
+            // segments that haven't been written to since the snapshot was taken are loaded from
+            // it, the rest are replayed from the WAL
+            let segment_snapshot = snapshot.as_mut().and_then(|snapshot| {
+                snapshot.take_segment(segment_header.id, segment_writer.last_sequence_number())
+            });
+            let buffer = match segment_snapshot {
+                Some(segment_snapshot) => {
+                    info!(
+                        segment_id = segment_header.id.0,
+                        "loading segment from snapshot"
+                    );
+                    let segment_key = PartitionKey::from(segment_header.range.key());
+                    segment_snapshot.into_buffer(&catalog, &segment_key)?
+                }
+                None => load_buffer_from_segment(&catalog, segment_reader)?,
+            };
 
             let segment = OpenBufferSegment::new(
                 Arc::clone(&catalog),
@@ -80,7 +118,7 @@ where
                 segment_header.range,
                 server_load_time,
                 starting_sequence_number,
-                wal.open_segment_writer(segment_file.segment_id)?,
+                segment_writer,
                 Some(buffer),
             );
 
@@ -142,9 +180,11 @@ where
 #[cfg(test)]
 mod tests {
     use super::*;
+    use crate::catalog::WriteFlags;
     use crate::persister::PersisterImpl;
     use crate::test_helpers::lp_to_write_batch;
     use crate::wal::{WalImpl, WalSegmentWriterNoopImpl};
+    use crate::write_buffer::snapshot::encode_snapshot;
     use crate::Precision;
     use crate::{
         DatabaseTables, LpWriteOp, ParquetFile, SegmentRange, SequenceNumber, TableParquetFiles,
@@ -326,6 +366,158 @@ mod tests {
         assert_eq!(loaded_state.last_segment_id, SegmentId::new(1));
     }
 
+    #[tokio::test]
+    async fn loads_open_segments_from_snapshot() {
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let wal = Arc::new(WalImpl::new(dir.clone()).unwrap());
+        let db_name = "db1";
+
+        let LoadedState {
+            catalog,
+            mut open_segments,
+            ..
+        } = load_starting_state(
+            Arc::clone(&persister),
+            Some(Arc::clone(&wal)),
+            Time::from_timestamp_nanos(0),
+            SegmentDuration::new_5m(),
+        )
+        .await
+        .unwrap();
+
+        let mut current_segment = open_segments.pop().unwrap();
+
+        let lp = "cpu,tag1=cupcakes bar=1 10\ncpu,tag1=snakes baz=2i 20";
+
+        let wal_op = WalOp::LpWrite(LpWriteOp {
+            db_name: db_name.to_string(),
+            lp: lp.to_string(),
+            default_time: 0,
+            precision: Precision::Nanosecond,
+        });
+
+        let write_batch = lp_to_write_batch(&catalog, db_name, lp);
+
+        let position = current_segment.write_wal_ops(vec![wal_op]).unwrap();
+        current_segment.buffer_writes(write_batch).unwrap();
+        current_segment.mark_readable(position.sequence_number);
+
+        // catalog changes that aren't in the wal are only kept by the snapshot
+        let write_flags = WriteFlags {
+            strict_schema: true,
+            ..Default::default()
+        };
+        catalog.set_write_flags(db_name, write_flags).unwrap();
+
+        let snapshot = encode_snapshot(&catalog, [&current_segment]).unwrap();
+        wal.write_snapshot(&snapshot).unwrap();
+
+        let loaded_state = load_starting_state(
+            persister,
+            Some(Arc::clone(&wal)),
+            Time::from_timestamp_nanos(0),
+            SegmentDuration::new_5m(),
+        )
+        .await
+        .unwrap();
+        let current_segment = loaded_state.open_segments.first().unwrap();
+
+        let db = loaded_state.catalog.db_schema(db_name).unwrap();
+        assert_eq!(db.write_flags(), write_flags);
+
+        let cpu_table = db.get_table("cpu").unwrap();
+        let cpu_data = current_segment
+            .table_record_batch(db_name, "cpu", cpu_table.schema().as_arrow(), &[])
+            .unwrap()
+            .unwrap();
+        let expected = [
+            "+-----+-----+----------+--------------------------------+",
+            "| bar | baz | tag1     | time                           |",
+            "+-----+-----+----------+--------------------------------+",
+            "| 1.0 |     | cupcakes | 1970-01-01T00:00:00.000000010Z |",
+            "|     | 2   | snakes   | 1970-01-01T00:00:00.000000020Z |",
+            "+-----+-----+----------+--------------------------------+",
+        ];
+        assert_batches_eq!(&expected, &[cpu_data]);
+
+        // the snapshot is only loaded once
+        assert!(wal.take_snapshot().unwrap().is_none());
+    }
+
+    #[tokio::test]
+    async fn replays_segments_with_batches_not_buffered_when_snapshotted() {
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let wal = Arc::new(WalImpl::new(dir.clone()).unwrap());
+        let db_name = "db1";
+
+        let LoadedState {
+            catalog,
+            mut open_segments,
+            ..
+        } = load_starting_state(
+            Arc::clone(&persister),
+            Some(Arc::clone(&wal)),
+            Time::from_timestamp_nanos(0),
+            SegmentDuration::new_5m(),
+        )
+        .await
+        .unwrap();
+
+        let mut current_segment = open_segments.pop().unwrap();
+
+        let lp_op = |lp: &str| {
+            WalOp::LpWrite(LpWriteOp {
+                db_name: db_name.to_string(),
+                lp: lp.to_string(),
+                default_time: 0,
+                precision: Precision::Nanosecond,
+            })
+        };
+        let lp = "cpu,tag1=cupcakes bar=1 10";
+        let write_batch = lp_to_write_batch(&catalog, db_name, lp);
+        let position = current_segment.write_wal_ops(vec![lp_op(lp)]).unwrap();
+        current_segment.buffer_writes(write_batch).unwrap();
+        current_segment.mark_readable(position.sequence_number);
+
+        // a batch that is in the WAL, but hasn't been buffered when the snapshot is taken
+        current_segment
+            .write_wal_ops(vec![lp_op("cpu,tag1=snakes bar=2 20")])
+            .unwrap();
+
+        let snapshot = encode_snapshot(&catalog, [&current_segment]).unwrap();
+        wal.write_snapshot(&snapshot).unwrap();
+
+        let loaded_state = load_starting_state(
+            persister,
+            Some(Arc::clone(&wal)),
+            Time::from_timestamp_nanos(0),
+            SegmentDuration::new_5m(),
+        )
+        .await
+        .unwrap();
+        let current_segment = loaded_state.open_segments.first().unwrap();
+
+        let db = loaded_state.catalog.db_schema(db_name).unwrap();
+        let cpu_table = db.get_table("cpu").unwrap();
+        let cpu_data = current_segment
+            .table_record_batch(db_name, "cpu", cpu_table.schema().as_arrow(), &[])
+            .unwrap()
+            .unwrap();
+        let expected = [
+            "+-----+----------+--------------------------------+",
+            "| bar | tag1     | time                           |",
+            "+-----+----------+--------------------------------+",
+            "| 1.0 | cupcakes | 1970-01-01T00:00:00.000000010Z |",
+            "| 2.0 | snakes   | 1970-01-01T00:00:00.000000020Z |",
+            "+-----+----------+--------------------------------+",
+        ];
+        assert_batches_eq!(&expected, &[cpu_data]);
+    }
+
     #[tokio::test]
     async fn loads_with_persisted_segments_and_wal() {
         let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
@@ -1,21 +1,33 @@
 //! Implementation of an in-memory buffer for writes that persists data into a wal if it is configured.
 
+mod blocklist;
 pub(crate) mod buffer_segment;
 mod flusher;
 mod loader;
 mod segment_state;
+mod snapshot;
 mod table_buffer;
+mod transform;
+
+pub use blocklist::{BlockAction, Blocklist};
+use transform::{apply_ingest_transforms, coerce_field_types};
 
 use crate::cache::ParquetCache;
-use crate::catalog::{Catalog, DatabaseSchema, TableDefinition, TIME_COLUMN_NAME};
+use crate::catalog::{
+    Catalog, DatabaseSchema, IngestTransform, TableDefinition, WriteFlags, TIME_COLUMN_NAME,
+};
 use crate::chunk::ParquetChunk;
 use crate::persister::PersisterImpl;
 use crate::write_buffer::flusher::WriteBufferFlusher;
 use crate::write_buffer::loader::load_starting_state;
-use crate::write_buffer::segment_state::{run_buffer_segment_persist_and_cleanup, SegmentState};
+use crate::write_buffer::segment_state::{
+    run_buffer_segment_persist_and_cleanup, PersistHealth, SegmentState,
+};
+use crate::write_buffer::snapshot::encode_snapshot;
 use crate::{
-    BufferedWriteRequest, Bufferer, ChunkContainer, LpWriteOp, Persister, Precision,
-    SegmentDuration, SequenceNumber, Wal, WalOp, WriteBuffer, WriteLineError,
+    histogram, BufferedWriteRequest, Bufferer, ChunkContainer, LpWriteOp, Persister, Precision,
+    SegmentDuration, SequenceNumber, TableWriteSummary, Wal, WalOp, Watermarks, WriteBuffer,
+    WriteLineError,
 };
 use async_trait::async_trait;
 use data_types::{
@@ -31,7 +43,7 @@ use iox_query::QueryChunk;
 use iox_time::{Time, TimeProvider};
 use object_store::path::Path as ObjPath;
 use object_store::ObjectMeta;
-use observability_deps::tracing::{debug, error};
+use observability_deps::tracing::{debug, error, info};
 use parking_lot::{Mutex, RwLock};
 use parquet_file::storage::ParquetExecInput;
 use sha2::Digest;
@@ -41,7 +53,7 @@ use std::collections::{BTreeMap, HashMap};
 use std::i64;
 use std::sync::{Arc, OnceLock};
 use thiserror::Error;
-use tokio::sync::watch;
+use tokio::sync::{broadcast, watch};
 
 #[derive(Debug, Error)]
 pub enum Error {
@@ -55,6 +67,15 @@ pub enum Error {
         new: ColumnType,
     },
 
+    #[error("write to {db_name} would change its schema, which isn't allowed for the database")]
+    SchemaChangeNotAllowed { db_name: String },
+
+    #[error(
+        "writes to {db_name} are paused after {failures} consecutive failures to persist its data \
+         to object storage"
+    )]
+    PersistBackpressure { db_name: String, failures: usize },
+
     #[error("catalog update erorr {0}")]
     CatalogUpdateError(#[from] crate::catalog::Error),
 
@@ -67,6 +88,9 @@ pub enum Error {
     #[error("error from persister: {0}")]
     PersisterError(#[from] crate::persister::Error),
 
+    #[error("invalid write buffer snapshot: {0}")]
+    InvalidSnapshot(String),
+
     #[error("corrupt load state: {0}")]
     CorruptLoadState(String),
 
@@ -82,6 +106,15 @@ pub enum Error {
 
 pub type Result<T, E = Error> = std::result::Result<T, E>;
 
+/// The maximum number of lines from a single write that are held parsed, or converted into rows
+/// and buffered, together. Larger writes go into the WAL whole, and are then converted and
+/// buffered in chunks of this many lines.
+const WRITE_CHUNK_LINE_LIMIT: usize = 10_000;
+
+/// The number of writes queued for each subscriber to the buffer. Subscribers that fall further
+/// behind than this miss writes.
+const SUBSCRIBER_QUEUE_SIZE: usize = 1_024;
+
 #[derive(Debug)]
 pub struct WriteRequest<'a> {
     pub db_name: NamespaceName<'static>,
@@ -104,6 +137,10 @@ pub struct WriteBufferImpl<W, T> {
     segment_persist_handle: Mutex<tokio::task::JoinHandle<()>>,
     #[allow(dead_code)]
     shutdown_segment_persist_tx: watch::Sender<()>,
+    write_tx: broadcast::Sender<Arc<LpWriteOp>>,
+    persist_health: Arc<PersistHealth>,
+    persist_failure_limit: usize,
+    blocklist: Blocklist,
 }
 
 impl<W: Wal, T: TimeProvider> WriteBufferImpl<W, T> {
@@ -113,6 +150,7 @@ impl<W: Wal, T: TimeProvider> WriteBufferImpl<W, T> {
         time_provider: Arc<T>,
         segment_duration: SegmentDuration,
         executor: Arc<iox_query::exec::Executor>,
+        metric_registry: Arc<metric::Registry>,
     ) -> Result<Self> {
         let now = time_provider.now();
         let loaded_state =
@@ -129,18 +167,22 @@ impl<W: Wal, T: TimeProvider> WriteBufferImpl<W, T> {
             wal.clone(),
         )));
 
-        let write_buffer_flusher = WriteBufferFlusher::new(Arc::clone(&segment_state));
+        let write_buffer_flusher =
+            WriteBufferFlusher::new(Arc::clone(&segment_state), &metric_registry);
 
         let segment_state_persister = Arc::clone(&segment_state);
         let time_provider_persister = Arc::clone(&time_provider);
         let wal_perister = wal.clone();
         let cloned_persister = Arc::clone(&persister);
+        let persist_health = Arc::new(PersistHealth::default());
+        let persist_health_persister = Arc::clone(&persist_health);
 
         let (shutdown_segment_persist_tx, shutdown_rx) = watch::channel(());
         let segment_persist_handle = tokio::task::spawn(async move {
             run_buffer_segment_persist_and_cleanup(
                 cloned_persister,
                 segment_state_persister,
+                persist_health_persister,
                 shutdown_rx,
                 time_provider_persister,
                 wal_perister,
@@ -160,13 +202,48 @@ impl<W: Wal, T: TimeProvider> WriteBufferImpl<W, T> {
             segment_duration,
             segment_persist_handle: Mutex::new(segment_persist_handle),
             shutdown_segment_persist_tx,
+            write_tx: broadcast::channel(SUBSCRIBER_QUEUE_SIZE).0,
+            persist_health,
+            persist_failure_limit: 0,
+            blocklist: Blocklist::default(),
         })
     }
 
+    /// Rejects writes to a database once this many consecutive attempts to persist segments with
+    /// its data have failed, until a segment is persisted again. Zero, the default, never rejects
+    /// writes.
+    pub fn with_persist_failure_limit(mut self, limit: usize) -> Self {
+        self.persist_failure_limit = limit;
+        self
+    }
+
+    /// Applies the blocklist to writes to every database, before their ingest transforms
+    pub fn with_blocklist(mut self, blocklist: Blocklist) -> Self {
+        self.blocklist = blocklist;
+        self
+    }
+
     pub fn catalog(&self) -> Arc<Catalog> {
         Arc::clone(&self.catalog)
     }
 
+    /// Writes a snapshot of the catalog and the open segments to the WAL directory, so that the
+    /// next start loads them from the snapshot rather than replaying their WAL files. This is
+    /// meant to be called once writes have stopped before a planned shutdown. Segments that are
+    /// written to after the snapshot is taken are replayed from the WAL as usual.
+    pub fn snapshot(&self) -> Result<()> {
+        let Some(wal) = &self.wal else {
+            return Ok(());
+        };
+
+        let segment_state = self.segment_state.read();
+        let data = encode_snapshot(&self.catalog, segment_state.open_segments())?;
+        wal.write_snapshot(&data)?;
+        info!(bytes = data.len(), "wrote write buffer snapshot");
+
+        Ok(())
+    }
+
     async fn write_lp(
         &self,
         db_name: NamespaceName<'static>,
@@ -174,29 +251,180 @@ impl<W: Wal, T: TimeProvider> WriteBufferImpl<W, T> {
         ingest_time: Time,
         accept_partial: bool,
         precision: Precision,
+    ) -> Result<BufferedWriteRequest> {
+        self.buffer_lp(db_name, lp, ingest_time, accept_partial, precision, false)
+            .await
+    }
+
+    /// Persists the catalog as it is now, for changes to it that aren't made by writes and so
+    /// aren't in the WAL to be replayed. It is persisted under the last segment, which persists it
+    /// again if the catalog changes before the segment is persisted.
+    async fn persist_catalog(&self) -> Result<()> {
+        let segment_id = self.segment_state.read().last_segment_id();
+        self.persister
+            .persist_catalog(segment_id, Catalog::from_inner(self.catalog.clone_inner()))
+            .await?;
+        Ok(())
+    }
+
+    /// Buffers the write, marking the segments older than the current one that it writes to as
+    /// backfilled if `backfill` is set
+    async fn buffer_lp(
+        &self,
+        db_name: NamespaceName<'static>,
+        lp: &str,
+        ingest_time: Time,
+        accept_partial: bool,
+        precision: Precision,
+        backfill: bool,
     ) -> Result<BufferedWriteRequest> {
         debug!("write_lp to {} in writebuffer", db_name);
 
-        let result = parse_validate_and_update_catalog(
-            db_name.clone(),
-            lp,
-            &self.catalog,
+        if self.persist_failure_limit > 0 {
+            let failures = self.persist_health.consecutive_failures(db_name.as_str());
+            if failures >= self.persist_failure_limit {
+                return Err(Error::PersistBackpressure {
+                    db_name: db_name.to_string(),
+                    failures,
+                });
+            }
+        }
+
+        let (sequence, db) = self.catalog.db_or_create(db_name.as_str())?;
+        let write_flags = db.write_flags();
+        let accept_partial = write_flags.accept_partial.unwrap_or(accept_partial);
+
+        let (unblocked, mut blocked) = self.blocklist.apply(lp);
+        if !accept_partial && !blocked.is_empty() {
+            return Err(Error::ParseError(blocked.remove(0)));
+        }
+
+        // transforms are applied before the write goes into the WAL, so they are not applied
+        // again when the WAL is replayed
+        let mut lp = apply_ingest_transforms(&unblocked, db.ingest_transforms());
+        if write_flags.coerce_field_types {
+            if let Cow::Owned(coerced) = coerce_field_types(&lp, &db) {
+                lp = Cow::Owned(coerced);
+            }
+        }
+        let ValidatedLines {
+            mut validation,
+            mut errors,
+            lines,
+        } = parse_and_validate_lines(
+            &lp,
+            accept_partial,
+            &db,
             ingest_time,
             self.segment_duration,
-            accept_partial,
             precision,
         )?;
+        if !blocked.is_empty() {
+            errors.extend(blocked);
+            errors.sort_by_key(|error| error.line_number);
+        }
+
+        // the schema of the whole write is validated up front, so that any schema errors reject
+        // the write before any of it is buffered
+        if write_flags.strict_schema && validation.schema.is_some() {
+            return Err(Error::SchemaChangeNotAllowed {
+                db_name: db_name.to_string(),
+            });
+        }
+        if let Some(schema) = validation.schema.take() {
+            debug!("replacing schema for {:?}", schema);
+
+            self.catalog.replace_database(sequence, Arc::new(schema))?;
+        }
+
+        // the rows of a write with more lines than can be held parsed at once are built and
+        // buffered a chunk at a time, after all of the write is in the WAL, to bound the memory
+        // used by the write without a failure leaving only part of it durable.
+        let (valid_segmented_data, deferred_rows) = match lines {
+            ValidLines::Parsed(lines) => {
+                let valid_segmented_data = convert_lines_into_segments(
+                    lines,
+                    &db_name,
+                    ingest_time,
+                    self.segment_duration,
+                    precision,
+                    sequence,
+                );
+                (valid_segmented_data, vec![])
+            }
+            ValidLines::BySegment(segment_lines) => segment_lines
+                .into_iter()
+                .map(|(segment_start, lines)| {
+                    let lp = lines.join("\n");
+                    let valid_segmented_data = ValidSegmentedData {
+                        database_name: db_name.clone(),
+                        segment_start,
+                        table_batches: HashMap::new(),
+                        wal_op: WalOp::LpWrite(LpWriteOp {
+                            db_name: db_name.to_string(),
+                            lp: lp.clone(),
+                            default_time: ingest_time.timestamp_nanos(),
+                            precision,
+                        }),
+                        starting_catalog_sequence_number: sequence,
+                    };
+                    let deferred_rows = DeferredRows {
+                        database_name: db_name.clone(),
+                        segment_start,
+                        starting_catalog_sequence_number: sequence,
+                        lp,
+                        ingest_time,
+                        segment_duration: self.segment_duration,
+                        precision,
+                    };
+                    (valid_segmented_data, deferred_rows)
+                })
+                .unzip(),
+        };
+
+        let accepted_ops: Vec<_> = valid_segmented_data
+            .iter()
+            .map(|data| match &data.wal_op {
+                WalOp::LpWrite(op) => Arc::new(op.clone()),
+            })
+            .collect();
+
+        let backfill_segment_starts: Vec<_> = if backfill {
+            valid_segmented_data
+                .iter()
+                .map(|data| data.segment_start)
+                .collect()
+        } else {
+            vec![]
+        };
 
-        self.write_buffer_flusher
-            .write_to_open_segment(result.valid_segmented_data)
+        let wal_positions = self
+            .write_buffer_flusher
+            .write_with_deferred_rows(valid_segmented_data, deferred_rows, ingest_time)
             .await?;
 
+        if !backfill_segment_starts.is_empty() {
+            self.segment_state
+                .write()
+                .mark_backfill_segments(&backfill_segment_starts);
+        }
+
+        if self.write_tx.receiver_count() > 0 {
+            for op in &accepted_ops {
+                // there being no subscribers left isn't an error
+                let _ = self.write_tx.send(Arc::clone(op));
+            }
+        }
+
         Ok(BufferedWriteRequest {
             db_name,
-            invalid_lines: result.errors,
-            line_count: result.line_count,
-            field_count: result.field_count,
-            tag_count: result.tag_count,
+            invalid_lines: errors,
+            line_count: validation.line_count,
+            field_count: validation.field_count,
+            tag_count: validation.tag_count,
+            table_summaries: validation.table_summaries,
+            wal_positions,
+            accepted_ops,
         })
     }
 
@@ -394,6 +622,18 @@ impl<W: Wal, T: TimeProvider> Bufferer for WriteBufferImpl<W, T> {
             .await
     }
 
+    async fn write_lp_backfill(
+        &self,
+        database: NamespaceName<'static>,
+        lp: &str,
+        ingest_time: Time,
+        accept_partial: bool,
+        precision: Precision,
+    ) -> Result<BufferedWriteRequest> {
+        self.buffer_lp(database, lp, ingest_time, accept_partial, precision, true)
+            .await
+    }
+
     fn wal(&self) -> Option<Arc<impl Wal>> {
         self.wal.clone()
     }
@@ -401,6 +641,28 @@ impl<W: Wal, T: TimeProvider> Bufferer for WriteBufferImpl<W, T> {
     fn catalog(&self) -> Arc<Catalog> {
         self.catalog()
     }
+
+    async fn set_ingest_transforms(
+        &self,
+        db_name: &str,
+        transforms: Vec<IngestTransform>,
+    ) -> Result<()> {
+        self.catalog.set_ingest_transforms(db_name, transforms)?;
+        self.persist_catalog().await
+    }
+
+    async fn set_write_flags(&self, db_name: &str, write_flags: WriteFlags) -> Result<()> {
+        self.catalog.set_write_flags(db_name, write_flags)?;
+        self.persist_catalog().await
+    }
+
+    fn subscribe(&self) -> broadcast::Receiver<Arc<LpWriteOp>> {
+        self.write_tx.subscribe()
+    }
+
+    fn watermarks(&self) -> Watermarks {
+        self.segment_state.read().watermarks()
+    }
 }
 
 impl<W: Wal, T: TimeProvider> ChunkContainer for WriteBufferImpl<W, T> {
@@ -463,39 +725,7 @@ pub(crate) fn parse_validate_and_update_schema(
     precision: Precision,
     starting_catalog_sequence_number: SequenceNumber,
 ) -> Result<ValidationResult> {
-    let mut errors = vec![];
-    let mut lp_lines = lp.lines();
-
-    let mut valid_parsed_and_raw_lines: Vec<(ParsedLine, &str)> = vec![];
-
-    for (line_idx, maybe_line) in parse_lines(lp).enumerate() {
-        let line = match maybe_line {
-            Ok(line) => line,
-            Err(e) => {
-                if !accept_partial {
-                    return Err(Error::ParseError(WriteLineError {
-                        // This unwrap is fine because we're moving line by line
-                        // alongside the output from parse_lines
-                        original_line: lp_lines.next().unwrap().to_string(),
-                        line_number: line_idx + 1,
-                        error_message: e.to_string(),
-                    }));
-                } else {
-                    errors.push(WriteLineError {
-                        original_line: lp_lines.next().unwrap().to_string(),
-                        // This unwrap is fine because we're moving line by line
-                        // alongside the output from parse_lines
-                        line_number: line_idx + 1,
-                        error_message: e.to_string(),
-                    });
-                }
-                continue;
-            }
-        };
-        // This unwrap is fine because we're moving line by line
-        // alongside the output from parse_lines
-        valid_parsed_and_raw_lines.push((line, lp_lines.next().unwrap()));
-    }
+    let (valid_parsed_and_raw_lines, errors) = parse_lines_with_errors(lp, accept_partial)?;
 
     validate_or_insert_schema_and_partitions(
         valid_parsed_and_raw_lines,
@@ -512,6 +742,57 @@ pub(crate) fn parse_validate_and_update_schema(
     })
 }
 
+/// Parses each line of the line protocol, returning the result with the number of the line and
+/// the raw line. Blank and comment lines are skipped, as they are by [`parse_lines`], but are
+/// still counted, so that every line is numbered as it is in the line protocol.
+pub(crate) fn parse_numbered_lines(
+    lp: &str,
+) -> impl Iterator<
+    Item = (
+        usize,
+        &str,
+        Result<ParsedLine<'_>, influxdb_line_protocol::Error>,
+    ),
+> {
+    lp.lines().enumerate().filter_map(|(line_idx, raw_line)| {
+        let maybe_line = parse_lines(raw_line).next()?;
+        Some((line_idx + 1, raw_line, maybe_line))
+    })
+}
+
+/// Parses the line protocol, returning the lines that parsed successfully alongside their raw
+/// line protocol. If `accept_partial` is false, the first line that fails to parse is returned
+/// as an error, otherwise the lines that failed are returned in the error list.
+pub(crate) fn parse_lines_with_errors(
+    lp: &str,
+    accept_partial: bool,
+) -> Result<(Vec<(ParsedLine<'_>, &str)>, Vec<WriteLineError>)> {
+    let mut errors = vec![];
+
+    let mut valid_parsed_and_raw_lines: Vec<(ParsedLine<'_>, &str)> = vec![];
+
+    for (line_number, raw_line, maybe_line) in parse_numbered_lines(lp) {
+        let line = match maybe_line {
+            Ok(line) => line,
+            Err(e) => {
+                let error = WriteLineError {
+                    original_line: raw_line.to_string(),
+                    line_number,
+                    error_message: e.to_string(),
+                };
+                if !accept_partial {
+                    return Err(Error::ParseError(error));
+                }
+                errors.push(error);
+                continue;
+            }
+        };
+        valid_parsed_and_raw_lines.push((line, raw_line));
+    }
+
+    Ok((valid_parsed_and_raw_lines, errors))
+}
+
 /// Takes parsed lines, validates their schema. If new tables or columns are defined, they
 /// are passed back as a new DatabaseSchema as part of the ValidationResult. Lines are split
 /// into partitions and the validation result contains the data that can then be serialized
@@ -525,37 +806,245 @@ pub(crate) fn validate_or_insert_schema_and_partitions(
     precision: Precision,
     starting_catalog_sequence_number: SequenceNumber,
 ) -> Result<ValidationResult> {
-    // The (potentially updated) DatabaseSchema to return to the caller.
-    let mut schema = Cow::Borrowed(schema);
+    let SchemaValidation {
+        schema,
+        line_count,
+        field_count,
+        tag_count,
+        table_summaries,
+    } = validate_schema_for_lines(&lines, schema);
 
-    // The parsed and validated table_batches
-    let mut segment_table_batches: HashMap<Time, TableBatchMap> = HashMap::new();
+    let valid_segmented_data = convert_lines_into_segments(
+        lines,
+        &db_name,
+        ingest_time,
+        segment_duration,
+        precision,
+        starting_catalog_sequence_number,
+    );
 
-    let line_count = lines.len();
-    let mut field_count = 0;
-    let mut tag_count = 0;
+    Ok(ValidationResult {
+        schema,
+        line_count,
+        field_count,
+        tag_count,
+        table_summaries,
+        errors: vec![],
+        valid_segmented_data,
+    })
+}
+
+/// Validates the schema of all the lines, adding any new tables or columns to a copy of the
+/// passed in schema. No rows are built, so this is cheap to run over a whole write before any
+/// of it is converted and buffered.
+pub(crate) fn validate_schema_for_lines(
+    lines: &[(ParsedLine<'_>, &str)],
+    schema: &DatabaseSchema,
+) -> SchemaValidation {
+    let mut validator = SchemaValidator::new(schema);
+    for (line, raw_line) in lines {
+        validator.validate(line, raw_line);
+    }
+    validator.finish()
+}
+
+/// Validates the schema of lines one at a time, so that the lines don't have to be held at once
+#[derive(Debug)]
+struct SchemaValidator<'a> {
+    /// The (potentially updated) DatabaseSchema to return to the caller.
+    schema: Cow<'a, DatabaseSchema>,
+    line_count: usize,
+    field_count: usize,
+    tag_count: usize,
+    table_summaries: HashMap<String, TableWriteSummary>,
+}
+
+impl<'a> SchemaValidator<'a> {
+    fn new(schema: &'a DatabaseSchema) -> Self {
+        Self {
+            schema: Cow::Borrowed(schema),
+            line_count: 0,
+            field_count: 0,
+            tag_count: 0,
+            table_summaries: HashMap::new(),
+        }
+    }
+
+    fn validate(&mut self, line: &ParsedLine<'_>, raw_line: &str) {
+        self.line_count += 1;
+        self.field_count += line.field_set.len();
+        self.tag_count += line.series.tag_set.as_ref().map(|t| t.len()).unwrap_or(0);
+
+        let table_summary = self
+            .table_summaries
+            .entry(line.series.measurement.to_string())
+            .or_default();
+        table_summary.line_count += 1;
+        table_summary.bytes += raw_line.len();
+        table_summary.new_column_count += validate_and_update_schema(line, &mut self.schema);
+    }
+
+    fn finish(self) -> SchemaValidation {
+        let schema = match self.schema {
+            Cow::Owned(s) => Some(s),
+            Cow::Borrowed(_) => None,
+        };
+
+        SchemaValidation {
+            schema,
+            line_count: self.line_count,
+            field_count: self.field_count,
+            tag_count: self.tag_count,
+            table_summaries: self.table_summaries,
+        }
+    }
+}
+
+/// The lines of a write that were parsed and had their schema validated, along with the lines
+/// that failed to parse
+#[derive(Debug)]
+pub(crate) struct ValidatedLines<'a> {
+    pub(crate) validation: SchemaValidation,
+    pub(crate) errors: Vec<WriteLineError>,
+    pub(crate) lines: ValidLines<'a>,
+}
+
+#[derive(Debug)]
+pub(crate) enum ValidLines<'a> {
+    /// The parsed lines of a write with no more than [`WRITE_CHUNK_LINE_LIMIT`] valid lines
+    Parsed(Vec<(ParsedLine<'a>, &'a str)>),
+    /// The raw lines of a larger write, by the start of the segment that they fall into
+    BySegment(BTreeMap<Time, Vec<&'a str>>),
+}
+
+/// Parses the line protocol and validates the schema of the lines a line at a time. Once there
+/// are more than [`WRITE_CHUNK_LINE_LIMIT`] valid lines, only the raw lines are kept, so that
+/// every line of a large write isn't held parsed at once. If `accept_partial` is false, the
+/// first line that fails to parse is returned as an error.
+pub(crate) fn parse_and_validate_lines<'a>(
+    lp: &'a str,
+    accept_partial: bool,
+    schema: &DatabaseSchema,
+    ingest_time: Time,
+    segment_duration: SegmentDuration,
+    precision: Precision,
+) -> Result<ValidatedLines<'a>> {
+    let mut validator = SchemaValidator::new(schema);
+    let mut errors = vec![];
+    let check_histograms = schema.write_flags().validate_histograms;
+
+    let mut lines = ValidLines::Parsed(vec![]);
+    for (line_number, raw_line, maybe_line) in parse_numbered_lines(lp) {
+        let maybe_line = match maybe_line {
+            Ok(line) if check_histograms => validate_histograms(&line).map(|()| line),
+            Ok(line) => Ok(line),
+            Err(e) => Err(e.to_string()),
+        };
+        let line = match maybe_line {
+            Ok(line) => line,
+            Err(error_message) => {
+                let error = WriteLineError {
+                    original_line: raw_line.to_string(),
+                    line_number,
+                    error_message,
+                };
+                if !accept_partial {
+                    return Err(Error::ParseError(error));
+                }
+                errors.push(error);
+                continue;
+            }
+        };
+        validator.validate(&line, raw_line);
+
+        match &mut lines {
+            ValidLines::Parsed(parsed) if parsed.len() < WRITE_CHUNK_LINE_LIMIT => {
+                parsed.push((line, raw_line));
+            }
+            ValidLines::Parsed(parsed) => {
+                let mut segment_lines: BTreeMap<Time, Vec<&str>> = BTreeMap::new();
+                for (line, raw_line) in parsed.drain(..).chain([(line, raw_line)]) {
+                    let segment_start =
+                        line_segment_start(&line, ingest_time, segment_duration, precision);
+                    segment_lines
+                        .entry(segment_start)
+                        .or_default()
+                        .push(raw_line);
+                }
+                lines = ValidLines::BySegment(segment_lines);
+            }
+            ValidLines::BySegment(segment_lines) => {
+                let segment_start =
+                    line_segment_start(&line, ingest_time, segment_duration, precision);
+                segment_lines
+                    .entry(segment_start)
+                    .or_default()
+                    .push(raw_line);
+            }
+        }
+    }
+
+    Ok(ValidatedLines {
+        validation: validator.finish(),
+        errors,
+        lines,
+    })
+}
+
+/// Returns an error message for the first string field of the line that is an invalid
+/// histogram, see [`crate::histogram`]
+fn validate_histograms(line: &ParsedLine<'_>) -> Result<(), String> {
+    for (key, value) in &line.field_set {
+        if let FieldValue::String(value) = value {
+            histogram::validate_string_field(value.as_str())
+                .map_err(|e| format!("invalid histogram in field {key}: {e}"))?;
+        }
+    }
+    Ok(())
+}
+
+/// Returns the time of the line in nanoseconds, which is the ingest time if it has no timestamp
+fn line_time_nanos(line: &ParsedLine<'_>, ingest_time: Time, precision: Precision) -> i64 {
+    line.timestamp
+        .map(|ts| precision.timestamp_to_nanos(ts))
+        .unwrap_or(ingest_time.timestamp_nanos())
+}
 
-    for (line, raw_line) in lines.into_iter() {
-        field_count += line.field_set.len();
-        tag_count += line.series.tag_set.as_ref().map(|t| t.len()).unwrap_or(0);
+/// Returns the start of the segment that the time of the line falls into
+fn line_segment_start(
+    line: &ParsedLine<'_>,
+    ingest_time: Time,
+    segment_duration: SegmentDuration,
+    precision: Precision,
+) -> Time {
+    segment_duration.start_time(line_time_nanos(line, ingest_time, precision) / 1_000_000_000)
+}
+
+/// Converts lines, whose schema has already been validated, into rows split up by the segment
+/// that their timestamps fall into.
+pub(crate) fn convert_lines_into_segments<'a>(
+    lines: impl IntoIterator<Item = (ParsedLine<'a>, &'a str)>,
+    db_name: &NamespaceName<'static>,
+    ingest_time: Time,
+    segment_duration: SegmentDuration,
+    precision: Precision,
+    starting_catalog_sequence_number: SequenceNumber,
+) -> Vec<ValidSegmentedData> {
+    // The parsed and validated table_batches
+    let mut segment_table_batches: HashMap<Time, TableBatchMap<'_>> = HashMap::new();
 
-        validate_and_convert_parsed_line(
+    for (line, raw_line) in lines {
+        convert_parsed_line(
             line,
             raw_line,
             &mut segment_table_batches,
-            &mut schema,
             ingest_time,
             segment_duration,
             precision,
-        )?;
+        );
     }
 
-    let schema = match schema {
-        Cow::Owned(s) => Some(s),
-        Cow::Borrowed(_) => None,
-    };
-
-    let valid_segmented_data = segment_table_batches
+    segment_table_batches
         .into_iter()
         .map(|(segment_start, table_batches)| ValidSegmentedData {
             database_name: db_name.clone(),
@@ -569,22 +1058,17 @@ pub(crate) fn validate_or_insert_schema_and_partitions(
             }),
             starting_catalog_sequence_number,
         })
-        .collect();
-
-    Ok(ValidationResult {
-        schema,
-        line_count,
-        field_count,
-        tag_count,
-        errors: vec![],
-        valid_segmented_data,
-    })
+        .collect()
 }
 
-/// Check if the table exists in the schema and update the schema if it does not
+/// Check if the table exists in the schema and update the schema if it does not. Returns the
+/// number of columns that were added to the schema.
 // Because the entry API requires &mut it is not used to avoid a premature
 // clone of the Cow.
-fn validate_and_update_schema(line: &ParsedLine<'_>, schema: &mut Cow<'_, DatabaseSchema>) {
+fn validate_and_update_schema(
+    line: &ParsedLine<'_>,
+    schema: &mut Cow<'_, DatabaseSchema>,
+) -> usize {
     let table_name = line.series.measurement.as_str();
     match schema.tables.get(table_name) {
         Some(t) => {
@@ -603,10 +1087,13 @@ fn validate_and_update_schema(line: &ParsedLine<'_>, schema: &mut Cow<'_, Databa
                 }
             }
 
+            let new_column_count = new_cols.len();
             if !new_cols.is_empty() {
                 let t = schema.to_mut().tables.get_mut(table_name).unwrap();
                 t.add_columns(new_cols);
             }
+
+            new_column_count
         }
         None => {
             let mut columns = BTreeMap::new();
@@ -619,6 +1106,8 @@ fn validate_and_update_schema(line: &ParsedLine<'_>, schema: &mut Cow<'_, Databa
                 columns.insert(field_name.to_string(), column_type_from_field(value) as i16);
             }
 
+            // every column of a new table is new, not counting the time column
+            let new_column_count = columns.len();
             columns.insert(TIME_COLUMN_NAME.to_string(), ColumnType::Time as i16);
 
             let table = TableDefinition::new(table_name, columns);
@@ -628,21 +1117,22 @@ fn validate_and_update_schema(line: &ParsedLine<'_>, schema: &mut Cow<'_, Databa
                 .tables
                 .insert(table_name.to_string(), table)
                 .is_none());
+
+            new_column_count
         }
-    };
+    }
 }
 
-fn validate_and_convert_parsed_line<'a>(
+/// Builds the row for a line, whose schema has already been validated, and adds it to the
+/// table batch for the segment its timestamp falls into.
+fn convert_parsed_line<'a>(
     line: ParsedLine<'_>,
     raw_line: &'a str,
     segment_table_batches: &mut HashMap<Time, TableBatchMap<'a>>,
-    schema: &mut Cow<'_, DatabaseSchema>,
     ingest_time: Time,
     segment_duration: SegmentDuration,
     precision: Precision,
-) -> Result<()> {
-    validate_and_update_schema(&line, schema);
-
+) {
     // now that we've ensured all columns exist in the schema, construct the actual row and values
     // while validating the column types match.
     let mut values = Vec::with_capacity(line.column_count() + 1);
@@ -675,27 +1165,7 @@ fn validate_and_convert_parsed_line<'a>(
     }
 
     // set the time value
-    let time_value_nanos = line
-        .timestamp
-        .map(|ts| {
-            let multiplier = match precision {
-                Precision::Auto => match crate::guess_precision(ts) {
-                    Precision::Second => 1_000_000_000,
-                    Precision::Millisecond => 1_000_000,
-                    Precision::Microsecond => 1_000,
-                    Precision::Nanosecond => 1,
-
-                    Precision::Auto => unreachable!(),
-                },
-                Precision::Second => 1_000_000_000,
-                Precision::Millisecond => 1_000_000,
-                Precision::Microsecond => 1_000,
-                Precision::Nanosecond => 1,
-            };
-
-            ts * multiplier
-        })
-        .unwrap_or(ingest_time.timestamp_nanos());
+    let time_value_nanos = line_time_nanos(&line, ingest_time, precision);
 
     let segment_start = segment_duration.start_time(time_value_nanos / 1_000_000_000);
 
@@ -716,8 +1186,6 @@ fn validate_and_convert_parsed_line<'a>(
     });
 
     table_batch_map.lines.push(raw_line);
-
-    Ok(())
 }
 
 #[derive(Debug, Default)]
@@ -767,6 +1235,21 @@ impl PartialEq for FieldData {
 
 impl Eq for FieldData {}
 
+/// Result of validating the schema of a set of lines, without converting them into rows.
+#[derive(Debug, Default)]
+pub(crate) struct SchemaValidation {
+    /// If the schema is updated with new tables or columns it will be here
+    pub(crate) schema: Option<DatabaseSchema>,
+    /// Number of lines validated
+    pub(crate) line_count: usize,
+    /// Number of fields in the lines
+    pub(crate) field_count: usize,
+    /// Number of tags in the lines
+    pub(crate) tag_count: usize,
+    /// Per table counts of the lines
+    pub(crate) table_summaries: HashMap<String, TableWriteSummary>,
+}
+
 /// Result of the validation. If the NamespaceSchema or PartitionMap were updated, they will be
 /// in the result.
 #[derive(Debug, Default)]
@@ -781,6 +1264,8 @@ pub(crate) struct ValidationResult {
     pub(crate) field_count: usize,
     /// Number of tags passed in
     pub(crate) tag_count: usize,
+    /// Per table counts of the valid lines passed in
+    pub(crate) table_summaries: HashMap<String, TableWriteSummary>,
     /// Any errors that occurred while parsing the lines
     pub(crate) errors: Vec<crate::WriteLineError>,
     /// Only valid lines from what was passed in to validate, segmented based on the
@@ -798,6 +1283,59 @@ pub(crate) struct ValidSegmentedData {
     pub(crate) starting_catalog_sequence_number: SequenceNumber,
 }
 
+/// The lines of a large write that fall into one segment, whose rows are built and buffered a
+/// chunk of lines at a time, once the write is in the WAL
+#[derive(Debug)]
+pub(crate) struct DeferredRows {
+    pub(crate) database_name: NamespaceName<'static>,
+    pub(crate) segment_start: Time,
+    /// The sequence number of the catalog before any updates were applied based on this write.
+    pub(crate) starting_catalog_sequence_number: SequenceNumber,
+    /// The valid lines, which have already been validated against the catalog
+    lp: String,
+    ingest_time: Time,
+    segment_duration: SegmentDuration,
+    precision: Precision,
+}
+
+impl DeferredRows {
+    /// Returns the table batches of each chunk of up to [`WRITE_CHUNK_LINE_LIMIT`] lines in turn
+    pub(crate) fn chunks(&self) -> impl Iterator<Item = HashMap<String, TableBatch>> + '_ {
+        let mut lines = self.lp.lines().peekable();
+        std::iter::from_fn(move || {
+            lines.peek()?;
+
+            let mut segment_table_batches = HashMap::new();
+            for raw_line in lines.by_ref().take(WRITE_CHUNK_LINE_LIMIT) {
+                // the lines parsed when the write was validated, so they still do
+                let Some(Ok(line)) = parse_lines(raw_line).next() else {
+                    continue;
+                };
+                convert_parsed_line(
+                    line,
+                    raw_line,
+                    &mut segment_table_batches,
+                    self.ingest_time,
+                    self.segment_duration,
+                    self.precision,
+                );
+            }
+
+            let mut table_batches: HashMap<String, TableBatch> = HashMap::new();
+            for batch_map in segment_table_batches.into_values() {
+                for (table_name, table_batch) in batch_map.table_batches {
+                    table_batches
+                        .entry(table_name)
+                        .or_default()
+                        .rows
+                        .extend(table_batch.rows);
+                }
+            }
+            Some(table_batches)
+        })
+    }
+}
+
 #[derive(Debug, Default)]
 pub(crate) struct TableBatchMap<'a> {
     pub(crate) lines: Vec<&'a str>,
@@ -831,7 +1369,7 @@ mod tests {
     use super::*;
     use crate::persister::PersisterImpl;
     use crate::wal::WalImpl;
-    use crate::{SegmentId, SequenceNumber, WalOpBatch};
+    use crate::{SegmentId, SequenceNumber, WalOpBatch, WalPosition};
     use arrow::record_batch::RecordBatch;
     use arrow_util::assert_batches_eq;
     use datafusion_util::config::register_iox_object_store;
@@ -862,6 +1400,23 @@ mod tests {
         assert_eq!(db.tables.len(), 2);
         assert_eq!(db.tables.get("cpu").unwrap().columns().len(), 3);
         assert_eq!(db.tables.get("foo").unwrap().columns().len(), 2);
+
+        assert_eq!(
+            result.table_summaries.get("cpu").unwrap(),
+            &TableWriteSummary {
+                line_count: 1,
+                bytes: 29,
+                new_column_count: 2,
+            }
+        );
+        assert_eq!(
+            result.table_summaries.get("foo").unwrap(),
+            &TableWriteSummary {
+                line_count: 1,
+                bytes: 9,
+                new_column_count: 1,
+            }
+        );
     }
 
     #[tokio::test]
@@ -878,6 +1433,7 @@ mod tests {
             Arc::clone(&time_provider),
             segment_duration,
             crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
         )
         .await
         .unwrap();
@@ -929,13 +1485,484 @@ mod tests {
             time_provider,
             segment_duration,
             crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
+        )
+        .await
+        .unwrap();
+        let actual = write_buffer.get_table_record_batches("foo", "cpu");
+        assert_batches_eq!(&expected, &actual);
+    }
+
+    #[tokio::test]
+    async fn buffers_large_writes_in_chunks() {
+        let dir = test_helpers::tmp_dir().unwrap().into_path();
+        let wal = WalImpl::new(dir.clone()).unwrap();
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister),
+            Some(Arc::new(wal)),
+            Arc::clone(&time_provider),
+            SegmentDuration::new_5m(),
+            crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
+        )
+        .await
+        .unwrap();
+
+        let line_count = WRITE_CHUNK_LINE_LIMIT * 2 + 1;
+        let lp = (0..line_count)
+            .map(|i| format!("cpu,host=h{} bar={i} {i}", i % 10))
+            .collect::<Vec<_>>()
+            .join("\n");
+
+        let summary = write_buffer
+            .write_lp(
+                NamespaceName::new("foo").unwrap(),
+                &lp,
+                Time::from_timestamp_nanos(123),
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(summary.line_count, line_count);
+        assert_eq!(
+            summary.table_summaries.get("cpu").unwrap().new_column_count,
+            2
+        );
+        // the whole write goes into the wal in one batch
+        assert_eq!(
+            summary.wal_positions,
+            vec![WalPosition {
+                segment_id: SegmentId::new(1),
+                sequence_number: SequenceNumber::new(1),
+            }]
+        );
+        // the write is queryable once it has returned
+        let watermarks = write_buffer.watermarks();
+        assert!(watermarks.is_readable(summary.wal_positions[0]));
+        assert!(!watermarks.is_persisted(summary.wal_positions[0]));
+
+        let rows: usize = write_buffer
+            .get_table_record_batches("foo", "cpu")
+            .iter()
+            .map(|batch| batch.num_rows())
+            .sum();
+        assert_eq!(rows, line_count);
+
+        // the rows are built a chunk at a time, but the write is a single op in the wal
+        let wal = WalImpl::new(dir).unwrap();
+        let mut reader = wal.open_segment_reader(SegmentId::new(1)).unwrap();
+        let mut ops = vec![];
+        while let Some(batch) = reader.next_batch().unwrap() {
+            ops.extend(batch.ops);
+        }
+        assert_eq!(ops.len(), 1);
+        let wal_lines: usize = ops
+            .iter()
+            .map(|op| match op {
+                WalOp::LpWrite(write) => write.lp.lines().count(),
+            })
+            .sum();
+        assert_eq!(wal_lines, line_count);
+    }
+
+    #[tokio::test]
+    async fn sends_accepted_writes_to_subscribers() {
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister),
+            None::<Arc<crate::wal::WalImpl>>,
+            Arc::clone(&time_provider),
+            SegmentDuration::new_5m(),
+            crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
+        )
+        .await
+        .unwrap();
+
+        let mut subscriber = write_buffer.subscribe();
+        write_buffer
+            .write_lp(
+                NamespaceName::new("foo").unwrap(),
+                "cpu,host=a usage=0.5 1\nnot valid lp",
+                Time::from_timestamp_nanos(123),
+                true,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+
+        let op = subscriber.try_recv().unwrap();
+        assert_eq!(
+            *op,
+            LpWriteOp {
+                db_name: "foo".to_string(),
+                lp: "cpu,host=a usage=0.5 1".to_string(),
+                default_time: 123,
+                precision: Precision::Nanosecond,
+            }
+        );
+        assert!(subscriber.try_recv().is_err());
+    }
+
+    #[tokio::test]
+    async fn persists_ingest_transforms() {
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister),
+            None::<Arc<crate::wal::WalImpl>>,
+            Arc::clone(&time_provider),
+            SegmentDuration::new_5m(),
+            crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
+        )
+        .await
+        .unwrap();
+        let transforms = vec![IngestTransform::DropField {
+            table: None,
+            field: "debug".to_string(),
+        }];
+        write_buffer
+            .set_ingest_transforms("foo", transforms.clone())
+            .await
+            .unwrap();
+
+        // lines keep their numbers through the transforms, around blank and comment lines
+        let summary = write_buffer
+            .write_lp(
+                NamespaceName::new("foo").unwrap(),
+                "# a comment\ncpu usage=1,debug=true 1\n\ndisk debug=true 2\nnot valid lp",
+                Time::from_timestamp_nanos(0),
+                true,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(summary.line_count, 1);
+        let invalid_lines: Vec<_> = summary
+            .invalid_lines
+            .iter()
+            .map(|e| (e.line_number, e.original_line.as_str()))
+            .collect();
+        assert_eq!(invalid_lines, vec![(5, "not valid lp")]);
+
+        // the transforms are loaded by a buffer started from the persisted state
+        let write_buffer = WriteBufferImpl::new(
+            persister,
+            None::<Arc<crate::wal::WalImpl>>,
+            time_provider,
+            SegmentDuration::new_5m(),
+            crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            write_buffer
+                .catalog()
+                .db_schema("foo")
+                .unwrap()
+                .ingest_transforms(),
+            transforms.as_slice()
+        );
+    }
+
+    #[tokio::test]
+    async fn applies_write_flags() {
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister),
+            None::<Arc<crate::wal::WalImpl>>,
+            Arc::clone(&time_provider),
+            SegmentDuration::new_5m(),
+            crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
         )
         .await
         .unwrap();
+        let db_name = NamespaceName::new("foo").unwrap();
+
+        write_buffer
+            .write_lp(
+                db_name.clone(),
+                "cpu,host=a usage=0.5 1",
+                Time::from_timestamp_nanos(0),
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        let write_flags = WriteFlags {
+            accept_partial: Some(true),
+            coerce_field_types: true,
+            strict_schema: true,
+            replicate: true,
+            validate_histograms: false,
+        };
+        write_buffer
+            .set_write_flags("foo", write_flags)
+            .await
+            .unwrap();
+
+        // the integer is written to the float column, and the invalid line is skipped even though
+        // the request didn't ask for partial writes
+        let summary = write_buffer
+            .write_lp(
+                db_name.clone(),
+                "cpu,host=b usage=2i 2\nnot valid lp",
+                Time::from_timestamp_nanos(0),
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(summary.line_count, 1);
+        assert_eq!(summary.invalid_lines.len(), 1);
+
         let actual = write_buffer.get_table_record_batches("foo", "cpu");
+        let expected = [
+            "+------+-------+--------------------------------+",
+            "| host | usage | time                           |",
+            "+------+-------+--------------------------------+",
+            "| a    | 0.5   | 1970-01-01T00:00:00.000000001Z |",
+            "| b    | 2.0   | 1970-01-01T00:00:00.000000002Z |",
+            "+------+-------+--------------------------------+",
+        ];
+        assert_batches_eq!(&expected, &actual);
+
+        // new columns are rejected
+        let err = write_buffer
+            .write_lp(
+                db_name,
+                "cpu,host=a,region=us usage=1 3",
+                Time::from_timestamp_nanos(0),
+                false,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap_err();
+        assert!(matches!(err, Error::SchemaChangeNotAllowed { .. }));
+        assert!(!write_buffer
+            .catalog()
+            .db_schema("foo")
+            .unwrap()
+            .tables
+            .get("cpu")
+            .unwrap()
+            .column_exists("region"));
+
+        // the flags are loaded by a buffer started from the persisted state
+        let write_buffer = WriteBufferImpl::new(
+            persister,
+            None::<Arc<crate::wal::WalImpl>>,
+            time_provider,
+            SegmentDuration::new_5m(),
+            crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
+        )
+        .await
+        .unwrap();
+        assert_eq!(
+            write_buffer
+                .catalog()
+                .db_schema("foo")
+                .unwrap()
+                .write_flags(),
+            write_flags
+        );
+    }
+
+    #[tokio::test]
+    async fn validates_histograms() {
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister),
+            None::<Arc<crate::wal::WalImpl>>,
+            Arc::clone(&time_provider),
+            SegmentDuration::new_5m(),
+            crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
+        )
+        .await
+        .unwrap();
+        let lp = "http,host=a latency=\"histogram:sum=2.5,count=3;0.5=1,1=2,+Inf=3\" 1\n\
+                  http,host=b latency=\"histogram:sum=2.5,count=3;1=2,0.5=1,+Inf=3\" 2";
+
+        // histograms are only validated for databases with the write flag set
+        let summary = write_buffer
+            .write_lp(
+                NamespaceName::new("bar").unwrap(),
+                lp,
+                Time::from_timestamp_nanos(0),
+                true,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(summary.line_count, 2);
+        assert!(summary.invalid_lines.is_empty());
+
+        write_buffer
+            .set_write_flags(
+                "foo",
+                WriteFlags {
+                    validate_histograms: true,
+                    ..Default::default()
+                },
+            )
+            .await
+            .unwrap();
+        let summary = write_buffer
+            .write_lp(
+                NamespaceName::new("foo").unwrap(),
+                lp,
+                Time::from_timestamp_nanos(0),
+                true,
+                Precision::Nanosecond,
+            )
+            .await
+            .unwrap();
+        assert_eq!(summary.line_count, 1);
+        let invalid_lines: Vec<_> = summary
+            .invalid_lines
+            .iter()
+            .map(|e| (e.line_number, e.error_message.as_str()))
+            .collect();
+        assert_eq!(
+            invalid_lines,
+            vec![(
+                2,
+                "invalid histogram in field latency: histogram bucket upper bounds must \
+                 increase, but 0.5 follows 1"
+            )]
+        );
+
+        let actual = write_buffer.get_table_record_batches("foo", "http");
+        let expected = [
+            "+------+--------------------------------------------+--------------------------------+",
+            "| host | latency                                    | time                           |",
+            "+------+--------------------------------------------+--------------------------------+",
+            "| a    | histogram:sum=2.5,count=3;0.5=1,1=2,+Inf=3 | 1970-01-01T00:00:00.000000001Z |",
+            "+------+--------------------------------------------+--------------------------------+",
+        ];
         assert_batches_eq!(&expected, &actual);
     }
 
+    #[tokio::test]
+    async fn rejects_writes_after_persist_failures() {
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister),
+            None::<Arc<crate::wal::WalImpl>>,
+            Arc::clone(&time_provider),
+            SegmentDuration::new_5m(),
+            crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
+        )
+        .await
+        .unwrap()
+        .with_persist_failure_limit(2);
+        let write = |db_name: &'static str| {
+            write_buffer.write_lp(
+                NamespaceName::new(db_name).unwrap(),
+                "cpu,host=a usage=0.5 1",
+                Time::from_timestamp_nanos(0),
+                false,
+                Precision::Nanosecond,
+            )
+        };
+
+        write_buffer
+            .persist_health
+            .record_failure(["foo".to_string(), "bar".to_string()]);
+        write("foo").await.unwrap();
+
+        write_buffer
+            .persist_health
+            .record_failure(["foo".to_string()]);
+        let err = write("foo").await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::PersistBackpressure { failures: 2, .. }
+        ));
+        // failures are counted for each database, so a database that was only in one of the
+        // failed segments is still written to, as are databases that weren't in any of them
+        write("bar").await.unwrap();
+        write("baz").await.unwrap();
+
+        // persisting a segment with data for another database doesn't clear the failures
+        write_buffer
+            .persist_health
+            .record_success(["bar".to_string()]);
+        assert!(write("foo").await.is_err());
+
+        write_buffer
+            .persist_health
+            .record_success(["foo".to_string()]);
+        write("foo").await.unwrap();
+    }
+
+    #[tokio::test]
+    async fn rejects_blocked_lines() {
+        let object_store: Arc<dyn ObjectStore> = Arc::new(InMemory::new());
+        let persister = Arc::new(PersisterImpl::new(Arc::clone(&object_store)));
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let write_buffer = WriteBufferImpl::new(
+            Arc::clone(&persister),
+            None::<Arc<crate::wal::WalImpl>>,
+            Arc::clone(&time_provider),
+            SegmentDuration::new_5m(),
+            crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
+        )
+        .await
+        .unwrap()
+        .with_blocklist(Blocklist::new(
+            vec!["debug_*".to_string()],
+            vec![],
+            BlockAction::Reject,
+        ));
+        let write = |accept_partial| {
+            write_buffer.write_lp(
+                NamespaceName::new("foo").unwrap(),
+                "cpu,host=a usage=0.5 1
+debug_agent x=1i 1
+cpu,host=b usage= 1",
+                Time::from_timestamp_nanos(0),
+                accept_partial,
+                Precision::Nanosecond,
+            )
+        };
+
+        let err = write(false).await.unwrap_err();
+        assert!(matches!(
+            err,
+            Error::ParseError(WriteLineError { line_number: 2, .. })
+        ));
+
+        // the blocked line is blanked out, so the lines after it keep their numbers
+        let result = write(true).await.unwrap();
+        let invalid_line_numbers: Vec<_> =
+            result.invalid_lines.iter().map(|e| e.line_number).collect();
+        assert_eq!(invalid_line_numbers, vec![2, 3]);
+        assert_eq!(result.line_count, 1);
+        let db = write_buffer.catalog().db_schema("foo").unwrap();
+        assert_eq!(db.table_names(), vec!["cpu"]);
+    }
+
     #[tokio::test(flavor = "multi_thread", worker_threads = 2)]
     async fn returns_chunks_across_buffered_persisted_and_persisting_data() {
         let dir = test_helpers::tmp_dir().unwrap().into_path();
@@ -950,6 +1977,7 @@ mod tests {
             Arc::clone(&time_provider),
             segment_duration,
             crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
         )
         .await
         .unwrap();
@@ -1027,6 +2055,7 @@ mod tests {
             Arc::clone(&time_provider),
             segment_duration,
             crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
         )
         .await
         .unwrap();
@@ -1071,6 +2100,7 @@ mod tests {
             Arc::clone(&time_provider),
             segment_duration,
             crate::test_help::make_exec(),
+            Arc::new(metric::Registry::new()),
         )
         .await
         .unwrap();
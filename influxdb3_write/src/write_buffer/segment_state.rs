@@ -6,7 +6,7 @@ use crate::wal::WalSegmentWriterNoopImpl;
 use crate::write_buffer::buffer_segment::{ClosedBufferSegment, OpenBufferSegment, WriteBatch};
 use crate::{
     persister, wal, write_buffer, ParquetFile, PersistedSegment, Persister, SegmentDuration,
-    SegmentId, SegmentRange, SequenceNumber, Wal, WalOp,
+    SegmentId, SegmentRange, SequenceNumber, Wal, WalOp, WalPosition, Watermarks,
 };
 use arrow::datatypes::SchemaRef;
 #[cfg(test)]
@@ -18,11 +18,11 @@ use datafusion::logical_expr::Expr;
 use iox_query::chunk_statistics::create_chunk_statistics;
 use iox_query::QueryChunk;
 use iox_time::{Time, TimeProvider};
-use observability_deps::tracing::error;
-use parking_lot::RwLock;
+use observability_deps::tracing::{error, info, warn};
+use parking_lot::{Mutex, RwLock};
 #[cfg(test)]
 use schema::Schema;
-use std::collections::BTreeMap;
+use std::collections::{BTreeMap, HashMap};
 use std::sync::Arc;
 use std::time::Duration;
 use tokio::sync::watch;
@@ -41,8 +41,13 @@ pub(crate) struct SegmentState<T, W> {
     // Map of segment start times to open segments. Should always have a segment open for the
     // start time that time.now falls into.
     segments: BTreeMap<Time, OpenBufferSegment>,
-    persisting_segments: BTreeMap<Time, Arc<ClosedBufferSegment>>,
-    persisted_segments: BTreeMap<Time, Arc<PersistedSegment>>,
+    // Map of the start time and id of closed segments to the segments, while they're persisted.
+    // As with persisted segments, more than one segment may be closed for the same time.
+    persisting_segments: BTreeMap<(Time, SegmentId), Arc<ClosedBufferSegment>>,
+    // Map of the min time and id of persisted segments to the segments. The id is part of the
+    // key as a segment for the same time may be persisted more than once, e.g. for late or
+    // backfilled data.
+    persisted_segments: BTreeMap<(Time, SegmentId), Arc<PersistedSegment>>,
 }
 
 impl<T: TimeProvider, W: Wal> SegmentState<T, W> {
@@ -64,13 +69,19 @@ impl<T: TimeProvider, W: Wal> SegmentState<T, W> {
 
         let mut persisting_segments_map = BTreeMap::new();
         for segment in persisting_segments {
-            persisting_segments_map.insert(segment.segment_range.start_time, Arc::new(segment));
+            persisting_segments_map.insert(
+                (segment.segment_range.start_time, segment.segment_id),
+                Arc::new(segment),
+            );
         }
 
         let mut persisted_segments_map = BTreeMap::new();
         for segment in persisted_segments {
             persisted_segments_map.insert(
-                Time::from_timestamp_nanos(segment.segment_min_time),
+                (
+                    Time::from_timestamp_nanos(segment.segment_min_time),
+                    segment.segment_id,
+                ),
                 Arc::new(segment),
             );
         }
@@ -92,12 +103,54 @@ impl<T: TimeProvider, W: Wal> SegmentState<T, W> {
         segment_start: Time,
         ops: Vec<WalOp>,
         starting_catalog_sequence_number: SequenceNumber,
-    ) -> wal::Result<()> {
+    ) -> wal::Result<WalPosition> {
         let segment =
             self.get_or_create_segment_for_time(segment_start, starting_catalog_sequence_number)?;
         segment.write_wal_ops(ops)
     }
 
+    /// Records that the batches up to the sequence number have been buffered into the open
+    /// segment starting at the time
+    pub(crate) fn mark_readable(&mut self, segment_start: Time, sequence_number: SequenceNumber) {
+        if let Some(segment) = self.segments.get_mut(&segment_start) {
+            segment.mark_readable(sequence_number);
+        }
+    }
+
+    pub(crate) fn watermarks(&self) -> Watermarks {
+        let mut open: Vec<_> = self
+            .segments
+            .values()
+            .map(|segment| segment.readable_position())
+            .collect();
+        open.sort();
+        let mut persisting: Vec<_> = self
+            .persisting_segments
+            .values()
+            .map(|segment| segment.segment_id)
+            .collect();
+        persisting.sort();
+
+        Watermarks {
+            last_segment_id: self.last_segment_id,
+            open,
+            persisting,
+        }
+    }
+
+    pub(crate) fn open_segments(&self) -> impl Iterator<Item = &OpenBufferSegment> {
+        self.segments.values()
+    }
+
+    pub(crate) fn last_segment_id(&self) -> SegmentId {
+        self.last_segment_id
+    }
+
+    /// Returns the current time from the state's time provider
+    pub(crate) fn now(&self) -> Time {
+        self.time_provider.now()
+    }
+
     pub(crate) fn write_batch_to_segment(
         &mut self,
         segment_start: Time,
@@ -264,29 +317,54 @@ impl<T: TimeProvider, W: Wal> SegmentState<T, W> {
         self.segments.get(&time)
     }
 
+    /// Marks the open segments that start at the given times as holding backfilled data, if
+    /// they're older than the current segment, so that they're persisted as soon as the backfill
+    /// stops writing to them
+    pub(crate) fn mark_backfill_segments(&mut self, segment_starts: &[Time]) {
+        let now = self.time_provider.now();
+        let current_segment_start =
+            SegmentRange::from_time_and_duration(now, self.segment_duration, false).start_time;
+
+        for segment_start in segment_starts {
+            if *segment_start >= current_segment_start {
+                continue;
+            }
+            if let Some(segment) = self.segments.get_mut(segment_start) {
+                segment.mark_backfill(now);
+            }
+        }
+    }
+
     // Looks at the open buffer segments and returns the start `Time` of any that meet the following
     // criteria (in time ascending order):
     // 1. The segment is not in the current time or next time
     // 2. The segment has been open for longer than half the segment duration
+    // segments with backfilled data are persisted first, as they're only in memory until the
+    // bulk load is on disk
     fn segments_to_persist(&self, current_time: Time) -> Vec<Time> {
         let mut segments_to_persist = vec![];
 
         for (start_time, segment) in &self.segments {
             if segment.should_persist(current_time) {
-                segments_to_persist.push(*start_time);
+                segments_to_persist.push((!segment.is_backfill(), *start_time));
             }
         }
 
         segments_to_persist.sort();
         segments_to_persist
+            .into_iter()
+            .map(|(_, start_time)| start_time)
+            .collect()
     }
 
     fn close_segment(&mut self, segment_start: Time) -> Option<Arc<ClosedBufferSegment>> {
         self.segments.remove(&segment_start).map(|segment| {
             let closed_segment = Arc::new(segment.into_closed_segment(Arc::clone(&self.catalog)));
 
-            self.persisting_segments
-                .insert(segment_start, Arc::clone(&closed_segment));
+            self.persisting_segments.insert(
+                (segment_start, closed_segment.segment_id),
+                Arc::clone(&closed_segment),
+            );
 
             closed_segment
         })
@@ -335,9 +413,51 @@ const PERSISTER_CHECK_INTERVAL: Duration = Duration::from_millis(10);
 #[cfg(not(test))]
 const PERSISTER_CHECK_INTERVAL: Duration = Duration::from_secs(1);
 
+/// Tracks failures to persist closed segments to the object store, so that writes to the
+/// databases with data in those segments can be turned away while the object store is degraded,
+/// rather than piling up in the buffer
+#[derive(Debug, Default)]
+pub(crate) struct PersistHealth {
+    /// The number of consecutive failures to persist a segment with data for each database
+    failures: Mutex<HashMap<String, usize>>,
+}
+
+impl PersistHealth {
+    pub(crate) fn record_failure(&self, databases: impl IntoIterator<Item = String>) {
+        let mut failures = self.failures.lock();
+        for db_name in databases {
+            *failures.entry(db_name).or_default() += 1;
+        }
+    }
+
+    /// Clears the failures of the databases once a segment with their data is persisted
+    pub(crate) fn record_success(&self, databases: impl IntoIterator<Item = String>) {
+        let mut failures = self.failures.lock();
+        for db_name in databases {
+            if let Some(failures) = failures.remove(&db_name) {
+                info!(
+                    db_name,
+                    failures, "persisted segment for database after previous failures"
+                );
+            }
+        }
+    }
+
+    /// Returns the number of consecutive failures to persist segments with data for the
+    /// database, which is zero if the database's data wasn't in any of them
+    pub(crate) fn consecutive_failures(&self, db_name: &str) -> usize {
+        self.failures
+            .lock()
+            .get(db_name)
+            .copied()
+            .unwrap_or_default()
+    }
+}
+
 pub(crate) async fn run_buffer_segment_persist_and_cleanup<P, T, W>(
     persister: Arc<P>,
     segment_state: Arc<RwLock<SegmentState<T, W>>>,
+    persist_health: Arc<PersistHealth>,
     mut shutdown_rx: watch::Receiver<()>,
     time_provider: Arc<T>,
     wal: Option<Arc<W>>,
@@ -355,7 +475,7 @@ pub(crate) async fn run_buffer_segment_persist_and_cleanup<P, T, W>(
                 break;
             }
             _ = tokio::time::sleep(PERSISTER_CHECK_INTERVAL) => {
-                if let Err(e) = persist_and_cleanup_ready_segments(Arc::clone(&persister), Arc::clone(&segment_state), Arc::clone(&time_provider), wal.clone(), Arc::clone(&executor)).await {
+                if let Err(e) = persist_and_cleanup_ready_segments(Arc::clone(&persister), Arc::clone(&segment_state), &persist_health, Arc::clone(&time_provider), wal.clone(), Arc::clone(&executor)).await {
                     error!("Error persisting and cleaning up segments: {}", e);
                 }
             }
@@ -366,6 +486,7 @@ pub(crate) async fn run_buffer_segment_persist_and_cleanup<P, T, W>(
 async fn persist_and_cleanup_ready_segments<P, T, W>(
     persister: Arc<P>,
     segment_state: Arc<RwLock<SegmentState<T, W>>>,
+    persist_health: &PersistHealth,
     time_provider: Arc<T>,
     wal: Option<Arc<W>>,
     executor: Arc<iox_query::exec::Executor>,
@@ -377,8 +498,9 @@ where
     W: Wal,
     write_buffer::Error: From<<P as Persister>::Error>,
 {
-    // this loop is where persistence happens so if anything is in persisting,
-    // it's either been dropped or remaining from a restart, so clear those out first.
+    // this loop is where persistence happens so if anything is in persisting, it's either failed
+    // to persist before or is remaining from a restart, so clear those out first. Segments that
+    // fail to persist are left in persisting and retried on the next check.
     let persisting_segments = {
         let segment_state = segment_state.read();
         segment_state
@@ -393,11 +515,11 @@ where
             segment,
             Arc::clone(&persister),
             Arc::clone(&segment_state),
+            persist_health,
             wal.clone(),
             Arc::clone(&executor),
         )
-        .await
-        .unwrap()
+        .await?
     }
 
     // check for open segments to persist
@@ -419,11 +541,11 @@ where
                 closed_segment,
                 Arc::clone(&persister),
                 Arc::clone(&segment_state),
+                persist_health,
                 wal.clone(),
                 Arc::clone(&executor),
             )
-            .await
-            .unwrap()
+            .await?
         }
     }
 
@@ -438,6 +560,7 @@ async fn persist_closed_segment_and_cleanup<P, T, W>(
     closed_segment: Arc<ClosedBufferSegment>,
     persister: Arc<P>,
     segment_state: Arc<RwLock<SegmentState<T, W>>>,
+    persist_health: &PersistHealth,
     wal: Option<Arc<W>>,
     executor: Arc<iox_query::exec::Executor>,
 ) -> Result<(), crate::Error>
@@ -450,16 +573,35 @@ where
 {
     let closed_segment_start_time = closed_segment.segment_range.start_time;
     let closed_segment_id = closed_segment.segment_id;
-    let persisted_segment = closed_segment.persist(persister, executor, None).await?;
+    let databases = closed_segment
+        .buffered_data
+        .database_names()
+        .cloned()
+        .collect::<Vec<_>>();
+    let persisted_segment = match closed_segment.persist(persister, executor, None).await {
+        Ok(persisted_segment) => persisted_segment,
+        Err(e) => {
+            warn!(
+                segment_id = closed_segment_id.0,
+                ?databases,
+                error = %e,
+                "failed to persist segment, will retry"
+            );
+            persist_health.record_failure(databases);
+            return Err(e.into());
+        }
+    };
+    persist_health.record_success(databases);
 
     {
         let mut segment_state = segment_state.write();
         segment_state
             .persisting_segments
-            .remove(&closed_segment_start_time);
-        segment_state
-            .persisted_segments
-            .insert(closed_segment_start_time, Arc::new(persisted_segment));
+            .remove(&(closed_segment_start_time, closed_segment_id));
+        segment_state.persisted_segments.insert(
+            (closed_segment_start_time, closed_segment_id),
+            Arc::new(persisted_segment),
+        );
     }
 
     if let Some(wal) = wal {
@@ -553,6 +695,62 @@ mod tests {
         );
     }
 
+    #[test]
+    fn backfill_segments_persist_first() {
+        let catalog = Arc::new(Catalog::new());
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let segment_duration = SegmentDuration::new_5m();
+
+        let open_segments = [0, 300, 600]
+            .into_iter()
+            .enumerate()
+            .map(|(i, start)| {
+                let segment_id = SegmentId::new(i as u32 + 1);
+                OpenBufferSegment::new(
+                    Arc::clone(&catalog),
+                    segment_id,
+                    SegmentRange::from_time_and_duration(
+                        Time::from_timestamp(start, 0).unwrap(),
+                        segment_duration,
+                        false,
+                    ),
+                    time_provider.now(),
+                    catalog.sequence_number(),
+                    Box::new(WalSegmentWriterNoopImpl::new(segment_id)),
+                    None,
+                )
+            })
+            .collect();
+
+        let mut segment_state: SegmentState<MockProvider, WalImpl> = SegmentState::new(
+            segment_duration,
+            SegmentId::new(4),
+            Arc::clone(&catalog),
+            Arc::clone(&time_provider),
+            open_segments,
+            vec![],
+            vec![],
+            None,
+        );
+
+        // the current segment isn't marked, even if a backfill writes to it
+        time_provider.set(Time::from_timestamp(800, 0).unwrap());
+        segment_state.mark_backfill_segments(&[
+            Time::from_timestamp(300, 0).unwrap(),
+            Time::from_timestamp(600, 0).unwrap(),
+        ]);
+
+        let segments_to_persist =
+            segment_state.segments_to_persist(Time::from_timestamp(800 + 31, 0).unwrap());
+        assert_eq!(
+            segments_to_persist,
+            vec![
+                Time::from_timestamp(300, 0).unwrap(),
+                Time::from_timestamp_nanos(0),
+            ]
+        );
+    }
+
     #[tokio::test]
     async fn persist_and_cleanup_ready_segments_handles_persisting_and_rotates_old() {
         let catalog = Arc::new(Catalog::new());
@@ -632,6 +830,7 @@ mod tests {
         persist_and_cleanup_ready_segments(
             Arc::clone(&persister),
             Arc::clone(&segment_state),
+            &PersistHealth::default(),
             Arc::clone(&time_provider),
             Some(Arc::clone(&wal)),
             crate::test_help::make_exec(),
@@ -656,6 +855,95 @@ mod tests {
         assert_eq!(deleted_segments, vec![SegmentId::new(1), SegmentId::new(2)]);
     }
 
+    #[tokio::test]
+    async fn persists_closed_segments_for_the_same_time() {
+        let catalog = Arc::new(Catalog::new());
+        let time_provider = Arc::new(MockProvider::new(Time::from_timestamp_nanos(0)));
+        let segment_duration = SegmentDuration::new_5m();
+
+        // a segment is closed for the same time more than once if late data reopens it
+        let closed_segments = [(1, "cpu bar=1 10"), (2, "cpu bar=2 20")]
+            .into_iter()
+            .map(|(id, lp)| {
+                let segment_id = SegmentId::new(id);
+                let mut segment = OpenBufferSegment::new(
+                    Arc::clone(&catalog),
+                    segment_id,
+                    SegmentRange::from_time_and_duration(
+                        Time::from_timestamp_nanos(0),
+                        segment_duration,
+                        false,
+                    ),
+                    time_provider.now(),
+                    catalog.sequence_number(),
+                    Box::new(WalSegmentWriterNoopImpl::new(segment_id)),
+                    None,
+                );
+                segment
+                    .buffer_writes(lp_to_write_batch(&catalog, "foo", lp))
+                    .unwrap();
+                segment.into_closed_segment(Arc::clone(&catalog))
+            })
+            .collect();
+
+        let wal = Arc::new(TestWal::default());
+
+        let segment_state: SegmentState<MockProvider, TestWal> = SegmentState::new(
+            segment_duration,
+            SegmentId::new(2),
+            Arc::clone(&catalog),
+            Arc::clone(&time_provider),
+            vec![],
+            closed_segments,
+            vec![],
+            Some(Arc::clone(&wal)),
+        );
+        assert_eq!(
+            segment_state.watermarks().persisting,
+            vec![SegmentId::new(1), SegmentId::new(2)]
+        );
+        let segment_state = Arc::new(RwLock::new(segment_state));
+
+        let persister = Arc::new(TestPersister::default());
+
+        persist_and_cleanup_ready_segments(
+            Arc::clone(&persister),
+            Arc::clone(&segment_state),
+            &PersistHealth::default(),
+            Arc::clone(&time_provider),
+            Some(Arc::clone(&wal)),
+            crate::test_help::make_exec(),
+        )
+        .await
+        .unwrap();
+
+        let persisted_state = persister
+            .as_any()
+            .downcast_ref::<TestPersister>()
+            .unwrap()
+            .state
+            .lock();
+        assert_eq!(persisted_state.segments.len(), 2);
+
+        let wal_state = wal.as_any().downcast_ref::<TestWal>().unwrap();
+        let deleted_segments = wal_state.deleted_wal_segments.lock().clone();
+        assert_eq!(deleted_segments, vec![SegmentId::new(1), SegmentId::new(2)]);
+
+        let segment_state = segment_state.read();
+        assert!(segment_state.persisting_segments.is_empty());
+        assert_eq!(
+            segment_state
+                .persisted_segments
+                .keys()
+                .cloned()
+                .collect::<Vec<_>>(),
+            vec![
+                (Time::from_timestamp_nanos(0), SegmentId::new(1)),
+                (Time::from_timestamp_nanos(0), SegmentId::new(2)),
+            ]
+        );
+    }
+
     #[derive(Debug, Default)]
     struct TestWal {
         deleted_wal_segments: Mutex<Vec<SegmentId>>,
@@ -693,6 +981,14 @@ mod tests {
             Ok(())
         }
 
+        fn write_snapshot(&self, _data: &[u8]) -> wal::Result<()> {
+            todo!()
+        }
+
+        fn take_snapshot(&self) -> wal::Result<Option<Vec<u8>>> {
+            todo!()
+        }
+
         fn as_any(&self) -> &dyn Any {
             self as &dyn Any
         }
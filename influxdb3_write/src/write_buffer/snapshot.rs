@@ -0,0 +1,283 @@
+//! A snapshot of the write buffer that is written before a planned shutdown and loaded on the
+//! next start in place of replaying the WAL.
+//!
+//! The snapshot holds the catalog and the buffered rows of every open segment, along with the
+//! sequence number of the last batch of each segment's WAL file that had been buffered when it
+//! was taken. A segment is only loaded from the snapshot if its WAL file ends at that sequence
+//! number, otherwise it has batches that aren't in the snapshot and is replayed from the WAL as
+//! usual.
+
+use crate::catalog::{Catalog, InnerCatalog, TIME_COLUMN_NAME};
+use crate::write_buffer::buffer_segment::{BufferedData, OpenBufferSegment};
+use crate::write_buffer::{Error, Field, FieldData, Result, Row, TableBatch};
+use crate::{SegmentId, SequenceNumber};
+use arrow::array::{Array, AsArray};
+use arrow::datatypes::{
+    DataType, Float64Type, Int32Type, Int64Type, TimeUnit, TimestampNanosecondType, UInt64Type,
+};
+use arrow::ipc::reader::StreamReader;
+use arrow::ipc::writer::StreamWriter;
+use arrow::record_batch::RecordBatch;
+use byteorder::{BigEndian, ReadBytesExt, WriteBytesExt};
+use data_types::PartitionKey;
+use serde::{Deserialize, Serialize};
+use std::collections::HashMap;
+use std::io::{Cursor, Read};
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SnapshotManifest {
+    catalog: InnerCatalog,
+    segments: Vec<SegmentManifest>,
+}
+
+#[derive(Debug, Serialize, Deserialize)]
+struct SegmentManifest {
+    segment_id: SegmentId,
+    last_sequence_number: SequenceNumber,
+    segment_size: usize,
+    tables: Vec<TableManifest>,
+}
+
+/// A table buffer in the snapshot, whose rows follow the manifest as an Arrow IPC stream of
+/// `len` bytes
+#[derive(Debug, Serialize, Deserialize)]
+struct TableManifest {
+    db_name: String,
+    table_name: String,
+    len: u64,
+}
+
+/// A snapshot that has been read back in
+#[derive(Debug)]
+pub(crate) struct BufferSnapshot {
+    pub(crate) catalog: InnerCatalog,
+    segments: HashMap<SegmentId, SegmentSnapshot>,
+}
+
+#[derive(Debug)]
+pub(crate) struct SegmentSnapshot {
+    last_sequence_number: SequenceNumber,
+    segment_size: usize,
+    tables: Vec<(String, String, Vec<RecordBatch>)>,
+}
+
+/// Encodes the catalog and the buffered data of the open segments into a snapshot. The data is
+/// laid out as the length of the JSON manifest, the manifest, and then the Arrow IPC stream of
+/// each table in the order they are listed in the manifest.
+pub(crate) fn encode_snapshot<'a>(
+    catalog: &Catalog,
+    segments: impl IntoIterator<Item = &'a OpenBufferSegment>,
+) -> Result<Vec<u8>> {
+    let mut manifest = SnapshotManifest {
+        catalog: catalog.clone_inner(),
+        segments: vec![],
+    };
+    let mut table_data = vec![];
+
+    for segment in segments {
+        let mut tables = vec![];
+        for (db_name, table_name, batch) in segment.buffered_data().table_snapshots(catalog)? {
+            let mut data = vec![];
+            let mut writer = StreamWriter::try_new(&mut data, &batch.schema())
+                .map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+            writer
+                .write(&batch)
+                .map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+            writer
+                .finish()
+                .map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+            drop(writer);
+
+            tables.push(TableManifest {
+                db_name,
+                table_name,
+                len: data.len() as u64,
+            });
+            table_data.push(data);
+        }
+
+        manifest.segments.push(SegmentManifest {
+            segment_id: segment.segment_id(),
+            // batches written to the WAL that haven't been buffered yet aren't in the snapshot,
+            // so the segment is replayed if its WAL file is past what was buffered
+            last_sequence_number: segment.readable_position().sequence_number,
+            segment_size: segment.segment_size(),
+            tables,
+        });
+    }
+
+    let manifest =
+        serde_json::to_vec(&manifest).map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+    let mut data =
+        Vec::with_capacity(4 + manifest.len() + table_data.iter().map(|t| t.len()).sum::<usize>());
+    data.write_u32::<BigEndian>(
+        u32::try_from(manifest.len()).map_err(|e| Error::InvalidSnapshot(e.to_string()))?,
+    )
+    .expect("writing to a vec can't fail");
+    data.extend_from_slice(&manifest);
+    for table in table_data {
+        data.extend_from_slice(&table);
+    }
+
+    Ok(data)
+}
+
+pub(crate) fn decode_snapshot(data: &[u8]) -> Result<BufferSnapshot> {
+    let mut data = Cursor::new(data);
+    let invalid = |e: std::io::Error| Error::InvalidSnapshot(e.to_string());
+
+    let manifest_len = data.read_u32::<BigEndian>().map_err(invalid)?;
+    let mut manifest = vec![0u8; manifest_len as usize];
+    data.read_exact(&mut manifest).map_err(invalid)?;
+    let manifest: SnapshotManifest =
+        serde_json::from_slice(&manifest).map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+
+    let mut segments = HashMap::with_capacity(manifest.segments.len());
+    for segment in manifest.segments {
+        let mut tables = Vec::with_capacity(segment.tables.len());
+        for table in segment.tables {
+            let mut ipc = vec![0u8; table.len as usize];
+            data.read_exact(&mut ipc).map_err(invalid)?;
+            let batches = StreamReader::try_new(Cursor::new(ipc), None)
+                .and_then(|reader| reader.collect::<Result<Vec<_>, _>>())
+                .map_err(|e| Error::InvalidSnapshot(e.to_string()))?;
+            tables.push((table.db_name, table.table_name, batches));
+        }
+
+        segments.insert(
+            segment.segment_id,
+            SegmentSnapshot {
+                last_sequence_number: segment.last_sequence_number,
+                segment_size: segment.segment_size,
+                tables,
+            },
+        );
+    }
+
+    Ok(BufferSnapshot {
+        catalog: manifest.catalog,
+        segments,
+    })
+}
+
+impl BufferSnapshot {
+    /// Removes and returns the snapshot of the segment, if it was taken when the segment's WAL
+    /// file ended at the given sequence number
+    pub(crate) fn take_segment(
+        &mut self,
+        segment_id: SegmentId,
+        last_sequence_number: SequenceNumber,
+    ) -> Option<SegmentSnapshot> {
+        self.segments
+            .remove(&segment_id)
+            .filter(|segment| segment.last_sequence_number == last_sequence_number)
+    }
+}
+
+impl SegmentSnapshot {
+    /// Rebuilds the buffer of the segment from the snapshot
+    pub(crate) fn into_buffer(
+        self,
+        catalog: &Catalog,
+        segment_key: &PartitionKey,
+    ) -> Result<(BufferedData, usize)> {
+        let mut buffered_data = BufferedData::default();
+
+        for (db_name, table_name, batches) in self.tables {
+            let schema = catalog
+                .db_schema(&db_name)
+                .filter(|schema| schema.get_table(&table_name).is_some())
+                .ok_or_else(|| {
+                    Error::InvalidSnapshot(format!(
+                        "table {db_name}.{table_name} isn't in the catalog"
+                    ))
+                })?;
+
+            for batch in batches {
+                let table_batch = TableBatch {
+                    name: table_name.clone(),
+                    rows: rows_from_record_batch(&batch)?,
+                };
+                buffered_data.buffer_table_batch(
+                    &db_name,
+                    table_name.clone(),
+                    segment_key,
+                    table_batch,
+                    &schema,
+                );
+            }
+        }
+
+        Ok((buffered_data, self.segment_size))
+    }
+}
+
+/// Converts the record batch of a table buffer back into the rows it was built from. Null values
+/// are left out of the rows, as they are filled in again when the rows are buffered.
+fn rows_from_record_batch(batch: &RecordBatch) -> Result<Vec<Row>> {
+    let mut rows: Vec<Row> = (0..batch.num_rows())
+        .map(|_| Row {
+            time: 0,
+            fields: Vec::with_capacity(batch.num_columns()),
+        })
+        .collect();
+
+    for (field, column) in batch.schema().fields().iter().zip(batch.columns()) {
+        let name = field.name();
+        for (row_index, row) in rows.iter_mut().enumerate() {
+            if column.is_null(row_index) {
+                continue;
+            }
+
+            let value = match column.data_type() {
+                DataType::Dictionary(key, value)
+                    if **key == DataType::Int32 && **value == DataType::Utf8 =>
+                {
+                    let dictionary = column.as_dictionary::<Int32Type>();
+                    let key = dictionary.keys().value(row_index) as usize;
+                    FieldData::Tag(
+                        dictionary
+                            .values()
+                            .as_string::<i32>()
+                            .value(key)
+                            .to_string(),
+                    )
+                }
+                DataType::Utf8 => {
+                    FieldData::String(column.as_string::<i32>().value(row_index).to_string())
+                }
+                DataType::Int64 => {
+                    FieldData::Integer(column.as_primitive::<Int64Type>().value(row_index))
+                }
+                DataType::UInt64 => {
+                    FieldData::UInteger(column.as_primitive::<UInt64Type>().value(row_index))
+                }
+                DataType::Float64 => {
+                    FieldData::Float(column.as_primitive::<Float64Type>().value(row_index))
+                }
+                DataType::Boolean => FieldData::Boolean(column.as_boolean().value(row_index)),
+                DataType::Timestamp(TimeUnit::Nanosecond, _) => {
+                    let time = column
+                        .as_primitive::<TimestampNanosecondType>()
+                        .value(row_index);
+                    if name == TIME_COLUMN_NAME {
+                        row.time = time;
+                    }
+                    FieldData::Timestamp(time)
+                }
+                other => {
+                    return Err(Error::InvalidSnapshot(format!(
+                        "unexpected type {other} for column {name}"
+                    )))
+                }
+            };
+
+            row.fields.push(Field {
+                name: name.to_string(),
+                value,
+            });
+        }
+    }
+
+    Ok(rows)
+}
@@ -0,0 +1,284 @@
+//! Applies the ingest transforms configured for a database to incoming line protocol.
+
+use crate::catalog::{DatabaseSchema, IngestTransform};
+use crate::write_buffer::parse_numbered_lines;
+use data_types::ColumnType;
+use influxdb_line_protocol::{parse_lines, EscapedStr, FieldValue, ParsedLine};
+use std::borrow::Cow;
+
+/// Applies the transforms, in order, to every line in the line protocol. Lines that are changed
+/// are re-serialized, lines that are unchanged or fail to parse are passed through as they are so
+/// that parse errors are reported against the original input. Lines that are left with no fields
+/// are blanked out rather than removed, so that every line keeps its number.
+pub(crate) fn apply_ingest_transforms<'a>(
+    lp: &'a str,
+    transforms: &[IngestTransform],
+) -> Cow<'a, str> {
+    if transforms.is_empty() {
+        return Cow::Borrowed(lp);
+    }
+
+    let mut out: Vec<Cow<'_, str>> = lp.lines().map(Cow::Borrowed).collect();
+    for (line_number, _, maybe_line) in parse_numbered_lines(lp) {
+        let Ok(mut line) = maybe_line else {
+            continue;
+        };
+
+        if !transform_line(&mut line, transforms) {
+            continue;
+        }
+        out[line_number - 1] = if line.field_set.is_empty() {
+            Cow::Borrowed("")
+        } else {
+            Cow::Owned(line.to_string())
+        };
+    }
+
+    Cow::Owned(out.join("\n"))
+}
+
+/// Applies the transforms to the line, returning true if it was changed.
+fn transform_line<'a>(line: &mut ParsedLine<'a>, transforms: &'a [IngestTransform]) -> bool {
+    let mut changed = false;
+
+    for transform in transforms {
+        match transform {
+            IngestTransform::MapMeasurement { from, to } => {
+                if line.series.measurement.as_str() == from.as_str() {
+                    line.series.measurement = EscapedStr::from(to.as_str());
+                    changed = true;
+                }
+            }
+            IngestTransform::RenameTag { table, from, to } => {
+                if !applies_to(line, table.as_deref()) {
+                    continue;
+                }
+                for (tag_key, _) in line.series.tag_set.iter_mut().flatten() {
+                    if tag_key.as_str() == from.as_str() {
+                        *tag_key = EscapedStr::from(to.as_str());
+                        changed = true;
+                    }
+                }
+            }
+            IngestTransform::DropField { table, field } => {
+                if !applies_to(line, table.as_deref()) {
+                    continue;
+                }
+                let field_count = line.field_set.len();
+                line.field_set
+                    .retain(|(field_key, _)| field_key.as_str() != field.as_str());
+                changed |= line.field_set.len() != field_count;
+            }
+        }
+    }
+
+    changed
+}
+
+fn applies_to(line: &ParsedLine<'_>, table: Option<&str>) -> bool {
+    table.map_or(true, |table| line.series.measurement.as_str() == table)
+}
+
+/// Converts field values to the type of the existing column they are written to, where that can
+/// be done without losing precision. Lines that are unchanged or fail to parse are passed through
+/// as they are, and every line keeps its place so that errors are reported against its number.
+pub(crate) fn coerce_field_types<'a>(lp: &'a str, db: &DatabaseSchema) -> Cow<'a, str> {
+    let mut changed = false;
+    let mut out: Vec<Cow<'_, str>> = lp.lines().map(Cow::Borrowed).collect();
+    for (line_number, _, maybe_line) in parse_numbered_lines(lp) {
+        let Ok(mut line) = maybe_line else {
+            continue;
+        };
+
+        if coerce_line(&mut line, db) {
+            out[line_number - 1] = Cow::Owned(line.to_string());
+            changed = true;
+        }
+    }
+
+    if changed {
+        Cow::Owned(out.join("\n"))
+    } else {
+        Cow::Borrowed(lp)
+    }
+}
+
+/// Coerces the fields of the line to the types of their columns, returning true if any were
+/// changed.
+fn coerce_line(line: &mut ParsedLine<'_>, db: &DatabaseSchema) -> bool {
+    let Some(table) = db.tables.get(line.series.measurement.as_str()) else {
+        return false;
+    };
+
+    let mut changed = false;
+    for (field_name, value) in line.field_set.iter_mut() {
+        let Some(&column_type) = table.columns().get(field_name.as_str()) else {
+            continue;
+        };
+
+        let coerced = match *value {
+            FieldValue::I64(v) if column_type == ColumnType::F64 as i16 => {
+                exact_i64_float(v).map(FieldValue::F64)
+            }
+            FieldValue::U64(v) if column_type == ColumnType::F64 as i16 => {
+                exact_u64_float(v).map(FieldValue::F64)
+            }
+            FieldValue::U64(v) if column_type == ColumnType::I64 as i16 => {
+                i64::try_from(v).ok().map(FieldValue::I64)
+            }
+            FieldValue::I64(v) if column_type == ColumnType::U64 as i16 => {
+                u64::try_from(v).ok().map(FieldValue::U64)
+            }
+            _ => None,
+        };
+
+        if let Some(coerced) = coerced {
+            *value = coerced;
+            changed = true;
+        }
+    }
+
+    changed
+}
+
+/// The integer as a float, if it can be represented exactly, which not all integers beyond 2^53
+/// can. The maximum rounds up to 2^63, which saturates back to the maximum, so it is excluded.
+fn exact_i64_float(v: i64) -> Option<f64> {
+    let float = v as f64;
+    (v != i64::MAX && float as i64 == v).then_some(float)
+}
+
+/// The integer as a float, if it can be represented exactly, see [`exact_i64_float`]
+fn exact_u64_float(v: u64) -> Option<f64> {
+    let float = v as f64;
+    (v != u64::MAX && float as u64 == v).then_some(float)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::catalog::TableDefinition;
+    use std::collections::BTreeMap;
+
+    #[test]
+    fn no_transforms_borrows_input() {
+        let lp = "cpu,host=a usage=1 10";
+        assert!(matches!(
+            apply_ingest_transforms(lp, &[]),
+            Cow::Borrowed(s) if s == lp
+        ));
+    }
+
+    #[test]
+    fn applies_transforms_in_order() {
+        let transforms = vec![
+            IngestTransform::MapMeasurement {
+                from: "cpu_old".to_string(),
+                to: "cpu".to_string(),
+            },
+            IngestTransform::RenameTag {
+                table: Some("cpu".to_string()),
+                from: "hostname".to_string(),
+                to: "host".to_string(),
+            },
+            IngestTransform::DropField {
+                table: None,
+                field: "debug".to_string(),
+            },
+        ];
+        let lp = "cpu_old,hostname=a usage=1 10\n\
+                  mem,hostname=a used=2i,debug=true 20\n\
+                  disk debug=true 30\n\
+                  not valid lp\n\
+                  net,host=b rx=3i 40";
+
+        let transformed = apply_ingest_transforms(lp, &transforms);
+
+        assert_eq!(
+            transformed,
+            "cpu,host=a usage=1 10\n\
+             mem,hostname=a used=2i 20\n\
+             \n\
+             not valid lp\n\
+             net,host=b rx=3i 40"
+        );
+    }
+
+    #[test]
+    fn keeps_lines_in_place_around_blank_and_comment_lines() {
+        let transforms = vec![
+            IngestTransform::MapMeasurement {
+                from: "cpu_old".to_string(),
+                to: "cpu".to_string(),
+            },
+            IngestTransform::DropField {
+                table: None,
+                field: "debug".to_string(),
+            },
+        ];
+        let lp = "# a comment\n\
+                  cpu_old usage=1 10\n\
+                  \n\
+                  mem used=2i 20\n\
+                  disk debug=true 30\n\
+                  cpu_old usage=2 40";
+
+        let transformed = apply_ingest_transforms(lp, &transforms);
+
+        assert_eq!(
+            transformed,
+            "# a comment\n\
+             cpu usage=1 10\n\
+             \n\
+             mem used=2i 20\n\
+             \n\
+             cpu usage=2 40"
+        );
+    }
+
+    #[test]
+    fn coerces_fields_to_column_types() {
+        let mut db = DatabaseSchema::new("foo");
+        db.tables.insert(
+            "cpu".to_string(),
+            TableDefinition::new(
+                "cpu",
+                BTreeMap::from([
+                    ("usage".to_string(), ColumnType::F64 as i16),
+                    ("count".to_string(), ColumnType::I64 as i16),
+                    ("total".to_string(), ColumnType::U64 as i16),
+                    ("time".to_string(), ColumnType::Time as i16),
+                ]),
+            ),
+        );
+
+        let lp = "cpu usage=1i,count=2u,total=3i 10\n\
+                  \n\
+                  # a comment\n\
+                  cpu total=-1i 20\n\
+                  mem usage=1i 30\n\
+                  cpu usage=9007199254740993i 40\n\
+                  cpu usage=18446744073709551615u 50\n\
+                  cpu usage=9007199254740992i 60";
+
+        let coerced = coerce_field_types(lp, &db);
+
+        // integers that can't be converted to a float exactly are left as they are, and lines keep
+        // their places around blank and comment lines
+        assert_eq!(
+            coerced,
+            "cpu usage=1,count=2i,total=3u 10\n\
+             \n\
+             # a comment\n\
+             cpu total=-1i 20\n\
+             mem usage=1i 30\n\
+             cpu usage=9007199254740993i 40\n\
+             cpu usage=18446744073709551615u 50\n\
+             cpu usage=9007199254740992 60"
+        );
+        assert!(matches!(
+            coerce_field_types("cpu usage=0.5 10", &db),
+            Cow::Borrowed(_)
+        ));
+    }
+}